@@ -0,0 +1,27 @@
+//! Load and run arbitrary bytecode: malformed code must fail with an error, never panic.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use script::{
+    vm::VM,
+    program::Program,
+    bytecode,
+};
+
+fuzz_target!(|data: &[u8]| {
+    let code = match bytecode::deserialize(data) {
+        Ok(code) => code,
+        Err(_) => return,
+    };
+
+    // integers are run both allocated and held by their pointers
+    let program = Program::new(code);
+    for tagging in [false, true] {
+        let mut vm = VM::new()
+            .with_output(std::io::sink())
+            .with_heap_limit(1 << 16)
+            .with_max_repeat_length(1 << 16)
+            .with_small_int_tagging(tagging);
+        let _ = vm.run_with_limit(&program, 1 << 16);
+    }
+});
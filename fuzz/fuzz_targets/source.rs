@@ -0,0 +1,45 @@
+//! Compile and run arbitrary source: every stage must return `Ok` or `Err`, never panic.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use script::{
+    vm::VM,
+    lexer::Lexer,
+    parser::Parser,
+    compiler::Compiler,
+};
+
+fuzz_target!(|data: &[u8]| {
+    let source = match std::str::from_utf8(data) {
+        Ok(source) => source,
+        Err(_) => return,
+    };
+
+    let mut parser = match Parser::new(Lexer::new(source)) {
+        Ok(parser) => parser,
+        Err(_) => return,
+    };
+    let (asts, _) = parser.parse_all();
+
+    let mut compiler = Compiler::new();
+    for ast in &asts {
+        if compiler.feed(ast).is_err() {
+            return;
+        }
+    }
+    let program = match compiler.build() {
+        Ok(program) => program,
+        Err(_) => return,
+    };
+
+    // bound the resources, so a slow or large script is not reported as a hang
+    // integers are run both allocated and held by their pointers
+    for tagging in [false, true] {
+        let mut vm = VM::new()
+            .with_output(std::io::sink())
+            .with_heap_limit(1 << 16)
+            .with_max_repeat_length(1 << 16)
+            .with_small_int_tagging(tagging);
+        let _ = vm.run_with_limit(&program, 1 << 16);
+    }
+});
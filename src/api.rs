@@ -0,0 +1,58 @@
+use crate::{
+    ast::Ast,
+    compiler::Compiler,
+    errors::Result,
+    lexer::Lexer,
+    parser::Parser,
+    program::Program,
+    value::Value,
+    vm::VM,
+};
+
+/// Compile `source` for `vm` (knowing its registered natives), keeping the value of
+/// the last statement (if an expression) on the stack.
+fn compile_keeping_last(source: &str, vm: &VM) -> Result<Program> {
+    let mut parser = Parser::new(Lexer::new(source))?;
+    let mut compiler = Compiler::new().foreign_of(vm);
+
+    // the last statement is only fed when the next one is known
+    let mut last = None;
+    while let Some(ast) = parser.next()? {
+        if let Some(previous) = last.replace(ast) {
+            compiler.feed(&previous)?;
+        }
+    }
+    match last {
+        Some(Ast::Sttm(expr)) => compiler.feed(&expr)?,
+        Some(ast) => compiler.feed(&ast)?,
+        None => 0,
+    };
+
+    compiler.build()
+}
+
+/// Compile and run `source` on a new `VM`, returning the value of the last statement.
+///
+/// The result is `nil` if the last statement is not an expression (e.g. a loop).
+/// Pointers inside the result (e.g. elements of a list) refer to a `VM` that no longer
+/// exists; use `eval_on` to inspect those.
+pub fn eval(source: &str) -> Result<Value> {
+    eval_on(&mut VM::new(), source)
+}
+
+/// Compile and run `source` on `vm`, returning the value of the last statement.
+///
+/// Globals are kept in `vm`, so they persist across calls, and natives registered on
+/// `vm` can be called.  The result is taken off the stack, and is `nil` if the last
+/// statement is not an expression.
+pub fn eval_on(vm: &mut VM, source: &str) -> Result<Value> {
+    let program = compile_keeping_last(source, vm)?;
+    let depth = vm.stack_len();
+    vm.run(&program)?;
+    if vm.stack_len() > depth {
+        let ptr = vm.pop()?;
+        vm.get_clone(ptr)
+    } else {
+        Ok(Value::Nil)
+    }
+}
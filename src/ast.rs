@@ -8,29 +8,78 @@ pub enum Ast {
     /// literal integer
     Int(i64, Token),
 
+    /// literal float
+    Float(f64, Token),
+
     /// literal string
     Str(String, Token),
 
+    /// string with `${...}` interpolations (first token, parts)
+    /// parts alternate literal `Str` pieces and expressions, starting and ending with a `Str`
+    Interpolation(Token, Vec<Ast>),
+
+    /// literal nil
+    Nil(Token),
+
     /// literal list
     Lst(Vec<Ast>, Token),
 
+    /// literal map, as (key, value) pairs
+    Map(Vec<(Ast, Ast)>, Token),
+
     /// variable name
     Var(String, Token),
 
+    /// unary operator ('-' or '!')
+    Unary(Token, Box<Ast>),
+
     /// binary operator
     BinOp(Token, Box<Ast>, Box<Ast>),
 
+    /// chained comparison (operators, operands), with one more operand than operators
+    /// `a < b < c` is `a < b && b < c`, with `b` evaluated once
+    Chain(Vec<Token>, Vec<Ast>),
+
     /// loop (keyword, starting, comparison, body, updating)
     /// same node for all looping constructs (while, for)
     Loop(Token, Option<Box<Ast>>, Option<Box<Ast>>, Box<Ast>, Option<Box<Ast>>),
 
+    /// loop with a label, for 'break' and 'continue' from nested loops (label, loop)
+    Labeled(Token, Box<Ast>),
+
+    /// 'break' out of the innermost loop, or the one with the label
+    Break(Token, Option<Token>),
+
+    /// 'continue' with the next iteration of the innermost loop, or the one with the label
+    Continue(Token, Option<Token>),
+
     /// ('if', <conditional>, <if_true>, <if_false>)
     /// for expressions, the <if_false> is required!
     IfElse(Token, Box<Ast>, Box<Ast>, Option<Box<Ast>>),
 
+    /// ('match', <subject>, <arms>, <default>) runs the first arm with a pattern equal to
+    /// the subject, each arm as (<patterns>, <block>), or else the default ('_') block
+    Match(Token, Box<Ast>, Vec<(Vec<Ast>, Ast)>, Option<Box<Ast>>),
+
     /// A block is a sequence of Ast's ('{' '}')
     Block(Token, Vec<Ast>),
 
+    /// ('let', <name>, <initial-value>) declaration of a variable
+    Let(Token, String, Box<Ast>),
+
+    /// ('let', <expression>) check if the value of expression is not nil
+    /// used as the condition of `while let`
+    NotNil(Token, Box<Ast>),
+
+    /// ('fun', <name>, <parameters>, <body>) definition of a function
+    Fun(Token, Token, Vec<Token>, Box<Ast>),
+
+    /// ('return', <value>) from a function, `nil` if no value
+    Return(Token, Option<Box<Ast>>),
+
+    /// ('yield', <value>) suspend a generator, making value the result of `resume`
+    Yield(Token, Box<Ast>),
+
     /// expression wrapped as statement
     Sttm(Box<Ast>),
 
@@ -39,38 +88,111 @@ pub enum Ast {
 
     /// Indexing
     Index(Token, Box<Ast>, Box<Ast>),
+
+    /// Slicing ('[', target, start, end)
+    Slice(Token, Box<Ast>, Box<Ast>, Box<Ast>),
 }
 
 impl Ast {
+    /// Range of the source of this node.
     pub fn at(&self) -> Range<usize> {
+        self.start() .. self.end()
+    }
+
+    /// Start of the source of this node, following the leftmost descendants
+    /// in a loop, so it takes no stack and each node is visited only once.
+    fn start(&self) -> usize {
+        let mut ast = self;
+        loop {
+            ast = match ast {
+                Ast::BinOp(_, lhs, _) => lhs,
+                Ast::Chain(_, operands) => operands.first().unwrap(),
+                Ast::Map(entries, _) if !entries.is_empty() => &entries.first().unwrap().0,
+                Ast::Lst(lst, _) | Ast::Block(_, lst) if !lst.is_empty() => lst.first().unwrap(),
+                Ast::Sttm(inner) => inner,
+                Ast::Call(_, callee, _) | Ast::Index(_, callee, _) | Ast::Slice(_, callee, _, _) => callee,
+                Ast::Int(_, tk) | Ast::Float(_, tk) | Ast::Nil(tk) | Ast::Str(_, tk) | Ast::Var(_, tk) => return tk.at.start,
+                Ast::Interpolation(tk, _) | Ast::Map(_, tk) | Ast::Lst(_, tk) | Ast::Block(tk, _) => return tk.at.start,
+                Ast::Unary(tk, _) | Ast::Loop(tk, _, _, _, _) | Ast::Labeled(tk, _) | Ast::Let(tk, _, _) | Ast::NotNil(tk, _) => return tk.at.start,
+                Ast::Yield(tk, _) | Ast::Match(tk, _, _, _) | Ast::Return(tk, _) => return tk.at.start,
+                Ast::Break(tk, _) | Ast::Continue(tk, _) | Ast::IfElse(tk, _, _, _) | Ast::Fun(tk, _, _, _) => return tk.at.start,
+            }
+        }
+    }
+
+    /// End of the source of this node, following the rightmost descendants
+    /// in a loop, for the same reasons as `start`.
+    fn end(&self) -> usize {
+        let mut ast = self;
+        loop {
+            ast = match ast {
+                Ast::Interpolation(_, parts) if !parts.is_empty() => parts.last().unwrap(),
+                Ast::Map(entries, _) if !entries.is_empty() => &entries.last().unwrap().1,
+                Ast::Lst(lst, _) | Ast::Block(_, lst) if !lst.is_empty() => lst.last().unwrap(),
+                Ast::Call(_, _, args) if !args.is_empty() => args.last().unwrap(),
+                Ast::Unary(_, inner) | Ast::Let(_, _, inner) | Ast::NotNil(_, inner) | Ast::Sttm(inner) | Ast::Yield(_, inner) => inner,
+                Ast::BinOp(_, _, rhs) | Ast::Index(_, _, rhs) | Ast::Slice(_, _, _, rhs) => rhs,
+                Ast::Chain(_, operands) => operands.last().unwrap(),
+                Ast::Loop(_, _, _, body, _) | Ast::Labeled(_, body) | Ast::Fun(_, _, _, body) => body,
+                Ast::IfElse(_, _, if_true, if_false) => if_false.as_ref().unwrap_or(if_true),
+                Ast::Match(_, subject, arms, default) => match (default, arms.last()) {
+                    (Some(default), _) => default,
+                    (None, Some((_, body))) => body,
+                    (None, None) => subject,
+                },
+                Ast::Break(tk, label) | Ast::Continue(tk, label) => return label.as_ref().unwrap_or(tk).at.end,
+                Ast::Return(_, Some(value)) => value,
+                Ast::Return(tk, None) => return tk.at.end,
+                Ast::Int(_, tk) | Ast::Float(_, tk) | Ast::Nil(tk) | Ast::Str(_, tk) | Ast::Var(_, tk) => return tk.at.end,
+                Ast::Interpolation(tk, _) | Ast::Map(_, tk) | Ast::Lst(_, tk) | Ast::Block(tk, _) | Ast::Call(tk, _, _) => return tk.at.end,
+            }
+        }
+    }
+
+    /// Line where the source of this node starts
+    pub fn line(&self) -> usize {
         match self {
-            Ast::Int(_, tk) => tk.at.clone(),
-            Ast::Str(_, tk) => tk.at.clone(),
-            Ast::Var(_, tk) => tk.at.clone(),
-            Ast::Lst(lst, tk) => if lst.is_empty() { tk.at.clone() } else { lst.first().unwrap().at().start .. lst.last().unwrap().at().end },
-            Ast::BinOp(_, lhs, rhs) => lhs.at().start .. rhs.at().end,
-            Ast::Loop(tk, _, _, body, _) => tk.at.start .. body.at().end,
-            Ast::Sttm(ast) => ast.at(),
-            Ast::Block(tk, lst) => if lst.is_empty() { tk.at.clone() } else { lst.first().unwrap().at().start .. lst.last().unwrap().at().end },
-            Ast::Call(tk, callee, args) => callee.at().start .. if args.is_empty() { tk.at.end } else { args.last().unwrap().at().end },
-            Ast::Index(_, callee, index) => callee.at().start .. index.at().end,
-            Ast::IfElse(tk, _, if_true, if_false) => tk.at.start .. if if_false.is_some() { if_false.as_ref().unwrap().at().end } else { if_true.at().end },
+            Ast::Int(_, tk) | Ast::Float(_, tk) | Ast::Str(_, tk) | Ast::Var(_, tk) | Ast::Lst(_, tk) | Ast::Map(_, tk) => tk.line,
+            Ast::BinOp(_, lhs, _) => lhs.line(),
+            Ast::Chain(_, operands) => operands.first().unwrap().line(),
+            Ast::Loop(tk, _, _, _, _) | Ast::IfElse(tk, _, _, _) | Ast::Block(tk, _) => tk.line,
+            Ast::Let(tk, _, _) | Ast::NotNil(tk, _) | Ast::Nil(tk) | Ast::Unary(tk, _) => tk.line,
+            Ast::Interpolation(tk, _) | Ast::Yield(tk, _) | Ast::Match(tk, _, _, _) | Ast::Return(tk, _) => tk.line,
+            Ast::Labeled(tk, _) | Ast::Break(tk, _) | Ast::Continue(tk, _) | Ast::Fun(tk, _, _, _) => tk.line,
+            Ast::Sttm(ast) => ast.line(),
+            Ast::Call(_, callee, _) | Ast::Index(_, callee, _) | Ast::Slice(_, callee, _, _) => callee.line(),
         }
     }
 
     pub fn pretty(&self) -> String {
         match self {
             Ast::Int(n, _) => n.to_string(),
+            Ast::Float(n, _) => format!("{:?}", n),
+            Ast::Nil(_) => "nil".to_string(),
             Ast::Str(s, _) => format!("{:?}", s),
-            Ast::Lst(_, _) => format!("list"),
+            Ast::Interpolation(_, _) => "string interpolation".to_string(),
+            Ast::Lst(_, _) => "list".to_string(),
+            Ast::Map(_, _) => "map".to_string(),
             Ast::Var(s, _) => s.clone(),
-            Ast::BinOp(_, _, _) => format!("binary operator"),
+            Ast::Unary(_, _) => "unary operator".to_string(),
+            Ast::BinOp(_, _, _) => "binary operator".to_string(),
+            Ast::Chain(_, _) => "chained comparison".to_string(),
             Ast::Loop(tk, _, _, _, _) => format!("{:?} loop", tk.kind),
-            Ast::IfElse(_, _, _, _) => format!("conditional"),
-            Ast::Block(_, _) => format!("block"),
-            Ast::Sttm(_) => format!("statement"),
-            Ast::Call(_, _, _) => format!("function call"),
-            Ast::Index(_, _, _) => format!("indexing"),
+            Ast::IfElse(_, _, _, _) => "conditional".to_string(),
+            Ast::Match(_, _, _, _) => "match".to_string(),
+            Ast::Labeled(label, body) => format!("{}: {}", label.value, body.pretty()),
+            Ast::Break(_, _) => "break".to_string(),
+            Ast::Continue(_, _) => "continue".to_string(),
+            Ast::Block(_, _) => "block".to_string(),
+            Ast::Let(_, name, _) => format!("declaration of {}", name),
+            Ast::NotNil(_, _) => "nil check".to_string(),
+            Ast::Fun(_, name, _, _) => format!("function {}", name.value),
+            Ast::Return(_, _) => "return".to_string(),
+            Ast::Yield(_, _) => "yield".to_string(),
+            Ast::Sttm(_) => "statement".to_string(),
+            Ast::Call(_, _, _) => "function call".to_string(),
+            Ast::Index(_, _, _) => "indexing".to_string(),
+            Ast::Slice(_, _, _, _) => "slicing".to_string(),
         }
     }
 }
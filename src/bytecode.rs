@@ -0,0 +1,419 @@
+use std::ops::Range;
+use std::sync::Arc;
+use crate::{
+    opcodes::{Op, Native},
+    program::{Program, Function},
+    diagnostic::{Diagnostic, Severity},
+    errors::{Error, Result},
+};
+
+/// First bytes of serialized code, followed by `VERSION`
+const MAGIC: &[u8] = b"SCRB";
+
+/// Version of the encoding, changed on incompatible changes to it
+const VERSION: u8 = 4;
+
+/// Serialize `code` into a compact binary format, to be read back with `deserialize`.
+///
+/// After a header, each opcode is a one-byte tag followed by its operands: `usize` and
+/// `i64` as (zig-zag) LEB128 varints, `f64` as its 8 little-endian bytes, strings as their
+/// length followed by their UTF-8 bytes, and natives by name.  Functions are written with
+/// their name, arity, number of locals, a byte set if a generator and then their code and
+/// source map, as a program's.
+pub fn serialize(code: &[Op]) -> Vec<u8> {
+    write(code, &[], &[])
+}
+
+/// Serialize a whole `program`, to be read back with `deserialize_program`.
+///
+/// The code is written as by `serialize`, followed by the source map as pairs of varints
+/// and the diagnostics as their severity, message and range.
+pub fn serialize_program(program: &Program) -> Vec<u8> {
+    write(&program.code, &program.source_map, &program.diagnostics)
+}
+
+fn write(code: &[Op], source_map: &[Range<usize>], diagnostics: &[Diagnostic]) -> Vec<u8> {
+    let mut out = Writer(MAGIC.to_vec());
+    out.0.push(VERSION);
+    out.code(code, source_map);
+    out.usize(diagnostics.len());
+    for diagnostic in diagnostics {
+        out.0.push(match diagnostic.severity {
+            Severity::Warning => 0,
+            Severity::Note => 1,
+        });
+        out.str(&diagnostic.message);
+        out.range(&diagnostic.at);
+    }
+    out.0
+}
+
+/// `true` if `bytes` start as written by `serialize` (e.g. to tell bytecode from source).
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Read back code written by `serialize`.
+///
+/// Truncated or otherwise invalid input fails with `CorruptBytecode`, with the offset
+/// where the problem was found.
+pub fn deserialize(bytes: &[u8]) -> Result<Vec<Op>> {
+    Ok(deserialize_program(bytes)?.code)
+}
+
+/// Read back a program written by `serialize_program` (or code written by `serialize`,
+/// without source information).
+pub fn deserialize_program(bytes: &[u8]) -> Result<Program> {
+    let mut input = Reader { bytes, at: 0, in_function: false };
+    if input.take(MAGIC.len())? != MAGIC || input.byte()? != VERSION {
+        return Err(Error::CorruptBytecode(0));
+    }
+    let (code, source_map) = input.code()?;
+    let len = input.usize()?;
+    let mut diagnostics = Vec::with_capacity(len.min(bytes.len()));
+    for _ in 0 .. len {
+        let start = input.at;
+        let severity = match input.byte()? {
+            0 => Severity::Warning,
+            1 => Severity::Note,
+            _ => return Err(Error::CorruptBytecode(start)),
+        };
+        let message = input.str()?;
+        diagnostics.push(Diagnostic { severity, message, at: input.range()? });
+    }
+    if input.at != bytes.len() {
+        return Err(Error::CorruptBytecode(input.at));
+    }
+    Ok(Program { code, source_map, diagnostics })
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn usize(&mut self, mut n: usize) {
+        loop {
+            let byte = (n & 0x7f) as u8;
+            n >>= 7;
+            if n == 0 {
+                self.0.push(byte);
+                return;
+            }
+            self.0.push(byte | 0x80);
+        }
+    }
+
+    fn i64(&mut self, n: i64) {
+        // zig-zag, so small negative numbers are short too
+        self.usize(((n << 1) ^ (n >> 63)) as u64 as usize);
+    }
+
+    fn str(&mut self, s: &str) {
+        self.usize(s.len());
+        self.0.extend_from_slice(s.as_bytes());
+    }
+
+    fn range(&mut self, range: &Range<usize>) {
+        self.usize(range.start);
+        self.usize(range.end);
+    }
+
+    fn code(&mut self, code: &[Op], source_map: &[Range<usize>]) {
+        self.usize(code.len());
+        for op in code {
+            self.op(op);
+        }
+        // an empty source map stands for code without source information
+        self.usize(source_map.len());
+        for range in source_map {
+            self.range(range);
+        }
+    }
+
+    fn tagged(&mut self, tag: u8, n: usize) {
+        self.0.push(tag);
+        self.usize(n);
+    }
+
+    fn op(&mut self, op: &Op) {
+        match op {
+            Op::Target(id) => self.tagged(0, *id),
+            Op::Nop => self.0.push(1),
+            Op::Native(nargs, native) => {
+                self.tagged(2, *nargs);
+                self.str(native.name());
+            }
+            Op::Foreign(nargs, name) => {
+                self.tagged(3, *nargs);
+                self.str(name);
+            }
+            Op::PushI(n) => {
+                self.0.push(4);
+                self.i64(*n);
+            }
+            Op::PushI0 => self.0.push(5),
+            Op::PushI1 => self.0.push(6),
+            Op::PushIByte(n) => self.0.extend_from_slice(&[7, *n as u8]),
+            Op::PushF(n) => {
+                self.0.push(8);
+                self.0.extend_from_slice(&n.to_le_bytes());
+            }
+            Op::PushNil => self.0.push(9),
+            Op::PushS(s) => {
+                self.0.push(10);
+                self.str(s);
+            }
+            Op::MakeList(n) => self.tagged(11, *n),
+            Op::MakeMap(n) => self.tagged(12, *n),
+            Op::Index => self.0.push(13),
+            Op::IndexStore => self.0.push(14),
+            Op::Slice => self.0.push(15),
+            Op::SliceStore => self.0.push(16),
+            Op::Dup(n) => self.tagged(17, *n),
+            Op::Pop => self.0.push(18),
+            Op::Swap => self.0.push(19),
+            Op::Line(n) => self.tagged(20, *n),
+            Op::NotNil => self.0.push(21),
+            Op::LoadG(name) => {
+                self.0.push(22);
+                self.str(name);
+            }
+            Op::StoreG(name) => {
+                self.0.push(23);
+                self.str(name);
+            }
+            Op::MoveG(name) => {
+                self.0.push(24);
+                self.str(name);
+            }
+            Op::Lt => self.0.push(25),
+            Op::Lte => self.0.push(26),
+            Op::Gt => self.0.push(27),
+            Op::Gte => self.0.push(28),
+            Op::Eq => self.0.push(29),
+            Op::Neq => self.0.push(30),
+            Op::JmpF(target) => self.tagged(31, *target),
+            Op::JmpT(target) => self.tagged(32, *target),
+            Op::Jmp(target) => self.tagged(33, *target),
+            Op::Add => self.0.push(34),
+            Op::Sub => self.0.push(35),
+            Op::Mul => self.0.push(36),
+            Op::Div => self.0.push(37),
+            Op::Mod => self.0.push(38),
+            Op::Neg => self.0.push(39),
+            Op::Not => self.0.push(40),
+            Op::Concat => self.0.push(41),
+            Op::AddI => self.0.push(42),
+            Op::SubI => self.0.push(43),
+            Op::MulI => self.0.push(44),
+            Op::Interpolate(n) => self.tagged(45, *n),
+            Op::Shl => self.0.push(46),
+            Op::Shr => self.0.push(47),
+            Op::Function(function) => {
+                self.0.push(48);
+                self.str(&function.name);
+                self.usize(function.arity);
+                self.usize(function.locals);
+                self.0.push(function.generator as u8);
+                self.code(&function.program.code, &function.program.source_map);
+            }
+            Op::Call(nargs) => self.tagged(49, *nargs),
+            Op::Return => self.0.push(50),
+            Op::LoadL(slot) => self.tagged(51, *slot),
+            Op::StoreL(slot) => self.tagged(52, *slot),
+            Op::MoveL(slot) => self.tagged(53, *slot),
+            Op::Yield => self.0.push(54),
+            Op::IndexInto => self.0.push(56),
+            Op::JmpTable(base, targets) => {
+                self.0.push(55);
+                self.i64(*base);
+                self.usize(targets.len());
+                targets.iter().for_each(|target| self.usize(*target));
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+    /// Reading the code of a function, where no other function can be defined
+    in_function: bool,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8]> {
+        if n > self.bytes.len() - self.at {
+            return Err(Error::CorruptBytecode(self.bytes.len()));
+        }
+        self.at += n;
+        Ok(&self.bytes[self.at - n .. self.at])
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn usize(&mut self) -> Result<usize> {
+        let start = self.at;
+        let mut n: usize = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.byte()?;
+            let bits = (byte & 0x7f) as usize;
+            if shift >= usize::BITS || (bits << shift) >> shift != bits {
+                return Err(Error::CorruptBytecode(start));
+            }
+            n |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+            shift += 7;
+        }
+    }
+
+    fn i64(&mut self) -> Result<i64> {
+        let n = self.usize()? as u64;
+        Ok((n >> 1) as i64 ^ -((n & 1) as i64))
+    }
+
+    fn str(&mut self) -> Result<String> {
+        let start = self.at;
+        let len = self.usize()?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| Error::CorruptBytecode(start))
+    }
+
+    fn range(&mut self) -> Result<Range<usize>> {
+        let start = self.usize()?;
+        Ok(start .. self.usize()?)
+    }
+
+    fn code(&mut self) -> Result<(Vec<Op>, Vec<Range<usize>>)> {
+        let len = self.usize()?;
+        // do not trust `len` for the capacity, as each opcode takes at least one byte
+        let mut code = Vec::with_capacity(len.min(self.bytes.len()));
+        for _ in 0 .. len {
+            code.push(self.op()?);
+        }
+        let start = self.at;
+        let source_map = match self.usize()? {
+            0 => vec![0 .. 0; code.len()],
+            n if n == code.len() => (0 .. n).map(|_| self.range()).collect::<Result<_>>()?,
+            _ => return Err(Error::CorruptBytecode(start)),
+        };
+        Ok((code, source_map))
+    }
+
+    fn function(&mut self, start: usize) -> Result<Function> {
+        // as the compiler, no nesting (that would also be read recursively)
+        if self.in_function {
+            return Err(Error::CorruptBytecode(start));
+        }
+        let name = self.str()?;
+        let arity = self.usize()?;
+        let locals = self.usize()?;
+        let generator = match self.byte()? {
+            0 => false,
+            1 => true,
+            _ => return Err(Error::CorruptBytecode(self.at - 1)),
+        };
+        self.in_function = true;
+        let code = self.code();
+        self.in_function = false;
+        let (code, source_map) = code?;
+        Ok(Function { name, arity, locals, generator, program: Program { code, source_map, diagnostics: vec![] } })
+    }
+
+    fn native(&mut self) -> Result<Native> {
+        let start = self.at;
+        let name = self.str()?;
+        Native::ALL.iter()
+            .find(|native| native.name() == name)
+            .cloned()
+            .ok_or(Error::CorruptBytecode(start))
+    }
+
+    fn op(&mut self) -> Result<Op> {
+        let start = self.at;
+        let op = match self.byte()? {
+            0 => Op::Target(self.usize()?),
+            1 => Op::Nop,
+            2 => {
+                let nargs = self.usize()?;
+                Op::Native(nargs, self.native()?)
+            }
+            3 => {
+                let nargs = self.usize()?;
+                Op::Foreign(nargs, self.str()?)
+            }
+            4 => Op::PushI(self.i64()?),
+            5 => Op::PushI0,
+            6 => Op::PushI1,
+            7 => Op::PushIByte(self.byte()? as i8),
+            8 => {
+                let mut n = [0; 8];
+                n.copy_from_slice(self.take(8)?);
+                Op::PushF(f64::from_le_bytes(n))
+            }
+            9 => Op::PushNil,
+            10 => Op::PushS(self.str()?),
+            11 => Op::MakeList(self.usize()?),
+            12 => Op::MakeMap(self.usize()?),
+            13 => Op::Index,
+            14 => Op::IndexStore,
+            15 => Op::Slice,
+            16 => Op::SliceStore,
+            17 => Op::Dup(self.usize()?),
+            18 => Op::Pop,
+            19 => Op::Swap,
+            20 => Op::Line(self.usize()?),
+            21 => Op::NotNil,
+            22 => Op::LoadG(self.str()?),
+            23 => Op::StoreG(self.str()?),
+            24 => Op::MoveG(self.str()?),
+            25 => Op::Lt,
+            26 => Op::Lte,
+            27 => Op::Gt,
+            28 => Op::Gte,
+            29 => Op::Eq,
+            30 => Op::Neq,
+            31 => Op::JmpF(self.usize()?),
+            32 => Op::JmpT(self.usize()?),
+            33 => Op::Jmp(self.usize()?),
+            34 => Op::Add,
+            35 => Op::Sub,
+            36 => Op::Mul,
+            37 => Op::Div,
+            38 => Op::Mod,
+            39 => Op::Neg,
+            40 => Op::Not,
+            41 => Op::Concat,
+            42 => Op::AddI,
+            43 => Op::SubI,
+            44 => Op::MulI,
+            45 => Op::Interpolate(self.usize()?),
+            46 => Op::Shl,
+            47 => Op::Shr,
+            48 => Op::Function(Arc::new(self.function(start)?)),
+            49 => Op::Call(self.usize()?),
+            50 => Op::Return,
+            51 => Op::LoadL(self.usize()?),
+            52 => Op::StoreL(self.usize()?),
+            53 => Op::MoveL(self.usize()?),
+            54 => Op::Yield,
+            55 => {
+                let base = self.i64()?;
+                let len = self.usize()?;
+                // as for code, each target takes at least one byte
+                let mut targets = Vec::with_capacity(len.min(self.bytes.len()));
+                for _ in 0 .. len {
+                    targets.push(self.usize()?);
+                }
+                Op::JmpTable(base, targets)
+            }
+            56 => Op::IndexInto,
+            _ => return Err(Error::CorruptBytecode(start)),
+        };
+        Ok(op)
+    }
+}
@@ -1,24 +1,112 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::sync::Arc;
 
 use crate::{
     ast::Ast,
     opcodes::{Op, Native},
+    program::{Program, Function},
+    diagnostic::Diagnostic,
+    vm::VM,
     errors::{Error, Result},
     token::{Token, Kind},
 };
 
+/// Default maximum number of opcodes in a compiled program
+const DEFAULT_MAX_OPCODES: usize = 1 << 20;
+
+/// Default maximum nesting of the `Ast`s fed, as `Parser`'s
+const DEFAULT_MAX_DEPTH: usize = crate::parser::DEFAULT_MAX_DEPTH;
+
+/// Minimum number of integer patterns in a `match` compiled into an `Op::JmpTable`
+const MIN_JUMP_TABLE_PATTERNS: usize = 4;
+
+/// Maximum number of opcodes in the body of a function whose calls are inlined
+const MAX_INLINED_OPCODES: usize = 16;
+
+/// Opcodes, each with the range of source that originated it
+type Code = Vec<(Op, Range<usize>)>;
+
+/// Type of an expression, as far as it is known at compile time.
+///
+/// It is inferred from literals and operators, bottom-up as `Ast`s are fed.  Variables
+/// (and anything depending on them) are `Unknown`, as globals can be assigned values of
+/// any type at any moment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StaticType {
+    Int,
+    Str,
+    Unknown,
+}
+
 /// The compiler is fed `Ast`'s from the `Parser` and, in the end, output a sequence of `Op` with
 /// the instructions that shall be executed by the `VM`.
 pub struct Compiler {
-    /// Vector of opcodes generated during compilation
-    code: Vec<Op>,
+    /// Vector of opcodes generated during compilation,
+    /// each with the range of source that originated it
+    code: Vec<(Op, Range<usize>)>,
+
+    /// Number of opcodes in `code` kept by `build` (all but `Target` and `Nop`),
+    /// that is, the index in the built code of the next one emitted
+    built_len: usize,
 
     /// List of local jump targets
     target_count: usize,
 
     /// Name of native function calls, handled directly by the VM
-    /// map to (opcodes::Native, min-num-of-args)
-    native_calls: HashMap<String, (Native, usize)>,
+    native_calls: HashMap<String, Native>,
+
+    /// Name and arity of native functions registered by the host on the VM
+    foreign_calls: HashMap<String, usize>,
+
+    /// Names of globals declared so far (with `let` or, if not strict, by assignment)
+    declared: HashSet<String>,
+
+    /// In strict mode assigning to a name that was not declared is an error
+    strict: bool,
+
+    /// Emit `Op::Line` markers before statements
+    line_markers: bool,
+
+    /// Jump targets of the loops being compiled, innermost last: (label, continue, break)
+    loops: Vec<(Option<String>, usize, usize)>,
+
+    /// Label of the next loop to be compiled
+    next_label: Option<String>,
+
+    /// Maximum number of opcodes in the built program
+    max_opcodes: usize,
+
+    /// Non-fatal findings so far
+    diagnostics: Vec<Diagnostic>,
+
+    /// Globals declared with `let`, and where, to note those never read
+    lets: Vec<(String, Range<usize>)>,
+
+    /// Globals called as functions, and where, to warn of those never declared
+    called: Vec<(String, Range<usize>)>,
+
+    /// Current nesting of the `Ast` being fed
+    depth: usize,
+
+    /// Maximum allowed `depth`, to avoid overflowing the (native) stack
+    max_depth: usize,
+
+    /// Names of the parameters and locals of the function being compiled, by slot
+    /// (starting at 1, as slot 0 is for the value returned); `None` at the top level
+    locals: Option<Vec<String>>,
+
+    /// Replace calls to small functions by their body
+    inline_calls: bool,
+
+    /// Functions whose calls are inlined, by name: arity and code run in place of the call
+    inlined: HashMap<String, (usize, Code)>,
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 // TODO: scopes
@@ -26,20 +114,98 @@ pub struct Compiler {
 
 impl Compiler {
     pub fn new() -> Compiler {
-        let native_calls = {
-            let mut h = HashMap::new();
-            h.insert("print".to_string(), (Native::Print, 0));
-            h.insert("length".to_string(), (Native::Length, 1));
-            h.insert("to_string".to_string(), (Native::ToString, 1));
-            h.insert("append".to_string(), (Native::Append, 2));
-            h.insert("dump_stack".to_string(), (Native::DumpStack, 0));
-            h
-        };
+        let native_calls = Native::ALL.iter()
+            .map(|native| (native.name().to_string(), native.clone()))
+            .collect();
 
         Compiler {
             code: vec![],
+            built_len: 0,
             target_count: 0,
             native_calls,
+            foreign_calls: HashMap::new(),
+            declared: HashSet::new(),
+            strict: false,
+            line_markers: false,
+            loops: vec![],
+            next_label: None,
+            max_opcodes: DEFAULT_MAX_OPCODES,
+            diagnostics: vec![],
+            lets: vec![],
+            called: vec![],
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            locals: None,
+            inline_calls: false,
+            inlined: HashMap::new(),
+        }
+    }
+
+    /// Enable or disable strict mode.
+    ///
+    /// In strict mode a variable must be declared with `let` before being assigned to,
+    /// so a typo on the target of an assignment is reported instead of silently
+    /// creating a new global.
+    pub fn strict(mut self, strict: bool) -> Compiler {
+        self.strict = strict;
+        self
+    }
+
+    /// Enable or disable line markers.
+    ///
+    /// With line markers, an `Op::Line` is emitted before each statement (and loop
+    /// condition), so the VM knows the source line being executed.
+    pub fn line_markers(mut self, line_markers: bool) -> Compiler {
+        self.line_markers = line_markers;
+        self
+    }
+
+    /// Set the maximum number of opcodes of the built program.
+    ///
+    /// `build` fails with `ProgramTooLarge` above it, so a script from an untrusted source
+    /// cannot produce arbitrarily large code.
+    pub fn max_opcodes(mut self, max_opcodes: usize) -> Compiler {
+        self.max_opcodes = max_opcodes;
+        self
+    }
+
+    /// Set the maximum nesting of the `Ast`s fed.
+    ///
+    /// Compiling is recursive, so `feed` fails with `NestingTooDeep` above it instead of
+    /// crashing on `Ast`s built by hand (the `Parser` already limits those it returns).
+    pub fn max_depth(mut self, max_depth: usize) -> Compiler {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Enable or disable inlining of calls.
+    ///
+    /// With inlining, a call by name to a function defined before, whose body is short
+    /// and straight (no loops, conditionals, calls or `let`s), runs its body in place,
+    /// without the cost of the call.  This assumes the global holding the function is not
+    /// assigned by the host, or by code compiled after the call, before the call is run.
+    pub fn inline_calls(mut self, inline_calls: bool) -> Compiler {
+        self.inline_calls = inline_calls;
+        self
+    }
+
+    /// Declare a native function `name`, taking `arity` arguments, registered by the host.
+    ///
+    /// Calls to `name` are compiled into `Op::Foreign`, to be resolved by the VM when run.
+    pub fn foreign(mut self, name: &str, arity: usize) -> Compiler {
+        self.foreign_calls.insert(name.to_string(), arity);
+        self
+    }
+
+    /// Declare all native functions registered on `vm`, as with `foreign`.
+    pub fn foreign_of(self, vm: &VM) -> Compiler {
+        vm.foreign_natives().fold(self, |compiler, (name, arity)| compiler.foreign(name, arity))
+    }
+
+    /// Emit a line marker for `ast`, if enabled.
+    fn mark_line(&mut self, ast: &Ast) {
+        if self.line_markers {
+            self.emit(Op::Line(ast.line()), &ast.at());
         }
     }
 
@@ -50,22 +216,114 @@ impl Compiler {
         t
     }
 
+    /// Append `op` to the code, originating from source range `at`
+    fn emit(&mut self, op: Op, at: &Range<usize>) {
+        if !matches!(op, Op::Target(_) | Op::Nop) {
+            self.built_len += 1;
+        }
+        // calls compiled after a function is assigned another value are not inlined
+        if let Op::StoreG(name) | Op::MoveG(name) = &op {
+            self.inlined.remove(name);
+        }
+        self.code.push((op, at.clone()));
+    }
+
+    /// Type of the result of the binary operator `kind`, from the types of its operands.
+    fn binop_type(kind: Kind, types: (StaticType, StaticType)) -> StaticType {
+        match (kind, types) {
+            (Kind::Lt, _) | (Kind::Lte, _) | (Kind::Gt, _) | (Kind::Gte, _) | (Kind::Eq, _) | (Kind::NotEq, _) => StaticType::Int,
+            (Kind::Shl, _) | (Kind::Shr, _) => StaticType::Int,
+            (Kind::Add, (StaticType::Str, StaticType::Str)) => StaticType::Str,
+            (Kind::Mul, (StaticType::Str, StaticType::Int)) => StaticType::Str,
+            (_, (StaticType::Int, StaticType::Int)) => StaticType::Int,
+            _ => StaticType::Unknown,
+        }
+    }
+
+    /// Return the smallest push Op for an integer constant
+    fn push_int(n: i64) -> Op {
+        match n {
+            0 => Op::PushI0,
+            1 => Op::PushI1,
+            -128..=127 => Op::PushIByte(n as i8),
+            _ => Op::PushI(n),
+        }
+    }
+
+    /// Feed `cond` followed by a jump to `target` taken when its truth is `when`.
+    ///
+    /// A leading `!` is compiled by flipping the jump instead of emitting `Op::Not`.
+    fn feed_jump(&mut self, cond: &Ast, when: bool, target: usize, at: &Range<usize>) -> Result<()> {
+        let (mut cond, mut when) = (cond, when);
+        while let Ast::Unary(tk, operand) = cond {
+            if tk.kind != Kind::Not {
+                break;
+            }
+            cond = operand;
+            when = !when;
+        }
+        self.feed(cond)?;
+        self.emit(if when { Op::JmpT(target) } else { Op::JmpF(target) }, at);
+        Ok(())
+    }
+
     /// Return the Op to use from a BinOp Kind
-    fn op_from_tk(tk: &Token) -> Op {
-        match tk.kind {
+    fn op_from_tk(tk: &Token) -> Result<Op> {
+        let op = match tk.kind {
             Kind::Add => Op::Add,
             Kind::Sub => Op::Sub,
             Kind::Mul => Op::Mul,
             Kind::Div => Op::Div,
             Kind::Mod => Op::Mod,
+            Kind::Shl => Op::Shl,
+            Kind::Shr => Op::Shr,
             Kind::Lt  => Op::Lt,
             Kind::Lte => Op::Lte,
             Kind::Gt  => Op::Gt,
             Kind::Gte => Op::Gte,
             Kind::Eq  => Op::Eq,
             Kind::NotEq => Op::Neq,
-            _ => panic!("compiler got invalid binary operator from parser {:?}", tk),
+            _ => return Err(Error::ParsingError(tk.clone())),
+        };
+        Ok(op)
+    }
+
+    /// Feed a new `ast` to the compiler, as with `feed`.
+    ///
+    /// Return the range of indices, in the code of the built `Program`, of the opcodes
+    /// written for `ast`, along with its source range (in chars).  Jump targets are not
+    /// counted, as `build` removes them, and its optimizations replace opcodes but never
+    /// remove or move them, so the range stays valid.
+    pub fn feed_range(&mut self, ast: &Ast) -> Result<(Range<usize>, Range<usize>)> {
+        let starting = self.built_len;
+        self.feed(ast)?;
+        Ok((starting .. self.built_len, ast.at()))
+    }
+
+    /// Feed the assignment of `rhs` to the variable `lhs` (an `Ast::Var`), a local of the
+    /// function being compiled or else a global.
+    ///
+    /// With `keep` the value is left on the stack (`StoreG`), as the result of an assignment
+    /// inside an expression; otherwise it is moved to the variable (`MoveG`).
+    fn feed_assign_var(&mut self, lhs: &Ast, rhs: &Ast, keep: bool, at: &Range<usize>) -> Result<StaticType> {
+        let name = match lhs {
+            Ast::Var(name, _) => name,
+            _ => return Err(Error::InvalidAssignmentTarget(lhs.clone())),
+        };
+        if let Some(slot) = self.local(name) {
+            let typed = self.feed_typed(rhs)?;
+            self.emit(if keep { Op::StoreL(slot) } else { Op::MoveL(slot) }, at);
+            return Ok(typed);
+        }
+        if !self.declared.contains(name) {
+            if self.strict {
+                return Err(Error::UndeclaredAssignment(lhs.clone()))
+            }
+            self.declared.insert(name.clone());
         }
+        let typed = self.feed_typed(rhs)?;
+        self.emit(if keep { Op::StoreG(name.clone()) } else { Op::MoveG(name.clone()) }, at);
+        Ok(typed)
     }
 
     /// Feed a new `ast` to the compiler.
@@ -77,193 +335,900 @@ impl Compiler {
     /// final `build` may not match the sum of the returns of all `feed`s!
     pub fn feed(&mut self, ast: &Ast) -> Result<usize> {
         let starting = self.code.len();
+        self.feed_typed(ast)?;
+        Ok(self.code.len() - starting)
+    }
+
+    /// Feed `ast`, returning the type of the value it leaves on the stack (if any).
+    fn feed_typed(&mut self, ast: &Ast) -> Result<StaticType> {
+        if self.depth >= self.max_depth {
+            return Err(Error::NestingTooDeep(ast.at().start));
+        }
+        self.depth += 1;
+        let typed = self.feed_nested(ast);
+        self.depth -= 1;
+        typed
+    }
+
+    /// Implementation of `feed_typed`, one nesting level deeper
+    fn feed_nested(&mut self, ast: &Ast) -> Result<StaticType> {
+        // each case is fed by its own function, as they would all take space in the
+        // (recursive) stack frame of this one; the range is computed only where needed,
+        // as it walks down to the first and last tokens, and doing it at each level of a
+        // long `1 + 2 + ...` would be quadratic
+        let at = || ast.at();
+        if let Ast::Sttm(_) | Ast::Let(_, _, _) | Ast::IfElse(_, _, _, _) | Ast::Match(_, _, _, _) | Ast::Return(_, _) | Ast::Yield(_, _) = ast {
+            self.mark_line(ast);
+        }
+        match ast {
+            Ast::Sttm(inner) => self.feed_statement(inner, &at()),
+            Ast::Int(n, _) => self.feed_push(Self::push_int(*n), StaticType::Int, &at()),
+            Ast::Float(n, _) => self.feed_push(Op::PushF(*n), StaticType::Unknown, &at()),
+            Ast::Nil(_) => self.feed_push(Op::PushNil, StaticType::Unknown, &at()),
+            Ast::Str(s, _) => self.feed_push(Op::PushS(s.clone()), StaticType::Str, &at()),
+            Ast::Var(s, _) => match self.local(s) {
+                Some(slot) => self.feed_push(Op::LoadL(slot), StaticType::Unknown, &at()),
+                None => self.feed_push(Op::LoadG(s.clone()), StaticType::Unknown, &at()),
+            },
+            Ast::Interpolation(_, parts) => self.feed_interpolation(parts, &at()),
+            Ast::Lst(lst, _) => self.feed_list(lst, &at()),
+            Ast::Map(entries, _) => self.feed_map(entries, &at()),
+            Ast::BinOp(tk, lhs, rhs) if tk.kind == Kind::Assign => self.feed_assign(lhs, rhs, &at()),
+            Ast::Let(_, name, value) => self.feed_let(name, value, &at()),
+            Ast::NotNil(_, value) => self.feed_not_nil(value, &at()),
+            Ast::Unary(tk, operand) => self.feed_unary(tk, operand, &at()),
+            Ast::BinOp(tk, lhs, rhs) if tk.kind == Kind::And || tk.kind == Kind::Or => self.feed_short_circuit(tk, lhs, rhs),
+            Ast::BinOp(tk, lhs, rhs) => self.feed_binop(tk, lhs, rhs),
+            Ast::Chain(operators, operands) => self.feed_chain(operators, operands, &at()),
+            Ast::Labeled(label, body) => self.feed_labeled(label, body),
+            Ast::Loop(_, st, cmp, body, up) => self.feed_loop(st, cmp, body, up, &at()),
+            Ast::IfElse(_, conditional, if_true, if_false) => self.feed_if_else(conditional, if_true, if_false, &at()),
+            Ast::Match(_, subject, arms, default) => self.feed_match(subject, arms, default, &at()),
+            Ast::Break(_, label) | Ast::Continue(_, label) => self.feed_break_or_continue(ast, label, &at()),
+            Ast::Block(_, asts) => self.feed_block(asts),
+            Ast::Index(tk, lhs, rhs) => self.feed_index(tk, lhs, rhs),
+            Ast::Slice(tk, target, start, end) => self.feed_slice(tk, target, start, end),
+            Ast::Call(_, callee, args) => self.feed_call(ast, callee, args, &at()),
+            Ast::Fun(_, name, params, body) => self.feed_function(ast, name, params, body, &at()),
+            Ast::Return(_, value) => self.feed_return(ast, value, &at()),
+            Ast::Yield(_, value) => self.feed_yield(ast, value, &at()),
+        }
+    }
+
+    /// Feed a literal or variable, that is the single `op` pushing a value of type `typed`.
+    fn feed_push(&mut self, op: Op, typed: StaticType, at: &Range<usize>) -> Result<StaticType> {
+        self.emit(op, at);
+        Ok(typed)
+    }
+
+    /// Feed an expression statement `ast`, whose value is not kept.
+    fn feed_statement(&mut self, ast: &Ast, at: &Range<usize>) -> Result<StaticType> {
         match ast {
-            Ast::Sttm(ast) => {
+            // the value of an assignment statement is not used, so it is not kept
+            Ast::BinOp(tk, lhs, rhs) if tk.kind == Kind::Assign && matches!(**lhs, Ast::Var(_, _)) => {
+                self.feed_assign_var(lhs, rhs, false, &ast.at())?;
+            }
+            _ => {
                 self.feed(ast)?;
-                self.code.push(Op::Pop);
+                self.emit(Op::Pop, at);
             }
-            Ast::Int(n, _) => {
-                self.code.push(Op::PushI(*n));
+        }
+        Ok(StaticType::Unknown)
+    }
+
+    /// Feed the `parts` of a string interpolation, joined into a string.
+    fn feed_interpolation(&mut self, parts: &[Ast], at: &Range<usize>) -> Result<StaticType> {
+        // empty literal pieces (e.g. around `"${x}"`) add nothing to the result
+        let parts: Vec<&Ast> = parts.iter()
+            .filter(|part| !matches!(part, Ast::Str(s, _) if s.is_empty()))
+            .collect();
+        for part in parts.iter() {
+            self.feed(part)?;
+        }
+        self.emit(Op::Interpolate(parts.len()), at);
+        Ok(StaticType::Str)
+    }
+
+    /// Feed a literal list.
+    fn feed_list(&mut self, lst: &[Ast], at: &Range<usize>) -> Result<StaticType> {
+        for ast in lst.iter() {
+            self.feed(ast)?;
+        }
+        self.emit(Op::MakeList(lst.len()), at);
+        Ok(StaticType::Unknown)
+    }
+
+    /// Feed a literal map.
+    fn feed_map(&mut self, entries: &[(Ast, Ast)], at: &Range<usize>) -> Result<StaticType> {
+        for (key, value) in entries.iter() {
+            self.feed(key)?;
+            self.feed(value)?;
+        }
+        self.emit(Op::MakeMap(entries.len()), at);
+        Ok(StaticType::Unknown)
+    }
+
+    /// Feed the assignment of `rhs` to `lhs`, leaving the value on the stack.
+    fn feed_assign(&mut self, lhs: &Ast, rhs: &Ast, at: &Range<usize>) -> Result<StaticType> {
+        match lhs {
+            Ast::Var(_, _) => self.feed_assign_var(lhs, rhs, true, at),
+            Ast::Index(_, target, index) => {
+                // the value is fed first, as it is what is left as the result
+                let typed = self.feed_typed(rhs)?;
+                self.feed(index)?;
+                self.feed_path(target)?;
+                self.emit(Op::IndexStore, at);
+                Ok(typed)
             }
-            Ast::Str(s, _) => {
-                self.code.push(Op::PushS(s.clone()));
+            Ast::Slice(_, target, start, end) => {
+                let typed = self.feed_typed(rhs)?;
+                self.feed(start)?;
+                self.feed(end)?;
+                self.feed(target)?;
+                self.emit(Op::SliceStore, at);
+                Ok(typed)
             }
-            Ast::Lst(lst, _) => {
-                for ast in lst.iter() {
-                    self.feed(ast)?;
+            _ => Err(Error::InvalidAssignmentTarget(lhs.clone())),
+        }
+    }
+
+    /// Feed `target` of an indexed assignment, checking that each container indexed
+    /// along it (as `a.b` in `a.b.c = x`) is there to store into.
+    fn feed_path(&mut self, target: &Ast) -> Result<()> {
+        // followed in a loop, as a path can be as long as the parser allows
+        let mut path = vec![];
+        let mut root = target;
+        while let Ast::Index(tk, inner, index) = root {
+            path.push((tk, index));
+            root = inner;
+        }
+        self.feed(root)?;
+        for (tk, index) in path.into_iter().rev() {
+            self.feed(index)?;
+            self.emit(Op::IndexInto, &tk.at);
+        }
+        Ok(())
+    }
+
+    /// Slot of the local `name` of the function being compiled, if any.
+    fn local(&self, name: &str) -> Option<usize> {
+        self.locals.as_ref()?.iter().rposition(|local| local == name).map(|i| i + 1)
+    }
+
+    /// Feed the declaration of the variable `name`, with initial `value`.
+    ///
+    /// Inside a function it is a local, for the whole function; otherwise a global.
+    fn feed_let(&mut self, name: &str, value: &Ast, at: &Range<usize>) -> Result<StaticType> {
+        self.feed(value)?;
+        if let Some(locals) = self.locals.as_mut() {
+            let slot = match locals.iter().rposition(|local| local == name) {
+                Some(i) => {
+                    self.diagnostics.push(Diagnostic::warning(format!("'{}' is already declared", name), at.clone()));
+                    i + 1
                 }
-                self.code.push(Op::MakeList(lst.len()));
-            }
-            Ast::Var(s, _) => {
-                // TODO: lookup and check if global or local
-                self.code.push(Op::LoadG(s.clone()));
-            }
-            Ast::BinOp(tk, lhs, rhs) if tk.kind == Kind::Assign => {
-                match &**lhs {
-                    Ast::Var(name, _) => {
-                        // TODO: lookup and check if global or local
-                        self.feed(rhs)?;
-                        self.code.push(Op::StoreG(name.clone()));
-                    }
-                    Ast::Index(_, target, index) => {
-                        self.feed(rhs)?;
-                        self.feed(index)?;
-                        self.feed(target)?;
-                        self.code.push(Op::IndexStore);
-                    }
-                    _ => {
-                        return Err(Error::InvalidAssignmentTarget(*lhs.clone()))
+                None => {
+                    locals.push(name.to_string());
+                    locals.len()
+                }
+            };
+            self.emit(Op::MoveL(slot), at);
+            return Ok(StaticType::Unknown);
+        }
+        if !self.declared.insert(name.to_string()) {
+            self.diagnostics.push(Diagnostic::warning(format!("'{}' is already declared", name), at.clone()));
+        }
+        self.lets.push((name.to_string(), at.clone()));
+        self.emit(Op::MoveG(name.to_string()), at);
+        Ok(StaticType::Unknown)
+    }
+
+    /// Declare the variable bound by the assignment `value` of a `while let`, if any.
+    fn declare_bound(&mut self, value: &Ast) {
+        if let Ast::BinOp(_, lhs, _) = value {
+            if let Ast::Var(name, _) = &**lhs {
+                match self.locals.as_mut() {
+                    Some(locals) if !locals.contains(name) => locals.push(name.clone()),
+                    Some(_) => (),
+                    None => { self.declared.insert(name.clone()); }
+                }
+            }
+        }
+    }
+
+    /// Feed the nil check of `while let`.
+    fn feed_not_nil(&mut self, value: &Ast, at: &Range<usize>) -> Result<StaticType> {
+        // the variable bound by `while let` is declared by it
+        self.declare_bound(value);
+        self.feed(value)?;
+        self.emit(Op::NotNil, at);
+        Ok(StaticType::Unknown)
+    }
+
+    /// Feed the unary operator `tk` applied to `operand`.
+    fn feed_unary(&mut self, tk: &Token, operand: &Ast, at: &Range<usize>) -> Result<StaticType> {
+        // negative literals are folded into a single push (but not `i64::MIN`, only
+        // possible in an `Ast` built by hand, that overflows when negated at run time)
+        match (tk.kind, operand) {
+            (Kind::Sub, Ast::Int(n, _)) if *n != i64::MIN => {
+                self.emit(Self::push_int(-n), at);
+                Ok(StaticType::Int)
+            }
+            (Kind::Sub, Ast::Float(n, _)) => {
+                self.emit(Op::PushF(-n), at);
+                Ok(StaticType::Unknown)
+            }
+            (Kind::Sub, _) => {
+                let typed = self.feed_typed(operand)?;
+                self.emit(Op::Neg, &tk.at);
+                Ok(if typed == StaticType::Int { StaticType::Int } else { StaticType::Unknown })
+            }
+            _ => {
+                self.feed(operand)?;
+                self.emit(Op::Not, &tk.at);
+                Ok(StaticType::Int)
+            }
+        }
+    }
+
+    /// Feed `lhs && rhs` or `lhs || rhs`.
+    fn feed_short_circuit(&mut self, tk: &Token, lhs: &Ast, rhs: &Ast) -> Result<StaticType> {
+        // short-circuit: the result is `lhs` if it decides the outcome, otherwise `rhs`,
+        // which is only evaluated in that case
+        //   a Dup(0) JmpF(end) Pop b end:     (JmpT for `||`)
+        let target_end = self.next_target();
+        let lhs_type = self.feed_typed(lhs)?;
+        self.emit(Op::Dup(0), &tk.at);
+        if tk.kind == Kind::And {
+            self.emit(Op::JmpF(target_end), &tk.at);
+        } else {
+            self.emit(Op::JmpT(target_end), &tk.at);
+        }
+        self.emit(Op::Pop, &tk.at);
+        let rhs_type = self.feed_typed(rhs)?;
+        self.emit(Op::Target(target_end), &tk.at);
+        Ok(Self::binop_type(tk.kind, (lhs_type, rhs_type)))
+    }
+
+    /// Feed the (arithmetic or comparison) binary operator `tk`.
+    fn feed_binop(&mut self, tk: &Token, lhs: &Ast, rhs: &Ast) -> Result<StaticType> {
+        let types = (self.feed_typed(lhs)?, self.feed_typed(rhs)?);
+        // specialize operators for operands of known type
+        let op = match (tk.kind, types) {
+            (Kind::Add, (StaticType::Str, StaticType::Str)) => Op::Concat,
+            (Kind::Add, (StaticType::Int, StaticType::Int)) => Op::AddI,
+            (Kind::Sub, (StaticType::Int, StaticType::Int)) => Op::SubI,
+            (Kind::Mul, (StaticType::Int, StaticType::Int)) => Op::MulI,
+            _ => Self::op_from_tk(tk)?,
+        };
+        self.emit(op, &tk.at);
+        Ok(Self::binop_type(tk.kind, types))
+    }
+
+    /// Feed a chained comparison.
+    fn feed_chain(&mut self, operators: &[Token], operands: &[Ast], at: &Range<usize>) -> Result<StaticType> {
+        // each middle operand is kept on the stack for the next comparison:
+        //   a b Swap Dup(1) Lt  ->  b (a < b)
+        // on the first false comparison, it is popped and 0 is the result
+        let target_false = self.next_target();
+        let target_end = self.next_target();
+        let last = operators.len() - 1;
+        self.feed(&operands[0])?;
+        for (i, (tk, operand)) in operators.iter().zip(operands[1..].iter()).enumerate() {
+            self.feed(operand)?;
+            if i < last {
+                self.emit(Op::Swap, &tk.at);
+                self.emit(Op::Dup(1), &tk.at);
+                self.emit(Self::op_from_tk(tk)?, &tk.at);
+                self.emit(Op::JmpF(target_false), &tk.at);
+            } else {
+                self.emit(Self::op_from_tk(tk)?, &tk.at);
+            }
+        }
+        self.emit(Op::Jmp(target_end), at);
+        self.emit(Op::Target(target_false), at);
+        self.emit(Op::Pop, at);
+        self.emit(Self::push_int(0), at);
+        self.emit(Op::Target(target_end), at);
+        Ok(StaticType::Int)
+    }
+
+    /// Feed a loop, with optional starting, comparison and updating parts.
+    fn feed_loop(&mut self, st: &Option<Box<Ast>>, cmp: &Option<Box<Ast>>, body: &Ast, up: &Option<Box<Ast>>, at: &Range<usize>) -> Result<StaticType> {
+        let label = self.next_label.take();
+        if let Some(ast) = st {
+            self.feed(ast)?;
+        }
+        // the condition is fed after the body, that may use the variable it binds
+        if let Some(Ast::NotNil(_, value)) = cmp.as_deref() {
+            self.declare_bound(value);
+        }
+        // the comparison is placed after the body, so each iteration
+        // runs a single JmpT instead of a JmpF at the start and a Jmp at the end:
+        //   Jmp(cmp) body: <body> <up> cmp: <cmp> JmpT(body)
+        let loop_body = self.next_target();
+        let loop_cmp = self.next_target();
+        let loop_continue = self.next_target();
+        let loop_end = self.next_target();
+        if cmp.is_some() {
+            self.emit(Op::Jmp(loop_cmp), at);
+        }
+        self.emit(Op::Target(loop_body), at);
+        self.loops.push((label, loop_continue, loop_end));
+        let fed = self.feed(body);
+        self.loops.pop();
+        fed?;
+        self.emit(Op::Target(loop_continue), at);
+        if let Some(ast) = up {
+            self.feed(ast)?;
+        }
+        self.emit(Op::Target(loop_cmp), at);
+        match cmp {
+            Some(ast) => {
+                self.mark_line(ast);
+                self.feed_jump(ast, true, loop_body, at)?;
+            }
+            None => {
+                self.emit(Op::Jmp(loop_body), at);
+            }
+        }
+        self.emit(Op::Target(loop_end), at);
+        Ok(StaticType::Unknown)
+    }
+
+    /// Feed the loop `body` with a `label`.
+    fn feed_labeled(&mut self, label: &Token, body: &Ast) -> Result<StaticType> {
+        self.next_label = Some(label.value.clone());
+        self.feed(body)?;
+        Ok(StaticType::Unknown)
+    }
+
+    /// Feed a conditional, with an optional `else` part.
+    fn feed_if_else(&mut self, conditional: &Ast, if_true: &Ast, if_false: &Option<Box<Ast>>, at: &Range<usize>) -> Result<StaticType> {
+        // target_end is after block, always present
+        let target_end = self.next_target();
+
+        // target_false is the target of the if_false,
+        // only really present if an else block exists
+        let target_false = if if_false.is_some() {
+            self.next_target()
+        } else {
+            target_end
+        };
+
+        // code...
+        self.feed_jump(conditional, false, target_false, at)?;
+        self.feed(if_true)?;
+        if let Some(ast) = if_false {
+            self.emit(Op::Jmp(target_end), at);
+            self.emit(Op::Target(target_false), at);
+            self.feed(ast)?;
+        }
+
+        self.emit(Op::Target(target_end), at);
+        Ok(StaticType::Unknown)
+    }
+
+    /// Feed `match subject { arms _ => default }`.
+    ///
+    /// Dense integer patterns are dispatched by a single `Op::JmpTable`, others are compared
+    /// to the subject one at a time, in order, so the first arm with an equal pattern is run.
+    fn feed_match(&mut self, subject: &Ast, arms: &[(Vec<Ast>, Ast)], default: &Option<Box<Ast>>, at: &Range<usize>) -> Result<StaticType> {
+        let target_end = self.next_target();
+        let target_default = if default.is_some() { self.next_target() } else { target_end };
+        let targets: Vec<usize> = arms.iter().map(|_| self.next_target()).collect();
+
+        self.feed(subject)?;
+        let table = Self::jump_table(arms);
+        match &table {
+            Some((base, arm_of)) => {
+                let jumps = arm_of.iter().map(|arm| arm.map_or(target_default, |i| targets[i])).collect();
+                self.emit(Op::JmpTable(*base, jumps), at);
+            }
+            None => {
+                // the subject is kept for each comparison, and popped by the arm run
+                for ((patterns, _), target) in arms.iter().zip(targets.iter()) {
+                    for pattern in patterns {
+                        let pattern_at = pattern.at();
+                        self.emit(Op::Dup(0), &pattern_at);
+                        self.feed(pattern)?;
+                        self.emit(Op::Eq, &pattern_at);
+                        self.emit(Op::JmpT(*target), &pattern_at);
                     }
                 }
+                self.emit(Op::Pop, at);
             }
-            Ast::BinOp(tk, lhs, rhs) => {
-                self.feed(lhs)?;
-                self.feed(rhs)?;
-                self.code.push(Self::op_from_tk(tk));
+        }
+        self.emit(Op::Jmp(target_default), at);
+
+        for ((_, body), target) in arms.iter().zip(targets.iter()) {
+            self.emit(Op::Target(*target), at);
+            if table.is_none() {
+                self.emit(Op::Pop, at);
             }
-            Ast::Loop(_, st, cmp, body, up) => {
-                if let Some(ast) = st {
-                    self.feed(ast)?;
+            self.feed(body)?;
+            self.emit(Op::Jmp(target_end), at);
+        }
+        if let Some(default) = default {
+            self.emit(Op::Target(target_default), at);
+            self.feed(default)?;
+        }
+        self.emit(Op::Target(target_end), at);
+        Ok(StaticType::Unknown)
+    }
+
+    /// The jump table of a `match` with `arms`, if all their patterns are integer literals,
+    /// enough of them and dense enough for the table to be at most twice as long.
+    ///
+    /// Return the first integer and, from it, the index of the arm of each integer (the
+    /// first with it), `None` for integers of no arm.
+    fn jump_table(arms: &[(Vec<Ast>, Ast)]) -> Option<(i64, Vec<Option<usize>>)> {
+        let mut patterns = vec![];
+        for (i, (arm_patterns, _)) in arms.iter().enumerate() {
+            for pattern in arm_patterns {
+                let n = match pattern {
+                    Ast::Int(n, _) => *n,
+                    Ast::Unary(tk, operand) if tk.kind == Kind::Sub => match **operand {
+                        Ast::Int(n, _) => n.checked_neg()?,
+                        _ => return None,
+                    },
+                    _ => return None,
+                };
+                patterns.push((n, i));
+            }
+        }
+        if patterns.len() < MIN_JUMP_TABLE_PATTERNS {
+            return None;
+        }
+        let base = patterns.iter().map(|(n, _)| *n).min()?;
+        let last = patterns.iter().map(|(n, _)| *n).max()?;
+        let span = last.checked_sub(base)?;
+        if span >= 2 * patterns.len() as i64 {
+            return None;
+        }
+        let mut arm_of = vec![None; span as usize + 1];
+        for (n, i) in patterns {
+            arm_of[(n - base) as usize].get_or_insert(i);
+        }
+        Some((base, arm_of))
+    }
+
+    /// Feed a `break` or `continue` (`ast`) of the innermost loop, or the one with `label`.
+    fn feed_break_or_continue(&mut self, ast: &Ast, label: &Option<Token>, at: &Range<usize>) -> Result<StaticType> {
+        let found = match label {
+            Some(label) => self.loops.iter().rev()
+                .find(|(name, _, _)| name.as_deref() == Some(label.value.as_str()))
+                .ok_or_else(|| Error::UnknownLabel(label.clone()))?,
+            None => self.loops.last().ok_or_else(|| Error::BreakOutsideLoop(ast.clone()))?,
+        };
+        let (_, loop_continue, loop_end) = *found;
+        let target = if let Ast::Break(_, _) = ast { loop_end } else { loop_continue };
+        self.emit(Op::Jmp(target), at);
+        Ok(StaticType::Unknown)
+    }
+
+    /// Feed the statements of a block.
+    fn feed_block(&mut self, asts: &[Ast]) -> Result<StaticType> {
+        for (i, ast) in asts.iter().enumerate() {
+            self.feed(ast)?;
+            if let (Ast::Break(_, _) | Ast::Continue(_, _) | Ast::Return(_, _), Some(next)) = (ast, asts.get(i + 1)) {
+                self.diagnostics.push(Diagnostic::warning("unreachable statement".to_string(), next.at()));
+            }
+        }
+        Ok(StaticType::Unknown)
+    }
+
+    /// Feed the indexing of `lhs` by `rhs`.
+    fn feed_index(&mut self, tk: &Token, lhs: &Ast, rhs: &Ast) -> Result<StaticType> {
+        self.feed(lhs)?;
+        self.feed(rhs)?;
+        self.emit(Op::Index, &tk.at);
+        Ok(StaticType::Unknown)
+    }
+
+    /// Feed the slicing of `target` from `start` to `end`.
+    fn feed_slice(&mut self, tk: &Token, target: &Ast, start: &Ast, end: &Ast) -> Result<StaticType> {
+        self.feed(target)?;
+        self.feed(start)?;
+        self.feed(end)?;
+        self.emit(Op::Slice, &tk.at);
+        Ok(StaticType::Unknown)
+    }
+
+    /// Feed the call `ast` of `callee` with `args`.
+    ///
+    /// Natives and foreign functions are called by name, with their arguments checked
+    /// here; any other callee is evaluated and called as a function defined by the script.
+    fn feed_call(&mut self, ast: &Ast, callee: &Ast, args: &[Ast], at: &Range<usize>) -> Result<StaticType> {
+        let typed = match callee {
+            Ast::Var(name, _) if self.native_calls.contains_key(name) => {
+                let native = self.native_calls[name].clone();
+                let (min, max) = native.arity();
+                if args.len() < min {
+                    return Err(Error::NotEnoughArguments(ast.clone(), name.clone(), args.len(), min));
                 }
-                let loop_start = self.next_target();
-                let loop_end = self.next_target();
-                self.code.push(Op::Target(loop_start));
-                if let Some(ast) = cmp {
-                    self.feed(ast)?;
-                    self.code.push(Op::JmpF(loop_end));
+                if let Some(max) = max {
+                    if args.len() > max {
+                        return Err(Error::TooManyArguments(ast.clone(), name.clone(), args.len(), max));
+                    }
                 }
-                self.feed(body)?;
-                if let Some(ast) = up {
-                    self.feed(ast)?;
+
+                for arg in args {
+                    self.feed(arg)?;
                 }
-                self.code.push(Op::Jmp(loop_start));
-                self.code.push(Op::Target(loop_end));
-            }
-            Ast::IfElse(_, conditional, if_true, if_false) => {
-                // target_end is after block, always present
-                let target_end = self.next_target();
-
-                // target_false is the target of the if_false,
-                // only really present if an else block exists
-                let target_false = if let Some(_) = if_false {
-                    self.next_target()
-                } else {
-                    target_end
+
+                let typed = match native {
+                    Native::Length | Native::Ord | Native::ApproxEq => StaticType::Int,
+                    Native::ToString => StaticType::Str,
+                    _ => StaticType::Unknown,
                 };
+                self.emit(Op::Native(args.len(), native), at);
+                typed
+            }
+            Ast::Var(name, _) if self.foreign_calls.contains_key(name) => {
+                let arity = self.foreign_calls[name];
+                if args.len() < arity {
+                    return Err(Error::NotEnoughArguments(ast.clone(), name.clone(), args.len(), arity));
+                }
+                if args.len() > arity {
+                    return Err(Error::TooManyArguments(ast.clone(), name.clone(), args.len(), arity));
+                }
 
-                // code...
-                self.feed(conditional)?;
-                self.code.push(Op::JmpF(target_false));
-                self.feed(if_true)?;
-                if let Some(ast) = if_false {
-                    self.code.push(Op::Jmp(target_end));
-                    self.code.push(Op::Target(target_false));
-                    self.feed(ast)?;
+                for arg in args {
+                    self.feed(arg)?;
                 }
 
-                self.code.push(Op::Target(target_end));
+                self.emit(Op::Foreign(args.len(), name.clone()), at);
+                StaticType::Unknown
+            }
+            Ast::Int(_, _) | Ast::Float(_, _) | Ast::Str(_, _) | Ast::Interpolation(_, _) | Ast::Nil(_) | Ast::Lst(_, _) | Ast::Map(_, _) => {
+                return Err(Error::UnknownFunction(callee.clone()));
             }
-            Ast::Block(_, asts) => {
-                for ast in asts {
-                    self.feed(ast)?;
+            Ast::Var(name, _) if self.local(name).is_none() && matches!(self.inlined.get(name), Some((arity, _)) if *arity == args.len()) => {
+                // the arguments are where the call would have them, with the body reading them
+                for arg in args {
+                    self.feed(arg)?;
                 }
+                for (op, at) in self.inlined[name].1.clone() {
+                    self.emit(op, &at);
+                }
+                StaticType::Unknown
             }
-            Ast::Index(_, lhs, rhs) => {
-                self.feed(lhs)?;
-                self.feed(rhs)?;
-                self.code.push(Op::Index);
-            }
-            Ast::Call(_, callee, args) => {
-                match &**callee {
-                    Ast::Var(name, _) if self.native_calls.contains_key(name) => {
-                        let native = self.native_calls.get(name).unwrap();
-                        if args.len() < native.1 {
-                            return Err(Error::NotEnoughArguments(ast.clone(), name.clone(), args.len(), native.1));
-                        }
-
-                        let native = native.clone();
-                        for arg in args {
-                            self.feed(arg)?;
-                        }
-
-                        self.code.push(Op::Native(args.len(), native.0));
+            _ => {
+                // whether it is a function is only known when called
+                if let Ast::Var(name, _) = callee {
+                    if self.local(name).is_none() {
+                        self.called.push((name.clone(), callee.at()));
                     }
-                    _ => {
-                        panic!("general calls not implemented");
+                }
+                self.feed(callee)?;
+                for arg in args {
+                    self.feed(arg)?;
+                }
+                self.emit(Op::Call(args.len()), at);
+                StaticType::Unknown
+            }
+        };
+        Ok(typed)
+    }
+
+    /// Feed the definition (`ast`) of the function `name`, with `params` and `body`.
+    ///
+    /// The body is compiled into code of its own, in an `Op::Function` that is assigned
+    /// to the global `name`.  Its parameters and `let`s are locals, other names are globals.
+    fn feed_function(&mut self, ast: &Ast, name: &Token, params: &[Token], body: &Ast, at: &Range<usize>) -> Result<StaticType> {
+        if self.locals.is_some() {
+            return Err(Error::NestedFunction(ast.clone()));
+        }
+        // declared before the body, so it can call itself
+        self.declared.insert(name.value.clone());
+
+        let code = std::mem::take(&mut self.code);
+        let built_len = std::mem::replace(&mut self.built_len, 0);
+        let loops = std::mem::take(&mut self.loops);
+        self.locals = Some(params.iter().map(|param| param.value.clone()).collect());
+        let fed = self.feed(body);
+        // falling off the end returns nil, so `Return` always finds a value in slot 0
+        // and the caller's stack is left as after any other call
+        self.emit(Op::PushNil, at);
+        self.emit(Op::MoveL(0), at);
+        self.emit(Op::Return, at);
+        let mut body_code = std::mem::replace(&mut self.code, code);
+        self.built_len = built_len;
+        self.loops = loops;
+        let locals = self.locals.take().unwrap_or_default();
+        fed?;
+
+        Self::optimize(&mut body_code);
+        let generator = body_code.iter().any(|(op, _)| matches!(op, Op::Yield));
+        let inlined = if self.inline_calls && locals.len() == params.len() {
+            Self::inlined_body(&body_code, params.len())
+        } else {
+            None
+        };
+        let program = Self::link(body_code, self.target_count)?;
+        if program.code.len() > self.max_opcodes {
+            return Err(Error::ProgramTooLarge(program.code.len()));
+        }
+        let function = Function {
+            name: name.value.clone(),
+            arity: params.len(),
+            locals: locals.len() - params.len(),
+            generator,
+            program,
+        };
+        self.emit(Op::Function(Arc::new(function)), at);
+        self.emit(Op::MoveG(name.value.clone()), at);
+        if let Some(inlined) = inlined {
+            self.inlined.insert(name.value.clone(), (params.len(), inlined));
+        }
+        Ok(StaticType::Unknown)
+    }
+
+    /// Code run in place of a call to a function with `arity` parameters and `body`, if
+    /// its body is short and straight up to its first `return`.
+    ///
+    /// The arguments are left on the stack, read with `Dup` instead of `LoadL`, and
+    /// dropped from below the value returned at the end.
+    fn inlined_body(body: &[(Op, Range<usize>)], arity: usize) -> Option<Code> {
+        let mut inlined = vec![];
+        // number of values on the stack above the arguments
+        let mut depth = 0;
+        for (i, (op, at)) in body.iter().enumerate() {
+            if inlined.len() >= MAX_INLINED_OPCODES {
+                return None;
+            }
+            let (pops, pushes) = match op {
+                Op::MoveL(0) if depth == 1 && matches!(body.get(i + 1), Some((Op::Return, _))) => {
+                    for _ in 0 .. arity {
+                        inlined.push((Op::Swap, at.clone()));
+                        inlined.push((Op::Pop, at.clone()));
                     }
+                    return Some(inlined);
                 }
+                Op::LoadL(slot) if (1 ..= arity).contains(slot) => {
+                    inlined.push((Op::Dup(depth + arity - slot), at.clone()));
+                    depth += 1;
+                    continue;
+                }
+                Op::Line(_) => (0, 0),
+                Op::Dup(n) if *n < depth => (0, 1),
+                Op::PushI(_) | Op::PushI0 | Op::PushI1 | Op::PushIByte(_) | Op::PushF(_) | Op::PushNil | Op::PushS(_) | Op::LoadG(_) => (0, 1),
+                Op::Pop | Op::MoveG(_) => (1, 0),
+                Op::Neg | Op::Not | Op::NotNil | Op::StoreG(_) => (1, 1),
+                Op::Swap => (2, 2),
+                Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Mod | Op::Shl | Op::Shr
+                | Op::Lt | Op::Lte | Op::Gt | Op::Gte | Op::Eq | Op::Neq
+                | Op::Concat | Op::AddI | Op::SubI | Op::MulI | Op::Index | Op::IndexInto => (2, 1),
+                Op::IndexStore | Op::Slice => (3, 1),
+                Op::SliceStore => (4, 1),
+                Op::MakeList(n) | Op::Interpolate(n) | Op::Foreign(n, _) => (*n, 1),
+                Op::MakeMap(n) => (2 * n, 1),
+                Op::Native(n, native) if !matches!(native, Native::Resume) => (*n, 1),
+                _ => return None,
+            };
+            if pops > depth {
+                return None;
             }
+            depth = depth - pops + pushes;
+            inlined.push((op.clone(), at.clone()));
         }
-        Ok(self.code.len() - starting)
+        None
+    }
+
+    /// Feed `return value` (`ast`), with `nil` if no value, from the function being compiled.
+    fn feed_return(&mut self, ast: &Ast, value: &Option<Box<Ast>>, at: &Range<usize>) -> Result<StaticType> {
+        if self.locals.is_none() {
+            return Err(Error::ReturnOutsideFunction(ast.clone()));
+        }
+        match value {
+            Some(value) => {
+                self.feed(value)?;
+            }
+            None => self.emit(Op::PushNil, at),
+        }
+        self.emit(Op::MoveL(0), at);
+        self.emit(Op::Return, at);
+        Ok(StaticType::Unknown)
+    }
+
+    /// Feed `yield value` (`ast`), that makes the function being compiled a generator.
+    fn feed_yield(&mut self, ast: &Ast, value: &Ast, at: &Range<usize>) -> Result<StaticType> {
+        if self.locals.is_none() {
+            return Err(Error::YieldOutsideFunction(ast.clone()));
+        }
+        self.feed(value)?;
+        self.emit(Op::Yield, at);
+        Ok(StaticType::Unknown)
     }
 
     /// Optimization steps
-    fn optimize(&mut self) {
+    ///
+    /// Opcodes are only replaced, never removed or moved, so the ranges returned by
+    /// `feed_range` stay valid.
+    fn optimize(code: &mut [(Op, Range<usize>)]) {
         // TODO: perhaps create a new Vec<Op> and move stuff over is better than in-place?
 
         let mut i = 0;
-        while i < self.code.len() {
-            // replace StoreG(x) || Pop by a single MoveG(x)
-            if let Op::StoreG(name) = &self.code[i] {
-                if i + 1 < self.code.len() && matches!(self.code[i + 1], Op::Pop) {
-                    self.code[i] = Op::MoveG(name.clone());
-                    self.code.remove(i + 1);
+        while i < code.len() {
+            // replace LoadG(x) || LoadG(x) by LoadG(x) || Dup(0), as in `x * x`
+            // both push the same pointer, but Dup skips the globals lookup; only
+            // adjacent loads are replaced, so no store to `x` can happen in between
+            if let (Op::LoadG(name), _) = &code[i] {
+                if i + 1 < code.len() && matches!(&code[i + 1].0, Op::LoadG(other) if other == name) {
+                    code[i + 1].0 = Op::Dup(0);
+                }
+            }
+            // replace a Jmp to the next opcode by Nop, as in an `if` with an empty `else`
+            if let (Op::Jmp(id), _) = &code[i] {
+                let mut next = code[i + 1..].iter().take_while(|(op, _)| matches!(op, Op::Target(_)));
+                if next.any(|(op, _)| matches!(op, Op::Target(t) if t == id)) {
+                    code[i].0 = Op::Nop;
                 }
             }
             i += 1;
         }
     }
 
-    /// Replace all jumps to target ID's with actual addresses
-    fn expand_targets(mut self) -> Result<Vec<Op>> {
-        let mut target = vec![usize::MAX; self.target_count];
+    /// Replace all jumps to target ID's (below `target_count`) in `code` with actual addresses
+    fn link(mut code: Vec<(Op, Range<usize>)>, target_count: usize) -> Result<Program> {
+        let mut target = vec![usize::MAX; target_count];
 
         // 1st pass -- store the position of each target
         // note that must account for the fact that all the Op::Target's
-        // will be removed from the final version
+        // (and Op::Nop's, that do nothing) will be removed from the final version
         let mut i = 0;
-        for op in self.code.iter() {
-            if let Op::Target(id) = op {
-                target[*id] = i;
-            } else {
-                i += 1;
+        for (op, _) in code.iter() {
+            match op {
+                Op::Target(id) => target[*id] = i,
+                Op::Nop => (),
+                _ => i += 1,
             }
         }
 
         // TODO: check if all jumps are covered
-        for op in self.code.iter() {
-            let target_id = match op {
-                Op::Jmp(id) => Some(*id),
-                Op::JmpF(id) => Some(*id),
-                _ => None
+        for (op, _) in code.iter() {
+            let target_ids = match op {
+                Op::Jmp(id) | Op::JmpF(id) | Op::JmpT(id) => std::slice::from_ref(id),
+                Op::JmpTable(_, ids) => ids.as_slice(),
+                _ => &[],
             };
 
-            if let Some(id) = target_id {
-                if target[id] == usize::MAX {
-                    // TODO: better error reporting... name of target or somesuch...
-                    return Err(Error::JumpTargetNotFound(id))
-                }
+            if let Some(id) = target_ids.iter().find(|id| target[**id] == usize::MAX) {
+                // TODO: better error reporting... name of target or somesuch...
+                return Err(Error::JumpTargetNotFound(*id))
             }
         }
 
-        // 2nd pass -- remove all Op::Target from code
-        self.code.retain(|x| !matches!(x, Op::Target(_)));
+        // 2nd pass -- remove all Op::Target and Op::Nop from code
+        code.retain(|(x, _)| !matches!(x, Op::Target(_) | Op::Nop));
 
         // 3rd pass -- rewrite all jumps to use direct address instead of target ID
-        for op in self.code.iter_mut() {
+        for (op, _) in code.iter_mut() {
             match op {
                 Op::Jmp(id) => *id = target[*id],
                 Op::JmpF(id) => *id = target[*id],
+                Op::JmpT(id) => *id = target[*id],
+                Op::JmpTable(_, ids) => ids.iter_mut().for_each(|id| *id = target[*id]),
                 _ => (),
             }
         }
 
-        Ok(self.code)
+        let (code, source_map) = code.into_iter().unzip();
+        Ok(Program { code, source_map, diagnostics: vec![] })
     }
 
-    /// Return the final compiled sequence of `Op` codes.
-    pub fn build(mut self) -> Result<Vec<Op>> {
-        self.optimize();
-        self.expand_targets()
+    /// Non-fatal findings (e.g. unreachable statements) in what was fed so far.
+    ///
+    /// Those found when building (e.g. variables never read) are only in the `Program`.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    /// Add to `read` the globals loaded by `code`, including in the functions it defines.
+    fn globals_read<'a>(code: impl Iterator<Item = &'a Op>, read: &mut HashSet<&'a String>) {
+        for op in code {
+            match op {
+                Op::LoadG(name) => { read.insert(name); }
+                Op::Function(function) => Self::globals_read(function.program.code.iter(), read),
+                _ => (),
+            }
+        }
+    }
+
+    /// Note the variables declared with `let` that are never read.
+    fn note_unread_lets(&mut self) {
+        let mut read = HashSet::new();
+        Self::globals_read(self.code.iter().map(|(op, _)| op), &mut read);
+        for (name, at) in &self.lets {
+            if !read.contains(name) {
+                self.diagnostics.push(Diagnostic::note(format!("'{}' is never read", name), at.clone()));
+            }
+        }
+    }
+
+    /// Warn of the calls to globals that are never declared (e.g. a misspelled name).
+    ///
+    /// These are not errors, as the global may be defined by the host or a previous run.
+    fn warn_unknown_calls(&mut self) {
+        for (name, at) in &self.called {
+            if !self.declared.contains(name) {
+                self.diagnostics.push(Diagnostic::warning(format!("'{}' is not a known function", name), at.clone()));
+            }
+        }
+    }
+
+    /// Return the final compiled `Program`.
+    pub fn build(mut self) -> Result<Program> {
+        self.warn_unknown_calls();
+        self.note_unread_lets();
+        Self::optimize(&mut self.code);
+        let mut program = Self::link(self.code, self.target_count)?;
+        if program.code.len() > self.max_opcodes {
+            return Err(Error::ProgramTooLarge(program.code.len()));
+        }
+        program.diagnostics = self.diagnostics;
+        Ok(program)
+    }
+}
+
+/// Convert the expression `ast` into postfix (RPN) text, with the tokens in the same
+/// order the VM evaluates them: `1 + 2 * 3` is `1 2 3 * +`.
+///
+/// Lists are written as their items followed by `[n]`, indexing as `a i []`,
+/// slicing as `a b c [:]` and calls as the arguments followed by `name(n)`.
+///
+/// Return `None` if `ast` is not an expression (a loop, a declaration, ...) or has
+/// no postfix form (a chained comparison).
+pub fn to_rpn(ast: &Ast) -> Option<String> {
+    let mut out = vec![];
+    rpn_into(ast, &mut out)?;
+    Some(out.join(" "))
+}
+
+fn rpn_into(ast: &Ast, out: &mut Vec<String>) -> Option<()> {
+    match ast {
+        Ast::Int(n, _) => out.push(n.to_string()),
+        Ast::Float(n, _) => out.push(format!("{:?}", n)),
+        Ast::Nil(_) => out.push("nil".to_string()),
+        Ast::Str(s, _) => out.push(format!("{:?}", s)),
+        Ast::Var(name, _) => out.push(name.clone()),
+        Ast::Lst(items, _) => {
+            for item in items {
+                rpn_into(item, out)?;
+            }
+            out.push(format!("[{}]", items.len()));
+        }
+        Ast::BinOp(tk, lhs, rhs) => {
+            rpn_into(lhs, out)?;
+            rpn_into(rhs, out)?;
+            out.push(tk.value.clone());
+        }
+        Ast::Unary(tk, operand) => {
+            rpn_into(operand, out)?;
+            out.push(if tk.kind == Kind::Sub { "neg".to_string() } else { tk.value.clone() });
+        }
+        Ast::Index(_, lhs, index) => {
+            rpn_into(lhs, out)?;
+            rpn_into(index, out)?;
+            out.push("[]".to_string());
+        }
+        Ast::Slice(_, lhs, start, end) => {
+            rpn_into(lhs, out)?;
+            rpn_into(start, out)?;
+            rpn_into(end, out)?;
+            out.push("[:]".to_string());
+        }
+        Ast::Call(_, callee, args) => {
+            for arg in args {
+                rpn_into(arg, out)?;
+            }
+            match &**callee {
+                Ast::Var(name, _) => out.push(format!("{}({})", name, args.len())),
+                _ => return None,
+            }
+        }
+        Ast::Sttm(ast) => rpn_into(ast, out)?,
+        _ => return None,
     }
+    Some(())
 }
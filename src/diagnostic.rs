@@ -0,0 +1,60 @@
+use std::fmt;
+use std::ops::Range;
+use crate::errors::Error;
+
+/// How serious a `Diagnostic` is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// Probably a mistake, e.g. code that is never executed
+    Warning,
+
+    /// Possibly intended, e.g. a variable that is never read
+    Note,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(fmt, "warning"),
+            Severity::Note => write!(fmt, "note"),
+        }
+    }
+}
+
+/// A non-fatal finding of the compiler, that does not stop the program from being built.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+
+    /// Description of the finding
+    pub message: String,
+
+    /// Source range (in char offsets) it refers to
+    pub at: Range<usize>,
+}
+
+impl Diagnostic {
+    pub fn warning(message: String, at: Range<usize>) -> Diagnostic {
+        Diagnostic { severity: Severity::Warning, message, at }
+    }
+
+    pub fn note(message: String, at: Range<usize>) -> Diagnostic {
+        Diagnostic { severity: Severity::Note, message, at }
+    }
+
+    /// Format with the source line it refers to, as `Error::pretty`.
+    pub fn pretty(&self, source: &str) -> String {
+        format!("{}: {}\n{}", self.severity, self.message, Error::pretty_source_line(source, None, self.at.start))
+    }
+
+    /// Format with the source line it refers to, as `Error::pretty_named`.
+    pub fn pretty_named(&self, source: &str, name: &str) -> String {
+        format!("{}: {}\n{}", self.severity, self.message, Error::pretty_source_line(source, Some(name), self.at.start))
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "{}: {} at {}", self.severity, self.message, self.at.start)
+    }
+}
@@ -1,6 +1,7 @@
+use std::ops::Range;
 use crate::{
     vm::HeapPtr,
-    opcodes::Op,
+    opcodes::{Op, Native},
     value::Value,
     token::{Kind, Token},
     ast::Ast,
@@ -23,6 +24,9 @@ pub enum Error {
     /// Access to a memory location that is currently empty
     InvalidMemoryAccess(HeapPtr),
 
+    /// Attempt to change a value that is not on the heap (a small integer held by its pointer)
+    ImmutableValue(HeapPtr),
+
     /// Global variable not found
     GlobalNotFound(String),
 
@@ -32,15 +36,82 @@ pub enum Error {
     /// Index out of range
     IndexOutOfRange(Value, usize),
 
+    /// Value cannot be used as a map key
+    InvalidKey(Value),
+
+    /// Key not found in map
+    KeyNotFound(Value),
+
+    /// Nothing to store into along the path of an assignment, as `a.b` in `a.b.c = x`
+    /// (key, value found there, `nil` if none)
+    CannotIndexInto(Value, Value),
+
     /// An invalid opcode was found on code
     InvalidOpCode(usize),
 
+    /// Value is not of the expected type (name of expected type)
+    UnexpectedType(String, Value),
+
     /// Attempted to append to a non-list
     InvalidAppend(Value),
 
+    /// Native called with an invalid number of arguments (number given)
+    InvalidNativeCall(Native, usize),
+
+    /// Call to a native function that was not registered by the host
+    UnknownForeign(String),
+
+    /// Native registered by the host called with an invalid number of arguments (number given)
+    InvalidForeignCall(String, usize),
+
+    /// Invalid argument given to a native function
+    InvalidArgument(Native, Value),
+
+    /// The heap is full and no entry could be freed
+    OutOfMemory,
+
+    /// Integer division or remainder by zero
+    DivisionByZero,
+
+    /// Result of integer arithmetic does not fit an `i64`
+    IntegerOverflow,
+
+    /// Shift by a number of bits outside 0 to 63
+    InvalidShift(i64),
+
+    /// Attempt to build a value larger than allowed (requested size)
+    AllocationTooLarge(usize),
+
+    /// Failed writing the output of the script
+    OutputError(std::io::Error),
+
+    /// Failed reading the source of the script
+    InputError(std::io::Error),
+
+    /// The script wrote more output than allowed
+    OutputLimitExceeded,
+
+    /// The script executed more opcodes than allowed
+    StepLimitExceeded,
+
     /// Jump to an unknown location
     JumpTargetNotFound(usize),
 
+    /// Serialized code is truncated or invalid (at offset)
+    CorruptBytecode(usize),
+
+    /// Too many nested function calls
+    StackOverflow,
+
+    /// A generator resumed from its own code
+    GeneratorRunning,
+
+    /// Script function called with a number of arguments other than its parameters (name, given, expected)
+    ArityMismatch(String, usize, usize),
+
+    /// Error running the opcode compiled from the source range (in chars)
+    Located(Box<Error>, Range<usize>),
+
     // === Script Source errors ===
 
     /// Syntax error reading script text
@@ -52,17 +123,56 @@ pub enum Error {
     /// Invalid escape inside a string
     InvalidStringEscape(char, usize),
 
+    /// A block comment not closed before the end of the source (where it starts)
+    UnterminatedComment(usize),
+
+    /// Digit not valid in the base of an integer literal (digit, base, position)
+    InvalidDigit(char, u32, usize),
+
+    /// A `_` in a number literal that is not between two digits
+    MisplacedSeparator(usize),
+
     /// Error parsing input (unexpected token)
     ParsingError(Token),
 
     /// Got a token, but was expecting other possibilities
     UnexpectedToken(Token, Vec<Kind>),
 
+    /// Expressions or statements nested too deep (at offset)
+    NestingTooDeep(usize),
+
     /// Not a valid target for an assignment
     InvalidAssignmentTarget(Ast),
 
+    /// Assignment to a variable that was not declared (strict mode)
+    UndeclaredAssignment(Ast),
+
     /// Not enough arguments to a function call
     NotEnoughArguments(Ast, String, usize, usize),
+
+    /// Too many arguments to a function call
+    TooManyArguments(Ast, String, usize, usize),
+
+    /// Compiled program has more opcodes than allowed (number of opcodes)
+    ProgramTooLarge(usize),
+
+    /// `break` or `continue` outside of a loop
+    BreakOutsideLoop(Ast),
+
+    /// Call to something that is not a known (native or foreign) function
+    UnknownFunction(Ast),
+
+    /// `break` or `continue` with a label not of an enclosing loop
+    UnknownLabel(Token),
+
+    /// Function defined inside another function
+    NestedFunction(Ast),
+
+    /// `yield` outside of a function
+    YieldOutsideFunction(Ast),
+
+    /// `return` outside of a function
+    ReturnOutsideFunction(Ast),
 }
 
 impl std::fmt::Display for Error {
@@ -71,42 +181,122 @@ impl std::fmt::Display for Error {
             Error::StackUnderflow => write!(fmt, "Stack Underflow"),
             Error::MemoryAccessOutOfRange(ptr) => write!(fmt, "Memory access out of range at {:?}", ptr),
             Error::InvalidMemoryAccess(ptr) => write!(fmt, "Attempt to access empty memory position at {:?}", ptr),
+            Error::ImmutableValue(ptr) => write!(fmt, "Attempt to change the value held by {:?}, that is not on the heap", ptr),
             Error::GlobalNotFound(name) => write!(fmt, "Global variable '{}' not found", name),
             Error::IncompatibleOperands(op, lhs, rhs) => write!(fmt, "Cannot execute {:?} on {} and {}", op, lhs.type_name(), rhs.type_name()),
             Error::SyntaxError(at) => write!(fmt, "Syntax error at {}", at),
             Error::UnexpectedEOF => write!(fmt, "Unexpected end of source"),
             Error::InvalidStringEscape(c, at) => write!(fmt, "Invalid string escape '{}' at {}", c, at),
-            Error::ParsingError(tk) => write!(fmt, "Unexpected token {:?} at {}", tk, tk.at.start),
+            Error::UnterminatedComment(at) => write!(fmt, "Unterminated comment starting at {}", at),
+            Error::InvalidDigit(c, base, at) => write!(fmt, "Invalid digit '{}' for base {} at {}", c, base, at),
+            Error::MisplacedSeparator(at) => write!(fmt, "Misplaced digit separator at {}", at),
+            Error::ParsingError(tk) => write!(fmt, "Unexpected token {:?} at {}:{}", tk, tk.line, tk.col),
             Error::UnexpectedToken(tk, possible) => {
                 if possible.len() > 1 {
-                    write!(fmt, "Unexpected token {:?} at {}, expected one of {:?}", tk, tk.at.start, possible)
+                    write!(fmt, "Unexpected token {:?} at {}:{}, expected one of {:?}", tk, tk.line, tk.col, possible)
                 } else {
-                    write!(fmt, "Unexpected token {:?} at {}, expected {:?}", tk, tk.at.start, possible[0])
+                    write!(fmt, "Unexpected token {:?} at {}:{}, expected {:?}", tk, tk.line, tk.col, possible[0])
                 }
             }
+            Error::NestingTooDeep(at) => write!(fmt, "Nesting too deep at {}", at),
             Error::InvalidAssignmentTarget(ast) => write!(fmt, "{:?} is not a valid target for an assignment", ast),
+            Error::UndeclaredAssignment(ast) => write!(fmt, "Assignment to undeclared variable {}", ast.pretty()),
             Error::IndexOutOfRange(value, index) => write!(fmt, "Index out of range {} of {:?}", index, value),
+            Error::InvalidKey(key) => write!(fmt, "Cannot use {} as a map key", key.type_name()),
+            Error::KeyNotFound(key) => write!(fmt, "Key {:?} not found in map", key),
+            Error::CannotIndexInto(key, found) => write!(fmt, "Cannot index into {} at key {:?}", found.type_name(), key),
             Error::InvalidOpCode(index) => write!(fmt, "Invalid opcode at {}", index),
             Error::NotEnoughArguments(_, name, given, expected) => write!(fmt, "Not enough arguments to {}, given {} but expected {}", name, given, expected),
+            Error::TooManyArguments(_, name, given, expected) => write!(fmt, "Too many arguments to {}, given {} but expected at most {}", name, given, expected),
+            Error::UnexpectedType(expected, value) => write!(fmt, "Expected {} but got {}", expected, value.type_name()),
             Error::InvalidAppend(target) => write!(fmt, "Cannot append to {}", target.type_name()),
+            Error::InvalidNativeCall(native, nargs) => write!(fmt, "Invalid call to {} with {} arguments", native.name(), nargs),
+            Error::UnknownForeign(name) => write!(fmt, "Native function '{}' not registered", name),
+            Error::InvalidForeignCall(name, nargs) => write!(fmt, "Invalid call to {} with {} arguments", name, nargs),
+            Error::InvalidArgument(native, arg) => write!(fmt, "Invalid {} argument to {}", arg.type_name(), native.name()),
+            Error::OutOfMemory => write!(fmt, "Out of memory"),
+            Error::DivisionByZero => write!(fmt, "Division by zero"),
+            Error::IntegerOverflow => write!(fmt, "Integer overflow"),
+            Error::InvalidShift(bits) => write!(fmt, "Invalid shift by {} bits", bits),
+            Error::AllocationTooLarge(size) => write!(fmt, "Allocation too large ({})", size),
+            Error::OutputError(err) => write!(fmt, "Output error: {}", err),
+            Error::InputError(err) => write!(fmt, "Input error: {}", err),
+            Error::OutputLimitExceeded => write!(fmt, "Output limit exceeded"),
+            Error::StepLimitExceeded => write!(fmt, "Step limit exceeded"),
             Error::JumpTargetNotFound(id) => write!(fmt, "Jump with unknown target {}", id),
+            Error::CorruptBytecode(at) => write!(fmt, "Corrupt bytecode at offset {}", at),
+            Error::StackOverflow => write!(fmt, "Stack overflow, too many nested calls"),
+            Error::GeneratorRunning => write!(fmt, "Generator resumed while running"),
+            Error::ArityMismatch(name, given, expected) => write!(fmt, "Wrong number of arguments to {}, given {} but expected {}", name, given, expected),
+            Error::Located(err, _) => write!(fmt, "{}", err),
+            Error::ProgramTooLarge(size) => write!(fmt, "Program too large ({} opcodes)", size),
+            Error::BreakOutsideLoop(ast) => write!(fmt, "{} outside of a loop", ast.pretty()),
+            Error::UnknownFunction(ast) => write!(fmt, "{} is not a known function", ast.pretty()),
+            Error::UnknownLabel(tk) => write!(fmt, "Unknown loop label '{}' at {}:{}", tk.value, tk.line, tk.col),
+            Error::NestedFunction(ast) => write!(fmt, "{} inside another function", ast.pretty()),
+            Error::YieldOutsideFunction(_) => write!(fmt, "yield outside of a function"),
+            Error::ReturnOutsideFunction(_) => write!(fmt, "return outside of a function"),
         }
     }
 }
 
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::OutputError(err)
+    }
+}
+
 impl Error {
+    /// Attach the source range `at` to this error, if known (i.e. not empty).
+    ///
+    /// Used by the VM, with the range of the opcode that failed.
+    pub fn located(self, at: Option<Range<usize>>) -> Error {
+        match (self, at) {
+            (err @ Error::Located(_, _), _) => err,
+            (err, Some(at)) if !at.is_empty() => Error::Located(Box::new(err), at),
+            (err, _) => err,
+        }
+    }
+
+    /// The error itself, without the source range attached by `located`.
+    pub fn cause(&self) -> &Error {
+        match self {
+            Error::Located(err, _) => err,
+            err => err,
+        }
+    }
+
+    /// Offset (in chars) in the source where the error happened, as shown by `pretty`,
+    /// if known.
+    ///
+    /// Errors at the end of the source (`UnexpectedEOF`) have no offset.
+    pub fn position(&self) -> Option<usize> {
+        match self {
+            Error::SyntaxError(at) | Error::InvalidStringEscape(_, at) | Error::UnterminatedComment(at) |
+            Error::InvalidDigit(_, _, at) | Error::MisplacedSeparator(at) | Error::NestingTooDeep(at) => Some(*at),
+            Error::ParsingError(tk) | Error::UnexpectedToken(tk, _) | Error::UnknownLabel(tk) => Some(tk.at.start),
+            Error::InvalidAssignmentTarget(ast) | Error::UndeclaredAssignment(ast) |
+            Error::NotEnoughArguments(ast, _, _, _) | Error::TooManyArguments(ast, _, _, _) |
+            Error::BreakOutsideLoop(ast) | Error::UnknownFunction(ast) | Error::NestedFunction(ast) |
+            Error::YieldOutsideFunction(ast) | Error::ReturnOutsideFunction(ast) => Some(ast.at().start),
+            Error::Located(_, at) => Some(at.start),
+            _ => None,
+        }
+    }
 
     /// Extract more precise location information of offset `at` inside `source`.
     ///
     /// Return `(row-number, column-number, row-starting-offset, row-ending-offset)`
     /// where `row-number` and `column-number` are both 1-based.
+    ///
+    /// All offsets are in chars (not bytes), as are the ranges recorded by the lexer.
     fn location(source: &str, at: usize) -> (usize, usize, usize, usize) {
         let mut row = 1;
         let mut column = 1;
         let mut row_start = 0;
         let mut row_end = 0;
         let mut found = false;
-        for (i, c) in source.char_indices() {
+        for (i, c) in source.chars().enumerate() {
             if i == at {
                 found = true;
             } else if c == '\n' {
@@ -124,7 +314,7 @@ impl Error {
         }
 
         if row_end == 0 {
-            row_end = source.len();
+            row_end = source.chars().count();
         }
 
         (row, column, row_start, row_end)
@@ -132,33 +322,77 @@ impl Error {
 
     /// Return two lines, separated with '\n':
     ///
-    ///     (<row>, <col>): | <source-line-where-at-is>
-    ///                     |        ^  (caret pointing for <col> inside <line>)
+    /// ```text
+    /// <row>:<col>: | <source-line-where-at-is>
+    ///              |        ^  (caret pointing for <col> inside <line>)
+    /// ```
     ///
-    fn pretty_source_line(source: &str, at: usize) -> String {
+    /// If the source has a `name`, the address is `<name>:<row>:<col>: ` instead.
+    pub(crate) fn pretty_source_line(source: &str, name: Option<&str>, at: usize) -> String {
         let (row, column, row_start, row_end) = Self::location(source, at);
-        let address = format!("({}, {}): ", row, column);
-        let line: String = source.chars().skip(row_start).take(row_end - row_start).collect();
+        let address = match name {
+            Some(name) => format!("{}:{}:{}: ", name, row, column),
+            None => format!("{}:{}: ", row, column),
+        };
+        let line: String = source.chars().skip(row_start).take(row_end.saturating_sub(row_start)).collect();
         let marker = format!("{}| {}^", " ".repeat(address.len()), " ".repeat(column - 1));
         format!("{}| {}\n{}", address, line, marker)
     }
 
+    /// Return a human readable description of the error, showing where in `source` it happened.
     pub fn pretty(&self, source: &str) -> String {
+        self.pretty_in(source, None)
+    }
+
+    /// Same as `pretty`, for a source identified by `name` (usually the file name).
+    pub fn pretty_named(&self, source: &str, name: &str) -> String {
+        self.pretty_in(source, Some(name))
+    }
+
+    fn pretty_in(&self, source: &str, name: Option<&str>) -> String {
         match self {
             Error::SyntaxError(at) =>
-                format!("syntax error\n{}", Self::pretty_source_line(source, *at)),
+                format!("syntax error\n{}", Self::pretty_source_line(source, name, *at)),
             Error::UnexpectedEOF =>
-                format!("unexpected end of file\n{}", Self::pretty_source_line(source, source.len())),
+                format!("unexpected end of file\n{}", Self::pretty_source_line(source, name, source.chars().count())),
             Error::InvalidStringEscape(ch, at) =>
-                format!("invalid escape '{}' inside a string\n{}", ch, Self::pretty_source_line(source, *at)),
+                format!("invalid escape '{}' inside a string\n{}", ch, Self::pretty_source_line(source, name, *at)),
+            Error::UnterminatedComment(at) =>
+                format!("comment is not closed\n{}", Self::pretty_source_line(source, name, *at)),
+            Error::InvalidDigit(ch, base, at) =>
+                format!("invalid digit '{}' in a base {} integer\n{}", ch, base, Self::pretty_source_line(source, name, *at)),
+            Error::MisplacedSeparator(at) =>
+                format!("'_' must be between two digits\n{}", Self::pretty_source_line(source, name, *at)),
             Error::ParsingError(tk) =>
-                format!("unexpected input when reading a {:?} with value \"{}\"\n{}", tk.kind, tk.value, Self::pretty_source_line(source, tk.at.start)),
+                format!("unexpected input when reading a {:?} with value \"{}\"\n{}", tk.kind, tk.value, Self::pretty_source_line(source, name, tk.at.start)),
             Error::UnexpectedToken(tk, which) =>
-                format!("got a {:?} but expected one of {:?}\n{}", tk.kind, which, Self::pretty_source_line(source, tk.at.start)),
+                format!("got a {:?} but expected one of {:?}\n{}", tk.kind, which, Self::pretty_source_line(source, name, tk.at.start)),
+            Error::NestingTooDeep(at) =>
+                format!("nesting too deep\n{}", Self::pretty_source_line(source, name, *at)),
             Error::InvalidAssignmentTarget(ast) =>
-                format!("{} is not a valid target for assignment\n{}", ast.pretty(), Self::pretty_source_line(source, ast.at().start)),
-            Error::NotEnoughArguments(ast, name, given, expected) =>
-                format!("not enough arguments to function '{}' (given {}, expected {})\n{}", name, given, expected, Self::pretty_source_line(source, ast.at().start)),
+                format!("{} is not a valid target for assignment\n{}", ast.pretty(), Self::pretty_source_line(source, name, ast.at().start)),
+            Error::UndeclaredAssignment(ast) =>
+                format!("assignment to undeclared variable {}\n{}", ast.pretty(), Self::pretty_source_line(source, name, ast.at().start)),
+            Error::NotEnoughArguments(ast, function, given, expected) =>
+                format!("not enough arguments to function '{}' (given {}, expected {})\n{}", function, given, expected, Self::pretty_source_line(source, name, ast.at().start)),
+            Error::TooManyArguments(ast, function, given, expected) =>
+                format!("too many arguments to function '{}' (given {}, expected at most {})\n{}", function, given, expected, Self::pretty_source_line(source, name, ast.at().start)),
+
+            Error::BreakOutsideLoop(ast) =>
+                format!("'{}' outside of a loop\n{}", ast.pretty(), Self::pretty_source_line(source, name, ast.at().start)),
+            Error::UnknownFunction(ast) =>
+                format!("{} is not a known function\n{}", ast.pretty(), Self::pretty_source_line(source, name, ast.at().start)),
+            Error::UnknownLabel(tk) =>
+                format!("no enclosing loop labeled '{}'\n{}", tk.value, Self::pretty_source_line(source, name, tk.at.start)),
+            Error::NestedFunction(ast) =>
+                format!("functions cannot be defined inside other functions\n{}", Self::pretty_source_line(source, name, ast.at().start)),
+            Error::YieldOutsideFunction(ast) =>
+                format!("'yield' outside of a function\n{}", Self::pretty_source_line(source, name, ast.at().start)),
+            Error::ReturnOutsideFunction(ast) =>
+                format!("'return' outside of a function\n{}", Self::pretty_source_line(source, name, ast.at().start)),
+
+            Error::Located(err, at) =>
+                format!("{}\n{}", err, Self::pretty_source_line(source, name, at.start)),
 
             // others are internal VM errors that have not a really good printing
             _ => self.to_string(),
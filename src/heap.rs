@@ -0,0 +1,78 @@
+use crate::value::Value;
+
+/// Storage of the values managed by the `VM`.
+///
+/// A heap is a sequence of slots, each either holding a value or free.  The `VM` decides
+/// which slot each value goes to, and runs the garbage collection (marking reachable
+/// slots and releasing the others with `take`); a backend only stores the slots.
+///
+/// This allows a host to supply a specialized storage (e.g. a pre-allocated pool) with
+/// `VM::with_heap`.  The default is `VecHeap`.
+pub trait Heap {
+    /// Number of slots, free or not.
+    fn len(&self) -> usize;
+
+    /// `true` if there are no slots.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Add a free slot at the end, returning its index.
+    fn grow(&mut self) -> usize;
+
+    /// Reference to the value at slot `index`, `None` if free or out of range.
+    fn get(&self, index: usize) -> Option<&Value>;
+
+    /// Mutable reference to the value at slot `index`, `None` if free or out of range.
+    fn get_mut(&mut self, index: usize) -> Option<&mut Value>;
+
+    /// Store `value` at slot `index`, which must be less than `len`.
+    fn store(&mut self, index: usize, value: Value);
+
+    /// Remove the value at slot `index`, leaving it free.
+    fn take(&mut self, index: usize) -> Option<Value>;
+
+    /// Remove all slots.
+    fn clear(&mut self);
+}
+
+/// Default heap, where `None` marks free slots.
+#[derive(Debug, Default)]
+pub struct VecHeap(Vec<Option<Value>>);
+
+impl VecHeap {
+    pub fn new() -> VecHeap {
+        VecHeap(vec![])
+    }
+}
+
+impl Heap for VecHeap {
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn grow(&mut self) -> usize {
+        self.0.push(None);
+        self.0.len() - 1
+    }
+
+    fn get(&self, index: usize) -> Option<&Value> {
+        self.0.get(index).and_then(|v| v.as_ref())
+    }
+
+    fn get_mut(&mut self, index: usize) -> Option<&mut Value> {
+        self.0.get_mut(index).and_then(|v| v.as_mut())
+    }
+
+    fn store(&mut self, index: usize, value: Value) {
+        self.0[index] = Some(value);
+    }
+
+    fn take(&mut self, index: usize) -> Option<Value> {
+        self.0.get_mut(index).and_then(|v| v.take())
+    }
+
+    fn clear(&mut self) {
+        self.0.clear();
+    }
+}
@@ -1,17 +1,53 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::io::BufRead;
 use crate::{
     token::{Kind, Token},
     errors::{Error, Result},
 };
 
+/// Number of chars the lexer may look ahead of the current one
+const LOOKAHEAD: usize = 4;
+
+/// Source read incrementally, for `Lexer::from_reader`
+struct Input(Box<dyn BufRead>);
+
+impl fmt::Debug for Input {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "Input")
+    }
+}
+
 /// Lexer for the script language
 #[derive(Debug)]
 pub struct Lexer {
+    // chars of the source not yet consumed (and, when not reading from `input`, all of them)
     source: Vec<char>,
+    // index of the current char, counting from the start of the source
     index: usize,
+    // index of `source[0]`, counting from the start of the source
+    base: usize,
+
+    // source still to be read, if reading incrementally
+    input: Option<Input>,
+    // error found reading `input`, reported by the next call to `next`
+    input_error: Option<std::io::Error>,
+
+    // name of the source (usually the file name), for error reporting
+    name: Option<String>,
+
+    // current line and index where it starts, for the line/col of tokens
+    line: usize,
+    line_start: usize,
+    // line/col where the token being read starts
+    token_line: usize,
+    token_col: usize,
+
+    // for each `${` of a string interpolation not yet closed, the braces open inside it
+    interpolations: Vec<usize>,
 
     keywords: HashMap<String, Kind>,
-    operators: Vec<(char, char, Kind, Option<Kind>)>,
+    operators: Vec<(&'static str, Kind)>,
 }
 
 impl Lexer {
@@ -25,43 +61,125 @@ impl Lexer {
             h.insert("while".to_string(), Kind::While);
             h.insert("for".to_string(), Kind::For);
             h.insert("fun".to_string(), Kind::Fun);
+            h.insert("match".to_string(), Kind::Match);
+            h.insert("return".to_string(), Kind::Return);
+            h.insert("yield".to_string(), Kind::Yield);
+            h.insert("let".to_string(), Kind::Let);
+            h.insert("nil".to_string(), Kind::Nil);
+            h.insert("break".to_string(), Kind::Break);
+            h.insert("continue".to_string(), Kind::Continue);
             h
         };
 
-        // list of operators
-        // (<first-char>, <optional-second-char>, <first-char-kind>, <optional-second-char-kind>)
-        // use '\0' for second-char if not present.
-        let operators = vec![
-            ('(', '\0', Kind::LPar,     None),
-            (')', '\0', Kind::RPar,     None),
-            ('{', '\0', Kind::LBraces,  None),
-            ('}', '\0', Kind::RBraces,  None),
-            ('[', '\0', Kind::LBracket, None),
-            (']', '\0', Kind::RBracket, None),
-            ('+', '\0', Kind::Add,      None),
-            ('-', '\0', Kind::Sub,      None),
-            ('/', '\0', Kind::Div,      None),
-            ('*', '\0', Kind::Mul,      None),
-            ('%', '\0', Kind::Mod,      None),
-            (';', '\0', Kind::Semi,     None),
-            (',', '\0', Kind::Comma,    None),
-            ('<', '=',  Kind::Lt,       Some(Kind::Lte)),
-            ('>', '=',  Kind::Gt,       Some(Kind::Gte)),
-            ('!', '=',  Kind::Not,      Some(Kind::NotEq)),
-            ('=', '=',  Kind::Assign,   Some(Kind::Eq)),
+        // list of operators and their token kind
+        // operators can have any length, the longest match wins
+        let mut operators = vec![
+            ("(",  Kind::LPar),
+            (")",  Kind::RPar),
+            ("{",  Kind::LBraces),
+            ("}",  Kind::RBraces),
+            ("[",  Kind::LBracket),
+            ("]",  Kind::RBracket),
+            ("+",  Kind::Add),
+            ("-",  Kind::Sub),
+            ("/",  Kind::Div),
+            ("*",  Kind::Mul),
+            ("%",  Kind::Mod),
+            (";",  Kind::Semi),
+            (",",  Kind::Comma),
+            (".",  Kind::Dot),
+            (":",  Kind::Colon),
+            ("<",  Kind::Lt),
+            ("<=", Kind::Lte),
+            ("<<", Kind::Shl),
+            (">",  Kind::Gt),
+            (">=", Kind::Gte),
+            (">>", Kind::Shr),
+            ("!",  Kind::Not),
+            ("!=", Kind::NotEq),
+            ("=",  Kind::Assign),
+            ("==", Kind::Eq),
+            ("=>", Kind::Arrow),
+            ("&&", Kind::And),
+            ("||", Kind::Or),
         ];
 
+        // sort longest first, so the first match found is the longest one
+        operators.sort_by_key(|(op, _)| std::cmp::Reverse(op.chars().count()));
+
         Lexer {
             source: source.chars().collect(),
             index: 0,
+            base: 0,
+            input: None,
+            input_error: None,
+            name: None,
+            line: 1,
+            line_start: 0,
+            token_line: 1,
+            token_col: 1,
             keywords,
+            interpolations: vec![],
             operators,
         }
     }
 
+    /// Create a new lexer for a string, identified by `name` (usually the file name).
+    ///
+    /// The name is kept by the parser, so errors can be reported with `Error::pretty_named`.
+    pub fn new_named(source: &str, name: &str) -> Lexer {
+        let mut lexer = Self::new(source);
+        lexer.name = Some(name.to_string());
+        lexer
+    }
+
+    /// Create a new lexer reading the source incrementally from `reader`.
+    ///
+    /// The source is read a line at a time as tokens are requested, and chars already
+    /// tokenized are discarded, so the whole source is never kept in memory.  Tokens
+    /// are the same as if the source was read into a string and given to `new`.
+    pub fn from_reader<R: BufRead + 'static>(reader: R) -> Lexer {
+        let mut lexer = Self::new("");
+        lexer.input = Some(Input(Box::new(reader)));
+        lexer.fill();
+        lexer
+    }
+
+    /// Read from `input` until there are `LOOKAHEAD` chars after the current one, or it ends.
+    fn fill(&mut self) {
+        while self.base + self.source.len() < self.index + LOOKAHEAD {
+            let input = match &mut self.input {
+                Some(input) => input,
+                None => return,
+            };
+            let mut line = String::new();
+            match input.0.read_line(&mut line) {
+                Ok(0) => self.input = None,
+                Ok(_) => self.source.extend(line.chars()),
+                Err(err) => {
+                    self.input = None;
+                    self.input_error = Some(err);
+                }
+            }
+        }
+    }
+
+    /// Discard the chars before the current one, if reading incrementally.
+    fn discard_consumed(&mut self) {
+        if self.input.is_some() {
+            self.source.drain(.. self.index - self.base);
+            self.base = self.index;
+        }
+    }
+
+    /// Name of the source, if created with `new_named`.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
     /// Return `true` if reached end of source.
     pub fn is_empty(&self) -> bool {
-        self.index >= self.source.len()
+        self.index >= self.base + self.source.len()
     }
 
     /// Current char in source of '\0' if EOF
@@ -69,16 +187,32 @@ impl Lexer {
         self.at(0)
     }
 
-    /// Return the char at `offset` relative to current source index
+    /// Return the char at `offset` (less than `LOOKAHEAD`) relative to current source index
     fn at(&self, offset: usize) -> char {
-        self.source.get(self.index + offset)
+        self.source.get(self.index - self.base + offset)
             .cloned()
             .unwrap_or('\0')
     }
 
     /// Skip current char
     fn drop(&mut self) {
+        if self.current() == '\n' {
+            self.line += 1;
+            self.line_start = self.index + 1;
+        }
         self.index += 1;
+        self.fill();
+    }
+
+    /// Create a `Token` from `start` up to the current index
+    fn token(&self, kind: Kind, value: String, start: usize) -> Token {
+        Token {
+            kind,
+            value,
+            at: start .. self.index,
+            line: self.token_line,
+            col: self.token_col,
+        }
     }
 
     /// Return current and drop
@@ -99,6 +233,8 @@ impl Lexer {
                 while !self.is_empty() && self.current() != '\n' {
                     self.drop();
                 }
+            } else if self.current() == '/' && self.at(1) == '*' {
+                self.skip_block_comment()?;
             } else {
                 break
             }
@@ -106,6 +242,28 @@ impl Lexer {
         Ok(self.is_empty())
     }
 
+    /// Skip a `/* ... */` comment, that may contain other (nested) block comments
+    fn skip_block_comment(&mut self) -> Result<()> {
+        let start = self.index;
+        let mut depth = 0;
+        loop {
+            if self.is_empty() {
+                return Err(Error::UnterminatedComment(start));
+            } else if self.current() == '/' && self.at(1) == '*' {
+                depth += 1;
+                self.drop();
+            } else if self.current() == '*' && self.at(1) == '/' {
+                depth -= 1;
+                self.drop();
+                if depth == 0 {
+                    self.drop();
+                    return Ok(());
+                }
+            }
+            self.drop();
+        }
+    }
+
     /// `true` if `c` is the first char of an identifier
     fn is_first_id(c: char) -> bool {
         c.is_ascii_alphabetic() || c == '$' || c == '_'
@@ -116,19 +274,74 @@ impl Lexer {
         Self::is_first_id(c) || c.is_ascii_digit()
     }
 
-    /// Read next integer from source
-    fn next_int(&mut self) -> Result<Token> {
+    /// Read next number (integer or float) from source
+    ///
+    /// Integers may be given in hexadecimal (`0x1F`), octal (`0o17`) or binary (`0b1010`),
+    /// keeping the prefix in the token value, as are any `_` separating digits.  A float has a fractional part (`1.5`) and/or an exponent (`1e10`, `2.5e-3`);
+    /// both must have digits, so `1.` is not a float and `1e` is an integer followed by `e`.
+    fn next_number(&mut self) -> Result<Token> {
         let start = self.index;
         let mut v = String::new();
-        while self.current().is_ascii_digit() {
+        let mut kind = Kind::Int;
+
+        let base = match (self.current(), self.at(1)) {
+            ('0', 'x') => Some(16),
+            ('0', 'o') => Some(8),
+            ('0', 'b') => Some(2),
+            _ => None,
+        };
+        if let Some(base) = base {
+            v.push(self.pop());
             v.push(self.pop());
+            self.next_digits(&mut v, base)?;
+            // an alphanumeric right after is an error, so `0b12` is not `0b1` followed by `2`
+            if self.current().is_ascii_alphanumeric() {
+                return Err(Error::InvalidDigit(self.current(), base, self.index));
+            }
+            if v.len() == 2 {
+                return Err(Error::SyntaxError(self.index));
+            }
+            return Ok(self.token(kind, v, start));
         }
+        self.next_digits(&mut v, 10)?;
 
-        Ok(Token {
-            kind: Kind::Int,
-            value: v,
-            at: start .. self.index,
-        })
+        if self.current() == '.' && self.at(1).is_ascii_digit() {
+            kind = Kind::Float;
+            v.push(self.pop());
+            self.next_digits(&mut v, 10)?;
+        }
+
+        let sign = if self.at(1) == '+' || self.at(1) == '-' { 1 } else { 0 };
+        if (self.current() == 'e' || self.current() == 'E') && self.at(1 + sign).is_ascii_digit() {
+            kind = Kind::Float;
+            for _ in 0 .. 1 + sign {
+                v.push(self.pop());
+            }
+            self.next_digits(&mut v, 10)?;
+        }
+
+        Ok(self.token(kind, v, start))
+    }
+
+    /// Read digits in `base` into `v`, allowing `_` between digits as a separator (`1_000`).
+    ///
+    /// A `_` not between two digits (as in `1_` or `1__0`) is an error.
+    fn next_digits(&mut self, v: &mut String, base: u32) -> Result<()> {
+        let mut after_digit = false;
+        loop {
+            let c = self.current();
+            if c == '_' {
+                if !after_digit || !self.at(1).is_digit(base) {
+                    return Err(Error::MisplacedSeparator(self.index));
+                }
+                after_digit = false;
+            } else if c.is_digit(base) {
+                after_digit = true;
+            } else {
+                return Ok(());
+            }
+            v.push(self.pop());
+        }
     }
 
     /// Read next identifier or keyword from source
@@ -139,20 +352,35 @@ impl Lexer {
             v.push(self.pop())
         }
 
-        Ok(Token {
-            kind: self.keywords.get(&v).map(|k| k.clone()).unwrap_or(Kind::Id),
-            value: v,
-            at: start .. self.index,
-        })
+        Ok(self.token(self.keywords.get(&v).copied().unwrap_or(Kind::Id), v, start))
     }
 
     /// Read next quoted string from source
     fn next_str(&mut self) -> Result<Token> {
         let start = self.index;
-        assert_eq!(self.current(), '"');
+        if self.current() != '"' {
+            return Err(Error::SyntaxError(self.index));
+        }
         self.drop();
+        self.next_str_part(start, true)
+    }
+
+    /// Read a string up to its closing `"` or the `${` of an interpolation.
+    ///
+    /// A string without interpolations is a single `Str` token.  Otherwise, the text before
+    /// the first `${` is an `InterpStart`, that between a `}` and the next `${` an `InterpMid`
+    /// and the text after the last `}` an `InterpEnd`, with the tokens of each interpolated
+    /// expression in between.
+    fn next_str_part(&mut self, start: usize, first: bool) -> Result<Token> {
         let mut v = String::new();
         while !self.is_empty() && self.current() != '"' {
+            if self.current() == '$' && self.at(1) == '{' {
+                self.drop();
+                self.drop();
+                self.interpolations.push(0);
+                let kind = if first { Kind::InterpStart } else { Kind::InterpMid };
+                return Ok(self.token(kind, v, start));
+            }
             match self.pop() {
                 '\\' => {
                     match self.pop() {
@@ -161,6 +389,8 @@ impl Lexer {
                         'r' => v.push('\r'),
                         '\\' => v.push('\\'),
                         '"' => v.push('"'),
+                        '$' => v.push('$'),
+                        'u' => v.push(self.next_unicode_escape()?),
                         c => return Err(Error::InvalidStringEscape(c, self.index - 1)),
                     }
                 }
@@ -174,35 +404,51 @@ impl Lexer {
             Err(Error::UnexpectedEOF)
         } else {
             self.drop();
-            Ok(Token {
-                kind: Kind::Str,
-                value: v,
-                at: start .. self.index,
-            })
+            let kind = if first { Kind::Str } else { Kind::InterpEnd };
+            Ok(self.token(kind, v, start))
+        }
+    }
+
+    /// Read the `{XXXX}` of a `\u{XXXX}` escape, with 1 to 6 hex digits of a Unicode scalar value
+    fn next_unicode_escape(&mut self) -> Result<char> {
+        let at = self.index - 1;
+        if self.current() != '{' {
+            return Err(Error::InvalidStringEscape('u', at));
         }
+        self.drop();
+        let mut digits = String::new();
+        while self.current().is_ascii_hexdigit() && digits.len() < 6 {
+            digits.push(self.pop());
+        }
+        if digits.is_empty() || self.current() != '}' {
+            return Err(Error::InvalidStringEscape('u', at));
+        }
+        self.drop();
+        u32::from_str_radix(&digits, 16).ok()
+            .and_then(char::from_u32)
+            .ok_or(Error::InvalidStringEscape('u', at))
+    }
+
+    /// `true` if source at current index starts with `s`
+    fn starts_with(&self, s: &str) -> bool {
+        s.chars().enumerate().all(|(i, c)| self.at(i) == c)
     }
 
     /// Read next operator from source
+    ///
+    /// Operators are read with "maximal munch": the longest operator matching the source
+    /// is taken, and the rest is left for the next token.  So `a===b` is read as `a`, `==`,
+    /// `=`, `b` and `a= =b` as `a`, `=`, `=`, `b` (both then rejected by the parser), while
+    /// `a!=b` and `a==b` are each a single comparison operator.  There are no `--` or `<-`
+    /// operators, so `a--1` is `a - -1` and `a<-1` is `a < -1`.
     pub fn next_op(&mut self) -> Result<Token> {
         let start = self.index;
-        for (c0, c1, fst, snd) in &self.operators {
-            if self.current() == *c0 {
-                self.index += 1;
-                if snd.is_some() && self.current() == *c1 {
-                    self.index += 1;
-                    return Ok(Token {
-                        kind: snd.clone().unwrap(),
-                        value: [*c0, *c1].iter().collect(),
-                        at: start .. self.index,
-                    })
-                } else {
-                    return Ok(Token {
-                        kind: fst.clone(),
-                        value: c0.to_string(),
-                        at: start .. self.index,
-                    })
-                }
+        let found = self.operators.iter().find(|(op, _)| self.starts_with(op)).copied();
+        if let Some((op, kind)) = found {
+            for _ in op.chars() {
+                self.drop();
             }
+            return Ok(self.token(kind, op.to_string(), start))
         }
         Err(Error::SyntaxError(self.index))
     }
@@ -213,16 +459,45 @@ impl Lexer {
     /// * Return `Ok(None)` if reached EOF
     /// * Return `Err(err)` in case of lexing error
     pub fn next(&mut self) -> Result<Option<Token>> {
+        self.discard_consumed();
+        let result = self.next_token();
+        // a token cut short by an error reading the source is not returned
+        match self.input_error.take() {
+            Some(err) => Err(Error::InputError(err)),
+            None => result,
+        }
+    }
+
+    /// Read next `Token` from source, as `next`.
+    fn next_token(&mut self) -> Result<Option<Token>> {
         if self.skip_ws()? {
-            Ok(None)
-        } else if self.current().is_ascii_digit() {
-            Ok(Some(self.next_int()?))
+            return Ok(None)
+        }
+
+        self.token_line = self.line;
+        self.token_col = self.index - self.line_start + 1;
+        if self.current().is_ascii_digit() {
+            Ok(Some(self.next_number()?))
         } else if Self::is_first_id(self.current()) {
             Ok(Some(self.next_id()?))
         } else if self.current() == '"' {
             Ok(Some(self.next_str()?))
+        } else if self.current() == '}' && self.interpolations.last() == Some(&0) {
+            // end of an interpolated expression, the string continues
+            let start = self.index;
+            self.drop();
+            self.interpolations.pop();
+            Ok(Some(self.next_str_part(start, false)?))
         } else {
-            Ok(Some(self.next_op()?))
+            let tk = self.next_op()?;
+            if let Some(depth) = self.interpolations.last_mut() {
+                match tk.kind {
+                    Kind::LBraces => *depth += 1,
+                    Kind::RBraces => *depth -= 1,
+                    _ => (),
+                }
+            }
+            Ok(Some(tk))
         }
     }
 
@@ -1,9 +1,20 @@
+// `Error` carries tokens, Ast's and Values for reporting, and `next` is the
+// established name for the incremental lexer/parser entry points.
+#![allow(clippy::result_large_err, clippy::should_implement_trait)]
+
 pub mod value;
 pub mod opcodes;
 pub mod errors;
 pub mod lexer;
 pub mod token;
 pub mod vm;
+pub mod heap;
 pub mod parser;
 pub mod ast;
-pub mod compiler;
\ No newline at end of file
+pub mod compiler;
+pub mod program;
+pub mod diagnostic;
+pub mod bytecode;
+pub mod api;
+
+pub use api::{eval, eval_on};
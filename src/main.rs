@@ -1,39 +1,271 @@
-use clap::{Arg, App};
+#![allow(clippy::result_large_err)]
+
+use std::io::{self, BufRead, Write};
+
+use clap::{Arg, App, ArgMatches, SubCommand};
 
 use script::{
     vm::VM,
-    errors::Result,
+    errors::{Error, Result},
+    value::Value,
     lexer::Lexer,
     parser::Parser,
     compiler::Compiler,
-    opcodes::Op,
+    opcodes::{Op, disassemble},
+    program::Program,
+    ast::Ast,
+    bytecode,
+    eval_on,
 };
 
+/// Number of inputs evaluated by the REPL between full collections
+const REPL_COLLECT_EVERY: usize = 16;
+
+/// Options from the command line
+struct Options {
+    /// Compile in strict mode
+    strict: bool,
+
+    /// Print the value of the last expression statement after running
+    print_result: bool,
+
+    /// Compile with line markers, to report the line of runtime errors
+    lines: bool,
+
+    /// Print the compiled code disassembled, instead of as Rust values
+    disasm: bool,
+}
+
 fn print_code(code: &[Op]) {
-    for i in 0 .. code.len() {
-        println!("{:4}\t{:?}", i, code[i]);
+    for (i, op) in code.iter().enumerate() {
+        println!("{:4}\t{:?}", i, op);
     }
 }
 
-fn try_compiler(source: &str) -> Result<()> {
-    let mut parser = Parser::new(Lexer::new(source))?;
-    let mut compiler = Compiler::new();
-    while let Some(ast) = parser.next()? {
-        compiler.feed(&ast)?;
+/// Compile `source`, printing all syntax errors and the diagnostics.
+fn compile(source: &str, source_name: &str, options: &Options) -> Result<Program> {
+    let mut parser = Parser::new(Lexer::new_named(source, source_name))?;
+    let mut compiler = Compiler::new()
+        .strict(options.strict)
+        .line_markers(options.lines);
+
+    // report all syntax errors at once, the last one as the result
+    let (mut asts, mut errors) = parser.parse_all();
+    if let Some(last) = errors.pop() {
+        for err in errors {
+            eprintln!("error: {}", err.pretty_named(source, source_name));
+        }
+        return Err(last);
+    }
+
+    // the value of the last statement may be kept on the stack to be printed
+    let last = asts.pop();
+    for ast in &asts {
+        compiler.feed(ast)?;
     }
-    let code = compiler.build()?;
+    match last {
+        Some(Ast::Sttm(expr)) if options.print_result => compiler.feed(&expr)?,
+        Some(ast) => compiler.feed(&ast)?,
+        None => 0,
+    };
+
+    let program = compiler.build()?;
+    for diagnostic in &program.diagnostics {
+        eprintln!("{}", diagnostic.pretty_named(source, source_name));
+    }
+    Ok(program)
+}
+
+/// Lex, parse and compile `source` without running it, printing all errors and diagnostics.
+///
+/// Returns the number of errors found.
+fn check(source: &str, source_name: &str, options: &Options) -> usize {
+    let mut errors = vec![];
+    let mut compiler = Compiler::new()
+        .strict(options.strict)
+        .line_markers(options.lines);
+
+    match Parser::new(Lexer::new_named(source, source_name)) {
+        Ok(mut parser) => {
+            let (asts, syntax_errors) = parser.parse_all();
+            errors.extend(syntax_errors);
+
+            // statements are compiled independently, so an error does not hide the following ones
+            let mut compiled = true;
+            for ast in &asts {
+                if let Err(err) = compiler.feed(ast) {
+                    errors.push(err);
+                    compiled = false;
+                }
+            }
+            if compiled && errors.is_empty() {
+                match compiler.build() {
+                    Ok(program) => {
+                        for diagnostic in &program.diagnostics {
+                            eprintln!("{}", diagnostic.pretty_named(source, source_name));
+                        }
+                    }
+                    Err(err) => errors.push(err),
+                }
+            }
+        }
+        Err(err) => errors.push(err),
+    }
+
+    // syntax errors are found before compile errors, but are shown in source order
+    errors.sort_by_key(|err| err.position().unwrap_or(usize::MAX));
+    for err in &errors {
+        eprintln!("error: {}", err.pretty_named(source, source_name));
+    }
+    errors.len()
+}
+
+/// Run `program` on a new VM, without any debugging output.
+fn run(program: &Program, options: &Options) -> Result<()> {
+    let mut vm = VM::new().with_buffered_output(std::io::stdout());
+    let result = vm.run(program);
+    if result.is_err() && vm.current_line() > 0 {
+        eprintln!("runtime error at line {}", vm.current_line());
+    }
+    result?;
+    if options.print_result {
+        if let Some(value) = vm.top_value() {
+            println!("{}", value?.fmt(&vm, 0)?);
+        }
+    }
+    Ok(())
+}
+
+/// Compile `source` and write it as bytecode to `output`.
+fn compile_to_file(source: &str, source_name: &str, output: &str, options: &Options) -> Result<()> {
+    let program = compile(source, source_name, options)?;
+    std::fs::write(output, bytecode::serialize_program(&program))?;
+    Ok(())
+}
+
+fn try_compiler(source: &str, source_name: &str, options: &Options) -> Result<()> {
+    let program = compile(source, source_name, options)?;
     println!("COMPILED>");
-    print_code(&code);
+    if options.disasm {
+        print!("{}", disassemble(&program.code));
+    } else {
+        print_code(&program.code);
+    }
 
-    let mut vm = VM::new();
+    let mut vm = VM::new().with_buffered_output(std::io::stdout());
 
     println!("RUN>");
-    vm.run(&code)?;
+    let result = vm.run(&program);
+    if result.is_err() && vm.current_line() > 0 {
+        eprintln!("runtime error at line {}", vm.current_line());
+    }
+    result?;
+    if options.print_result {
+        if let Some(value) = vm.top_value() {
+            println!("{}", value?.fmt(&vm, 0)?);
+        }
+    }
     vm.collect();
     println!("{:?}", vm);
     Ok(())
 }
 
+/// `true` if `source` is a sequence of complete statements.
+fn parses(source: &str) -> bool {
+    match Parser::new(Lexer::new(source)) {
+        Ok(mut parser) => loop {
+            match parser.next() {
+                Ok(Some(_)) => (),
+                Ok(None) => return true,
+                Err(_) => return false,
+            }
+        },
+        Err(_) => false,
+    }
+}
+
+/// Read-eval-print loop on a single `VM`, so globals persist between inputs.
+///
+/// Input that ends before a statement is complete (e.g. an open brace) is continued on
+/// the next line.  Ends on end of input.
+fn repl() -> io::Result<()> {
+    let mut vm = VM::new();
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    let mut evaluated = 0;
+
+    loop {
+        let mut input = String::new();
+        print!("> ");
+        loop {
+            io::stdout().flush()?;
+            match lines.next() {
+                Some(line) => input.push_str(&line?),
+                None => return Ok(()),
+            }
+            input.push('\n');
+
+            // allow leaving out the `;` after the last statement
+            if !parses(&input) && parses(&format!("{};", input)) {
+                input.push(';');
+            }
+
+            match eval_on(&mut vm, &input) {
+                Err(Error::UnexpectedEOF) | Err(Error::UnterminatedComment(_)) => print!(". "),
+                Err(err) => {
+                    eprintln!("error: {}", err.pretty(&input));
+                    break;
+                }
+                Ok(Value::Nil) => break,
+                Ok(value) => {
+                    match value.fmt(&vm, 0) {
+                        Ok(text) => println!("{}", text),
+                        Err(err) => eprintln!("error: {}", err),
+                    }
+                    break;
+                }
+            }
+        }
+
+        evaluated += 1;
+        if evaluated % REPL_COLLECT_EVERY == 0 {
+            vm.collect();
+        }
+    }
+}
+
+/// Contents of the file `name`, or exit with 1 after reporting why it cannot be read.
+fn read_or_exit(name: &str) -> Vec<u8> {
+    match std::fs::read(name) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            eprintln!("error: cannot read {}: {}", name, err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Source in `bytes`, read from the file `name`, or exit with 1 if not valid UTF-8.
+fn source_or_exit(name: &str, bytes: Vec<u8>) -> String {
+    match String::from_utf8(bytes) {
+        Ok(source) => source,
+        Err(err) => {
+            eprintln!("error: cannot read {}: {}", name, err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Options common to all ways of compiling
+fn options(matches: &ArgMatches) -> Options {
+    Options {
+        strict: matches.is_present("strict"),
+        print_result: matches.is_present("print-result"),
+        lines: matches.is_present("lines"),
+        disasm: matches.is_present("disasm"),
+    }
+}
+
 fn main() {
     let matches = App::new("script")
         .version("0.1")
@@ -41,14 +273,105 @@ fn main() {
         .about("Script compiler and VM")
         .arg(Arg::with_name("source")
             .index(1)
-            .help("Name of input source file")
-            .required(true))
+            .help("Name of input source file, starts a REPL if not given"))
+        .arg(Arg::with_name("repl")
+            .long("repl")
+            .help("Read, evaluate and print statements interactively"))
+        .arg(Arg::with_name("strict")
+            .long("strict")
+            .global(true)
+            .help("Require variables to be declared with `let` before assignment"))
+        .arg(Arg::with_name("print-result")
+            .long("print-result")
+            .global(true)
+            .help("Print the value of the last expression after running"))
+        .arg(Arg::with_name("lines")
+            .long("lines")
+            .global(true)
+            .help("Emit line markers, so runtime errors report the line being executed"))
+        .arg(Arg::with_name("check")
+            .long("check")
+            .requires("source")
+            .conflicts_with("repl")
+            .help("Only check the source for errors, without running it; exits with 1 if any"))
+        .arg(Arg::with_name("disasm")
+            .long("disasm")
+            .help("Print the compiled code in readable form, with mnemonics and jump targets"))
+        .subcommand(SubCommand::with_name("compile")
+            .about("Compile a source file to bytecode")
+            .arg(Arg::with_name("source")
+                .index(1)
+                .required(true)
+                .help("Name of input source file"))
+            .arg(Arg::with_name("output")
+                .short("o")
+                .takes_value(true)
+                .help("Name of the bytecode file, the source with extension `.bc` if not given")))
+        .subcommand(SubCommand::with_name("run")
+            .about("Run a source or bytecode file, without debugging output")
+            .arg(Arg::with_name("file")
+                .index(1)
+                .required(true)
+                .help("Name of the source or bytecode (as written by `compile`) file")))
         .get_matches();
 
-    let source_name = matches.value_of("source").unwrap();
-    let source = std::fs::read_to_string(source_name).unwrap();
+    match matches.subcommand() {
+        ("compile", Some(sub)) => {
+            let source_name = sub.value_of("source").unwrap();
+            let source = source_or_exit(source_name, read_or_exit(source_name));
+            let output = match sub.value_of("output") {
+                Some(output) => output.to_string(),
+                None => std::path::Path::new(source_name).with_extension("bc").to_string_lossy().into_owned(),
+            };
+            if let Err(err) = compile_to_file(&source, source_name, &output, &options(sub)) {
+                eprintln!("error: {}", err.pretty_named(&source, source_name));
+                std::process::exit(1);
+            }
+            return;
+        }
+        ("run", Some(sub)) => {
+            let file_name = sub.value_of("file").unwrap();
+            let bytes = read_or_exit(file_name);
+            if bytecode::is_bytecode(&bytes) {
+                let result = bytecode::deserialize_program(&bytes)
+                    .and_then(|program| run(&program, &options(sub)));
+                if let Err(err) = result {
+                    eprintln!("error: {}", err);
+                    std::process::exit(1);
+                }
+            } else {
+                let source = source_or_exit(file_name, bytes);
+                let result = compile(&source, file_name, &options(sub))
+                    .and_then(|program| run(&program, &options(sub)));
+                if let Err(err) = result {
+                    eprintln!("error: {}", err.pretty_named(&source, file_name));
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        _ => (),
+    }
+
+    let source_name = match matches.value_of("source") {
+        Some(source_name) if !matches.is_present("repl") => source_name,
+        _ => {
+            if let Err(err) = repl() {
+                eprintln!("error: {}", err);
+            }
+            return;
+        }
+    };
+    let source = source_or_exit(source_name, read_or_exit(source_name));
+
+    if matches.is_present("check") {
+        if check(&source, source_name, &options(&matches)) > 0 {
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    if let Err(err) = try_compiler(&source) {
-        eprintln!("error: {}", err.pretty(&source));
+    if let Err(err) = try_compiler(&source, source_name, &options(&matches)) {
+        eprintln!("error: {}", err.pretty_named(&source, source_name));
     }
 }
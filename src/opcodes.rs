@@ -1,12 +1,143 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use crate::program::Function;
+
 /// Native operations that are defined directly in the VM.
 /// A scape-hatch for some low level operations.
 #[derive(Debug, Clone)]
 pub enum Native {
     Print,
+    PrettyPrint,
     ToString,
     Length,
     Append,
+    Insert,
     DumpStack,
+    DumpHeap,
+    Ord,
+    At,
+    Flush,
+    Weak,
+    DerefWeak,
+    Enqueue,
+    Dequeue,
+    Peek,
+    Keys,
+    Values,
+    Range,
+    Min,
+    Max,
+    ApproxEq,
+    Resume,
+    #[cfg(feature = "graphemes")]
+    GraphemeLength,
+    #[cfg(feature = "graphemes")]
+    GraphemeAt,
+}
+
+impl Native {
+    /// All native operations
+    pub const ALL: &'static [Native] = &[
+        Native::Print,
+        Native::PrettyPrint,
+        Native::ToString,
+        Native::Length,
+        Native::Append,
+        Native::Insert,
+        Native::DumpStack,
+        Native::DumpHeap,
+        Native::Ord,
+        Native::At,
+        Native::Flush,
+        Native::Weak,
+        Native::DerefWeak,
+        Native::Enqueue,
+        Native::Dequeue,
+        Native::Peek,
+        Native::Keys,
+        Native::Values,
+        Native::Range,
+        Native::Min,
+        Native::Max,
+        Native::ApproxEq,
+        Native::Resume,
+        #[cfg(feature = "graphemes")]
+        Native::GraphemeLength,
+        #[cfg(feature = "graphemes")]
+        Native::GraphemeAt,
+    ];
+
+    /// Name of the function that calls this native on scripts
+    pub fn name(&self) -> &'static str {
+        match self {
+            Native::Print => "print",
+            Native::PrettyPrint => "pretty_print",
+            Native::ToString => "to_string",
+            Native::Length => "length",
+            Native::Append => "append",
+            Native::Insert => "insert",
+            Native::DumpStack => "dump_stack",
+            Native::DumpHeap => "dump_heap",
+            Native::Ord => "ord",
+            Native::At => "at",
+            Native::Flush => "flush",
+            Native::Weak => "weak",
+            Native::DerefWeak => "deref_weak",
+            Native::Enqueue => "enqueue",
+            Native::Dequeue => "dequeue",
+            Native::Peek => "peek",
+            Native::Keys => "keys",
+            Native::Values => "values",
+            Native::Range => "range",
+            Native::Min => "min",
+            Native::Max => "max",
+            Native::ApproxEq => "approx_eq",
+            Native::Resume => "resume",
+            #[cfg(feature = "graphemes")]
+            Native::GraphemeLength => "gllength",
+            #[cfg(feature = "graphemes")]
+            Native::GraphemeAt => "glat",
+        }
+    }
+
+    /// Accepted number of arguments, as (minimum, maximum)
+    /// where a maximum of `None` means any number of arguments
+    pub fn arity(&self) -> (usize, Option<usize>) {
+        match self {
+            Native::Print => (0, None),
+            Native::PrettyPrint => (1, Some(1)),
+            Native::ToString => (1, Some(1)),
+            Native::Length => (1, Some(1)),
+            Native::Append => (2, None),
+            Native::Insert => (3, Some(3)),
+            Native::DumpStack => (0, Some(1)),
+            Native::DumpHeap => (0, Some(0)),
+            Native::Ord => (1, Some(1)),
+            Native::At => (2, Some(2)),
+            Native::Flush => (0, Some(0)),
+            Native::Weak => (1, Some(1)),
+            Native::DerefWeak => (1, Some(1)),
+            Native::Enqueue => (2, None),
+            Native::Dequeue => (1, Some(1)),
+            Native::Peek => (1, Some(1)),
+            Native::Keys => (1, Some(1)),
+            Native::Values => (1, Some(1)),
+            Native::Range => (1, Some(3)),
+            Native::Min | Native::Max => (1, None),
+            Native::ApproxEq => (2, Some(3)),
+            Native::Resume => (1, Some(1)),
+            #[cfg(feature = "graphemes")]
+            Native::GraphemeLength => (1, Some(1)),
+            #[cfg(feature = "graphemes")]
+            Native::GraphemeAt => (2, Some(2)),
+        }
+    }
+
+    /// `true` if `nargs` is an acceptable number of arguments
+    pub fn accepts(&self, nargs: usize) -> bool {
+        let (min, max) = self.arity();
+        nargs >= min && max.is_none_or(|max| nargs <= max)
+    }
 }
 
 /// List of opcodes supported by the VM
@@ -23,31 +154,93 @@ pub enum Op {
     /// (#-of-args, which-call)
     Native(usize, Native),
 
+    /// Call a native function registered by the host
+    /// (#-of-args, name)
+    Foreign(usize, String),
+
     /// Push Integer
     PushI(i64),
+    /// Push Integer 0
+    PushI0,
+    /// Push Integer 1
+    PushI1,
+    /// Push Integer that fits in a single byte
+    PushIByte(i8),
+    /// Push Float
+    PushF(f64),
+    /// Push nil
+    PushNil,
     /// Push String
     PushS(String),
     /// Make top (value) elements from stack into a Value::List
     MakeList(usize),
+    /// Make top (2 * value) elements from stack, as key-value pairs, into a Value::Map
+    MakeMap(usize),
+    /// Join top (value) elements from stack into a Value::Str, formatted as `to_string`
+    Interpolate(usize),
 
     /// Sub-indexing (a b -- a[b])
     Index,
 
-    /// Sub-indexed store (a b c -- c[b] = a)
+    /// Sub-indexing into the path of an assignment (a b -- a[b]), as `a.b` in `a.b.c = x`
+    ///
+    /// As `Index` of a list or map, but `a[b]` must be a list or map, to be stored into.
+    IndexInto,
+
+    /// Sub-indexed store (a b c -- a), with `c[b] = a`
+    ///
+    /// The stored value is left on the stack, so `c[b] = a` evaluates to `a`, as `x = a` does.
     IndexStore,
 
-    /// TODO: function call
+    /// Slicing (a b c -- a[b:c]), with the bounds clamped to the length of `a`
+    Slice,
+
+    /// Slice store (a b c d -- a), with `d[b:c] = a`; `a` must be a list and replaces the slice
+    SliceStore,
+
+    /// Push a function defined by the script
+    Function(Arc<Function>),
+
+    /// Call the function below the top (#-of-args) values, that are its arguments
+    /// (f a1 .. an -- r), where `r` is the value it returns
+    Call(usize),
+
+    /// Return from the current function, with the value in its local slot 0
+    Return,
+
+    /// Suspend the current call, of a generator, making the top value the result of the
+    /// `resume` that ran it
+    Yield,
+
+    /// Load a local (slot relative to the frame pointer)
+    LoadL(usize),
+    /// Store into a local (keep on stack)
+    StoreL(usize),
+    /// Move into a local (pop stack)
+    MoveL(usize),
 
     /// Duplicate (top - value)
     Dup(usize),
     /// Pop (discard) top
     Pop,
+    /// Swap the two values on top (a b -- b a)
+    Swap,
+    /// Source line marker, sets the current line of the VM
+    Line(usize),
+    /// Check for nil (a -- a != nil), pushes 1 if not nil, 0 otherwise
+    NotNil,
 
     /// Load a global
     LoadG(String),
     /// Store into a global (keep on stack)
+    ///
+    /// This is what makes assignment an expression: `a = (b = 3) + 1` leaves `3`
+    /// on the stack after storing `b`, so it can be used by the enclosing `+`.
     StoreG(String),
     /// Move into a global (pop stack)
+    ///
+    /// Only used when the value of the assignment is discarded, that is, for
+    /// assignment statements (`x = 1;`) and `let` declarations.
     MoveG(String),
 
     Lt, Lte,
@@ -57,9 +250,144 @@ pub enum Op {
     /// Jump if top stack if false
     JmpF(usize),
 
+    /// Jump if top stack if true
+    JmpT(usize),
+
     /// Unconditional jump
     Jmp(usize),
 
+    /// Jump to the target at index `top - base`, if the top (popped) is an integer (or a
+    /// float equal to one) inside the table; otherwise continue with the next opcode
+    /// (base, targets)
+    JmpTable(i64, Vec<usize>),
+
     Add, Sub,
     Mul, Div, Mod,
+
+    /// Bit shifts of an integer (a b -- a<<b, a>>b), by 0 to 63 bits;
+    /// right shifts keep the sign
+    Shl, Shr,
+
+    /// Arithmetic negation of an integer or float (a -- -a)
+    Neg,
+    /// Logical not (a -- !a), `1` if `a` is false and `0` otherwise
+    Not,
+
+    /// String concatenation (a b -- a+b), both operands *must* be strings.
+    /// Emitted instead of `Add` when the compiler knows both are strings.
+    Concat,
+
+    /// Integer arithmetic, both operands *must* be integers.
+    /// Emitted instead of the generic ones when the compiler knows both are integers.
+    AddI, SubI, MulI,
+}
+
+impl Op {
+    /// Short and stable name of the opcode, for tooling.
+    ///
+    /// Unlike the `Debug` output, this does not change with the Rust representation.
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Op::Target(_) => "target",
+            Op::Nop => "nop",
+            Op::Native(_, _) => "native",
+            Op::Foreign(_, _) => "foreign",
+            Op::PushI(_) => "pushi",
+            Op::PushI0 => "pushi0",
+            Op::PushI1 => "pushi1",
+            Op::PushIByte(_) => "pushib",
+            Op::PushF(_) => "pushf",
+            Op::PushNil => "pushnil",
+            Op::PushS(_) => "pushs",
+            Op::MakeList(_) => "mklist",
+            Op::MakeMap(_) => "mkmap",
+            Op::Interpolate(_) => "interp",
+            Op::Index => "index",
+            Op::IndexInto => "idxinto",
+            Op::IndexStore => "idxstore",
+            Op::Slice => "slice",
+            Op::SliceStore => "slicestore",
+            Op::Function(_) => "function",
+            Op::Call(_) => "call",
+            Op::Return => "return",
+            Op::Yield => "yield",
+            Op::LoadL(_) => "loadl",
+            Op::StoreL(_) => "storel",
+            Op::MoveL(_) => "movel",
+            Op::Dup(_) => "dup",
+            Op::Swap => "swap",
+            Op::NotNil => "notnil",
+            Op::Line(_) => "line",
+            Op::Pop => "pop",
+            Op::LoadG(_) => "loadg",
+            Op::StoreG(_) => "storeg",
+            Op::MoveG(_) => "moveg",
+            Op::Lt => "lt",
+            Op::Lte => "lte",
+            Op::Gt => "gt",
+            Op::Gte => "gte",
+            Op::Eq => "eq",
+            Op::Neq => "neq",
+            Op::JmpF(_) => "jmpf",
+            Op::JmpT(_) => "jmpt",
+            Op::Jmp(_) => "jmp",
+            Op::JmpTable(_, _) => "jmptable",
+            Op::Add => "add",
+            Op::Sub => "sub",
+            Op::Mul => "mul",
+            Op::Div => "div",
+            Op::Mod => "mod",
+            Op::Shl => "shl",
+            Op::Shr => "shr",
+            Op::Neg => "neg",
+            Op::Not => "not",
+            Op::Concat => "concat",
+            Op::AddI => "addi",
+            Op::SubI => "subi",
+            Op::MulI => "muli",
+        }
+    }
+}
+
+/// Format `code` as human-readable text, one instruction per line.
+///
+/// Each line has the index of the instruction, its mnemonic and operands, with `->` and the
+/// index of the target for jumps.  Instructions that are the target of a jump are marked
+/// with `>`.
+pub fn disassemble(code: &[Op]) -> String {
+    let targets: HashSet<usize> = code.iter()
+        .flat_map(|op| match op {
+            Op::Jmp(target) | Op::JmpF(target) | Op::JmpT(target) => std::slice::from_ref(target),
+            Op::JmpTable(_, targets) => targets.as_slice(),
+            _ => &[],
+        })
+        .copied()
+        .collect();
+
+    let mut s = String::new();
+    for (i, op) in code.iter().enumerate() {
+        let operands = match op {
+            Op::Target(id) => format!("#{}", id),
+            Op::Native(nargs, native) => format!("{}/{}", native.name(), nargs),
+            Op::Foreign(nargs, name) => format!("{}/{}", name, nargs),
+            Op::PushI(n) => n.to_string(),
+            Op::PushIByte(n) => n.to_string(),
+            Op::PushF(n) => format!("{:?}", n),
+            Op::PushS(text) => format!("{:?}", text),
+            Op::Function(function) => format!("{}/{}", function.name, function.arity),
+            Op::MakeList(n) | Op::MakeMap(n) | Op::Interpolate(n) | Op::Dup(n) | Op::Line(n) => n.to_string(),
+            Op::Call(n) | Op::LoadL(n) | Op::StoreL(n) | Op::MoveL(n) => n.to_string(),
+            Op::LoadG(name) | Op::StoreG(name) | Op::MoveG(name) => name.clone(),
+            Op::Jmp(target) | Op::JmpF(target) | Op::JmpT(target) => format!("-> {}", target),
+            Op::JmpTable(base, targets) => {
+                let targets: Vec<String> = targets.iter().map(|target| target.to_string()).collect();
+                format!("{} -> {}", base, targets.join(", "))
+            }
+            _ => String::new(),
+        };
+        let marker = if targets.contains(&i) { ">" } else { " " };
+        s += format!("{:4} {} {:8} {}", i, marker, op.mnemonic(), operands).trim_end();
+        s += "\n";
+    }
+    s
 }
@@ -6,10 +6,43 @@ use crate::{
     ast::Ast,
 };
 
+/// Default maximum nesting of expressions and statements.
+///
+/// Each level of nesting takes up to ~18KB of stack in debug builds (parsing and then
+/// compiling nested `while`s, the most expensive case), so 64 levels stay well inside
+/// the 2MB given by default to spawned threads.  Release builds take much less.
+pub(crate) const DEFAULT_MAX_DEPTH: usize = 64;
+
+/// Binary operators by precedence, from the lowest:
+/// `||`, `&&`, comparisons, `<< >>`, `+ -` and `* / %`
+const BINARY_OPERATORS: [&[Kind]; 6] = [
+    &[Kind::Or],
+    &[Kind::And],
+    &[Kind::Lt, Kind::Lte, Kind::Gt, Kind::Gte, Kind::Eq, Kind::NotEq],
+    &[Kind::Shl, Kind::Shr],
+    &[Kind::Add, Kind::Sub],
+    &[Kind::Mul, Kind::Div, Kind::Mod],
+];
+
+/// Precedence level of comparisons, that chain instead of being left associative
+const COMPARISON_LEVEL: usize = 2;
+
 /// A `Parser` read `Token`s and return `Ast`s.
 #[derive(Debug)]
 pub struct Parser {
     source: VecDeque<Token>,
+
+    /// Name of the source, from the lexer
+    name: Option<String>,
+
+    /// Current nesting of expressions and statements
+    depth: usize,
+
+    /// Maximum allowed `depth`, to avoid overflowing the (native) stack
+    max_depth: usize,
+
+    /// Kind of the last token taken, to resynchronize after errors
+    previous: Option<Kind>,
 }
 
 impl Parser {
@@ -23,9 +56,27 @@ impl Parser {
     pub fn new(mut source: Lexer) -> Result<Parser> {
         Ok(Parser {
             source: source.collect()?.into_iter().collect(),
+            name: source.name().map(str::to_string),
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            previous: None,
         })
     }
 
+    /// Set the maximum nesting of expressions and statements.
+    ///
+    /// The parser is recursive, so a limit is needed to reject (instead of crashing on)
+    /// inputs like `((((...))))` with thousands of levels.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Parser {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Name of the source being parsed, if the lexer was created with `Lexer::new_named`.
+    pub fn source_name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
     /// `true` if there are no more Ast's to return
     pub fn is_empty(&self) -> bool {
         self.source.is_empty()
@@ -42,7 +93,9 @@ impl Parser {
 
     /// Extract self.current() from the queue of tokens
     fn pop(&mut self) -> Result<Token> {
-        self.source.pop_front().ok_or(Error::UnexpectedEOF)
+        let tk = self.source.pop_front().ok_or(Error::UnexpectedEOF)?;
+        self.previous = Some(tk.kind);
+        Ok(tk)
     }
 
     /// **Require** self.current() to be one of `what`, error otherwise
@@ -57,7 +110,7 @@ impl Parser {
     /// *If* current is one of `what` pop it, otherwise return None
     fn check(&mut self, what: &[Kind]) -> Option<Token> {
         if self.one_of(what) {
-            self.source.pop_front()
+            self.pop().ok()
         } else {
             None
         }
@@ -65,70 +118,195 @@ impl Parser {
 
     /// `true` if self.current() is not EOF and one of `what`
     fn one_of(&self, what: &[Kind]) -> bool {
-        !self.is_empty() && what.contains(&self.current().unwrap().kind)
+        match self.source.front() {
+            Some(tk) => what.contains(&tk.kind),
+            None => false,
+        }
     }
 
-    /// Generic algorithm for left-associative binary operators
-    fn left_associative<F: Fn(&mut Self) -> Result<Ast>>(&mut self, which: &[Kind], previous: F) -> Result<Ast> {
-        let mut lhs = previous(self)?;
-        while self.one_of(which) {
-            let tk = self.pop()?;
-            let rhs = previous(self)?;
-            lhs = Ast::BinOp(tk, Box::new(lhs), Box::new(rhs));
+    /// Call `inner` one nesting level deeper, error if too deep
+    fn nested<F: Fn(&mut Self) -> Result<Ast>>(&mut self, inner: F) -> Result<Ast> {
+        if self.depth >= self.max_depth {
+            let at = self.current()?.at.start;
+            return Err(Error::NestingTooDeep(at));
         }
-        Ok(lhs)
+        self.depth += 1;
+        let result = inner(self);
+        self.depth -= 1;
+        result
     }
 
-    /// Int | Str | Var | '(' Expr ')'
+    /// Go one nesting level deeper for an operator that wraps what was parsed so far
+    /// (as in `a + b + c` or `a[0][1]`), error if too deep.
+    ///
+    /// Those loop instead of recursing, but build trees as deep as a nested expression,
+    /// that are later walked recursively.  The depth must be restored by the caller.
+    fn deeper(&mut self) -> Result<()> {
+        if self.depth >= self.max_depth {
+            let at = self.current()?.at.start;
+            return Err(Error::NestingTooDeep(at));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    /// Value of an integer literal, in the base given by its prefix and ignoring `_`
+    /// separators (`None` if too large)
+    fn parse_int(value: &str) -> Option<i64> {
+        let (digits, base) = match value.get(.. 2) {
+            Some("0x") => (&value[2 ..], 16),
+            Some("0o") => (&value[2 ..], 8),
+            Some("0b") => (&value[2 ..], 2),
+            _ => (value, 10),
+        };
+        i64::from_str_radix(&digits.replace('_', ""), base).ok()
+    }
+
+    /// Int | Float | Str | Interpolation | 'nil' | Var | '(' Expr ')' | List | Map
     fn atom(&mut self) -> Result<Ast> {
+        // the larger cases are parsed by their own functions, as they would all take
+        // space in the (recursive) stack frame of this one
         let tk = self.pop()?;
         match tk.kind {
-            Kind::Int => {
-                let n = i64::from_str_radix(&tk.value, 10).map_err(|_| Error::ParsingError(tk.clone()))?;
-                Ok(Ast::Int(n, tk))
-            }
-            Kind::Str => {
-                Ok(Ast::Str(tk.value.clone(), tk))
-            }
-            Kind::Id => {
-                Ok(Ast::Var(tk.value.clone(), tk))
-            }
-            Kind::LPar => {
-                let e = self.expression()?;
-                self.expect(&[Kind::RPar])?;
-                Ok(e)
-            }
-            Kind::LBracket => {
-                let v = self.list_of(Self::expression, Kind::Comma, Kind::RBracket)?;
-                Ok(Ast::Lst(v, tk))
-            }
-            _ => {
-                Err(Error::UnexpectedToken(tk, [Kind::Int, Kind::Str, Kind::Id, Kind::LPar].to_vec()))
+            Kind::Int | Kind::Float => self.number(tk),
+            Kind::Str => Ok(Ast::Str(tk.value.clone(), tk)),
+            Kind::InterpStart => self.interpolation(tk),
+            Kind::Nil => Ok(Ast::Nil(tk)),
+            Kind::Id => Ok(Ast::Var(tk.value.clone(), tk)),
+            Kind::LPar => self.parenthesized(),
+            Kind::LBracket => self.list(tk),
+            Kind::LBraces => self.map(tk),
+            _ => Err(Error::UnexpectedToken(tk, [Kind::Int, Kind::Float, Kind::Str, Kind::InterpStart, Kind::Nil, Kind::Id, Kind::LPar].to_vec())),
+        }
+    }
+
+    /// Literal number, from its token `tk`
+    fn number(&mut self, tk: Token) -> Result<Ast> {
+        if tk.kind == Kind::Int {
+            let n = Self::parse_int(&tk.value).ok_or_else(|| Error::ParsingError(tk.clone()))?;
+            Ok(Ast::Int(n, tk))
+        } else {
+            let n = tk.value.replace('_', "").parse::<f64>().map_err(|_| Error::ParsingError(tk.clone()))?;
+            Ok(Ast::Float(n, tk))
+        }
+    }
+
+    /// String with interpolations, after its first part `tk`
+    fn interpolation(&mut self, tk: Token) -> Result<Ast> {
+        let mut parts = vec![Ast::Str(tk.value.clone(), tk.clone())];
+        loop {
+            parts.push(self.expression()?);
+            let part = self.expect(&[Kind::InterpMid, Kind::InterpEnd])?;
+            let done = part.kind == Kind::InterpEnd;
+            parts.push(Ast::Str(part.value.clone(), part));
+            if done {
+                break;
             }
         }
+        Ok(Ast::Interpolation(tk, parts))
+    }
+
+    /// Expression inside parentheses, after the '('
+    fn parenthesized(&mut self) -> Result<Ast> {
+        let e = self.expression()?;
+        self.expect(&[Kind::RPar])?;
+        Ok(e)
+    }
+
+    /// Literal list, after its '[' (`tk`)
+    fn list(&mut self, tk: Token) -> Result<Ast> {
+        let v = self.list_of(Self::expression, Kind::Comma, Kind::RBracket)?;
+        Ok(Ast::Lst(v, tk))
     }
 
-    /// Function call (with '()') or indexing (with '[]').
+    /// Literal map, after its '{' (`tk`)
+    fn map(&mut self, tk: Token) -> Result<Ast> {
+        // at expression position braces are always a map, blocks only start statements
+        let v = self.list_of(Self::map_entry, Kind::Comma, Kind::RBraces)?;
+        Ok(Ast::Map(v, tk))
+    }
+
+    /// Entry of a map literal: Expression ':' Expression
+    fn map_entry(&mut self) -> Result<(Ast, Ast)> {
+        let key = self.expression()?;
+        self.expect(&[Kind::Colon])?;
+        let value = self.expression()?;
+        Ok((key, value))
+    }
+
+    /// Function call (with '()'), indexing (with '[]' or '.') or slicing (with '[:]').
     fn call_or_index(&mut self) -> Result<Ast> {
-        let mut lhs = self.atom()?;
-        while self.one_of(&[Kind::LBracket, Kind::LPar]) {
+        let lhs = self.atom()?;
+        if !self.one_of(&[Kind::LBracket, Kind::LPar, Kind::Dot]) {
+            return Ok(lhs);
+        }
+        let depth = self.depth;
+        let result = self.calls_or_indexes(lhs);
+        self.depth = depth;
+        result
+    }
+
+    /// Calls or indexes applied to `lhs`, each one level deeper
+    fn calls_or_indexes(&mut self, mut lhs: Ast) -> Result<Ast> {
+        while self.one_of(&[Kind::LBracket, Kind::LPar, Kind::Dot]) {
+            self.deeper()?;
             let tk = self.pop()?;
-            if tk.kind == Kind::LBracket {
-                let index = self.expression()?;
-                lhs = Ast::Index(tk, Box::new(lhs), Box::new(index));
-                self.expect(&[Kind::RBracket])?;
-            } else if tk.kind == Kind::LPar {
+            lhs = if tk.kind == Kind::LBracket {
+                self.index_or_slice(tk, lhs)?
+            } else if tk.kind == Kind::Dot {
+                // `a.b` is `a["b"]`
+                let name = self.expect(&[Kind::Id])?;
+                Ast::Index(tk, Box::new(lhs), Box::new(Ast::Str(name.value.clone(), name)))
+            } else {
                 let args = self.list_of(Self::expression, Kind::Comma, Kind::RPar)?;
-                lhs = Ast::Call(tk, Box::new(lhs), args);
-            }
+                Ast::Call(tk, Box::new(lhs), args)
+            };
         }
         Ok(lhs)
     }
 
-    /// Call_or_index [ '=' Expression ]
+    /// Indexing or slicing of `lhs`, after the '[' (`tk`)
+    fn index_or_slice(&mut self, tk: Token, lhs: Ast) -> Result<Ast> {
+        let index = self.expression()?;
+        let ast = if self.check(&[Kind::Colon]).is_some() {
+            let end = self.expression()?;
+            Ast::Slice(tk, Box::new(lhs), Box::new(index), Box::new(end))
+        } else {
+            Ast::Index(tk, Box::new(lhs), Box::new(index))
+        };
+        self.expect(&[Kind::RBracket])?;
+        Ok(ast)
+    }
+
+    /// { '-' | '!' } Unary | Call_or_index
+    fn unary(&mut self) -> Result<Ast> {
+        if self.one_of(&[Kind::Sub, Kind::Not]) {
+            self.prefix_operator()
+        } else {
+            self.call_or_index()
+        }
+    }
+
+    /// Unary operator applied to the following Unary
+    fn prefix_operator(&mut self) -> Result<Ast> {
+        let tk = self.pop()?;
+        let operand = self.nested(Self::unary)?;
+        Ok(Ast::Unary(tk, Box::new(operand)))
+    }
+
+    /// Unary [ '=' Expression ]
     fn assign(&mut self) -> Result<Ast> {
+        let lhs = self.unary()?;
+        if self.one_of(&[Kind::Assign]) {
+            self.assignment(lhs)
+        } else {
+            Ok(lhs)
+        }
+    }
+
+    /// Assignment of the following Expression to `lhs`
+    fn assignment(&mut self, mut lhs: Ast) -> Result<Ast> {
         // assignment is right associative
-        let mut lhs = self.call_or_index()?;
         while self.one_of(&[Kind::Assign]) {
             let tk = self.pop()?;
             let rhs = self.expression()?;
@@ -137,38 +315,129 @@ impl Parser {
         Ok(lhs)
     }
 
-    /// Assign [ { '*' | '/' | '%' } Assign ]*
-    fn factor(&mut self) -> Result<Ast> {
-        self.left_associative(&[Kind::Mul, Kind::Div, Kind::Mod], Self::assign)
+    /// Precedence level (index into `BINARY_OPERATORS`) of the current token, if a binary operator
+    fn binary_level(&self) -> Option<usize> {
+        let kind = self.source.front()?.kind;
+        BINARY_OPERATORS.iter().position(|operators| operators.contains(&kind))
+    }
+
+    /// Assign [ Operator Assign ]*, with operators of precedence `min_level` or higher.
+    ///
+    /// Operators of each level are left associative, and comparisons chain (see `comparison`).
+    /// Parsed by precedence climbing, instead of a function per level, so each nesting of an
+    /// expression takes a few stack frames.
+    fn binary(&mut self, min_level: usize) -> Result<Ast> {
+        let lhs = self.assign()?;
+        if self.binary_level().is_none_or(|level| level < min_level) {
+            return Ok(lhs);
+        }
+        let depth = self.depth;
+        let result = self.binary_operators(lhs, min_level);
+        self.depth = depth;
+        result
     }
 
-    /// Factor [ { '+' | '-' } Factor ]*
-    fn term(&mut self) -> Result<Ast> {
-        self.left_associative(&[Kind::Add, Kind::Sub], Self::factor)
+    /// Binary operators of precedence `min_level` or higher applied to `lhs`, each one level deeper
+    fn binary_operators(&mut self, mut lhs: Ast, min_level: usize) -> Result<Ast> {
+        while let Some(level) = self.binary_level().filter(|level| *level >= min_level) {
+            self.deeper()?;
+            lhs = if level == COMPARISON_LEVEL {
+                self.comparison(lhs)?
+            } else {
+                let tk = self.pop()?;
+                let rhs = self.binary(level + 1)?;
+                Ast::BinOp(tk, Box::new(lhs), Box::new(rhs))
+            };
+        }
+        Ok(lhs)
     }
 
-    /// Comparison operations.
-    fn cmp(&mut self) -> Result<Ast> {
-        self.left_associative(&[Kind::Lt, Kind::Lte, Kind::Gt, Kind::Gte, Kind::Eq, Kind::NotEq], Self::term)
+    /// Comparison operations, with `lhs` as the first operand.
+    ///
+    /// Comparisons chain as in Python: `a < b < c` is `a < b && b < c`, not `(a < b) < c`.
+    fn comparison(&mut self, lhs: Ast) -> Result<Ast> {
+        let mut operands = vec![lhs];
+        let mut operators = vec![];
+        while self.binary_level() == Some(COMPARISON_LEVEL) {
+            operators.push(self.pop()?);
+            operands.push(self.binary(COMPARISON_LEVEL + 1)?);
+        }
+
+        if operators.len() > 1 {
+            return Ok(Ast::Chain(operators, operands));
+        }
+        let mut operands = operands.into_iter();
+        match (operators.pop(), operands.next(), operands.next()) {
+            (Some(tk), Some(lhs), Some(rhs)) => Ok(Ast::BinOp(tk, Box::new(lhs), Box::new(rhs))),
+            (_, Some(operand), _) => Ok(operand),
+            _ => Err(Error::UnexpectedEOF),
+        }
     }
 
     /// Expression **always** leave something on the stack.
     fn expression(&mut self) -> Result<Ast> {
-        self.cmp()
+        self.nested(|parser| parser.binary(0))
     }
 
     /// A `while` loop
     fn while_loop(&mut self) -> Result<Ast> {
         let tk = self.expect(&[Kind::While])?;
-        let cmp = self.expression()?;
+        let cmp = if let Some(let_tk) = self.check(&[Kind::Let]) {
+            // `while let <target> = <expr>` loops while the assigned value is not nil
+            let assignment = self.expression()?;
+            match &assignment {
+                Ast::BinOp(op, _, _) if op.kind == Kind::Assign => Ast::NotNil(let_tk, Box::new(assignment)),
+                _ => return Err(Error::InvalidAssignmentTarget(assignment)),
+            }
+        } else {
+            self.expression()?
+        };
         let body = self.block()?;
         Ok(Ast::Loop(tk, None, Some(Box::new(cmp)), Box::new(body), None))
     }
 
+    /// A C-style `for (init; cond; update) { body }` loop, each clause may be empty.
+    ///
+    /// Without a condition, the loop only ends with a `break`.
+    fn for_loop(&mut self) -> Result<Ast> {
+        let tk = self.expect(&[Kind::For])?;
+        self.expect(&[Kind::LPar])?;
+
+        // init and update are executed as statements, nothing is left on the stack
+        let init = if self.one_of(&[Kind::Let]) {
+            Some(Box::new(self.declaration()?))
+        } else if self.check(&[Kind::Semi]).is_some() {
+            None
+        } else {
+            let e = self.expression()?;
+            self.expect(&[Kind::Semi])?;
+            Some(Box::new(Ast::Sttm(Box::new(e))))
+        };
+
+        let cmp = if self.check(&[Kind::Semi]).is_some() {
+            None
+        } else {
+            let e = self.expression()?;
+            self.expect(&[Kind::Semi])?;
+            Some(Box::new(e))
+        };
+
+        let update = if self.check(&[Kind::RPar]).is_some() {
+            None
+        } else {
+            let e = self.expression()?;
+            self.expect(&[Kind::RPar])?;
+            Some(Box::new(Ast::Sttm(Box::new(e))))
+        };
+
+        let body = self.block()?;
+        Ok(Ast::Loop(tk, init, cmp, Box::new(body), update))
+    }
+
     /// The `else` part of a `if_else` can be either a block or another `if`
     fn block_or_if(&mut self) -> Result<Ast> {
         if self.one_of(&[Kind::If]) {
-            self.if_else()
+            self.nested(Self::if_else)
         } else if self.one_of(&[Kind::LBraces]) {
             self.block()
         } else {
@@ -189,12 +458,41 @@ impl Parser {
         Ok(Ast::IfElse(tk, conditional, if_true, if_false))
     }
 
+    /// Match: 'match' Expression '{' { Expression { ',' Expression } '=>' Block } [ '_' '=>' Block ] '}'
+    ///
+    /// The default arm, with the pattern `_`, must be the last one.
+    fn match_arms(&mut self) -> Result<Ast> {
+        let tk = self.expect(&[Kind::Match])?;
+        let subject = self.expression()?;
+        self.expect(&[Kind::LBraces])?;
+        let mut arms = vec![];
+        let mut default = None;
+        while self.check(&[Kind::RBraces]).is_none() {
+            if default.is_some() {
+                return Err(Error::UnexpectedToken(self.pop()?, vec![Kind::RBraces]));
+            }
+            if self.source.front().is_some_and(|tk| tk.kind == Kind::Id && tk.value == "_") {
+                self.pop()?;
+                self.expect(&[Kind::Arrow])?;
+                default = Some(Box::new(self.block()?));
+                continue;
+            }
+            let mut patterns = vec![self.expression()?];
+            while self.check(&[Kind::Comma]).is_some() {
+                patterns.push(self.expression()?);
+            }
+            self.expect(&[Kind::Arrow])?;
+            arms.push((patterns, self.block()?));
+        }
+        Ok(Ast::Match(tk, Box::new(subject), arms, default))
+    }
+
     /// Read a list of `previous` separated by `separator` and terminated by `terminator`.
     /// A trailing `separator` is allowed.
-    fn list_of<F: Fn(&mut Self) -> Result<Ast>>(&mut self, previous: F, separator: Kind, terminator: Kind) -> Result<Vec<Ast>> {
+    fn list_of<T, F: Fn(&mut Self) -> Result<T>>(&mut self, previous: F, separator: Kind, terminator: Kind) -> Result<Vec<T>> {
         let mut v = vec![];
 
-        while !self.check(&[terminator]).is_some() {
+        while self.check(&[terminator]).is_none() {
             v.push(previous(self)?);
             let tk = self.expect(&[separator, terminator])?;
             if tk.kind == terminator {
@@ -209,20 +507,86 @@ impl Parser {
     fn block(&mut self) -> Result<Ast> {
         let tk = self.expect(&[Kind::LBraces])?;
         let mut v = vec![];
-        while !self.check(&[Kind::RBraces]).is_some() {
+        while self.check(&[Kind::RBraces]).is_none() {
             v.push(self.statement()?);
         }
         Ok(Ast::Block(tk, v))
     }
 
+    /// Variable declaration: 'let' Id '=' Expression ';'
+    fn declaration(&mut self) -> Result<Ast> {
+        let tk = self.expect(&[Kind::Let])?;
+        let name = self.expect(&[Kind::Id])?;
+        self.expect(&[Kind::Assign])?;
+        let value = self.expression()?;
+        self.expect(&[Kind::Semi])?;
+        Ok(Ast::Let(tk, name.value, Box::new(value)))
+    }
+
+    /// Function definition: 'fun' Id '(' [ Id { ',' Id } ] ')' Block
+    fn function(&mut self) -> Result<Ast> {
+        let tk = self.expect(&[Kind::Fun])?;
+        let name = self.expect(&[Kind::Id])?;
+        self.expect(&[Kind::LPar])?;
+        let params = self.list_of(|parser| parser.expect(&[Kind::Id]), Kind::Comma, Kind::RPar)?;
+        let body = self.block()?;
+        Ok(Ast::Fun(tk, name, params, Box::new(body)))
+    }
+
     /// Statement execute and leave nothing on the stack
     fn statement(&mut self) -> Result<Ast> {
-        if self.one_of(&[Kind::While]) {
+        self.nested(Self::simple_statement)
+    }
+
+    /// Statement, without accounting for nesting
+    fn simple_statement(&mut self) -> Result<Ast> {
+        if self.one_of(&[Kind::Let]) {
+            self.declaration()
+        } else if self.one_of(&[Kind::While]) {
             self.while_loop()
+        } else if self.one_of(&[Kind::For]) {
+            self.for_loop()
         } else if self.one_of(&[Kind::If]) {
             self.if_else()
+        } else if self.one_of(&[Kind::Match]) {
+            self.match_arms()
+        } else if self.one_of(&[Kind::Fun]) {
+            self.function()
+        } else if let Some(tk) = self.check(&[Kind::Return]) {
+            let value = if self.check(&[Kind::Semi]).is_some() {
+                None
+            } else {
+                let value = self.expression()?;
+                self.expect(&[Kind::Semi])?;
+                Some(Box::new(value))
+            };
+            Ok(Ast::Return(tk, value))
+        } else if let Some(tk) = self.check(&[Kind::Yield]) {
+            let value = self.expression()?;
+            self.expect(&[Kind::Semi])?;
+            Ok(Ast::Yield(tk, Box::new(value)))
         } else if self.one_of(&[Kind::LBraces]) {
             self.block()
+        } else if self.one_of(&[Kind::Id]) && self.source.get(1).map(|tk| tk.kind) == Some(Kind::Colon) {
+            // labeled loop: Id ':' ( While | For )
+            let label = self.pop()?;
+            self.pop()?;
+            let body = if self.one_of(&[Kind::While]) {
+                self.while_loop()?
+            } else if self.one_of(&[Kind::For]) {
+                self.for_loop()?
+            } else {
+                return Err(Error::UnexpectedToken(self.pop()?, vec![Kind::While, Kind::For]));
+            };
+            Ok(Ast::Labeled(label, Box::new(body)))
+        } else if let Some(tk) = self.check(&[Kind::Break, Kind::Continue]) {
+            let label = self.check(&[Kind::Id]);
+            self.expect(&[Kind::Semi])?;
+            if tk.kind == Kind::Break {
+                Ok(Ast::Break(tk, label))
+            } else {
+                Ok(Ast::Continue(tk, label))
+            }
         } else {
             // wrap an expression, so a `pop` is inserted
             let e = self.expression()?;
@@ -244,4 +608,39 @@ impl Parser {
             Ok(Some(self.statement()?))
         }
     }
+
+    /// Parse all remaining statements, recovering from errors to report as many as possible.
+    ///
+    /// After an error, tokens are skipped up to the end of the statement (a `;` or `}`)
+    /// and parsing resumes from there.  At least one token is consumed on each error, so
+    /// this always terminates.  Errors after the first may be a consequence of it.
+    pub fn parse_all(&mut self) -> (Vec<Ast>, Vec<Error>) {
+        let mut asts = vec![];
+        let mut errors = vec![];
+        while !self.is_empty() {
+            let before = self.source.len();
+            match self.statement() {
+                Ok(ast) => asts.push(ast),
+                Err(err) => {
+                    errors.push(err);
+                    self.synchronize(before);
+                }
+            }
+        }
+        (asts, errors)
+    }
+
+    /// Skip tokens up to (and including) the end of the statement where an error was
+    /// found, when there were `before` tokens left at its start.
+    fn synchronize(&mut self, before: usize) {
+        if self.source.len() == before {
+            self.previous = None;
+            let _ = self.pop();
+        }
+        while !matches!(self.previous, Some(Kind::Semi) | Some(Kind::RBraces)) {
+            if self.pop().is_err() {
+                break;
+            }
+        }
+    }
 }
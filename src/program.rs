@@ -0,0 +1,54 @@
+use std::ops::Range;
+use crate::{
+    opcodes::Op,
+    diagnostic::Diagnostic,
+};
+
+/// A compiled program, as output by the `Compiler` and executed by the `VM`.
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    /// Sequence of opcodes to execute
+    pub code: Vec<Op>,
+
+    /// Source range that originated each opcode (same length as `code`)
+    pub source_map: Vec<Range<usize>>,
+
+    /// Non-fatal findings of the compiler
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Program {
+    /// Create a program from bare `code`, without any source information.
+    pub fn new(code: Vec<Op>) -> Program {
+        let source_map = vec![0 .. 0; code.len()];
+        Program { code, source_map, diagnostics: vec![] }
+    }
+
+    /// Return the source range that originated the opcode at `pc`, if known.
+    pub fn location(&self, pc: usize) -> Option<Range<usize>> {
+        self.source_map.get(pc).cloned()
+    }
+}
+
+/// A function defined by the script (with `fun`), compiled into its own code.
+///
+/// When called, the stack from the frame pointer holds the function itself (replaced by
+/// the value returned), the `arity` arguments and then `locals` slots, all addressed by
+/// `Op::LoadL` and `Op::StoreL` relative to the frame pointer.
+#[derive(Debug, Clone)]
+pub struct Function {
+    /// Name given by the definition
+    pub name: String,
+
+    /// Number of parameters
+    pub arity: usize,
+
+    /// Number of local variables, besides the parameters
+    pub locals: usize,
+
+    /// Calling it returns a generator, that runs the code on each `resume` (it has a `yield`)
+    pub generator: bool,
+
+    /// Code of the body, always ending in an `Op::Return`
+    pub program: Program,
+}
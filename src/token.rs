@@ -3,27 +3,36 @@ use std::ops::Range;
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum Kind {
     Int,
+    Float,
     Str,
+    // parts of a string with interpolated `${expr}`s: before the first, between two, after the last
+    InterpStart, InterpMid, InterpEnd,
+    Nil,
     Id,
 
-    If, Else,
+    If, Else, Match,
     While,
     For,
-    Fun,
+    Break, Continue,
+    Fun, Return, Yield,
+    Let,
 
     Add, Sub,
     Mul, Div, Mod,
+    Shl, Shr,
 
     Lt, Lte,
     Gt, Gte,
-    Assign, Eq,
+    Assign, Eq, Arrow,
     Not, NotEq,
+    And, Or,
 
     LPar, RPar,
+    Dot,
     LBraces, RBraces,
     LBracket, RBracket,
 
-    Semi, Comma,
+    Semi, Comma, Colon,
 }
 
 #[derive(Debug, Clone)]
@@ -31,4 +40,8 @@ pub struct Token {
     pub kind: Kind,
     pub value: String,
     pub at: Range<usize>,
+    /// Line of the first char of the token, starting at 1
+    pub line: usize,
+    /// Column of the first char of the token, starting at 1
+    pub col: usize,
 }
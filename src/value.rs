@@ -1,24 +1,184 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+#[cfg(feature = "graphemes")]
+use unicode_segmentation::UnicodeSegmentation;
+
 use crate::{
     vm::{VM, HeapPtr},
     errors::{Error, Result},
     opcodes::Op,
+    program::Function,
 };
 
+/// Keys of a map, only integers and strings can be used as keys.
+///
+/// Keys are ordered with all integers before all strings, which is the order
+/// maps are formatted in (and `keys()` returns).
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Key {
+    Int(i64),
+    Str(String),
+}
+
+impl Key {
+    /// Return the key as a `Value`
+    pub fn to_value(&self) -> Value {
+        match self {
+            Key::Int(n) => Value::Int(*n),
+            Key::Str(s) => Value::Str(s.clone()),
+        }
+    }
+}
+
 /// Values supported by the script and its VM
 #[derive(Debug, Clone)]
 pub enum Value {
+    /// Absence of a value
+    Nil,
     Int(i64),
+    Float(f64),
     Str(String),
     List(Vec<HeapPtr>),
+    Map(HashMap<Key, HeapPtr>),
+
+    /// Weak reference, not followed by the garbage collector.
+    /// Becomes `None` when the referenced value is collected.
+    Weak(Option<HeapPtr>),
+
+    /// Function defined by the script, shared by all values of it
+    Func(Arc<Function>),
+
+    /// Call to a generator function, suspended between `resume`s
+    Generator(Generator),
+}
+
+/// State of a call to a function that `yield`s, returned by calling it and run by `resume`
+#[derive(Debug, Clone)]
+pub struct Generator {
+    /// Function called
+    pub function: Arc<Function>,
+
+    /// Position to continue from in the code of the function
+    pub pc: usize,
+
+    /// The stack slots of the call (the function, its arguments and locals), while suspended;
+    /// while running, these are on the stack
+    pub slots: Vec<HeapPtr>,
+
+    /// Being run by a `resume`
+    pub running: bool,
+
+    /// Returned, so there is nothing more to run
+    pub done: bool,
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        Value::Int(n)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        Value::Float(n)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Str(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::Str(s)
+    }
 }
 
 impl Value {
+    /// Return the integer value, if this is an integer
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Value::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// Return this value as a map key, if it is an integer or a string
+    pub fn as_key(&self) -> Option<Key> {
+        match self {
+            Value::Int(n) => Some(Key::Int(*n)),
+            Value::Str(s) => Some(Key::Str(s.clone())),
+            _ => None,
+        }
+    }
+
+    /// Return the entries of a map sorted by key, or `None` if this is not a map
+    pub fn sorted_entries(&self) -> Option<Vec<(&Key, HeapPtr)>> {
+        match self {
+            Value::Map(map) => {
+                let mut entries: Vec<_> = map.iter().map(|(k, v)| (k, *v)).collect();
+                entries.sort_by(|a, b| a.0.cmp(b.0));
+                Some(entries)
+            }
+            _ => None,
+        }
+    }
+
+    /// Return the floating-point value, if this is a float
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Float(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// Return the value as a float, if a number (integers are converted)
+    pub fn to_f64(&self) -> Option<f64> {
+        match self {
+            Value::Int(n) => Some(*n as f64),
+            Value::Float(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// Return both `self` and `other` as floats, if they are numbers and at least one
+    /// is a float (mixed arithmetic promotes integers to floats)
+    fn as_float_pair(&self, other: &Value) -> Option<(f64, f64)> {
+        match (self, other) {
+            (Value::Float(a), Value::Float(b)) => Some((*a, *b)),
+            (Value::Int(a), Value::Float(b)) => Some((*a as f64, *b)),
+            (Value::Float(a), Value::Int(b)) => Some((*a, *b as f64)),
+            _ => None,
+        }
+    }
+
+    /// Return the string value, if this is a string
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
     /// Push into `roots` all HeapPtr accessible from self
     pub fn mark(&self, roots: &mut Vec<HeapPtr>) {
         match self {
             Value::List(values) => {
                 values.iter().for_each(|ptr| roots.push(*ptr));
             }
+            Value::Map(map) => {
+                // keys are not heap values, only the values must be followed
+                map.values().for_each(|ptr| roots.push(*ptr));
+            }
+            Value::Weak(_) => {
+                // weak references are not followed, that is the whole point
+            }
+            Value::Generator(generator) => {
+                generator.slots.iter().for_each(|ptr| roots.push(*ptr));
+            }
             _ => {
                 // no pointers inside, do nothing
             }
@@ -26,21 +186,44 @@ impl Value {
     }
 
     /// `true` if this value presents a falsehood
+    ///
+    /// Falsy values are `nil`, `0`, `0.0`, the empty string, list and map,
+    /// everything else is truthy (including NaN).  All conditionals go through this.
     pub fn is_false(&self) -> bool {
         match self {
-            Value::Int(n) if *n == 0 => true,
-            _ => false
+            Value::Nil => true,
+            Value::Int(n) => *n == 0,
+            Value::Float(n) => *n == 0.0,
+            Value::Str(s) => s.is_empty(),
+            Value::List(lst) => lst.is_empty(),
+            Value::Map(map) => map.is_empty(),
+            Value::Weak(_) => false,
+            Value::Func(_) => false,
+            Value::Generator(_) => false,
         }
     }
 
+    /// `true` if this value presents a truth (the opposite of `is_false`)
+    pub fn is_truthy(&self) -> bool {
+        !self.is_false()
+    }
+
     /// Pretty formatting of values
     pub fn fmt(&self, vm: &VM, depth: usize) -> Result<String> {
         // XXX: perhaps move inside VM?
         match self {
+            Value::Nil => Ok("nil".to_string()),
+            Value::Weak(Some(_)) => Ok("<weak>".to_string()),
+            Value::Weak(None) => Ok("<weak collected>".to_string()),
+            Value::Func(function) => Ok(format!("<function {}>", function.name)),
+            Value::Generator(generator) => Ok(format!("<generator {}>", generator.function.name)),
             Value::Int(n) => Ok(n.to_string()),
+            // debug formatting always shows a decimal point (or exponent), so `1.0` is not
+            // confused with the integer `1`
+            Value::Float(n) => Ok(format!("{:?}", n)),
             Value::Str(s) => Ok(s.clone()),
             Value::List(lst) => {
-                if depth > 3 {
+                if depth > vm.print_depth() {
                     // avoid infinite recursion...
                     Ok("[...]".to_string())
                 } else {
@@ -58,15 +241,75 @@ impl Value {
                     Ok(s)
                 }
             }
+            Value::Map(_) => {
+                if depth > vm.print_depth() {
+                    Ok("{...}".to_string())
+                } else {
+                    // sorted by key, so the output does not depend on the hash order
+                    let mut s = "{".to_string();
+                    for (i, (key, ptr)) in self.sorted_entries().unwrap_or_default().into_iter().enumerate() {
+                        if i > 0 {
+                            s += ", ";
+                        }
+                        s += &key.to_value().fmt(vm, depth + 1)?;
+                        s += ": ";
+                        s += &vm.get(ptr)?.fmt(vm, depth + 1)?;
+                    }
+                    s += "}";
+                    Ok(s)
+                }
+            }
+        }
+    }
+
+    /// Pretty formatting of values, with one list element per line.
+    ///
+    /// `indent` is the nesting level of `self`; nested elements are indented by two
+    /// spaces per level.  Scalars are formatted as with `fmt`.
+    pub fn fmt_pretty(&self, vm: &VM, indent: usize) -> Result<String> {
+        match self {
+            Value::List(lst) if !lst.is_empty() && indent <= vm.print_depth() => {
+                let padding = "  ".repeat(indent + 1);
+                let mut s = "[\n".to_string();
+                for (i, ptr) in lst.iter().enumerate() {
+                    s += &padding;
+                    s += &vm.get(*ptr)?.fmt_pretty(vm, indent + 1)?;
+                    s += if i + 1 < lst.len() { ",\n" } else { "\n" };
+                }
+                s += &"  ".repeat(indent);
+                s += "]";
+                Ok(s)
+            }
+            Value::Map(map) if !map.is_empty() && indent <= vm.print_depth() => {
+                let padding = "  ".repeat(indent + 1);
+                let mut s = "{\n".to_string();
+                for (i, (key, ptr)) in self.sorted_entries().unwrap_or_default().into_iter().enumerate() {
+                    s += &padding;
+                    s += &key.to_value().fmt(vm, indent + 1)?;
+                    s += ": ";
+                    s += &vm.get(ptr)?.fmt_pretty(vm, indent + 1)?;
+                    s += if i + 1 < map.len() { ",\n" } else { "\n" };
+                }
+                s += &"  ".repeat(indent);
+                s += "}";
+                Ok(s)
+            }
+            _ => self.fmt(vm, indent),
         }
     }
 
     /// Return a display name for the type of this value
     pub fn type_name(&self) -> String {
         match self {
+            Value::Nil => "nil".to_string(),
+            Value::Weak(_) => "weak reference".to_string(),
+            Value::Func(_) => "function".to_string(),
+            Value::Generator(_) => "generator".to_string(),
             Value::Int(_) => "integer".to_string(),
+            Value::Float(_) => "float".to_string(),
             Value::Str(_) => "string".to_string(),
             Value::List(_) => "list".to_string(),
+            Value::Map(_) => "map".to_string(),
         }
     }
 
@@ -74,9 +317,25 @@ impl Value {
     /// by the `length` built-in function
     pub fn length(&self) -> usize {
         match self {
+            Value::Nil => 0,
+            Value::Weak(_) => 0,
+            Value::Func(_) => 0,
+            Value::Generator(_) => 0,
             Value::Int(_) => 0,
+            Value::Float(_) => 0,
             Value::Str(s) => s.chars().count(),
             Value::List(lst) => lst.len(),
+            Value::Map(map) => map.len(),
+        }
+    }
+
+    /// As `length`, but counting strings by grapheme clusters (what a reader perceives as
+    /// one character, e.g. an emoji with a skin tone modifier) instead of `char`s.
+    #[cfg(feature = "graphemes")]
+    pub fn grapheme_length(&self) -> usize {
+        match self {
+            Value::Str(s) => s.graphemes(true).count(),
+            other => other.length(),
         }
     }
 
@@ -87,9 +346,69 @@ impl Value {
     /// * 0 if `self` == `other`
     /// * 1 if `self` > `other`
     ///
-    /// Return `Result<i64>` instead of `Result<Value>` to make recursion easier...
+    /// Integers and floats compare numerically.  NaN is ordered after every other number
+    /// (and the same as another NaN), so sorting and `<` never fail; `==` is not based on
+    /// this, and NaN is never equal to anything.
+    ///
+    /// Lists compare element by element, and a pair of lists already being compared is
+    /// the same (so cyclic lists compare equal instead of recursing forever).
     pub fn cmp(&self, vm: &VM, other: &Value) -> Result<i64> {
+        // the lists being compared, with the position in each and their pointers, are kept
+        // in an explicit stack, as lists built at run time may be nested deeper than the
+        // native stack allows
+        let mut frames = match (self, other) {
+            (Value::List(a), Value::List(b)) => vec![(a, b, 0, None)],
+            _ => return self.cmp_scalar(other),
+        };
+        let mut comparing = HashSet::new();
+        while let Some((a, b, i, pair)) = frames.last_mut() {
+            if *i == a.len().min(b.len()) {
+                let c = a.len().cmp(&b.len()) as i64;
+                if let Some(pair) = pair {
+                    comparing.remove(pair);
+                }
+                frames.pop();
+                if c != 0 {
+                    return Ok(c)
+                }
+                continue
+            }
+            let (aptr, bptr) = (a[*i], b[*i]);
+            *i += 1;
+            if aptr == bptr || comparing.contains(&(aptr, bptr)) {
+                continue
+            }
+            match (vm.get(aptr)?, vm.get(bptr)?) {
+                (Value::List(a), Value::List(b)) => {
+                    comparing.insert((aptr, bptr));
+                    frames.push((a, b, 0, Some((aptr, bptr))));
+                }
+                (a, b) => {
+                    let c = a.cmp_scalar(b)?;
+                    if c != 0 {
+                        return Ok(c)
+                    }
+                }
+            }
+        }
+        Ok(0)
+    }
+
+    /// As `cmp`, for values that are not both lists.
+    fn cmp_scalar(&self, other: &Value) -> Result<i64> {
+        if let Some((a, b)) = self.as_float_pair(other) {
+            return Ok(match a.partial_cmp(&b) {
+                Some(std::cmp::Ordering::Less) => -1,
+                Some(std::cmp::Ordering::Equal) => 0,
+                Some(std::cmp::Ordering::Greater) => 1,
+                None => a.is_nan() as i64 - b.is_nan() as i64,
+            })
+        }
+
         match (self, other) {
+            (Value::Nil, Value::Nil) => {
+                Ok(0)
+            }
             (Value::Int(a), Value::Int(b)) => {
                 Ok(if a < b { -1 }
                 else if a > b { 1 }
@@ -100,55 +419,184 @@ impl Value {
                 else if a > b { 1 }
                 else { 0 })
             }
+            _ => {
+                Err(Error::IncompatibleOperands(Op::Lt, self.clone(), other.clone()))
+            }
+        }
+    }
+
+    /// The smaller of `self` and `other` according to `cmp`, `self` if they are the same.
+    pub fn min<'a>(&'a self, vm: &VM, other: &'a Value) -> Result<&'a Value> {
+        Ok(if other.cmp(vm, self)? < 0 { other } else { self })
+    }
+
+    /// The larger of `self` and `other` according to `cmp`, `self` if they are the same.
+    pub fn max<'a>(&'a self, vm: &VM, other: &'a Value) -> Result<&'a Value> {
+        Ok(if other.cmp(vm, self)? > 0 { other } else { self })
+    }
+
+    /// Test `self` and `other` for equality executing under `vm`.
+    ///
+    /// This is the path used by `==` and `!=`, kept separate from `cmp` (ordering) so
+    /// that numeric equivalence between different numeric variants can be handled here
+    /// without imposing it on ordering.  Lists and maps are compared structurally with `deep_eq`,
+    /// weak references are equal if they point to the same entry, functions only to
+    /// themselves, generators never (but `g == g` is, as the same entry), and values of different types (including `nil` and anything else) are never equal, even if they
+    /// cannot be ordered.
+    pub fn equals(&self, vm: &VM, other: &Value) -> Result<bool> {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => {
+                Ok(a == b)
+            }
+            (Value::Int(_), Value::Float(_)) | (Value::Float(_), Value::Int(_)) | (Value::Float(_), Value::Float(_)) => {
+                Ok(self.numeric_eq(other))
+            }
+            (Value::List(_), Value::List(_)) | (Value::Map(_), Value::Map(_)) => {
+                self.deep_eq(vm, other)
+            }
+            (Value::Str(a), Value::Str(b)) => {
+                Ok(a == b)
+            }
+            (Value::Weak(a), Value::Weak(b)) => {
+                // collected references are cleared, so are all equal
+                Ok(a == b)
+            }
+            (Value::Func(a), Value::Func(b)) => {
+                // functions are only equal to themselves
+                Ok(Arc::ptr_eq(a, b))
+            }
+            _ => {
+                Ok(matches!((self, other), (Value::Nil, Value::Nil)))
+            }
+        }
+    }
+
+    /// Exact equality of numbers, `false` if either is not a number.
+    ///
+    /// An integer and a float are only equal if the float is integral and, as an `i64`,
+    /// the same integer: converting the integer to `f64` instead would round large
+    /// integers (`9007199254740993 == 9007199254740992.0` must be false).
+    fn numeric_eq(&self, other: &Value) -> bool {
+        // 2^63, the first float past the largest i64 (which itself is not representable)
+        const I64_END: f64 = 9223372036854775808.0;
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Float(a), Value::Float(b)) => a == b,
+            (Value::Int(n), Value::Float(f)) | (Value::Float(f), Value::Int(n)) => {
+                f.fract() == 0.0 && *f >= -I64_END && *f < I64_END && *f as i64 == *n
+            }
+            _ => false,
+        }
+    }
+
+    /// Structural equality of `self` and `other` executing under `vm`.
+    ///
+    /// Lists are compared element-by-element, and maps key-by-key, dereferencing pointers through `vm`.
+    /// Values of different types are simply not equal, so this only fails on invalid
+    /// memory accesses.  Cyclic lists are handled (a pair of lists already compared
+    /// is assumed equal).
+    pub fn deep_eq(&self, vm: &VM, other: &Value) -> Result<bool> {
+        // pairs of elements still to compare are kept in an explicit stack, as lists
+        // built at run time may be nested deeper than the native stack allows
+        let mut pending = vec![];
+        let mut seen = HashSet::new();
+        if !self.shallow_eq(other, &mut pending) {
+            return Ok(false)
+        }
+        while let Some((aptr, bptr)) = pending.pop() {
+            let a = vm.get(aptr)?;
+            // the same entry is equal to itself, except NaN, as in `x == x`
+            if (aptr == bptr && !matches!(a, Value::Float(n) if n.is_nan())) || !seen.insert((aptr, bptr)) {
+                continue
+            }
+            if !a.shallow_eq(vm.get(bptr)?, &mut pending) {
+                return Ok(false)
+            }
+        }
+        Ok(true)
+    }
+
+    /// As `deep_eq`, but pushing the pairs of elements of containers to `pending` instead
+    /// of comparing them
+    fn shallow_eq(&self, other: &Value, pending: &mut Vec<(HeapPtr, HeapPtr)>) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Weak(a), Value::Weak(b)) => a == b,
+            (Value::Func(a), Value::Func(b)) => Arc::ptr_eq(a, b),
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Int(_), Value::Float(_)) | (Value::Float(_), Value::Int(_)) | (Value::Float(_), Value::Float(_)) => {
+                self.numeric_eq(other)
+            }
+            (Value::Str(a), Value::Str(b)) => a == b,
             (Value::List(a), Value::List(b)) => {
-                // only dereference pointers as long as necessary
-                // there should be a nicer built-in API for this...
-                let n = a.len().min(b.len());
-                let mut i = 0;
-                while i < n {
-                    let av = vm.get(a[i])?;
-                    let bv = vm.get(b[i])?;
-                    let c = av.cmp(vm, bv)?;
-                    if c != 0 {
-                        return Ok(c)
-                    }
-                    i += 1;
+                if a.len() != b.len() {
+                    return false
                 }
-                if a.len() < b.len() { Ok(-1) }
-                else if a.len() > b.len() { Ok(1) }
-                else { Ok(0) }
+                pending.extend(a.iter().copied().zip(b.iter().copied()));
+                true
             }
-            _ => {
-                Err(Error::IncompatibleOperands(Op::Lt, self.clone(), other.clone()))
+            (Value::Map(a), Value::Map(b)) => {
+                if a.len() != b.len() || a.keys().any(|k| !b.contains_key(k)) {
+                    return false
+                }
+                pending.extend(a.iter().map(|(k, aptr)| (*aptr, b[k])));
+                true
             }
+            _ => false,
         }
     }
 
     /// Add `self` to `other`
+    ///
+    /// For all arithmetic, mixing an integer with a float gives a float.  Integer results
+    /// that do not fit an `i64` fail with `IntegerOverflow`, instead of wrapping around.
+    /// Lists are concatenated, and maps merged, with keys of `other` taking precedence.
     pub fn add(&self, other: &Value) -> Result<Value> {
+        if let Some((a, b)) = self.as_float_pair(other) {
+            return Ok(Value::Float(a + b))
+        }
+
         match (self, other) {
             (Value::Int(a), Value::Int(b)) => {
-                Ok(Value::Int(a + b))
+                a.checked_add(*b).map(Value::Int).ok_or(Error::IntegerOverflow)
             }
             (Value::Str(a), Value::Str(b)) => {
                 Ok(Value::Str(a.to_owned() + b))
             }
             (Value::List(a), Value::List(b)) => {
                 let mut c = a.clone();
-                c.extend_from_slice(&b);
+                c.extend_from_slice(b);
                 Ok(Value::List(c))
             }
+            (Value::Map(a), Value::Map(b)) => {
+                let mut c = a.clone();
+                c.extend(b.iter().map(|(k, v)| (k.clone(), *v)));
+                Ok(Value::Map(c))
+            }
             _ => {
                 Err(Error::IncompatibleOperands(Op::Add, self.clone(), other.clone()))
             }
         }
     }
 
+    /// Arithmetic negation of `self`
+    pub fn neg(&self) -> Result<Value> {
+        match self {
+            Value::Int(a) => a.checked_neg().map(Value::Int).ok_or(Error::IntegerOverflow),
+            Value::Float(a) => Ok(Value::Float(-a)),
+            _ => Err(Error::UnexpectedType("number".to_string(), self.clone())),
+        }
+    }
+
     /// Subtract `other` from `self`
     pub fn sub(&self, other: &Value) -> Result<Value> {
+        if let Some((a, b)) = self.as_float_pair(other) {
+            return Ok(Value::Float(a - b))
+        }
+
         match (self, other) {
             (Value::Int(a), Value::Int(b)) => {
-                Ok(Value::Int(a - b))
+                a.checked_sub(*b).map(Value::Int).ok_or(Error::IntegerOverflow)
             }
             _ => {
                 Err(Error::IncompatibleOperands(Op::Sub, self.clone(), other.clone()))
@@ -157,16 +605,30 @@ impl Value {
     }
 
     /// Multiply `self` and `other`
-    pub fn mul(&self, other: &Value) -> Result<Value> {
+    ///
+    /// Repeating a string or list fails if the result would be longer than `max_length`
+    /// (bytes for strings, elements for lists).
+    pub fn mul(&self, other: &Value, max_length: usize) -> Result<Value> {
+        let check = |len: usize, n: i64| -> Result<usize> {
+            match len.checked_mul(n as usize) {
+                Some(total) if total <= max_length => Ok(n as usize),
+                _ => Err(Error::AllocationTooLarge(len.saturating_mul(n as usize))),
+            }
+        };
+
+        if let Some((a, b)) = self.as_float_pair(other) {
+            return Ok(Value::Float(a * b))
+        }
+
         match (self, other) {
             (Value::Int(a), Value::Int(b)) => {
-                Ok(Value::Int(a * b))
+                a.checked_mul(*b).map(Value::Int).ok_or(Error::IntegerOverflow)
             }
             (Value::Str(a), Value::Int(b)) if *b >= 0 => {
-                Ok(Value::Str(a.repeat(*b as usize)))
+                Ok(Value::Str(a.repeat(check(a.len(), *b)?)))
             }
             (Value::List(a), Value::Int(b)) if *b >= 0 => {
-                Ok(Value::List(a.repeat(*b as usize)))
+                Ok(Value::List(a.repeat(check(a.len(), *b)?)))
             }
             _ => {
                 Err(Error::IncompatibleOperands(Op::Mul, self.clone(), other.clone()))
@@ -176,9 +638,17 @@ impl Value {
 
     /// Divide `self` by `other`
     pub fn div(&self, other: &Value) -> Result<Value> {
+        if let Some((a, b)) = self.as_float_pair(other) {
+            return Ok(Value::Float(a / b))
+        }
+
         match (self, other) {
+            (Value::Int(_), Value::Int(0)) => {
+                Err(Error::DivisionByZero)
+            }
             (Value::Int(a), Value::Int(b)) => {
-                Ok(Value::Int(a / b))
+                // only `i64::MIN / -1` overflows
+                a.checked_div(*b).map(Value::Int).ok_or(Error::IntegerOverflow)
             }
             _ => {
                 Err(Error::IncompatibleOperands(Op::Div, self.clone(), other.clone()))
@@ -188,13 +658,43 @@ impl Value {
 
     /// Remainder of `self` by `other`
     pub fn r#mod(&self, other: &Value) -> Result<Value> {
+        if let Some((a, b)) = self.as_float_pair(other) {
+            return Ok(Value::Float(a % b))
+        }
+
         match (self, other) {
+            (Value::Int(_), Value::Int(0)) => {
+                Err(Error::DivisionByZero)
+            }
             (Value::Int(a), Value::Int(b)) => {
-                Ok(Value::Int(a % b))
+                // the remainder always fits: `checked_rem` fails for `i64::MIN % -1` only
+                // because the division overflows, and the remainder is 0
+                Ok(Value::Int(a.checked_rem(*b).unwrap_or(0)))
             }
             _ => {
                 Err(Error::IncompatibleOperands(Op::Mod, self.clone(), other.clone()))
             }
         }
     }
+
+    /// Shift the bits of `self` by `other`, left for `Op::Shl` and right for `Op::Shr`.
+    ///
+    /// Only integers can be shifted, by 0 to 63 bits.  Bits shifted out are lost, and
+    /// right shifts keep the sign (`-8 >> 1` is `-4`).
+    pub fn shift(&self, op: Op, other: &Value) -> Result<Value> {
+        match (&op, self, other) {
+            (_, Value::Int(_), Value::Int(bits)) if !(0 .. 64).contains(bits) => {
+                Err(Error::InvalidShift(*bits))
+            }
+            (Op::Shl, Value::Int(a), Value::Int(bits)) => {
+                Ok(Value::Int(a << bits))
+            }
+            (Op::Shr, Value::Int(a), Value::Int(bits)) => {
+                Ok(Value::Int(a >> bits))
+            }
+            _ => {
+                Err(Error::IncompatibleOperands(op, self.clone(), other.clone()))
+            }
+        }
+    }
 }
@@ -1,25 +1,114 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufWriter, Write};
+use std::rc::Rc;
+use std::sync::{Arc, LazyLock};
+#[cfg(feature = "graphemes")]
+use unicode_segmentation::UnicodeSegmentation;
 use crate::{
-    value::Value,
+    value::{Value, Generator},
+    heap::{Heap, VecHeap},
     opcodes::{Op, Native},
+    program::{Program, Function},
     errors::Error,
 };
 
 /// Result of a operation on the VM
 pub type Result<T> = std::result::Result<T, Error>;
 
-/// A pointer into the managed heap
-#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+/// A pointer into the managed heap, or a small integer stored in the pointer itself
+///
+/// Pointers with the top bit (`INLINE_TAG`) set hold an integer in the other bits, and
+/// have no heap entry (see `VM::with_small_int_tagging`).
+#[derive(Clone, Copy, Hash, PartialEq, Eq)]
 pub struct HeapPtr(usize);
 
-// TODO: HeapPtr can also store 63-bit integers and tagged pointers (assuming usize is 64-bit...)
+/// Bit set in the `HeapPtr`s that hold an integer instead of an index
+const INLINE_TAG: usize = 1 << (usize::BITS - 1);
+
+impl HeapPtr {
+    /// Pointer holding the integer `n`, that must fit in the bits below `INLINE_TAG`
+    fn inline(n: i64) -> HeapPtr {
+        HeapPtr(n as usize | INLINE_TAG)
+    }
+
+    /// Integer held by the pointer itself, if any
+    fn inline_int(self) -> Option<i64> {
+        // shifting the tag out and back, as signed, restores the sign bit
+        if self.0 & INLINE_TAG != 0 { Some(((self.0 << 1) as i64) >> 1) } else { None }
+    }
+
+    /// Index of the heap entry pointed to, `None` for an integer held by the pointer
+    fn index(self) -> Option<usize> {
+        if self.0 & INLINE_TAG == 0 { Some(self.0) } else { None }
+    }
+}
+
+impl std::fmt::Debug for HeapPtr {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.inline_int() {
+            Some(n) => write!(fmt, "HeapPtr(int {})", n),
+            None => write!(fmt, "HeapPtr({})", self.0),
+        }
+    }
+}
+
+/// Integers held by the pointers themselves when small integer tagging is enabled
+///
+/// Any integer that fits the pointer could be held, but `VM::get` must return a reference
+/// to a `Value`, so only those in this range, kept in `SMALL_INT_VALUES`, are.
+const SMALL_INTS: std::ops::RangeInclusive<i64> = -256 ..= 1023;
+
+/// The `Value` of each integer in `SMALL_INTS`, shared by all VMs
+static SMALL_INT_VALUES: LazyLock<Vec<Value>> = LazyLock::new(|| SMALL_INTS.map(Value::Int).collect());
+
+/// Function called with the value of a heap entry when it is collected
+pub type Finalizer = Box<dyn FnOnce(&mut VM, Value)>;
+
+/// Native function registered by the host, called with pointers to its arguments
+pub type ForeignFn = Box<dyn Fn(&mut VM, &[HeapPtr]) -> Result<Value>>;
+
+/// A `ForeignFn` as kept by the VM, so it can be called with the VM borrowed mutably
+type SharedForeignFn = Rc<dyn Fn(&mut VM, &[HeapPtr]) -> Result<Value>>;
+
+/// Minimum number of old entries before a full collection is considered
+const MIN_MAJOR_THRESHOLD: usize = 1024;
+
+/// Minimum number of young entries before a minor collection is considered
+const MIN_YOUNG_THRESHOLD: usize = 256;
+
+/// Default nesting of lists and maps shown when formatting values
+const DEFAULT_PRINT_DEPTH: usize = 8;
+
+/// Default maximum length of a string or list built by repetition or `range`
+const DEFAULT_MAX_REPEAT_LENGTH: usize = 1 << 24;
+
+/// Tolerance of `approx_eq` when not given
+const DEFAULT_EPSILON: f64 = 1e-9;
+
+/// Default maximum number of nested calls
+const DEFAULT_MAX_CALL_DEPTH: usize = 1 << 14;
+
+/// A call to a function defined by the script, in progress
+struct Frame {
+    /// Function called
+    function: Arc<Function>,
+
+    /// Frame pointer: position in the stack of the function called, that is followed by
+    /// its arguments and locals (and replaced by the value returned)
+    fp: usize,
+
+    /// Position to continue from in the code of the caller
+    return_pc: usize,
+
+    /// Generator run by this call, on the stack right below the frame pointer, if resumed
+    generator: Option<HeapPtr>,
+}
 
 /// Script execution Virtual Machine
-#[derive(Debug)]
 pub struct VM {
     /// GC'ed heap.
-    /// A position is None if previously allocated, but released during a collection
-    heap: Vec<Option<Value>>,
+    /// A slot is free if previously allocated, but released during a collection
+    heap: Box<dyn Heap>,
 
     /// Value stack.
     /// Stack only store pointers into heap (all values are boxed -- even basic integers)
@@ -30,149 +119,802 @@ pub struct VM {
 
     /// List of free heap entries during last collection
     free_list: Vec<usize>,
-}
 
-/*
-    On a function call the stack looks like (starting at `fp`)
-    - return value
-    - arguments (already pushed by caller)
-    - locals
+    /// For each heap entry, `true` if it survived a collection (old generation)
+    old: Vec<bool>,
 
-    call_stack: Vec<usize>,     // empty when in root
-    frame_ptr: Vec<usize>, // empty when in root
+    /// Number of entries in the old generation
+    old_count: usize,
 
-    Op::PrepareCall => {
-        self.push_value(Value::Int(0));
-        self.frame_ptr.push(self.stack.len());
-    }
+    /// Size of the old generation that triggers a full collection
+    major_threshold: usize,
 
-    Op::Call(address) => {
-        // number of args explicit or implicit?
-        self.call_stack.push(next_pc);
-        next_pc = address;
-    }
+    /// Entries allocated since the last collection (young generation)
+    young: Vec<usize>,
 
-    Op::Return => {
-        Op::StoreL(0);
-        let fp = self.frame_ptr.pop().unwrap();
-        while self.stack.len() != fp {
-            self.stack.pop();
-        }
-        next_pc = self.call_stack.pop().unwrap();
-    }
+    /// Old entries that may have been changed to point to young ones since the last
+    /// collection (remembered set), filled by the write barrier in `get_mut`
+    remembered: HashSet<usize>,
 
-    Op::LoadL(index) => {
-        let fp = *self.frame_ptr.front().unwrap();
-        let ptr = self.stack[fp + index];
-        self.stack.push(ptr);
-    }
+    /// Entries that may hold a weak reference
+    weak_refs: HashSet<usize>,
+
+    /// Where `print` and other natives write to
+    output: Box<dyn Write>,
+
+    /// Maximum number of bytes natives may write to `output` between resets, if limited
+    output_limit: Option<usize>,
+
+    /// Remaining number of bytes natives may write to `output`, if limited
+    output_budget: Option<usize>,
+
+    /// Maximum length of a string (in bytes) or list built by repetition (`*`)
+    max_repeat_length: usize,
+
+    /// Maximum number of heap entries, if limited
+    heap_limit: Option<usize>,
+
+    /// If `true`, reading an undefined global results in `nil` instead of an error
+    undefined_as_nil: bool,
+
+    /// If `true`, indexing a map with a missing key results in `nil` instead of an error
+    missing_key_as_nil: bool,
 
-    Op::StoreL(index) => {
-        let ptr = self.stack.pop().unwrap();
-        let fp = *self.frame_ptr.front().unwrap();
-        self.stack[fp + index] = ptr;
+    /// Lists and maps nested deeper than this are formatted as `[...]` and `{...}`
+    print_depth: usize,
+
+    /// Finalizers to call when the heap entry is collected
+    finalizers: HashMap<HeapPtr, Finalizer>,
+
+    /// Source line of the last `Op::Line` executed, 0 if none
+    current_line: usize,
+
+    /// Literal strings already pushed, if interning is enabled; these are roots
+    interned: Option<HashMap<String, HeapPtr>>,
+
+    /// If `true`, integers in `SMALL_INTS` are held by their pointers instead of allocated
+    small_int_tagging: bool,
+
+    /// Copy of an integer held by a pointer, given by `modify` as if it were its entry
+    inline_scratch: Value,
+
+    /// Native functions registered by the host, by name: (arity, function)
+    foreign: HashMap<String, (usize, SharedForeignFn)>,
+
+    /// For each heap entry, the number of references to it (from the stack, globals, other
+    /// entries and the caches above), if reference counting is enabled
+    refcounts: Option<Vec<usize>>,
+
+    /// References dropped since values were last freed, counted down when they are
+    released: Vec<HeapPtr>,
+
+    /// Entries lent to the host with `get_mut`, with the pointers they held before, so
+    /// the references changed by the host are counted when values are next freed
+    lent: HashMap<HeapPtr, Vec<HeapPtr>>,
+
+    /// Calls in progress, innermost last (the call stack)
+    frames: Vec<Frame>,
+
+    /// Number of `frames` of calls made before the code being run started (e.g. when a
+    /// native runs code in the middle of a call), that it cannot return from
+    frame_base: usize,
+
+    /// Maximum number of calls in progress
+    max_call_depth: usize,
+}
+
+impl std::fmt::Debug for VM {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt.debug_struct("VM")
+            .field("heap", &(0 .. self.heap.len()).map(|i| self.heap.get(i)).collect::<Vec<_>>())
+            .field("stack", &self.stack)
+            .field("top", &self.top)
+            .field("free_list", &self.free_list)
+            .finish()
     }
-*/
+}
 
 // TODO: review the public interface of VM
 
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl VM {
     /// Create a new empty heap.
     pub fn new() -> VM {
         VM {
-            heap: vec![],
+            heap: Box::new(VecHeap::new()),
             stack: vec![],
             top: HashMap::new(),
             free_list: vec![],
+            old: vec![],
+            old_count: 0,
+            major_threshold: MIN_MAJOR_THRESHOLD,
+            young: vec![],
+            remembered: HashSet::new(),
+            weak_refs: HashSet::new(),
+            output: Box::new(std::io::stdout()),
+            output_limit: None,
+            output_budget: None,
+            max_repeat_length: DEFAULT_MAX_REPEAT_LENGTH,
+            heap_limit: None,
+            undefined_as_nil: false,
+            missing_key_as_nil: false,
+            print_depth: DEFAULT_PRINT_DEPTH,
+            finalizers: HashMap::new(),
+            current_line: 0,
+            interned: None,
+            small_int_tagging: false,
+            inline_scratch: Value::Nil,
+            foreign: HashMap::new(),
+            refcounts: None,
+            released: vec![],
+            lent: HashMap::new(),
+            frames: vec![],
+            frame_base: 0,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
         }
     }
 
-    /// Garbage collection of heap
+    /// Set the maximum length of strings (in bytes) and lists built by repetition or `range`.
+    ///
+    /// `"a" * n` or `range(n)` would otherwise allow a script to allocate arbitrary amounts of memory.
+    pub fn with_max_repeat_length(mut self, max_repeat_length: usize) -> VM {
+        self.max_repeat_length = max_repeat_length;
+        self
+    }
+
+    /// Limit the heap to `max` entries (values).
+    ///
+    /// An allocation that would grow the heap past the limit, even after a full collection,
+    /// fails with `OutOfMemory` instead.
+    pub fn with_heap_limit(mut self, max: usize) -> VM {
+        self.heap_limit = Some(max);
+        self
+    }
+
+    /// If `undefined_as_nil` is set, reading a global that was never assigned results in
+    /// `nil`, instead of a `GlobalNotFound` error.
+    ///
+    /// This allows scripts to reference globals that are only defined later on.
+    pub fn with_undefined_as_nil(mut self, undefined_as_nil: bool) -> VM {
+        self.undefined_as_nil = undefined_as_nil;
+        self
+    }
+
+    /// If `missing_key_as_nil` is set, indexing a map with a key it does not have (`m[k]`)
+    /// results in `nil`, instead of a `KeyNotFound` error.
+    ///
+    /// Assigning to a missing key (`m[k] = v`) always adds it.
+    pub fn with_missing_key_as_nil(mut self, missing_key_as_nil: bool) -> VM {
+        self.missing_key_as_nil = missing_key_as_nil;
+        self
+    }
+
+    /// Limit the number of nested calls to functions defined by the script.
+    ///
+    /// A call past the limit fails with `StackOverflow`, so runaway recursion is an error
+    /// instead of growing the stack until memory is exhausted.
+    pub fn with_max_call_depth(mut self, max_call_depth: usize) -> VM {
+        self.max_call_depth = max_call_depth;
+        self
+    }
+
+    /// Set how deep nested lists and maps are shown when formatting values (e.g. by `print`).
+    ///
+    /// Deeper ones are shown as `[...]` and `{...}`.  This also bounds the output for
+    /// lists that contain themselves.
+    pub fn with_print_depth(mut self, print_depth: usize) -> VM {
+        self.print_depth = print_depth;
+        self
+    }
+
+    /// Nesting of lists and maps shown when formatting values, see `with_print_depth`
+    pub fn print_depth(&self) -> usize {
+        self.print_depth
+    }
+
+    /// If `interning` is set, identical literal strings share a single heap entry.
+    ///
+    /// This saves heap entries for literals that are pushed repeatedly (e.g. inside loops),
+    /// at the cost of keeping each distinct literal alive until the VM is dropped.
+    /// Strings are never modified in place, so sharing is not observable by scripts.
+    pub fn with_string_interning(mut self, interning: bool) -> VM {
+        self.interned = if interning { Some(HashMap::new()) } else { None };
+        self
+    }
+
+    /// If `tagging` is set, integers in a small range (`-256 ..= 1023`) are held by their
+    /// `HeapPtr` itself (a tagged pointer), instead of allocated on the heap.
+    ///
+    /// Comparisons, counters and indices are mostly small integers, so this saves most of
+    /// the allocations of integer-heavy loops, and the collector does not trace them.
+    /// These integers are never collected, so a weak reference to one is never cleared
+    /// and a finalizer is never called.  They have no heap entry to change, so `get_mut`
+    /// fails with `ImmutableValue` for them.
+    pub fn with_small_int_tagging(mut self, tagging: bool) -> VM {
+        self.small_int_tagging = tagging;
+        self
+    }
+
+    /// If `counting` is set, values are freed as soon as nothing refers to them (reference counting).
+    ///
+    /// References from the stack, globals and other values are counted, and a value is freed
+    /// (along with what only it referred to) at the end of the instruction that drops its last
+    /// reference, instead of when the heap is traced.  Cycles (e.g. a list appended to itself)
+    /// are never counted down, so they are left to a full collection, done when the heap
+    /// doubles since the last one.
+    ///
+    /// Must be done before running anything, as values already allocated are lost.
+    pub fn with_reference_counting(mut self, counting: bool) -> VM {
+        self.refcounts = if counting { Some(vec![]) } else { None };
+        self.reset();
+        self
+    }
+
+    /// Replace the heap backend of the VM (by default a `VecHeap`).
+    ///
+    /// Must be done before running anything, as values already allocated are lost.
+    pub fn with_heap<H: Heap + 'static>(mut self, heap: H) -> VM {
+        self.heap = Box::new(heap);
+        self.reset();
+        self
+    }
+
+    /// Replace the output of the VM (by default the process' standard output).
+    pub fn with_output<W: Write + 'static>(mut self, output: W) -> VM {
+        self.output = Box::new(output);
+        self
+    }
+
+    /// Limit the total number of bytes natives (`print`, ...) may write to the output.
+    ///
+    /// A write that would go over the limit is not done, and fails with `OutputLimitExceeded`
+    /// instead, so an untrusted script cannot flood the output.
+    pub fn with_output_limit(mut self, limit: usize) -> VM {
+        self.output_limit = Some(limit);
+        self.output_budget = Some(limit);
+        self
+    }
+
+    /// Replace the output of the VM by a buffered version of `output`.
+    ///
+    /// Output is flushed when the script calls `flush()` and at the end of every `run`.
+    pub fn with_buffered_output<W: Write + 'static>(self, output: W) -> VM {
+        self.with_output(BufWriter::new(output))
+    }
+
+    /// Register `f` as the native function `name`, taking exactly `arity` arguments.
+    ///
+    /// The compiler must know about it too (see `Compiler::foreign_of`), so calls to `name`
+    /// are compiled into `Op::Foreign`.  Built-in natives take precedence over registered ones.
+    ///
+    /// `f` is called with pointers to the arguments, which stay on the stack during the call.
+    /// The `Value` it returns is allocated and pushed as the result of the call; any pointer
+    /// inside it must be kept reachable until then, e.g. by allocating with `push_value`.
+    /// Anything `f` leaves on the stack is discarded once the result is allocated.
+    pub fn register_native(&mut self, name: &str, arity: usize, f: ForeignFn) {
+        self.foreign.insert(name.to_string(), (arity, Rc::from(f)));
+    }
+
+    /// Name and arity of the native functions registered by the host.
+    pub fn foreign_natives(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.foreign.iter().map(|(name, (arity, _))| (name.as_str(), *arity))
+    }
+
+    /// Clear all state left by previous runs (heap, stack and globals), keeping configuration.
+    ///
+    /// Registered natives, output, limits and other settings done with the `with_*` methods are kept, and the output
+    /// budget is restored to its full limit.  Finalizers of values still alive are *not* called.
+    pub fn reset(&mut self) {
+        self.heap.clear();
+        self.stack.clear();
+        self.top.clear();
+        self.free_list.clear();
+        self.old.clear();
+        self.old_count = 0;
+        self.major_threshold = MIN_MAJOR_THRESHOLD;
+        self.young.clear();
+        self.remembered.clear();
+        self.weak_refs.clear();
+        self.finalizers.clear();
+        self.current_line = 0;
+        self.output_budget = self.output_limit;
+        if let Some(interned) = self.interned.as_mut() {
+            interned.clear();
+        }
+        if let Some(refcounts) = self.refcounts.as_mut() {
+            refcounts.clear();
+        }
+        self.released.clear();
+        self.lent.clear();
+        self.frames.clear();
+        self.frame_base = 0;
+    }
+
+    /// Garbage collection of the whole heap (major collection)
     pub fn collect(&mut self) {
         // the algorithm is a mark-and-sweep using stack and top as roots
+        self.count_lent();
+        let marked = self.mark();
+        let freed: Vec<usize> = (0 .. self.heap.len())
+            .filter(|i| !marked[*i] && self.heap.get(*i).is_some())
+            .collect();
+        self.uncount_freed(&freed, &marked);
+        let finalize = self.sweep(&freed);
 
-        // mark phase uses an explicit stack to follow pointers
+        // everything that survived is now in the old generation
+        self.young.clear();
+        self.remembered.clear();
+        self.old = marked;
+        self.old_count = self.old.iter().filter(|m| **m).count();
+        self.major_threshold = MIN_MAJOR_THRESHOLD.max(2 * self.old_count);
+
+        Self::finalize(self, finalize);
+    }
+
+    /// Garbage collection of the entries allocated since the last collection (minor collection)
+    ///
+    /// Old entries are assumed alive, and are only followed if they are in the remembered
+    /// set, so the cost depends on the roots and young entries, not on the whole heap.
+    fn collect_young(&mut self) {
+        // mark phase, stopping at old entries
+        // a bitmap of the whole heap is still cheaper than hashing each young entry
         let mut marked = vec![false; self.heap.len()];
         let mut roots = Vec::with_capacity(self.stack.len() + self.top.len());
         self.stack.iter().for_each(|ptr| roots.push(*ptr));
         self.top.values().for_each(|ptr| roots.push(*ptr));
-        while let Some(ptr) = roots.pop() {
-            if !marked[ptr.0] && self.heap[ptr.0].is_some() {
-                marked[ptr.0] = true;
-                self.heap[ptr.0].as_ref().unwrap().mark(&mut roots);
+        self.interned.iter().flat_map(|h| h.values()).for_each(|ptr| roots.push(*ptr));
+        for i in self.remembered.iter() {
+            if let Some(value) = self.heap.get(*i) {
+                value.mark(&mut roots);
+            }
+        }
+        while let Some(i) = roots.pop().map(HeapPtr::index) {
+            let i = match i {
+                Some(i) => i,
+                None => continue,
+            };
+            if !self.old[i] && !marked[i] {
+                marked[i] = true;
+                if let Some(value) = self.heap.get(i) {
+                    value.mark(&mut roots);
+                }
             }
         }
 
-        // release a heap entry by setting it to None
-        self.free_list.clear();
-        for (i, node) in self.heap.iter_mut().enumerate() {
+        // young survivors are promoted to the old generation
+        let young = std::mem::take(&mut self.young);
+        let mut freed = vec![];
+        for i in young {
+            if marked[i] {
+                self.old[i] = true;
+                self.old_count += 1;
+            } else if self.heap.get(i).is_some() {
+                freed.push(i);
+            }
+        }
+        self.remembered.clear();
+
+        let finalize = self.sweep(&freed);
+        Self::finalize(self, finalize);
+    }
+
+    /// Release the heap entries in `freed`, returning the values that must be finalized.
+    fn sweep(&mut self, freed: &[usize]) -> Vec<(Finalizer, Value)> {
+        // release a heap entry by freeing its slot
+        // values with finalizers are kept aside, to be finalized after the sweep
+        let mut finalize = vec![];
+        for i in freed.iter() {
+            let node = self.heap.take(*i);
+            if let (Some(f), Some(value)) = (self.finalizers.remove(&HeapPtr(*i)), node) {
+                finalize.push((f, value));
+            }
+            self.old[*i] = false;
+            self.free_list.push(*i);
+        }
+
+        // weak references to released entries (now empty slots) are cleared, so they
+        // do not point to whatever is allocated later in the same slot; those released
+        // themselves are forgotten
+        let heap = &mut self.heap;
+        self.weak_refs.retain(|i| {
+            let target = match heap.get(*i) {
+                Some(Value::Weak(Some(ptr))) => *ptr,
+                _ => return false,
+            };
+            // integers held by the pointer are never released
+            if target.index().is_none_or(|t| heap.get(t).is_some()) {
+                return true
+            }
+            heap.store(*i, Value::Weak(None));
+            false
+        });
+        finalize
+    }
+
+    /// Call the finalizers returned by `sweep`.
+    ///
+    /// Only done when the heap is consistent again, so finalizers can do as they please.
+    fn finalize(&mut self, finalize: Vec<(Finalizer, Value)>) {
+        for (f, value) in finalize {
+            f(self, value);
+        }
+    }
+
+    /// Count a new reference to `ptr`, if counting references.
+    ///
+    /// Integers held by the pointer itself are not counted.
+    fn retain(&mut self, ptr: HeapPtr) {
+        if let Some(count) = self.refcounts.as_mut().zip(ptr.index()).and_then(|(counts, i)| counts.get_mut(i)) {
+            *count += 1;
+        }
+    }
+
+    /// Drop a reference to `ptr`, if counting references.
+    ///
+    /// It is only counted down by `free_released`, as the pointer may still be in use
+    /// (e.g. the operands popped by an instruction, until it is done).
+    fn release(&mut self, ptr: HeapPtr) {
+        if self.refcounts.is_some() && ptr.index().is_some() {
+            self.released.push(ptr);
+        }
+    }
+
+    /// Count the references changed in the entries lent with `get_mut` since last called.
+    fn count_lent(&mut self) {
+        for (ptr, before) in std::mem::take(&mut self.lent) {
+            let mut after = vec![];
+            if let Some(value) = self.heap.get(ptr.0) {
+                value.mark(&mut after);
+            }
+            after.into_iter().for_each(|p| self.retain(p));
+            before.into_iter().for_each(|p| self.release(p));
+        }
+    }
+
+    /// Count down the references dropped since last called, freeing the entries left without
+    /// any (and, in turn, those only they referred to).
+    fn free_released(&mut self) {
+        self.count_lent();
+        let counts = match self.refcounts.as_mut() {
+            Some(counts) => counts,
+            None => return,
+        };
+        // an explicit stack of pointers to count down, so long chains need no recursion
+        let mut pending = std::mem::take(&mut self.released);
+        let mut freed = vec![];
+        while let Some(ptr) = pending.pop() {
+            let count = match counts.get_mut(ptr.0) {
+                Some(count) if *count > 0 => count,
+                _ => continue,
+            };
+            *count -= 1;
+            if *count == 0 {
+                if let Some(value) = self.heap.get(ptr.0) {
+                    freed.push(ptr.0);
+                    value.mark(&mut pending);
+                }
+            }
+        }
+        if !freed.is_empty() {
+            let finalize = self.sweep(&freed);
+            Self::finalize(self, finalize);
+        }
+    }
+
+    /// Forget the references held by the entries in `freed` by a full collection, if counting.
+    ///
+    /// These are cycles, or values only they referred to; the references to entries that
+    /// are kept (`marked`) must be counted down.
+    fn uncount_freed(&mut self, freed: &[usize], marked: &[bool]) {
+        let counts = match self.refcounts.as_mut() {
+            Some(counts) => counts,
+            None => return,
+        };
+        let mut held = vec![];
+        for i in freed.iter() {
+            counts[*i] = 0;
+            if let Some(value) = self.heap.get(*i) {
+                value.mark(&mut held);
+            }
+        }
+        for i in held.into_iter().filter_map(HeapPtr::index).filter(|i| marked[*i]) {
+            counts[i] = counts[i].saturating_sub(1);
+        }
+    }
+
+    /// Register a finalizer to be called when the value at `ptr` is collected.
+    ///
+    /// This is meant for values that stand for resources held by the host (e.g. a file
+    /// handle given to the script by a native), so they can be released.
+    ///
+    /// The finalizer is called once, after the collection finishes, with the collected
+    /// value.  Pointers inside that value (e.g. elements of a list) may have been
+    /// collected as well, and must not be dereferenced.
+    pub fn register_finalizer<F: FnOnce(&mut VM, Value) + 'static>(&mut self, ptr: HeapPtr, finalizer: F) -> Result<()> {
+        self.get(ptr)?;
+        self.finalizers.insert(ptr, Box::new(finalizer));
+        Ok(())
+    }
+
+    /// Mark phase of the garbage collection.
+    ///
+    /// Return, for each heap entry, if it is reachable from the roots (stack and top).
+    fn mark(&self) -> Vec<bool> {
+        // mark phase uses an explicit stack to follow pointers
+        let mut marked = vec![false; self.heap.len()];
+        let mut roots = Vec::with_capacity(self.stack.len() + self.top.len());
+        self.stack.iter().for_each(|ptr| roots.push(*ptr));
+        self.top.values().for_each(|ptr| roots.push(*ptr));
+        // with reference counting, values dropped during an instruction may still be in use by it
+        self.released.iter().for_each(|ptr| roots.push(*ptr));
+        self.interned.iter().flat_map(|h| h.values()).for_each(|ptr| roots.push(*ptr));
+        while let Some(i) = roots.pop().map(HeapPtr::index) {
+            let i = match i {
+                Some(i) => i,
+                None => continue,
+            };
             if !marked[i] {
-                self.free_list.push(i);
-                *node = None;
+                if let Some(value) = self.heap.get(i) {
+                    marked[i] = true;
+                    value.mark(&mut roots);
+                }
             }
         }
+        marked
+    }
+
+    /// Return a listing of all used heap entries, one per line, as
+    /// `<index>: <value>` followed by `(unreachable)` if it would be collected.
+    pub fn dump_heap(&self) -> Result<String> {
+        let marked = self.mark();
+        let mut s = String::new();
+        for (i, reachable) in marked.iter().enumerate() {
+            if let Some(value) = self.heap.get(i) {
+                s += &format!("{}: {}", i, value.fmt(self, 0)?);
+                if !reachable {
+                    s += " (unreachable)";
+                }
+                s += "\n";
+            }
+        }
+        Ok(s)
     }
 
     /// Return a currently free slot.
     /// Slot is *not* marked as used!!!
-    fn find_free_slot(&mut self) -> usize {
+    fn find_free_slot(&mut self) -> Result<usize> {
         // attempt to find free heap entry
         if let Some(i) = self.free_list.pop() {
-            return i;
+            return Ok(i);
         }
 
-        // not found; collect and try again
-        self.collect();
-        if let Some(i) = self.free_list.pop() {
-            return i;
+        // not found; when counting references, values are freed as soon as they are
+        // unreferenced, so the heap only grows with live values and cycles, and these
+        // are collected once the heap doubles since the last collection
+        // otherwise, once enough young entries were allocated, collect the young
+        // generation and try again; only if that is not enough, and the old generation
+        // grew, collect everything
+        // the young threshold grows with the old generation, as each minor collection
+        // scans the remembered old entries (e.g. a large list being appended to)
+        if self.refcounts.is_some() {
+            if self.heap.len() >= self.major_threshold {
+                self.collect();
+                if let Some(i) = self.free_list.pop() {
+                    return Ok(i);
+                }
+            }
+        } else if self.young.len() >= MIN_YOUNG_THRESHOLD.max(self.old_count / 2) {
+            self.collect_young();
+            if self.free_list.is_empty() && self.old_count > self.major_threshold {
+                self.collect();
+            }
+            if let Some(i) = self.free_list.pop() {
+                return Ok(i);
+            }
+        }
+
+        // at the limit, the heap only grows if a full collection frees nothing
+        if self.heap_limit.is_some_and(|max| self.heap.len() >= max) {
+            self.collect();
+            return self.free_list.pop().ok_or(Error::OutOfMemory);
         }
 
         // if no free entry was found, attempt to grow heap
-        // let runtime blow on not-enough-memory conditions :)
-        let i = self.heap.len();
-        self.heap.push(None);
-        i
+        // without a limit, let runtime blow on not-enough-memory conditions :)
+        let i = self.heap.grow();
+        self.old.push(false);
+        if let Some(counts) = self.refcounts.as_mut() {
+            counts.push(0);
+        }
+        Ok(i)
     }
 
     /// Store `value` into `self.heap` at `index`.
     fn store_heap(&mut self, index: usize, value: Value) {
-        self.heap[index] = Some(value);
+        if let Value::Weak(_) = value {
+            self.weak_refs.insert(index);
+        }
+        if self.refcounts.is_some() {
+            let mut held = vec![];
+            value.mark(&mut held);
+            held.into_iter().for_each(|ptr| self.retain(ptr));
+        } else {
+            self.young.push(index);
+        }
+        self.heap.store(index, value);
     }
 
     /// Directly push a `HeapPtr` into the stack
     pub fn push(&mut self, ptr: HeapPtr) {
+        self.retain(ptr);
         self.stack.push(ptr);
     }
 
-    /// Allocate a slot for `value` on the heap, and push the result on the stack
-    pub fn push_value(&mut self, value: Value) -> HeapPtr {
-        let i = self.find_free_slot();
+    /// Allocate a slot for `value` on the heap.
+    ///
+    /// The result is *not* a root, so it must be stored somewhere reachable
+    /// (e.g. the stack) before the next allocation.
+    fn alloc(&mut self, value: Value) -> Result<HeapPtr> {
+        // small integers are held by the pointer, if tagging
+        match value {
+            Value::Int(n) if self.small_int_tagging && SMALL_INTS.contains(&n) => return Ok(HeapPtr::inline(n)),
+            _ => (),
+        }
+
+        let i = self.find_free_slot()?;
         self.store_heap(i, value);
-        self.stack.push(HeapPtr(i));
-        HeapPtr(i)
+        Ok(HeapPtr(i))
+    }
+
+    /// Allocate a slot for `value` on the heap, and push the result on the stack
+    pub fn push_value(&mut self, value: Value) -> Result<HeapPtr> {
+        let ptr = self.alloc(value)?;
+        self.push(ptr);
+        Ok(ptr)
     }
 
     /// Return a reference to the value of `ptr` on the heap, or an error.
     pub fn get(&self, ptr: HeapPtr) -> Result<&Value> {
-        // the first `ok_or` fails if `ptr` is out of range for self.heap
-        // the second `ok_or` fails if the heap entry is `None`
-        self.heap.get(ptr.0)
-            .ok_or(Error::MemoryAccessOutOfRange(ptr))?
-            .as_ref()
-            .ok_or(Error::InvalidMemoryAccess(ptr))
+        if let Some(n) = ptr.inline_int() {
+            return SMALL_INT_VALUES.get((n - SMALL_INTS.start()) as usize).ok_or(Error::MemoryAccessOutOfRange(ptr));
+        }
+        if ptr.0 >= self.heap.len() {
+            return Err(Error::MemoryAccessOutOfRange(ptr));
+        }
+        self.heap.get(ptr.0).ok_or(Error::InvalidMemoryAccess(ptr))
     }
 
     /// Return a mutable reference to an entry on the heap
+    ///
+    /// This is the write barrier of the generational collector: an old entry may be
+    /// changed to point to young ones, so it is remembered until the next collection.
+    /// When counting references, the pointers the entry holds are copied, to count those
+    /// changed when values are next freed (at the end of an instruction, or by `collect`).
+    ///
+    /// Integers held by the pointer itself (see `with_small_int_tagging`) have no entry to
+    /// change, and fail with `ImmutableValue`.
     pub fn get_mut(&mut self, ptr: HeapPtr) -> Result<&mut Value> {
-        self.heap.get_mut(ptr.0)
-            .ok_or(Error::MemoryAccessOutOfRange(ptr))?
-            .as_mut()
-            .ok_or(Error::InvalidMemoryAccess(ptr))
+        if ptr.inline_int().is_some() {
+            return Err(Error::ImmutableValue(ptr));
+        }
+        if self.refcounts.is_some() && !self.lent.contains_key(&ptr) {
+            let mut held = vec![];
+            self.get(ptr)?.mark(&mut held);
+            self.lent.insert(ptr, held);
+        }
+        self.entry_mut(ptr)
+    }
+
+    /// Return a mutable reference to an entry on the heap, for the VM itself.
+    ///
+    /// Unlike `get_mut`, any reference changed must be counted by the caller.
+    ///
+    /// An integer held by the pointer gives a copy instead, so the caller fails as for
+    /// any other integer (no instruction changes integers in place).
+    fn modify(&mut self, ptr: HeapPtr) -> Result<&mut Value> {
+        if let Some(n) = ptr.inline_int() {
+            self.inline_scratch = Value::Int(n);
+            return Ok(&mut self.inline_scratch);
+        }
+        // references changed by the host are counted first, not to mix them with the caller's
+        if !self.lent.is_empty() {
+            self.count_lent();
+        }
+        self.entry_mut(ptr)
+    }
+
+    /// Return a mutable reference to an entry on the heap, through the write barrier.
+    fn entry_mut(&mut self, ptr: HeapPtr) -> Result<&mut Value> {
+        self.get(ptr)?;
+        if self.old[ptr.0] {
+            self.remembered.insert(ptr.0);
+        }
+        self.weak_refs.insert(ptr.0);
+        self.heap.get_mut(ptr.0).ok_or(Error::InvalidMemoryAccess(ptr))
     }
 
     /// Return a clone of an entry on the heap
     pub fn get_clone(&self, ptr: HeapPtr) -> Result<Value> {
-        self.get(ptr).map(|v| v.clone())
+        self.get(ptr).cloned()
+    }
+
+    /// Return a copy of the elements of the list at `ptr`.
+    ///
+    /// Elements are cloned, so pointers inside them (e.g. nested lists) still refer to this VM.
+    pub fn to_rust_list(&self, ptr: HeapPtr) -> Result<Vec<Value>> {
+        match self.get(ptr)? {
+            Value::List(lst) => lst.iter().map(|p| self.get_clone(*p)).collect(),
+            other => Err(Error::UnexpectedType("list".to_string(), other.clone())),
+        }
+    }
+
+    /// Source line being executed, as set by the last `Op::Line`; 0 if the code
+    /// was compiled without line markers.
+    pub fn current_line(&self) -> usize {
+        self.current_line
+    }
+
+    /// Number of values on the stack
+    pub fn stack_len(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// Return a clone of the value on top of the stack, if the stack is not empty
+    pub fn top_value(&self) -> Option<Result<Value>> {
+        self.stack.last().map(|ptr| self.get_clone(*ptr))
+    }
+
+    /// Return the pointer to the global `name`, if defined
+    pub fn get_global(&self, name: &str) -> Option<HeapPtr> {
+        self.top.get(name).cloned()
+    }
+
+    /// Allocate `value` and make it the global `name`
+    pub fn set_global(&mut self, name: &str, value: Value) -> Result<HeapPtr> {
+        let ptr = self.alloc(value)?;
+        self.store_global(name.to_string(), ptr);
+        Ok(ptr)
+    }
+
+    /// Make `ptr` the value of the global `name`
+    fn store_global(&mut self, name: String, ptr: HeapPtr) {
+        self.retain(ptr);
+        if let Some(previous) = self.top.insert(name, ptr) {
+            self.release(previous);
+        }
+    }
+
+    /// Depth-first traversal of the value at `ptr` and all values reachable from it.
+    ///
+    /// `visitor` is called with each value and its depth (0 for the value at `ptr`).
+    /// Elements of lists and values of maps (in key order) are visited after their container.
+    /// Each heap entry is visited at most once, so cyclic structures terminate.
+    pub fn walk(&self, ptr: HeapPtr, visitor: &mut dyn FnMut(&Value, usize)) -> Result<()> {
+        let mut visited = HashSet::new();
+        let mut pending = vec![(ptr, 0)];
+        while let Some((ptr, depth)) = pending.pop() {
+            if !visited.insert(ptr) {
+                continue
+            }
+            let value = self.get(ptr)?;
+            visitor(value, depth);
+            // reversed, so elements are visited in order
+            match value {
+                Value::List(lst) => lst.iter().rev().for_each(|p| pending.push((*p, depth + 1))),
+                Value::Map(map) => {
+                    let mut entries: Vec<_> = map.iter().collect();
+                    entries.sort_by_key(|(key, _)| *key);
+                    entries.iter().rev().for_each(|(_, p)| pending.push((**p, depth + 1)));
+                }
+                _ => (),
+            }
+        }
+        Ok(())
     }
 
     /// Return the value at stack[-i] or error
@@ -193,276 +935,1036 @@ impl VM {
     /// Return a *mutable* reference to the heap value of the pointer at offset `-i` on the stack
     fn dup_value_mut(&mut self, i: usize) -> Result<&mut Value> {
         let ptr = self.dup(i)?;
-        self.get_mut(ptr)
+        self.modify(ptr)
     }
 
     /// Pop from stack or error
-    fn pop(&mut self) -> Result<HeapPtr> {
-        self.stack.pop().ok_or(Error::StackUnderflow)
+    pub fn pop(&mut self) -> Result<HeapPtr> {
+        let ptr = self.stack.pop().ok_or(Error::StackUnderflow)?;
+        self.release(ptr);
+        Ok(ptr)
     }
 
     /// Pop from stack and return a reference the Value in the Heap.
     fn pop_value(&mut self) -> Result<&Value> {
-        let ptr = self.stack.pop().ok_or(Error::StackUnderflow)?;
+        let ptr = self.pop()?;
         self.get(ptr)
     }
 
-    /// Run `code` on the VM, keeping the current memory state from any previous execution (globals).
-    pub fn run(&mut self, code: &[Op]) -> Result<()> {
-        let mut pc = 0;
-        while pc < code.len() {
-            let mut next_pc = pc + 1;
-            match code[pc].clone() {
-                Op::Nop => {
-                    // do nothing
-                }
-                Op::Target(_) => {
-                    // jump targets must not happen in the output of compiler
-                    return Err(Error::InvalidOpCode(pc));
+    /// Pop the top `n` pointers from the stack (at most its length), in the order they were pushed
+    fn pop_n(&mut self, n: usize) -> Vec<HeapPtr> {
+        let popped = self.stack.split_off(self.stack.len().saturating_sub(n));
+        popped.iter().for_each(|ptr| self.release(*ptr));
+        popped
+    }
+
+    /// Normalize `index` into a container of `len` elements.
+    ///
+    /// Negative indices count from the end (`-1` is the last element).
+    /// Return `None` if the index is out of range.
+    fn wrap_index(index: i64, len: usize) -> Option<usize> {
+        let i = if index < 0 { index + len as i64 } else { index };
+        if i >= 0 && (i as usize) < len {
+            Some(i as usize)
+        } else {
+            None
+        }
+    }
+
+    /// `true` if `aptr` and `bptr` are the same heap entry, holding `a`, so they are
+    /// equal without comparing values (as in `x == x`, or with interned strings).
+    ///
+    /// NaN is the exception: it is not equal to itself, even in the same entry.
+    fn same_value(aptr: HeapPtr, bptr: HeapPtr, a: &Value) -> bool {
+        aptr == bptr && !matches!(a, Value::Float(n) if n.is_nan())
+    }
+
+    /// Convert a `start .. end` slice of a container of length `len` into a range.
+    ///
+    /// Negative indices count from the end, as in `wrap_index`.  Bounds past either end
+    /// are clamped, and an `end` before `start` gives an empty range at `start`, so any
+    /// slice is valid (`l[0:100]` is all of a shorter `l`, as in Python).
+    fn slice_range(start: i64, end: i64, len: usize) -> std::ops::Range<usize> {
+        let clamp = |i: i64| {
+            let i = if i < 0 { i.saturating_add(len as i64) } else { i };
+            i.clamp(0, len as i64) as usize
+        };
+        let start = clamp(start);
+        start .. clamp(end).max(start)
+    }
+
+    /// Write `s` to the output, accounting for the output budget
+    fn write_output(&mut self, s: &str) -> Result<()> {
+        if let Some(budget) = self.output_budget {
+            if s.len() > budget {
+                return Err(Error::OutputLimitExceeded);
+            }
+            self.output_budget = Some(budget - s.len());
+        }
+        self.output.write_all(s.as_bytes())?;
+        Ok(())
+    }
+
+    /// Execute the `native` function with `nargs` arguments on top of the stack.
+    ///
+    /// Arguments are left on the stack (the caller pops them), and the pointer to
+    /// the return value is returned.  This is either a fresh allocation or, for
+    /// natives that return an element of a container, the element's own pointer.
+    fn native(&mut self, nargs: usize, native: &Native) -> Result<HeapPtr> {
+        let value = match native {
+            Native::Print => {
+                for i in 0 .. nargs {
+                    let s = self.dup_value(nargs - i - 1)?.fmt(self, 0)?;
+                    self.write_output(&s)?;
                 }
-                Op::PushI(n) => {
-                    self.push_value(Value::Int(n));
+                self.write_output("\n")?;
+                Value::Nil
+            }
+            Native::PrettyPrint => {
+                let s = self.dup_value(0)?.fmt_pretty(self, 0)?;
+                self.write_output(&(s + "\n"))?;
+                Value::Nil
+            }
+            Native::Length => {
+                let n = self.dup_value(0)?.length();
+                Value::Int(n as i64)
+            }
+            Native::ToString => {
+                let s = self.dup_value(0)?.fmt(self, 0)?;
+                Value::Str(s)
+            }
+            Native::Append | Native::Enqueue => {
+                // enqueue is append, named for use with dequeue in FIFO queues
+                let mut to_add = vec![];
+                for i in 1 .. nargs {
+                    let ptr = self.dup(nargs - i - 1)?;
+                    to_add.push(ptr);
                 }
-                Op::PushS(s) => {
-                    self.push_value(Value::Str(s.clone()));
+
+                let target = self.dup_value_mut(nargs - 1)?;
+                match target {
+                    Value::List(lst) => {
+                        // appends in place, the Vec grows geometrically so repeated
+                        // appends are amortized O(1) and no MakeList capacity hint is needed
+                        lst.extend_from_slice(&to_add);
+                        let length = lst.len();
+                        to_add.into_iter().for_each(|ptr| self.retain(ptr));
+                        Value::Int(length as i64)
+                    }
+                    _ => return Err(Error::InvalidAppend(target.clone())),
                 }
-                Op::Dup(i) => {
-                    self.stack.push(self.dup(i)?);
+            }
+            Native::Insert => {
+                // insert(list, index, value): elements from index onwards shift right,
+                // a negative index counts from the end and `length(list)` appends
+                let ptr = self.dup(0)?;
+                let index = match self.dup_value(1)? {
+                    Value::Int(i) => *i,
+                    other => return Err(Error::InvalidArgument(Native::Insert, other.clone())),
+                };
+                match self.dup_value_mut(2)? {
+                    Value::List(lst) => {
+                        let i = if index < 0 { index + lst.len() as i64 } else { index };
+                        if i < 0 || i as usize > lst.len() {
+                            return Err(Error::IndexOutOfRange(Value::List(lst.clone()), index.unsigned_abs() as usize));
+                        }
+                        lst.insert(i as usize, ptr);
+                        let length = lst.len();
+                        self.retain(ptr);
+                        Value::Int(length as i64)
+                    }
+                    other => return Err(Error::InvalidArgument(Native::Insert, other.clone())),
                 }
-                Op::Pop => {
-                    self.pop()?;
+            }
+            Native::Dequeue | Native::Peek => {
+                // the element itself is returned (not a copy), or nil if the list is empty
+                let list = self.dup_value_mut(0)?;
+                let element = match (native, list) {
+                    (Native::Dequeue, Value::List(lst)) if !lst.is_empty() => Some(lst.remove(0)),
+                    (Native::Peek, Value::List(lst)) => lst.last().copied(),
+                    (_, Value::List(_)) => None,
+                    (_, other) => return Err(Error::InvalidArgument(native.clone(), other.clone())),
+                };
+                match element {
+                    Some(ptr) => {
+                        if let Native::Dequeue = native {
+                            self.release(ptr);
+                        }
+                        return Ok(ptr)
+                    }
+                    None => Value::Nil,
                 }
-                Op::LoadG(s) => {
-                    let ptr = *self.top.get(&s).ok_or_else(|| Error::GlobalNotFound(s.clone()))?;
-                    self.stack.push(ptr);
+            }
+            Native::Keys => {
+                // keys are not heap values, so they are allocated (and kept on the stack
+                // until the list is built, as each allocation may trigger a collection)
+                let keys: Vec<Value> = match self.dup_value(0)?.sorted_entries() {
+                    Some(entries) => entries.into_iter().map(|(key, _)| key.to_value()).collect(),
+                    None => return Err(Error::InvalidArgument(Native::Keys, self.dup_value(0)?.clone())),
+                };
+                let n = keys.len();
+                for key in keys {
+                    self.push_value(key)?;
                 }
-                Op::StoreG(s) => {
-                    let ptr = self.dup(0)?;
-                    self.top.insert(s.clone(), ptr);
+                let i = self.find_free_slot()?;
+                let lst = self.pop_n(n);
+                self.store_heap(i, Value::List(lst));
+                return Ok(HeapPtr(i))
+            }
+            Native::Values => {
+                match self.dup_value(0)?.sorted_entries() {
+                    Some(entries) => Value::List(entries.into_iter().map(|(_, ptr)| ptr).collect()),
+                    None => return Err(Error::InvalidArgument(Native::Values, self.dup_value(0)?.clone())),
                 }
-                Op::MoveG(s) => {
-                    let ptr = self.pop()?;
-                    self.top.insert(s.clone(), ptr);
+            }
+            Native::Range => {
+                // range(end), range(start, end) or range(start, end, step), with `end` excluded;
+                // a negative step counts down, and a range that never reaches `end` is empty
+                let mut args = [0, 0, 1];
+                for (i, arg) in args.iter_mut().skip(if nargs == 1 { 1 } else { 0 }).take(nargs).enumerate() {
+                    *arg = match self.dup_value(nargs - i - 1)? {
+                        Value::Int(n) => *n,
+                        other => return Err(Error::InvalidArgument(Native::Range, other.clone())),
+                    };
                 }
-                Op::MakeList(n) => {
-                    let i = self.find_free_slot();
-                    let lst = self.stack.split_off(self.stack.len() - n);
-                    self.store_heap(i, Value::List(lst));
-                    self.stack.push(HeapPtr(i));
+                let [start, end, step] = args;
+                if step == 0 {
+                    return Err(Error::InvalidArgument(Native::Range, Value::Int(step)));
                 }
-                Op::JmpF(target) => {
-                    if self.pop_value()?.is_false() {
-                        next_pc = target;
+
+                // the length is computed up front (without overflow, on the distance between the
+                // ends), so it is limited as lists built by repetition are
+                let n = if (step > 0 && start < end) || (step < 0 && start > end) {
+                    let (distance, stride) = (start.abs_diff(end), step.unsigned_abs());
+                    distance / stride + u64::from(distance % stride != 0)
+                } else {
+                    0
+                };
+                if n > self.max_repeat_length as u64 {
+                    return Err(Error::AllocationTooLarge(n as usize));
+                }
+                let n = n as usize;
+
+                // as with keys, the integers are kept on the stack until the list is built
+                let mut i = start;
+                for _ in 0 .. n {
+                    self.push_value(Value::Int(i))?;
+                    // only wraps after the last element, that is inside the range
+                    i = i.wrapping_add(step);
+                }
+                let i = self.find_free_slot()?;
+                let lst = self.pop_n(n);
+                self.store_heap(i, Value::List(lst));
+                return Ok(HeapPtr(i))
+            }
+            Native::Min | Native::Max => {
+                // the chosen value itself is returned (not a copy); with a single list
+                // argument its elements are compared, and an empty list gives nil
+                let candidates = match self.dup_value(0)? {
+                    Value::List(lst) if nargs == 1 => lst.clone(),
+                    _ => (0 .. nargs).rev().map(|i| self.dup(i)).collect::<Result<Vec<_>>>()?,
+                };
+                let mut best = match candidates.first() {
+                    Some(ptr) => *ptr,
+                    None => return self.alloc(Value::Nil),
+                };
+                for ptr in candidates.into_iter().skip(1) {
+                    let current = self.get(best)?;
+                    let other = self.get(ptr)?;
+                    let chosen = match native {
+                        Native::Min => current.min(self, other)?,
+                        _ => current.max(self, other)?,
+                    };
+                    if !std::ptr::eq(chosen, current) {
+                        best = ptr;
                     }
                 }
-                Op::Jmp(target) => {
-                    next_pc = target;
+                return Ok(best)
+            }
+            Native::DumpStack => {
+                let prefix = if nargs > 0 {
+                    format!("{} ", self.dup_value(0)?.fmt(self, 0)?)
+                } else {
+                    "STACK> ".to_string()
+                };
+                self.write_output(&format!("{}{:?}\n", prefix, self.stack))?;
+                Value::Int(self.stack.len() as i64)
+            }
+            Native::DumpHeap => {
+                let dump = self.dump_heap()?;
+                self.write_output(&dump)?;
+                Value::Int(dump.lines().count() as i64)
+            }
+            Native::Weak => {
+                let ptr = self.dup(nargs - 1)?;
+                Value::Weak(Some(ptr))
+            }
+            Native::DerefWeak => {
+                match self.dup_value(nargs - 1)? {
+                    Value::Weak(Some(ptr)) => return Ok(*ptr),
+                    Value::Weak(None) => Value::Nil,
+                    other => return Err(Error::InvalidArgument(Native::DerefWeak, other.clone())),
                 }
-                Op::Native(nargs, native_op) => {
-                    // built-in functions handled directly in native code
-                    let value = match native_op {
-                        Native::Print => {
-                            for i in 0 .. nargs {
-                                print!("{}", self.dup_value(nargs - i - 1)?.fmt(self, 0)?);
-                            }
-                            println!();
-                            Value::Int(nargs as i64)
-                        }
-                        Native::Length => {
-                            let n = self.dup_value(0)?.length();
-                            Value::Int(n as i64)
-                        }
-                        Native::ToString => {
-                            let s = self.dup_value(0)?.fmt(self, 0)?;
-                            Value::Str(s)
+            }
+            Native::Flush => {
+                self.output.flush()?;
+                Value::Nil
+            }
+            Native::Ord => {
+                let arg = self.dup_value(nargs - 1)?;
+                match arg {
+                    Value::Str(s) => match s.chars().next() {
+                        Some(c) => Value::Int(c as i64),
+                        None => return Err(Error::InvalidArgument(Native::Ord, arg.clone())),
+                    },
+                    _ => return Err(Error::InvalidArgument(Native::Ord, arg.clone())),
+                }
+            }
+            Native::At => {
+                let container = self.dup_value(nargs - 1)?;
+                if let Value::Map(map) = container {
+                    let key = self.dup_value(nargs - 2)?;
+                    let key = key.as_key().ok_or_else(|| Error::InvalidKey(key.clone()))?;
+                    return match map.get(&key) {
+                        Some(ptr) => Ok(*ptr),
+                        None => self.alloc(Value::Nil),
+                    }
+                }
+
+                let index = match self.dup_value(nargs - 2)? {
+                    Value::Int(i) => *i,
+                    other => return Err(Error::InvalidArgument(Native::At, other.clone())),
+                };
+                match container {
+                    Value::Str(s) => {
+                        match Self::wrap_index(index, s.chars().count()).and_then(|i| s.chars().nth(i)) {
+                            Some(c) => Value::Str(c.to_string()),
+                            None => Value::Nil,
                         }
-                        Native::Append => {
-                            let mut to_add = vec![];
-                            for i in 1 .. nargs {
-                                let ptr = self.dup(nargs - i - 1)?;
-                                to_add.push(ptr);
-                            }
-
-                            let target = self.dup_value_mut(nargs - 1)?;
-                            match target {
-                                Value::List(lst) => {
-                                    lst.extend_from_slice(&to_add);
-                                    Value::Int(lst.len() as i64)
-                                }
-                                _ => return Err(Error::InvalidAppend(target.clone())),
-                            }
+                    }
+                    Value::List(lst) => {
+                        match Self::wrap_index(index, lst.len()) {
+                            Some(i) => return Ok(lst[i]),
+                            None => Value::Nil,
                         }
-                        Native::DumpStack => {
-                            if nargs > 0 {
-                                print!("{} ", self.dup_value(0)?.fmt(self, 0)?);
-                            } else {
-                                print!("STACK> ");
-                            }
-                            println!("{:?}", self.stack);
-                            Value::Int(self.stack.len() as i64)
+                    }
+                    _ => return Err(Error::InvalidArgument(Native::At, container.clone())),
+                }
+            }
+            Native::Resume => {
+                // continues the code of the generator, so it is run by `step`
+                return Err(Error::InvalidNativeCall(Native::Resume, nargs));
+            }
+            Native::ApproxEq => {
+                let mut args = [0.0, 0.0, DEFAULT_EPSILON];
+                for (i, arg) in args.iter_mut().take(nargs).enumerate() {
+                    let value = self.dup_value(nargs - i - 1)?;
+                    *arg = value.to_f64().ok_or_else(|| Error::InvalidArgument(Native::ApproxEq, value.clone()))?;
+                }
+                let [a, b, epsilon] = args;
+                Value::Int(if (a - b).abs() <= epsilon { 1 } else { 0 })
+            }
+            #[cfg(feature = "graphemes")]
+            Native::GraphemeLength => {
+                let n = self.dup_value(0)?.grapheme_length();
+                Value::Int(n as i64)
+            }
+            #[cfg(feature = "graphemes")]
+            Native::GraphemeAt => {
+                let s = match self.dup_value(nargs - 1)? {
+                    Value::Str(s) => s,
+                    other => return Err(Error::InvalidArgument(Native::GraphemeAt, other.clone())),
+                };
+                let index = match self.dup_value(nargs - 2)? {
+                    Value::Int(i) => *i,
+                    other => return Err(Error::InvalidArgument(Native::GraphemeAt, other.clone())),
+                };
+                match Self::wrap_index(index, s.graphemes(true).count()).and_then(|i| s.graphemes(true).nth(i)) {
+                    Some(g) => Value::Str(g.to_string()),
+                    None => Value::Nil,
+                }
+            }
+        };
+
+        self.alloc(value)
+    }
+
+    /// Run `program` on the VM, keeping the current memory state from any previous execution (globals).
+    ///
+    /// The output is always flushed at the end, even if execution failed.
+    pub fn run(&mut self, program: &Program) -> Result<()> {
+        self.run_with_limit(program, u64::MAX)
+    }
+
+    /// Run `program` as with `run`, executing at most `max_steps` opcodes.
+    ///
+    /// Fails with `StepLimitExceeded` when the limit is reached, so a script from an untrusted
+    /// source cannot run forever (e.g. `while 1 {}`).
+    pub fn run_with_limit(&mut self, program: &Program, max_steps: u64) -> Result<()> {
+        let result = self.execute(program, max_steps);
+        // what the failed instruction, or the host before running, dropped
+        self.free_released();
+        self.output.flush()?;
+        result
+    }
+
+    /// Call the function defined by the script as the global `name` with `args`, returning
+    /// the value it returns.
+    ///
+    /// This lets the host drive the script (e.g. calling an `on_event` handler), also from
+    /// a native called by the script.  As with `register_native`, pointers inside `args` must
+    /// be kept reachable, and those inside the result refer to this VM but are not kept by it.
+    pub fn run_function(&mut self, name: &str, args: &[Value]) -> Result<Value> {
+        let function = self.top.get(name).copied().ok_or_else(|| Error::GlobalNotFound(name.to_string()))?;
+        let depth = self.stack.len();
+        self.push(function);
+        let result = self.call_with(args);
+        // the value returned (or what was left by an error) is not kept
+        self.pop_n(self.stack.len().saturating_sub(depth));
+        self.free_released();
+        result
+    }
+
+    /// Call the function on top of the stack with `args`, leaving the value returned on the stack.
+    fn call_with(&mut self, args: &[Value]) -> Result<Value> {
+        for arg in args {
+            self.push_value(arg.clone())?;
+        }
+        self.run(&Program::new(vec![Op::Call(args.len())]))?;
+        self.get_clone(self.dup(0)?)
+    }
+
+    /// Run `program`, with the calls it makes, up to its end.
+    ///
+    /// Calls already in progress are left as they are, and so are the calls made by
+    /// `program` if it fails.
+    fn execute(&mut self, program: &Program, max_steps: u64) -> Result<()> {
+        let base = std::mem::replace(&mut self.frame_base, self.frames.len());
+        let result = self.execute_calls(program, max_steps);
+        // generators that failed cannot be resumed, as their slots are not kept
+        for frame in self.frames.split_off(self.frame_base) {
+            if let Some(Ok(Value::Generator(generator))) = frame.generator.map(|ptr| self.modify(ptr)) {
+                generator.running = false;
+                generator.done = true;
+            }
+        }
+        self.frame_base = base;
+        result
+    }
+
+    /// Main interpreter loop
+    fn execute_calls(&mut self, program: &Program, max_steps: u64) -> Result<()> {
+        // the function being run, `None` for `program` itself
+        let mut function: Option<Arc<Function>> = None;
+        let mut pc = 0;
+        let mut steps = 0;
+        loop {
+            let running = function.as_ref().map_or(program, |function| &function.program);
+            if pc >= running.code.len() {
+                // the code of a function must end with a return
+                return match function {
+                    None => Ok(()),
+                    Some(_) => Err(Error::InvalidOpCode(pc)),
+                };
+            }
+            if steps == max_steps {
+                return Err(Error::StepLimitExceeded.located(running.location(pc)));
+            }
+            steps += 1;
+            let depth = self.frames.len();
+            pc = self.step(&running.code, pc).map_err(|err| err.located(running.location(pc)))?;
+            if self.frames.len() != depth {
+                function = self.frames[self.frame_base ..].last().map(|frame| frame.function.clone());
+            }
+            if !self.released.is_empty() {
+                self.free_released();
+            }
+        }
+    }
+
+    /// Position in the stack of the local `slot` of the innermost call (by `Op` at `pc`)
+    fn local(&self, slot: usize, pc: usize) -> Result<usize> {
+        let frame = self.frames[self.frame_base ..].last().ok_or(Error::InvalidOpCode(pc))?;
+        match frame.fp.checked_add(slot) {
+            Some(i) if i < self.stack.len() => Ok(i),
+            _ => Err(Error::StackUnderflow),
+        }
+    }
+
+    /// Continue the call of the generator on top of the stack, from where it was suspended,
+    /// returning to `return_pc` when it yields or returns.
+    ///
+    /// The generator stays on the stack, below the frame of its call, that gets back the
+    /// slots the generator kept.  A generator that returned gives `nil`.
+    fn resume(&mut self, return_pc: usize) -> Result<usize> {
+        let ptr = self.dup(0)?;
+        if self.frames.len() >= self.max_call_depth {
+            return Err(Error::StackOverflow);
+        }
+        let generator = match self.modify(ptr)? {
+            Value::Generator(generator) => generator,
+            other => return Err(Error::InvalidArgument(Native::Resume, other.clone())),
+        };
+        if generator.running {
+            return Err(Error::GeneratorRunning);
+        }
+        if generator.done {
+            self.pop()?;
+            self.push_value(Value::Nil)?;
+            return Ok(return_pc);
+        }
+        generator.running = true;
+        let function = generator.function.clone();
+        let pc = generator.pc;
+        // the slots move to the stack, so their references are still counted
+        let slots = std::mem::take(&mut generator.slots);
+        let fp = self.stack.len();
+        self.stack.extend(slots);
+        self.frames.push(Frame { function, fp, return_pc, generator: Some(ptr) });
+        Ok(pc)
+    }
+
+    /// Replace the pointer at position `i` of the stack by `ptr`
+    fn store_local(&mut self, i: usize, ptr: HeapPtr) {
+        self.retain(ptr);
+        let previous = std::mem::replace(&mut self.stack[i], ptr);
+        self.release(previous);
+    }
+
+    /// Execute the opcode at `pc`, returning the position of the next one to execute
+    fn step(&mut self, code: &[Op], pc: usize) -> Result<usize> {
+        let mut next_pc = pc + 1;
+        match code[pc].clone() {
+            Op::Nop => {
+                // do nothing
+            }
+            Op::Target(_) => {
+                // jump targets must not happen in the output of compiler
+                return Err(Error::InvalidOpCode(pc));
+            }
+            Op::PushI(n) => {
+                self.push_value(Value::Int(n))?;
+            }
+            Op::PushI0 => {
+                self.push_value(Value::Int(0))?;
+            }
+            Op::PushI1 => {
+                self.push_value(Value::Int(1))?;
+            }
+            Op::PushIByte(n) => {
+                self.push_value(Value::Int(n as i64))?;
+            }
+            Op::PushNil => {
+                self.push_value(Value::Nil)?;
+            }
+            Op::PushF(n) => {
+                self.push_value(Value::Float(n))?;
+            }
+            Op::PushS(s) => {
+                match self.interned.as_ref().and_then(|h| h.get(&s)) {
+                    Some(ptr) => self.push(*ptr),
+                    None => {
+                        let ptr = self.push_value(Value::Str(s.clone()))?;
+                        if let Some(interned) = self.interned.as_mut() {
+                            interned.insert(s, ptr);
+                            self.retain(ptr);
                         }
-                    };
+                    }
+                }
+            }
+            Op::Dup(i) => {
+                let ptr = self.dup(i)?;
+                self.push(ptr);
+            }
+            Op::Pop => {
+                self.pop()?;
+            }
+            Op::Swap => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(b);
+                self.push(a);
+            }
+            Op::Line(line) => {
+                self.current_line = line;
+            }
+            Op::NotNil => {
+                let ptr = self.pop()?;
+                let not_nil = !matches!(self.get(ptr)?, Value::Nil);
+                self.push_value(Value::Int(not_nil as i64))?;
+            }
+            Op::LoadG(s) => {
+                match self.top.get(&s) {
+                    Some(ptr) => self.push(*ptr),
+                    None if self.undefined_as_nil => { self.push_value(Value::Nil)?; }
+                    None => return Err(Error::GlobalNotFound(s.clone())),
+                }
+            }
+            Op::StoreG(s) => {
+                let ptr = self.dup(0)?;
+                self.store_global(s, ptr);
+            }
+            Op::MoveG(s) => {
+                let ptr = self.pop()?;
+                self.store_global(s, ptr);
+            }
+            Op::LoadL(slot) => {
+                let ptr = self.stack[self.local(slot, pc)?];
+                self.push(ptr);
+            }
+            Op::StoreL(slot) => {
+                let i = self.local(slot, pc)?;
+                let ptr = self.dup(0)?;
+                self.store_local(i, ptr);
+            }
+            Op::MoveL(slot) => {
+                let i = self.local(slot, pc)?;
+                let ptr = self.dup(0)?;
+                self.store_local(i, ptr);
+                self.pop()?;
+            }
+            Op::Function(function) => {
+                self.push_value(Value::Func(function))?;
+            }
+            Op::Call(nargs) => {
+                if nargs >= self.stack.len() {
+                    return Err(Error::StackUnderflow);
+                }
+                let fp = self.stack.len() - nargs - 1;
+                let function = match self.get(self.stack[fp])? {
+                    Value::Func(function) => function.clone(),
+                    other => return Err(Error::UnexpectedType("function".to_string(), other.clone())),
+                };
+                // parameters are the first locals, any other count would leave them out of place
+                if nargs != function.arity {
+                    return Err(Error::ArityMismatch(function.name.clone(), nargs, function.arity));
+                }
+                if self.frames.len() >= self.max_call_depth {
+                    return Err(Error::StackOverflow);
+                }
+                for _ in 0 .. function.locals {
+                    self.push_value(Value::Nil)?;
+                }
+                if function.generator {
+                    // the call is only run by `resume`, the generator keeps its slots until then
+                    let slots = self.stack[fp ..].to_vec();
+                    let generator = Generator { function, pc: 0, slots, running: false, done: false };
+                    let ptr = self.alloc(Value::Generator(generator))?;
+                    self.pop_n(self.stack.len() - fp);
+                    self.push(ptr);
+                    return Ok(next_pc);
+                }
+                self.frames.push(Frame { function, fp, return_pc: next_pc, generator: None });
+                // continue from the start of the code of the function
+                return Ok(0);
+            }
+            Op::Return => {
+                // the calls made before this code started are not returned from
+                if self.frames.len() == self.frame_base {
+                    return Err(Error::InvalidOpCode(pc));
+                }
+                let frame = self.frames.pop().ok_or(Error::InvalidOpCode(pc))?;
+                if frame.fp >= self.stack.len() {
+                    return Err(Error::StackUnderflow);
+                }
+                // only the value returned is left, in place of the function
+                self.pop_n(self.stack.len() - frame.fp - 1);
+                if let Some(ptr) = frame.generator {
+                    // and it replaces the generator, as the result of the last `resume`
+                    if let Value::Generator(generator) = self.modify(ptr)? {
+                        generator.running = false;
+                        generator.done = true;
+                    }
+                    let value = self.pop()?;
+                    self.pop()?;
+                    self.push(value);
+                }
+                return Ok(frame.return_pc);
+            }
+            Op::Yield => {
+                let ptr = match self.frames[self.frame_base ..].last() {
+                    Some(Frame { generator: Some(ptr), .. }) => *ptr,
+                    _ => return Err(Error::InvalidOpCode(pc)),
+                };
+                let value = self.pop()?;
+                let frame = self.frames.pop().ok_or(Error::InvalidOpCode(pc))?;
+                if frame.fp > self.stack.len() {
+                    return Err(Error::StackUnderflow);
+                }
+                // the slots move back to the generator, so their references are still counted
+                let slots = self.stack.split_off(frame.fp);
+                match self.modify(ptr)? {
+                    Value::Generator(generator) => {
+                        generator.slots = slots;
+                        generator.pc = next_pc;
+                        generator.running = false;
+                    }
+                    _ => return Err(Error::InvalidOpCode(pc)),
+                }
+                // the value yielded replaces the generator, as the result of `resume`
+                self.pop()?;
+                self.push(value);
+                return Ok(frame.return_pc);
+            }
+            Op::MakeList(n) => {
+                if n > self.stack.len() {
+                    return Err(Error::StackUnderflow);
+                }
+                let i = self.find_free_slot()?;
+                let lst = self.pop_n(n);
+                self.store_heap(i, Value::List(lst));
+                self.push(HeapPtr(i));
+            }
+            Op::Interpolate(n) => {
+                if n > self.stack.len() {
+                    return Err(Error::StackUnderflow);
+                }
+                let mut s = String::new();
+                for i in (0 .. n).rev() {
+                    s += &self.dup_value(i)?.fmt(self, 0)?;
+                }
+                self.pop_n(n);
+                self.push_value(Value::Str(s))?;
+            }
+            Op::MakeMap(n) => {
+                if n > self.stack.len() / 2 {
+                    return Err(Error::StackUnderflow);
+                }
+                let i = self.find_free_slot()?;
+                let entries = self.pop_n(2 * n);
+                let mut map = HashMap::with_capacity(n);
+                for pair in entries.chunks(2) {
+                    let key = self.get(pair[0])?;
+                    map.insert(key.as_key().ok_or_else(|| Error::InvalidKey(key.clone()))?, pair[1]);
+                }
+                self.store_heap(i, Value::Map(map));
+                self.push(HeapPtr(i));
+            }
+            Op::JmpF(target) => {
+                if self.pop_value()?.is_false() {
+                    next_pc = target;
+                }
+            }
+            Op::JmpT(target) => {
+                if self.pop_value()?.is_truthy() {
+                    next_pc = target;
+                }
+            }
+            Op::Jmp(target) => {
+                next_pc = target;
+            }
+            Op::JmpTable(base, targets) => {
+                let ptr = self.pop()?;
+                let value = self.get(ptr)?;
+                // a float is at the index of the integer it is equal to, as `==` compares
+                let n = match value {
+                    Value::Int(n) => Some(*n),
+                    Value::Float(f) => Some(*f as i64).filter(|n| value.equals(self, &Value::Int(*n)).unwrap_or(false)),
+                    _ => None,
+                };
+                let index = n.and_then(|n| n.checked_sub(base)).filter(|i| *i >= 0 && *i < targets.len() as i64);
+                if let Some(target) = index.map(|i| &targets[i as usize]) {
+                    next_pc = *target;
+                }
+            }
+            Op::Native(1, Native::Resume) => {
+                return self.resume(next_pc);
+            }
+            Op::Native(nargs, native_op) => {
+                // code may not come from the compiler, so check the arguments
+                if !native_op.accepts(nargs) {
+                    return Err(Error::InvalidNativeCall(native_op, nargs));
+                }
+                if nargs > self.stack.len() {
+                    return Err(Error::StackUnderflow);
+                }
+
+                // built-in functions handled directly in native code
+                let ret = self.native(nargs, &native_op)?;
+
+                // pop all arguments -- even unused ones!
+                for _ in 0 .. nargs {
+                    self.pop()?;
+                }
+
+                // push single return value
+                self.push(ret);
+            }
+            Op::Foreign(nargs, name) => {
+                let (arity, f) = match self.foreign.get(&name) {
+                    Some((arity, f)) => (*arity, f.clone()),
+                    None => return Err(Error::UnknownForeign(name)),
+                };
+                if nargs != arity {
+                    return Err(Error::InvalidForeignCall(name, nargs));
+                }
+                if nargs > self.stack.len() {
+                    return Err(Error::StackUnderflow);
+                }
+
+                // arguments stay on the stack (as roots) until the result is allocated
+                let base = self.stack.len() - nargs;
+                let args = self.stack[base ..].to_vec();
+                let value = f(self, &args)?;
+                let ret = self.alloc(value)?;
+                self.pop_n(self.stack.len().saturating_sub(base));
+                self.push(ret);
+            }
+            Op::Lt | Op::Lte | Op::Gt | Op::Gte => {
+                let bptr = self.pop()?;
+                let aptr = self.pop()?;
+
+                let b = self.get(bptr)?;
+                let a = self.get(aptr)?;
+                let c = a.cmp(self, b)?;
+                let c = match &code[pc] {
+                    Op::Lt => c < 0,
+                    Op::Lte => c <= 0,
+                    Op::Gt => c > 0,
+                    _ => c >= 0,
+                };
+                self.push_value(Value::Int(if c { 1 } else { 0 }))?;
+            }
+            Op::Eq => {
+                let bptr = self.pop()?;
+                let aptr = self.pop()?;
+
+                let b = self.get(bptr)?;
+                let a = self.get(aptr)?;
+                let c = Self::same_value(aptr, bptr, a) || a.equals(self, b)?;
+                self.push_value(Value::Int(if c { 1 } else { 0 }))?;
+            }
+            Op::Neq => {
+                let bptr = self.pop()?;
+                let aptr = self.pop()?;
+
+                let b = self.get(bptr)?;
+                let a = self.get(aptr)?;
+                let c = !(Self::same_value(aptr, bptr, a) || a.equals(self, b)?);
+                self.push_value(Value::Int(if c { 1 } else { 0 }))?;
+            }
+            Op::Neg => {
+                let a = self.pop_value()?.neg()?;
+                self.push_value(a)?;
+            }
+            Op::Not => {
+                let c = self.pop_value()?.is_false();
+                self.push_value(Value::Int(if c { 1 } else { 0 }))?;
+            }
+            Op::Add => {
+                let bptr = self.pop()?;
+                let aptr = self.pop()?;
+
+                let b = self.get(bptr)?;
+                let a = self.get(aptr)?;
+                let c = a.add(b)?;
+                self.push_value(c)?;
+            }
+            Op::Concat => {
+                let bptr = self.pop()?;
+                let aptr = self.pop()?;
 
-                    // pop all arguments -- even unused ones!
-                    for _ in 0 .. nargs {
-                        self.pop()?;
+                let b = self.get(bptr)?;
+                let a = self.get(aptr)?;
+                match (a, b) {
+                    (Value::Str(a), Value::Str(b)) => {
+                        let c = Value::Str(a.to_owned() + b);
+                        self.push_value(c)?;
                     }
+                    _ => return Err(Error::IncompatibleOperands(Op::Concat, a.clone(), b.clone())),
+                }
+            }
+            Op::AddI | Op::SubI | Op::MulI => {
+                let bptr = self.pop()?;
+                let aptr = self.pop()?;
 
-                    // push single return value
-                    self.push_value(value);
-                }
-                Op::Lt => {
-                    let bptr = self.pop()?;
-                    let aptr = self.pop()?;
-
-                    let b = self.get(bptr)?;
-                    let a = self.get(aptr)?;
-                    let c = a.cmp(self, b)? < 0;
-                    self.push_value(Value::Int(if c { 1 } else { 0 }));
-                }
-                Op::Lte => {
-                    let bptr = self.pop()?;
-                    let aptr = self.pop()?;
-
-                    let b = self.get(bptr)?;
-                    let a = self.get(aptr)?;
-                    let c = a.cmp(self, b)? <= 0;
-                    self.push_value(Value::Int(if c { 1 } else { 0 }));
-                }
-                Op::Gt => {
-                    let bptr = self.pop()?;
-                    let aptr = self.pop()?;
-
-                    let b = self.get(bptr)?;
-                    let a = self.get(aptr)?;
-                    let c = a.cmp(self, b)? > 0;
-                    self.push_value(Value::Int(if c { 1 } else { 0 }));
-                }
-                Op::Gte => {
-                    let bptr = self.pop()?;
-                    let aptr = self.pop()?;
-
-                    let b = self.get(bptr)?;
-                    let a = self.get(aptr)?;
-                    let c = a.cmp(self, b)? >= 0;
-                    self.push_value(Value::Int(if c { 1 } else { 0 }));
-                }
-                Op::Eq => {
-                    let bptr = self.pop()?;
-                    let aptr = self.pop()?;
-
-                    let b = self.get(bptr)?;
-                    let a = self.get(aptr)?;
-                    let c = a.cmp(self, b)? == 0;
-                    self.push_value(Value::Int(if c { 1 } else { 0 }));
-                }
-                Op::Neq => {
-                    let bptr = self.pop()?;
-                    let aptr = self.pop()?;
-
-                    let b = self.get(bptr)?;
-                    let a = self.get(aptr)?;
-                    let c = a.cmp(self, b)? != 0;
-                    self.push_value(Value::Int(if c { 1 } else { 0 }));
-                }
-                Op::Add => {
-                    let bptr = self.pop()?;
-                    let aptr = self.pop()?;
-
-                    let b = self.get(bptr)?;
-                    let a = self.get(aptr)?;
-                    let c = a.add(b)?;
-                    self.push_value(c);
-                }
-                Op::Sub => {
-                    let bptr = self.pop()?;
-                    let aptr = self.pop()?;
-
-                    let b = self.get(bptr)?;
-                    let a = self.get(aptr)?;
-                    let c = a.sub(b)?;
-                    self.push_value(c);
-                }
-                Op::Mul => {
-                    let bptr = self.pop()?;
-                    let aptr = self.pop()?;
-
-                    let b = self.get(bptr)?;
-                    let a = self.get(aptr)?;
-                    let c = a.mul(b)?;
-                    self.push_value(c);
-                }
-                Op::Div => {
-                    let bptr = self.pop()?;
-                    let aptr = self.pop()?;
-
-                    let b = self.get(bptr)?;
-                    let a = self.get(aptr)?;
-                    let c = a.div(b)?;
-                    self.push_value(c);
-                }
-                Op::Mod => {
-                    let bptr = self.pop()?;
-                    let aptr = self.pop()?;
-
-                    let b = self.get(bptr)?;
-                    let a = self.get(aptr)?;
-                    let c = a.r#mod(b)?;
-                    self.push_value(c);
-                }
-                Op::Index => {
-                    let bptr = self.pop()?;
-                    let aptr = self.pop()?;
-
-                    let b = self.get(bptr)?;
-                    let a = self.get(aptr)?;
-                    match (a, b) {
-                        (Value::Str(s), Value::Int(i)) => {
-                            let ch = s.chars().nth(*i as usize).ok_or_else(|| Error::IndexOutOfRange(a.clone(), *i as usize))?;
-                            self.push_value(Value::Int(ch as i64));
-                        }
-                        (Value::List(lst), Value::Int(i)) => {
-                            let ptr = *lst.get(*i as usize).ok_or_else(|| Error::IndexOutOfRange(a.clone(), *i as usize))?;
-                            self.push(ptr);
-                        }
-                        _ => {
-                            return Err(Error::IncompatibleOperands(Op::Index, a.clone(), b.clone()))
+                let b = self.get(bptr)?;
+                let a = self.get(aptr)?;
+                let c = match (&code[pc], a, b) {
+                    (Op::AddI, Value::Int(a), Value::Int(b)) => a.checked_add(*b),
+                    (Op::SubI, Value::Int(a), Value::Int(b)) => a.checked_sub(*b),
+                    (Op::MulI, Value::Int(a), Value::Int(b)) => a.checked_mul(*b),
+                    (op, _, _) => return Err(Error::IncompatibleOperands(op.clone(), a.clone(), b.clone())),
+                };
+                self.push_value(Value::Int(c.ok_or(Error::IntegerOverflow)?))?;
+            }
+            Op::Sub => {
+                let bptr = self.pop()?;
+                let aptr = self.pop()?;
+
+                let b = self.get(bptr)?;
+                let a = self.get(aptr)?;
+                let c = a.sub(b)?;
+                self.push_value(c)?;
+            }
+            Op::Mul => {
+                let bptr = self.pop()?;
+                let aptr = self.pop()?;
+
+                let b = self.get(bptr)?;
+                let a = self.get(aptr)?;
+                let c = a.mul(b, self.max_repeat_length)?;
+                self.push_value(c)?;
+            }
+            Op::Div => {
+                let bptr = self.pop()?;
+                let aptr = self.pop()?;
+
+                let b = self.get(bptr)?;
+                let a = self.get(aptr)?;
+                let c = a.div(b)?;
+                self.push_value(c)?;
+            }
+            Op::Mod => {
+                let bptr = self.pop()?;
+                let aptr = self.pop()?;
+
+                let b = self.get(bptr)?;
+                let a = self.get(aptr)?;
+                let c = a.r#mod(b)?;
+                self.push_value(c)?;
+            }
+            Op::Shl | Op::Shr => {
+                let bptr = self.pop()?;
+                let aptr = self.pop()?;
+
+                let b = self.get(bptr)?;
+                let a = self.get(aptr)?;
+                let c = a.shift(code[pc].clone(), b)?;
+                self.push_value(c)?;
+            }
+            Op::Index => {
+                let bptr = self.pop()?;
+                let aptr = self.pop()?;
+
+                let b = self.get(bptr)?;
+                let a = self.get(aptr)?;
+                match (a, b) {
+                    (Value::Str(s), Value::Int(i)) => {
+                        let ch = s.chars().nth(*i as usize).ok_or_else(|| Error::IndexOutOfRange(a.clone(), *i as usize))?;
+                        self.push_value(Value::Str(ch.to_string()))?;
+                    }
+                    (Value::List(lst), Value::Int(i)) => {
+                        let ptr = *lst.get(*i as usize).ok_or_else(|| Error::IndexOutOfRange(a.clone(), *i as usize))?;
+                        self.push(ptr);
+                    }
+                    (Value::Map(map), _) => {
+                        let key = b.as_key().ok_or_else(|| Error::InvalidKey(b.clone()))?;
+                        match map.get(&key) {
+                            Some(ptr) => self.push(*ptr),
+                            None if self.missing_key_as_nil => { self.push_value(Value::Nil)?; }
+                            None => return Err(Error::KeyNotFound(b.clone())),
                         }
                     }
+                    _ => {
+                        return Err(Error::IncompatibleOperands(Op::Index, a.clone(), b.clone()))
+                    }
                 }
-                Op::IndexStore => {
-                    let cptr = self.pop()?;
-                    let bptr = self.pop()?;
-                    let aptr = self.dup(0)?;
-
-                    let index = {
-                        let b = self.get(bptr)?;
-                        if let Value::Int(n) = b {
-                            *n as usize
-                        } else {
-                            return Err(Error::IncompatibleOperands(Op::IndexStore, self.get(cptr)?.clone(), b.clone()))
-                        }
-                    };
+            }
+            Op::IndexInto => {
+                let bptr = self.pop()?;
+                let aptr = self.pop()?;
 
-                    let c = self.get_mut(cptr)?;
-                    match c {
-                        Value::List(lst) => {
-                            match lst.get_mut(index) {
-                                Some(p) => *p = aptr,
-                                None => return Err(Error::IndexOutOfRange(c.clone(), index)),
-                            }
-                        }
-                        _ => {
-                            return Err(Error::IncompatibleOperands(Op::IndexStore, c.clone(), self.get(bptr)?.clone()))
+                let b = self.get(bptr)?;
+                let a = self.get(aptr)?;
+                let found = match (a, b) {
+                    (Value::List(lst), Value::Int(i)) => lst.get(*i as usize).copied(),
+                    (Value::Map(map), _) => map.get(&b.as_key().ok_or_else(|| Error::InvalidKey(b.clone()))?).copied(),
+                    _ => return Err(Error::IncompatibleOperands(Op::IndexInto, a.clone(), b.clone())),
+                };
+                match found.map(|ptr| self.get(ptr).map(|value| (ptr, value))).transpose()? {
+                    Some((ptr, Value::List(_) | Value::Map(_))) => self.push(ptr),
+                    Some((_, other)) => return Err(Error::CannotIndexInto(b.clone(), other.clone())),
+                    None => return Err(Error::CannotIndexInto(b.clone(), Value::Nil)),
+                }
+            }
+            Op::IndexStore => {
+                let cptr = self.pop()?;
+                let bptr = self.pop()?;
+                let aptr = self.dup(0)?;
+
+                let b = self.get(bptr)?.clone();
+                let c = self.modify(cptr)?;
+                let replaced = match (c, &b) {
+                    (Value::List(lst), Value::Int(n)) => {
+                        let index = *n as usize;
+                        match lst.get_mut(index) {
+                            Some(p) => Some(std::mem::replace(p, aptr)),
+                            None => return Err(Error::IndexOutOfRange(Value::List(lst.clone()), index)),
                         }
                     }
+                    (Value::Map(map), _) => {
+                        let key = b.as_key().ok_or_else(|| Error::InvalidKey(b.clone()))?;
+                        map.insert(key, aptr)
+                    }
+                    (c, _) => {
+                        return Err(Error::IncompatibleOperands(Op::IndexStore, c.clone(), b))
+                    }
+                };
+                self.retain(aptr);
+                if let Some(ptr) = replaced {
+                    self.release(ptr);
                 }
-                // _ => {
-                //     panic!("not supported: {:?}", code[pc]);
-                // }
             }
-            pc = next_pc;
+            Op::Slice => {
+                let cptr = self.pop()?;
+                let bptr = self.pop()?;
+                let aptr = self.pop()?;
+
+                let a = self.get(aptr)?;
+                let (start, end) = match (self.get(bptr)?, self.get(cptr)?) {
+                    (Value::Int(start), Value::Int(end)) => (*start, *end),
+                    (b, c) => return Err(Error::IncompatibleOperands(Op::Slice, b.clone(), c.clone())),
+                };
+                let value = match a {
+                    Value::Str(s) => {
+                        let chars: Vec<char> = s.chars().collect();
+                        Value::Str(chars[Self::slice_range(start, end, chars.len())].iter().collect())
+                    }
+                    Value::List(lst) => {
+                        Value::List(lst[Self::slice_range(start, end, lst.len())].to_vec())
+                    }
+                    _ => return Err(Error::IncompatibleOperands(Op::Slice, a.clone(), self.get(bptr)?.clone())),
+                };
+                self.push_value(value)?;
+            }
+            Op::SliceStore => {
+                let dptr = self.pop()?;
+                let cptr = self.pop()?;
+                let bptr = self.pop()?;
+                let aptr = self.dup(0)?;
+
+                let (start, end) = match (self.get(bptr)?, self.get(cptr)?) {
+                    (Value::Int(start), Value::Int(end)) => (*start, *end),
+                    (b, c) => return Err(Error::IncompatibleOperands(Op::SliceStore, b.clone(), c.clone())),
+                };
+                // copy the replacement first, as it may be the target itself
+                let replacement = match self.get(aptr)? {
+                    Value::List(lst) => lst.clone(),
+                    a => return Err(Error::IncompatibleOperands(Op::SliceStore, self.get(dptr)?.clone(), a.clone())),
+                };
+
+                let d = self.modify(dptr)?;
+                let removed: Vec<HeapPtr> = match d {
+                    Value::List(lst) => {
+                        let range = Self::slice_range(start, end, lst.len());
+                        lst.splice(range, replacement.iter().copied()).collect()
+                    }
+                    _ => {
+                        return Err(Error::IncompatibleOperands(Op::SliceStore, d.clone(), self.get(aptr)?.clone()))
+                    }
+                };
+                replacement.into_iter().for_each(|ptr| self.retain(ptr));
+                removed.into_iter().for_each(|ptr| self.release(ptr));
+            }
+            // _ => {
+            //     panic!("not supported: {:?}", code[pc]);
+            // }
         }
-        Ok(())
+        // a jump to `code.len()` ends the program normally, past it the code is malformed
+        if next_pc > code.len() {
+            return Err(Error::InvalidOpCode(pc));
+        }
+        Ok(next_pc)
     }
 }
@@ -0,0 +1,170 @@
+use std::{cell::RefCell, io::Write, rc::Rc, sync::Arc};
+use script::{bytecode, compiler::Compiler, errors::Error, lexer::Lexer, opcodes::{Native, Op}, parser::Parser, program::{Function, Program}, vm::VM};
+
+/// Position of the variant of `op` in `Op`, so a new opcode fails to compile here until covered
+fn variant(op: &Op) -> usize {
+    match op {
+        Op::Target(_) => 0,
+        Op::Nop => 1,
+        Op::Native(_, _) => 2,
+        Op::Foreign(_, _) => 3,
+        Op::PushI(_) => 4,
+        Op::PushI0 => 5,
+        Op::PushI1 => 6,
+        Op::PushIByte(_) => 7,
+        Op::PushF(_) => 8,
+        Op::PushNil => 9,
+        Op::PushS(_) => 10,
+        Op::MakeList(_) => 11,
+        Op::MakeMap(_) => 12,
+        Op::Interpolate(_) => 13,
+        Op::Index => 14,
+        Op::IndexStore => 15,
+        Op::Slice => 16,
+        Op::SliceStore => 17,
+        Op::Dup(_) => 18,
+        Op::Pop => 19,
+        Op::Swap => 20,
+        Op::Line(_) => 21,
+        Op::NotNil => 22,
+        Op::LoadG(_) => 23,
+        Op::StoreG(_) => 24,
+        Op::MoveG(_) => 25,
+        Op::Lt => 26,
+        Op::Lte => 27,
+        Op::Gt => 28,
+        Op::Gte => 29,
+        Op::Eq => 30,
+        Op::Neq => 31,
+        Op::JmpF(_) => 32,
+        Op::JmpT(_) => 33,
+        Op::Jmp(_) => 34,
+        Op::Add => 35,
+        Op::Sub => 36,
+        Op::Mul => 37,
+        Op::Div => 38,
+        Op::Mod => 39,
+        Op::Shl => 40,
+        Op::Shr => 41,
+        Op::Neg => 42,
+        Op::Not => 43,
+        Op::Concat => 44,
+        Op::AddI => 45,
+        Op::SubI => 46,
+        Op::MulI => 47,
+        Op::Function(_) => 48,
+        Op::Call(_) => 49,
+        Op::Return => 50,
+        Op::LoadL(_) => 51,
+        Op::StoreL(_) => 52,
+        Op::MoveL(_) => 53,
+        Op::Yield => 54,
+        Op::JmpTable(_, _) => 55,
+        Op::IndexInto => 56,
+    }
+}
+
+const VARIANTS: usize = 57;
+
+#[test]
+fn every_opcode_round_trips() {
+    let mut code = vec![
+        Op::Target(7), Op::Nop, Op::Foreign(2, "host_fn".to_string()),
+        Op::PushI(0), Op::PushI(-1), Op::PushI(i64::MAX), Op::PushI(i64::MIN),
+        Op::PushI0, Op::PushI1, Op::PushIByte(-128), Op::PushIByte(127),
+        Op::PushF(0.5), Op::PushF(-0.0), Op::PushF(f64::INFINITY), Op::PushF(f64::NAN),
+        Op::PushNil, Op::PushS(String::new()), Op::PushS("ünï\u{1F600}\n".to_string()),
+        Op::MakeList(0), Op::MakeMap(300), Op::Interpolate(3),
+        Op::Index, Op::IndexInto, Op::IndexStore, Op::Slice, Op::SliceStore,
+        Op::Dup(0), Op::Dup(usize::MAX), Op::Pop, Op::Swap, Op::Line(1 << 40), Op::NotNil,
+        Op::LoadG("a".to_string()), Op::StoreG("b".to_string()), Op::MoveG("c".to_string()),
+        Op::Lt, Op::Lte, Op::Gt, Op::Gte, Op::Eq, Op::Neq,
+        Op::JmpF(1), Op::JmpT(128), Op::Jmp(usize::MAX),
+        Op::Add, Op::Sub, Op::Mul, Op::Div, Op::Mod, Op::Shl, Op::Shr, Op::Neg, Op::Not,
+        Op::Concat, Op::AddI, Op::SubI, Op::MulI,
+        Op::Function(Arc::new(Function {
+            name: "f".to_string(),
+            arity: 2,
+            locals: 1,
+            generator: true,
+            program: Program { code: vec![Op::LoadL(1), Op::StoreL(3), Op::MoveL(0), Op::Yield, Op::Return], source_map: vec![0 .. 1, 2 .. 3, 4 .. 5, 6 .. 7, 8 .. 9], diagnostics: vec![] },
+        })),
+        Op::Call(3), Op::Return, Op::LoadL(0), Op::StoreL(1), Op::MoveL(usize::MAX), Op::Yield,
+        Op::JmpTable(i64::MIN, vec![]), Op::JmpTable(-3, vec![0, 300, usize::MAX]),
+    ];
+    code.extend(Native::ALL.iter().enumerate().map(|(nargs, native)| Op::Native(nargs, native.clone())));
+
+    let mut covered = vec![false; VARIANTS];
+    for op in &code {
+        covered[variant(op)] = true;
+    }
+    assert!(covered.iter().all(|covered| *covered), "not covered: {:?}", covered);
+
+    let read = bytecode::deserialize(&bytecode::serialize(&code)).unwrap();
+    assert_eq!(format!("{:?}", read), format!("{:?}", code));
+    // the sign of zero and NaN survive too
+    assert!(matches!(read[12], Op::PushF(n) if n == 0.0 && n.is_sign_negative()));
+    assert!(matches!(read[14], Op::PushF(n) if n.is_nan()));
+}
+
+/// A `Write` whose output can be read after it is given to a VM
+#[derive(Clone, Default)]
+struct Shared(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Output of running `program`
+fn output(program: &Program) -> String {
+    let out = Shared::default();
+    VM::new().with_output(out.clone()).run(program).unwrap();
+    let bytes = out.0.borrow().clone();
+    String::from_utf8(bytes).unwrap()
+}
+
+#[test]
+fn deserialized_code_runs_the_same() {
+    let source = "
+        m = {\"a\": [1, 2.5, nil], 3: \"x\"};
+        m[\"b\"] = m[\"a\"][0:2];
+        i = 0;
+        while i < 5 { i = i + 1; if i % 2 == 0 { continue; } print(\"${i}: ${i << 2} ${-i >> 1}\"); }
+        print(m, \" \", !(1 <= 2) || 3 >= 3 && 4 != 5, 7 / 2 * 2 - 1);
+    ";
+    let mut parser = Parser::new(Lexer::new(source)).unwrap();
+    let mut compiler = Compiler::new();
+    while let Some(ast) = parser.next().unwrap() {
+        compiler.feed(&ast).unwrap();
+    }
+    let program = compiler.build().unwrap();
+    let read = Program::new(bytecode::deserialize(&bytecode::serialize(&program.code)).unwrap());
+    assert_eq!(output(&read), output(&program));
+    assert_eq!(output(&program), "1: 4 -1\n3: 12 -2\n5: 20 -3\n{3: x, a: [1, 2.5, nil], b: [1, 2.5]} 15\n");
+}
+
+#[test]
+fn functions_round_trip_with_their_source_map() {
+    let source = "fun show(a, b) { let c = a * b; print(\"${a} * ${b} = ${c}\"); }\nshow(3, 4); show(5, 6);";
+    let mut parser = Parser::new(Lexer::new(source)).unwrap();
+    let mut compiler = Compiler::new();
+    while let Some(ast) = parser.next().unwrap() {
+        compiler.feed(&ast).unwrap();
+    }
+    let program = compiler.build().unwrap();
+    let read = bytecode::deserialize_program(&bytecode::serialize_program(&program)).unwrap();
+    assert_eq!(format!("{:?}", read.code), format!("{:?}", program.code));
+    assert_eq!(output(&read), "3 * 4 = 12\n5 * 6 = 30\n");
+
+    // functions are not defined inside functions, not even in bytecode
+    let inner = Function { name: "g".to_string(), arity: 0, locals: 0, generator: false, program: Program::new(vec![Op::Return]) };
+    let outer = Function { name: "f".to_string(), arity: 0, locals: 0, generator: false, program: Program::new(vec![Op::Function(Arc::new(inner)), Op::Return]) };
+    let bytes = bytecode::serialize(&[Op::Function(Arc::new(outer))]);
+    assert!(matches!(bytecode::deserialize(&bytes), Err(Error::CorruptBytecode(_))));
+}
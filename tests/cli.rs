@@ -0,0 +1,138 @@
+use std::{
+    path::PathBuf,
+    process::{Command, Output},
+};
+
+/// Path of a scratch file `name` for the test `test`, removed first if left by a previous run
+fn scratch(test: &str, name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("script-cli-{}", test));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+fn script(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_script")).args(args).output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn compile_and_run_round_trip() {
+    let source = scratch("round_trip", "prog.script");
+    let bytecode = scratch("round_trip", "prog.bc");
+    std::fs::write(&source, "l = [];\nfor (i = 0; i < 3; i = i + 1) { l = l + [i * i]; }\nprint(\"${l} ${length(l)}\");\n").unwrap();
+
+    let compiled = script(&["compile", source.to_str().unwrap(), "-o", bytecode.to_str().unwrap()]);
+    assert!(compiled.status.success(), "{}", stderr(&compiled));
+
+    let from_bytecode = script(&["run", bytecode.to_str().unwrap()]);
+    assert!(from_bytecode.status.success(), "{}", stderr(&from_bytecode));
+    assert_eq!(stdout(&from_bytecode), "[0, 1, 4] 3\n");
+
+    let from_source = script(&["run", source.to_str().unwrap()]);
+    assert!(from_source.status.success(), "{}", stderr(&from_source));
+    assert_eq!(stdout(&from_source), stdout(&from_bytecode));
+}
+
+#[test]
+fn compile_errors_exit_with_1() {
+    let source = scratch("compile_errors", "bad.script");
+    let bytecode = scratch("compile_errors", "bad.bc");
+    std::fs::write(&source, "x = (1 + ;\n").unwrap();
+
+    let compiled = script(&["compile", source.to_str().unwrap(), "-o", bytecode.to_str().unwrap()]);
+    assert_eq!(compiled.status.code(), Some(1));
+    assert!(stderr(&compiled).contains("error:"));
+    assert!(!bytecode.exists());
+
+    let missing = scratch("compile_errors", "missing.script");
+    let compiled = script(&["compile", missing.to_str().unwrap()]);
+    assert_eq!(compiled.status.code(), Some(1));
+    assert!(stderr(&compiled).contains("cannot read"));
+}
+
+#[test]
+fn run_errors_exit_with_1() {
+    // output before the error is still written
+    let source = scratch("run_errors", "fails.script");
+    std::fs::write(&source, "print(\"before\");\nx = [1] - 1;\n").unwrap();
+    let ran = script(&["run", source.to_str().unwrap()]);
+    assert_eq!(ran.status.code(), Some(1));
+    assert_eq!(stdout(&ran), "before\n");
+    assert!(stderr(&ran).contains("error:"));
+
+    let not_utf8 = scratch("run_errors", "latin1.script");
+    std::fs::write(&not_utf8, b"print(\"\xe9\");\n").unwrap();
+    let ran = script(&["run", not_utf8.to_str().unwrap()]);
+    assert_eq!(ran.status.code(), Some(1));
+    assert!(stderr(&ran).contains("cannot read"));
+
+    let truncated = scratch("run_errors", "truncated.bc");
+    std::fs::write(&truncated, b"SCRB\x02\xff").unwrap();
+    let ran = script(&["run", truncated.to_str().unwrap()]);
+    assert_eq!(ran.status.code(), Some(1));
+
+    let missing = scratch("run_errors", "missing.bc");
+    let ran = script(&["run", missing.to_str().unwrap()]);
+    assert_eq!(ran.status.code(), Some(1));
+    assert!(stderr(&ran).contains("cannot read"));
+}
+
+#[test]
+fn check_reports_errors_in_source_order() {
+    let source = scratch("check_order", "bad.script");
+    // compile errors on lines 1 and 4, syntax errors on lines 2 and 3
+    std::fs::write(&source, "break;\nx = (1 + ;\ny = ];\n\"unknown\"(1);\nz = 1;\n").unwrap();
+
+    let checked = script(&["--check", source.to_str().unwrap()]);
+    assert_eq!(checked.status.code(), Some(1));
+    let lines: Vec<usize> = stderr(&checked).lines()
+        .filter_map(|line| line.strip_prefix(&format!("{}:", source.to_str().unwrap())))
+        .map(|address| address.split(':').next().unwrap().parse().unwrap())
+        .collect();
+    assert_eq!(lines, vec![1, 2, 3, 4]);
+}
+
+#[test]
+fn check_exits_with_0_without_errors() {
+    let source = scratch("check_clean", "good.script");
+    std::fs::write(&source, "x = 1;\nprint(x);\n").unwrap();
+
+    let checked = script(&["--check", source.to_str().unwrap()]);
+    assert_eq!(checked.status.code(), Some(0), "{}", stderr(&checked));
+    // nothing is run
+    assert_eq!(stdout(&checked), "");
+}
+
+#[test]
+fn print_result_prints_the_last_expression() {
+    let source = scratch("print_result", "expr.script");
+    std::fs::write(&source, "x = 40;\nprint(\"first\");\nx + 2;\n").unwrap();
+    let ran = script(&["run", "--print-result", source.to_str().unwrap()]);
+    assert_eq!(ran.status.code(), Some(0), "{}", stderr(&ran));
+    assert_eq!(stdout(&ran), "first\n42\n");
+
+    // only with the flag
+    let ran = script(&["run", source.to_str().unwrap()]);
+    assert_eq!(ran.status.code(), Some(0));
+    assert_eq!(stdout(&ran), "first\n");
+
+    std::fs::write(&source, "[1, \"b\", {\"k\": nil}];\n").unwrap();
+    let ran = script(&["run", "--print-result", source.to_str().unwrap()]);
+    assert_eq!(stdout(&ran), "[1, b, {k: nil}]\n");
+
+    // nothing is printed after an error
+    std::fs::write(&source, "42;\nx = [1] - 1;\n").unwrap();
+    let ran = script(&["run", "--print-result", source.to_str().unwrap()]);
+    assert_eq!(ran.status.code(), Some(1));
+    assert_eq!(stdout(&ran), "");
+    assert!(stderr(&ran).contains("error:"));
+}
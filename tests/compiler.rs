@@ -0,0 +1,417 @@
+#![allow(clippy::result_large_err)]
+
+use script::{ast::Ast, bytecode, compiler::{to_rpn, Compiler}, diagnostic::{Diagnostic, Severity}, errors::Error, eval, eval_on, lexer::Lexer, opcodes::{disassemble, Op}, parser::Parser, program::Program, value::Value, vm::VM};
+
+/// Mnemonics of the code compiled from `source`, with nesting limited only by `max_depth`
+fn mnemonics(source: &str, max_depth: usize) -> Vec<&'static str> {
+    let mut parser = Parser::new(Lexer::new(source)).unwrap().with_max_depth(max_depth);
+    let mut compiler = Compiler::new().max_depth(max_depth);
+    while let Some(ast) = parser.next().unwrap() {
+        compiler.feed(&ast).unwrap();
+    }
+    compiler.build().unwrap().code.iter().map(|op| op.mnemonic()).collect()
+}
+
+fn count(mnemonics: &[&str], mnemonic: &str) -> usize {
+    mnemonics.iter().filter(|m| **m == mnemonic).count()
+}
+
+#[test]
+fn operators_are_specialized_by_static_type() {
+    let code = mnemonics("a = 1 + 2 * 3 - -4; b = \"x\" + \"y\" + to_string(1); c = (1 < 2) + length([]);", 64);
+    assert_eq!(count(&code, "addi"), 2);
+    assert_eq!(count(&code, "muli"), 1);
+    assert_eq!(count(&code, "subi"), 1);
+    assert_eq!(count(&code, "concat"), 2);
+
+    // variables can hold anything
+    let code = mnemonics("a = x + 1; b = \"x\" + y; c = (z = 1) + 2;", 64);
+    assert_eq!(count(&code, "add"), 2);
+    assert_eq!(count(&code, "addi"), 1);
+}
+
+#[test]
+fn long_sums_compile_in_linear_time() {
+    // each operand used to infer the types of the whole sum again, taking minutes for this
+    let terms = 20_000;
+    let source = format!("x = 1{};", " + 1".repeat(terms));
+    let code = std::thread::Builder::new()
+        .stack_size(1 << 30)
+        .spawn(move || mnemonics(&source, 2 * terms))
+        .unwrap()
+        .join()
+        .unwrap();
+    assert_eq!(count(&code, "addi"), terms);
+}
+
+#[test]
+fn fed_ranges_index_the_built_code() {
+    let source = "x = 1;\nwhile x < 3 { if x > 1 { x = x + 1; } else { x = x * 3; } }\nprint(x * x);";
+    let mut parser = Parser::new(Lexer::new(source)).unwrap();
+    let mut compiler = Compiler::new();
+    let mut fed = vec![];
+    while let Some(ast) = parser.next().unwrap() {
+        fed.push(compiler.feed_range(&ast).unwrap());
+    }
+    let program = compiler.build().unwrap();
+
+    // the ranges follow each other, and cover all the code
+    for pair in fed.windows(2) {
+        assert_eq!(pair[0].0.end, pair[1].0.start);
+    }
+    assert_eq!((fed[0].0.start, fed[2].0.end), (0, program.code.len()));
+    let mnemonics = |range: &std::ops::Range<usize>| program.code[range.clone()].iter().map(|op| op.mnemonic()).collect::<Vec<_>>();
+    assert_eq!(mnemonics(&fed[0].0), ["pushi1", "moveg"]);
+    // the jumps and optimized loads are still there, and the print right after them
+    assert_eq!(count(&mnemonics(&fed[1].0), "jmpf") + count(&mnemonics(&fed[1].0), "jmpt"), 2);
+    assert_eq!(mnemonics(&fed[2].0), ["loadg", "dup", "mul", "native", "pop"]);
+
+    // and their source is the statement's
+    for (range, at) in &fed {
+        for op_at in &program.source_map[range.clone()] {
+            assert!(at.start <= op_at.start && op_at.end <= at.end, "{:?} outside {:?}", op_at, at);
+        }
+    }
+}
+
+#[test]
+fn programs_serialize_round_trip() {
+    // an unused variable and unreachable code, for some diagnostics
+    let source = "x = 1;\nwhile 1 { break; y = 2; }\nprint(\"{x}\" + to_string(x * 2.5));";
+    let mut parser = Parser::new(Lexer::new(source)).unwrap();
+    let mut compiler = Compiler::new();
+    while let Some(ast) = parser.next().unwrap() {
+        compiler.feed(&ast).unwrap();
+    }
+    let program = compiler.build().unwrap();
+    assert!(!program.diagnostics.is_empty());
+
+    let read = bytecode::deserialize_program(&bytecode::serialize_program(&program)).unwrap();
+    assert_eq!(format!("{:?}", read.code), format!("{:?}", program.code));
+    assert_eq!(read.source_map, program.source_map);
+    assert_eq!(read.diagnostics, program.diagnostics);
+
+    // bare code has no source information
+    let read = bytecode::deserialize_program(&bytecode::serialize(&program.code)).unwrap();
+    assert_eq!(format!("{:?}", read.code), format!("{:?}", program.code));
+    assert!(read.source_map.iter().all(|range| range.is_empty()));
+    assert!(read.diagnostics.is_empty());
+}
+
+/// Errors compiling `source`, in strict mode if `strict`
+fn strict_errors(source: &str, strict: bool) -> Vec<Error> {
+    let mut parser = Parser::new(Lexer::new(source)).unwrap();
+    let mut compiler = Compiler::new().strict(strict);
+    let mut errors = vec![];
+    while let Some(ast) = parser.next().unwrap() {
+        if let Err(err) = compiler.feed(&ast) {
+            errors.push(err);
+        }
+    }
+    errors
+}
+
+/// Diagnostics of compiling `source`, both while feeding and once built
+fn diagnostics(source: &str) -> Vec<Diagnostic> {
+    let mut parser = Parser::new(Lexer::new(source)).unwrap();
+    let mut compiler = Compiler::new();
+    while let Some(ast) = parser.next().unwrap() {
+        compiler.feed(&ast).unwrap();
+    }
+    let fed = compiler.diagnostics().to_vec();
+    let built = compiler.build().unwrap().diagnostics;
+    assert_eq!(built[.. fed.len()], fed[..]);
+    built
+}
+
+#[test]
+fn unreachable_statements_are_warned_at_their_span() {
+    let source = "while 1 {\n  break;\n  y = 2;\n  z = 3;\n}";
+    let found = diagnostics(source);
+    assert_eq!(found.len(), 1, "{:?}", found);
+    assert_eq!(found[0].severity, Severity::Warning);
+    assert_eq!(found[0].message, "unreachable statement");
+    // the first statement after the break, in chars
+    assert_eq!(found[0].at.start, 21);
+    assert_eq!(source[found[0].at.clone()].trim_end_matches(';'), "y = 2");
+    assert!(found[0].pretty(source).contains("  y = 2;"), "{}", found[0].pretty(source));
+
+    assert_eq!(diagnostics("for (i = 0; i < 3; i = i + 1) { if i { continue; } else { break; } }").len(), 0);
+    assert_eq!(diagnostics("while 1 { if 1 { continue; print(1); } }")[0].at.start, 27);
+    assert_eq!(diagnostics("fun f() { return 1; g = 2; }")[0].at.start, 20);
+    // other findings have their own severity
+    let notes = diagnostics("let a = 1; let a = 2;");
+    assert!(notes.iter().any(|d| d.severity == Severity::Warning && d.message.contains("already declared")), "{:?}", notes);
+    assert!(notes.iter().any(|d| d.severity == Severity::Note && d.message.contains("never read")), "{:?}", notes);
+}
+
+#[test]
+fn strict_mode_rejects_undeclared_assignments() {
+    let typo = "let count = 0; while count < 3 { cuont = count + 1; }";
+    assert!(strict_errors(typo, false).is_empty());
+    let errors = strict_errors(typo, true);
+    assert_eq!(errors.len(), 1);
+    assert!(matches!(&errors[0], Error::UndeclaredAssignment(Ast::Var(name, _)) if name == "cuont"), "{:?}", errors);
+
+    // declared names can be assigned, also inside expressions and after their declaration
+    assert!(strict_errors("let a = 1; let b = a; a = b = (a = 2) + 1; let a = 3;", true).is_empty());
+    assert_eq!(strict_errors("a = 1; b = (c = 2);", true).len(), 2);
+    // a declaration is only in effect after it
+    assert_eq!(strict_errors("x = 1; let x = 2; x = 3;", true).len(), 1);
+}
+
+/// Value of `source`, that must be an integer
+fn int(source: &str) -> i64 {
+    match eval(source) {
+        Ok(Value::Int(n)) => n,
+        other => panic!("{}: expected an integer, got {:?}", source, other),
+    }
+}
+
+#[test]
+fn assignments_are_expressions() {
+    assert_eq!(int("a = (b = 3) + 1; a * 10 + b;"), 43);
+    assert_eq!(int("a = b = c = 7; a + b + c;"), 21);
+    assert_eq!(int("a = [0]; b = (a[0] = 5); b * 10 + a[0];"), 55);
+    assert_eq!(int("m = {}; x = (m[\"k\"] = [1]); x[0] = 2; m[\"k\"][0];"), 2);
+    assert_eq!(int("(x = 4) * x;"), 16);
+    assert_eq!(int("x = 1; y = 2; if (x = 0) { y = 3; } x * 10 + y;"), 2);
+    assert_eq!(int("i = 0; n = 0; while (i = i + 1) < 4 { n = n + i; } n;"), 6);
+
+    // only assignments whose value is used keep it on the stack
+    let code = mnemonics("a = 1; b = (c = 2) + (d = 3); e = f = 4;", 64);
+    assert_eq!(count(&code, "moveg"), 3);
+    assert_eq!(count(&code, "storeg"), 3);
+}
+
+#[test]
+fn indexed_assignments_evaluate_to_the_assigned_value() {
+    assert_eq!(int("a = [0, 0]; b = (a[0] = 5); b;"), 5);
+    assert_eq!(int("a = [0, 0]; a[0] = a[1] = 6; a[0] * 10 + a[1];"), 66);
+    assert_eq!(int("m = {}; n = (m[1] = 7) + 1; n * 10 + m[1];"), 87);
+    assert_eq!(int("a = [[0]]; (a[0][0] = 8) * 2;"), 16);
+    assert_eq!(int("a = [1, 2, 3]; b = (a[0:2] = [9]); length(b) * 100 + b[0] * 10 + length(a);"), 192);
+
+    // and leave nothing behind as statements
+    let mut vm = VM::new();
+    eval_on(&mut vm, "a = [0]; a[0] = 1; a[0:1] = [2]; m = {}; m[0] = 3;").unwrap();
+    assert_eq!(vm.stack_len(), 0);
+}
+
+#[test]
+fn assignments_keep_their_value_only_in_expressions() {
+    // (source, storeg, moveg)
+    for (source, kept, moved) in [
+        ("x = 1;", 0, 1), ("for (i = 0; i < 2; i = i + 1) {}", 0, 2), ("y = (x = 1);", 1, 1),
+        ("a = b = c = 1;", 2, 1), ("while (x = x - 1) > 0 {}", 1, 0), ("print(x = 2);", 1, 0),
+        ("if 1 { x = 1; } else { y = (x = 2) + 1; }", 1, 2), ("let z = 1;", 0, 1),
+    ] {
+        let code = mnemonics(source, 64);
+        assert_eq!((count(&code, "storeg"), count(&code, "moveg")), (kept, moved), "{}: {:?}", source, code);
+        // so a stored value is never just popped
+        assert!(!code.windows(2).any(|pair| pair == ["storeg", "pop"]), "{}: {:?}", source, code);
+    }
+    assert_eq!(int("x = 5; for (i = 0; i < 2; i = i + 1) { y = (x = x + i); } x * 10 + y;"), 66);
+}
+
+/// Value of `source`, that must be a string
+fn string(source: &str) -> String {
+    match eval(source) {
+        Ok(Value::Str(s)) => s,
+        other => panic!("{}: expected a string, got {:?}", source, other),
+    }
+}
+
+#[test]
+fn strings_interpolate_expressions() {
+    assert_eq!(string("x = 42; \"x is ${x}\";"), "x is 42");
+    assert_eq!(string("x = 2; \"${x * 3 + 1}${x}, ${[10, 20][x - 1] + length(\"ab\")}!\";"), "72, 22!");
+    // converted as with to_string
+    assert_eq!(int("x = [1, \"a\"]; \"${x} ${1.5} ${nil} ${x == x}\" == to_string(x) + \" 1.5 nil 1\";"), 1);
+    // also nested
+    assert_eq!(string("\"a${\"b${1 + 1}c\"}d\";"), "ab2cd");
+    // and a `$` is only special before `{`, or escaped
+    assert_eq!(string("x = 1; \"\\${x} $x $ {x} $\";"), "${x} $x $ {x} $");
+
+    // in a single opcode, leaving out empty pieces
+    let code = mnemonics("s = \"${a}-${b}\";", 64);
+    assert_eq!(code, ["loadg", "pushs", "loadg", "interp", "moveg"]);
+}
+
+#[test]
+fn repeated_loads_are_duplicated() {
+    let code = mnemonics("x = 3; y = x * x;", 64);
+    assert_eq!(code, ["pushib", "moveg", "loadg", "dup", "mul", "moveg"]);
+    assert_eq!(int("x = 3; x * x;"), 9);
+
+    // not across a store to the same name
+    let code = mnemonics("x = 3; y = x * (x = 2);", 64);
+    assert_eq!(count(&code, "loadg"), 1);
+    assert_eq!(count(&code, "dup"), 0);
+    assert_eq!(int("x = 3; y = x * (x = 2); y * 10 + x;"), 62);
+
+    // nor for different names
+    let code = mnemonics("x = 3; y = x; z = x * y;", 64);
+    assert_eq!(count(&code, "loadg"), 3);
+    assert_eq!(count(&code, "dup"), 0);
+}
+
+/// Postfix text of the only statement of `source`
+fn rpn(source: &str) -> Option<String> {
+    let (asts, errors) = Parser::new(Lexer::new(source)).unwrap().parse_all();
+    assert!(errors.is_empty() && asts.len() == 1, "{:?}", errors);
+    to_rpn(&asts[0])
+}
+
+#[test]
+fn expressions_convert_to_postfix() {
+    assert_eq!(rpn("1 + 2 * 3;").unwrap(), "1 2 3 * +");
+    assert_eq!(rpn("(1 + 2) * 3;").unwrap(), "1 2 + 3 *");
+    assert_eq!(rpn("a - b - c;").unwrap(), "a b - c -");
+    assert_eq!(rpn("-x % 2.5 == !y;").unwrap(), "x neg 2.5 % y ! ==");
+    assert_eq!(rpn("l[i + 1][0:2];").unwrap(), "l i 1 + [] 0 2 [:]");
+    assert_eq!(rpn("max([1, \"a b\"], nil);").unwrap(), "1 \"a b\" [2] nil max(2)");
+    assert_eq!(rpn("a || b && c;").unwrap(), "a b c && ||");
+
+    // not expressions, or without a postfix form
+    assert_eq!(rpn("while 1 { }"), None);
+    assert_eq!(rpn("let x = 1;"), None);
+    assert_eq!(rpn("1 < x < 3;"), None);
+}
+
+#[test]
+fn comparisons_chain() {
+    assert_eq!(int("1 < 2 < 3;"), 1);
+    assert_eq!(int("3 < 2 < 1;"), 0);
+    assert_eq!(int("1 < 3 > 2;"), 1);
+    assert_eq!(int("1 <= 1 < 2 == 2;"), 1);
+    assert_eq!(int("x = 5; (0 < x < 10) * 10 + (0 < x < 5);"), 10);
+    // all of them must hold
+    assert_eq!(int("1 < 2 < 3 < 2;"), 0);
+
+    // the middle operands are evaluated once
+    assert_eq!(int("i = 0; r = 0 < (i = i + 1) < 5; i * 10 + r;"), 11);
+    assert_eq!(int("i = 0; r = 0 < (i = i + 1) <= (i = i + 1) < 3; i * 10 + r;"), 21);
+    // and those after the first false comparison not at all
+    assert_eq!(int("i = 0; r = 3 < 2 < (i = 9); i * 10 + r;"), 0);
+    assert_eq!(int("i = 0; r = 1 < (i = i + 1) < (i = 9); i * 10 + r;"), 10);
+}
+
+#[test]
+fn loop_conditions_jump_once_per_iteration() {
+    // the condition is at the bottom, and a leading `!` flips the jump instead of a `not`
+    let negated = mnemonics("done = 0; i = 0; while !done { i = i + 1; done = i > 2; }", 64);
+    assert_eq!(negated[4..], ["jmp", "loadg", "pushi1", "add", "moveg", "loadg", "pushib", "gt", "moveg", "loadg", "jmpf"]);
+    let compared = mnemonics("done = 0; i = 0; while done == 0 { i = i + 1; done = i > 2; }", 64);
+    assert_eq!(compared[4..], ["jmp", "loadg", "pushi1", "add", "moveg", "loadg", "pushib", "gt", "moveg", "loadg", "pushi0", "eq", "jmpt"]);
+    assert_eq!(count(&mnemonics("while !!!x { }", 64), "not"), 0);
+    // where the value is used, it is still computed
+    assert_eq!(count(&mnemonics("y = !x;", 64), "not"), 1);
+
+    // with the same results
+    let run = |cond: &str| int(&format!("i = 0; done = 0; while {} {{ i = i + 1; done = i > 2; }} i * 10 + done;", cond));
+    for cond in ["!done", "done == 0", "!!!done", "!(done != 0)"] {
+        assert_eq!(run(cond), 31, "{}", cond);
+    }
+    assert_eq!(int("x = 0; y = 0; if !x { y = 1; } if !!x { y = y + 10; } y;"), 1);
+}
+
+/// The program compiled from `source`
+fn build(source: &str) -> Program {
+    let mut parser = Parser::new(Lexer::new(source)).unwrap();
+    let mut compiler = Compiler::new();
+    while let Some(ast) = parser.next().unwrap() {
+        compiler.feed(&ast).unwrap();
+    }
+    compiler.build().unwrap()
+}
+
+#[test]
+fn break_jumps_past_the_loop() {
+    let program = build("i = 0; while i < 10 { i = i + 1; if i == 3 { break; } } after = i;");
+    // the last jump inside the body is the break, to the first instruction after the loop
+    let loop_end = program.code.iter().position(|op| matches!(op, Op::JmpT(_))).unwrap() + 1;
+    assert!(matches!(&program.code[loop_end], Op::LoadG(name) if name == "i"));
+    let break_at = program.code[.. loop_end].iter().rposition(|op| matches!(op, Op::Jmp(_))).unwrap();
+    assert!(matches!(program.code[break_at], Op::Jmp(target) if target == loop_end), "{}", disassemble(&program.code));
+    assert!(matches!(program.code[break_at - 1], Op::JmpF(target) if target == break_at + 1));
+
+    assert_eq!(int("i = 0; n = 0; while i < 10 { i = i + 1; if i == 3 { break; } n = n + 1; } after = i; after * 10 + n;"), 32);
+    // only the innermost loop is left
+    assert_eq!(int("n = 0; for (i = 0; i < 3; i = i + 1) { while 1 { n = n + 1; break; } } n * 10 + i;"), 33);
+    assert_eq!(int("n = 0; while 1 { if n > 4 { break; } n = n + 1; } n;"), 5);
+}
+
+#[test]
+fn break_and_continue_outside_loops_are_errors() {
+    for source in ["break;", "continue;", "if 1 { break; }", "x = 1; { continue; }"] {
+        let errors = strict_errors(source, false);
+        assert!(matches!(errors.as_slice(), [Error::BreakOutsideLoop(_)]), "{}: {:?}", source, errors);
+    }
+    assert!(strict_errors("while 1 { if 1 { break; } else { continue; } }", false).is_empty());
+}
+
+#[test]
+fn labels_must_name_an_enclosing_loop() {
+    for source in ["while 1 { break outer; }", "outer: while 1 {} while 1 { continue outer; }", "a: while 1 { b: while 1 { break c; } }"] {
+        let errors = strict_errors(source, false);
+        assert!(matches!(errors.as_slice(), [Error::UnknownLabel(_)]), "{}: {:?}", source, errors);
+    }
+    assert!(strict_errors("a: while 1 { b: while 1 { break a; continue b; } }", false).is_empty());
+}
+
+/// Result of building `source` with at most `max_opcodes` opcodes
+fn build_limited(source: &str, max_opcodes: usize) -> Result<Program, Error> {
+    let mut parser = Parser::new(Lexer::new(source)).unwrap();
+    let mut compiler = Compiler::new().max_opcodes(max_opcodes);
+    while let Some(ast) = parser.next().unwrap() {
+        compiler.feed(&ast).unwrap();
+    }
+    compiler.build()
+}
+
+#[test]
+fn programs_over_the_opcode_limit_are_rejected() {
+    let source = "x = 1; y = 2; z = x + y;";
+    let size = build(source).code.len();
+    assert_eq!(build_limited(source, size).unwrap().code.len(), size);
+    assert!(matches!(build_limited(source, size - 1), Err(Error::ProgramTooLarge(n)) if n == size));
+
+    // however the code was produced
+    let repeated = "x = 1; ".repeat(10_000);
+    assert!(matches!(build_limited(&repeated, 1000), Err(Error::ProgramTooLarge(n)) if n > 1000));
+}
+
+#[test]
+fn jumps_to_the_next_opcode_are_removed() {
+    let empty_else = mnemonics("if x { y = 1; } else {} z = 2;", 64);
+    assert_eq!(count(&empty_else, "jmp"), 0);
+    assert_eq!(count(&empty_else, "nop"), 0);
+    assert_eq!(count(&mnemonics("if x { y = 1; } else { y = 2; }", 64), "jmp"), 1);
+
+    // and the remaining jumps still land where they should
+    for (x, expected) in [(0, 60), (1, 33)] {
+        let source = format!("x = {}; y = 0; i = 0; while i < 3 {{ if x {{ y = y + 1; }} else {{}} if !x {{ y = y + 10; }} else {{}} i = i + 1; }} i * 10 + y;", x);
+        assert_eq!(int(&source), expected, "{}", source);
+    }
+}
+
+#[test]
+fn small_integers_use_compact_pushes() {
+    for (literal, expected) in [
+        ("0", Op::PushI0), ("1", Op::PushI1), ("2", Op::PushIByte(2)), ("-1", Op::PushIByte(-1)),
+        ("127", Op::PushIByte(127)), ("-128", Op::PushIByte(-128)), ("128", Op::PushI(128)),
+        ("-129", Op::PushI(-129)), ("9223372036854775807", Op::PushI(i64::MAX)),
+    ] {
+        let source = format!("x = {};", literal);
+        let code = build(&source).code;
+        assert_eq!(format!("{:?}", code[0]), format!("{:?}", expected), "{}", literal);
+        // with the value of the literal
+        assert_eq!(int(&format!("{} x;", source)), literal.parse::<i64>().unwrap());
+    }
+
+    // which makes the bytecode smaller
+    let small = bytecode::serialize(&build("x = [0, 1, 2, 3];").code).len();
+    let large = bytecode::serialize(&build("x = [1000, 1001, 1002, 1003];").code).len();
+    assert!(small < large, "{} bytes for small integers, {} for large", small, large);
+}
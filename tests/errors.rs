@@ -0,0 +1,69 @@
+use script::{diagnostic::Diagnostic, errors::Error, eval, lexer::Lexer, parser::Parser};
+
+/// First error of parsing `lexer`
+fn parse_error(lexer: Lexer) -> Error {
+    let (_, errors) = Parser::new(lexer).unwrap().parse_all();
+    errors.into_iter().next().expect("expected an error")
+}
+
+#[test]
+fn named_sources_are_in_the_address() {
+    let source = "x = 1;\ny = 2 +;\n";
+    let err = parse_error(Lexer::new_named(source, "f.script"));
+    assert_eq!(err.pretty_named(source, "f.script"), "got a Semi but expected one of [Int, Float, Str, InterpStart, Nil, Id, LPar]\nf.script:2:8: | y = 2 +;\n              |        ^");
+}
+
+#[test]
+fn unnamed_sources_use_the_same_address() {
+    let source = "x = 1;\ny = 2 +;\n";
+    let err = parse_error(Lexer::new(source));
+    let pretty = err.pretty(source);
+    let lines: Vec<&str> = pretty.lines().collect();
+    assert_eq!(lines[1..], ["2:8: | y = 2 +;", "     |        ^"]);
+
+    // as are diagnostics
+    let warning = Diagnostic::warning("unused".to_string(), 7 .. 8);
+    assert_eq!(warning.pretty(source), "warning: unused\n2:1: | y = 2 +;\n     | ^");
+    assert_eq!(warning.pretty_named(source, "f.script"), "warning: unused\nf.script:2:1: | y = 2 +;\n              | ^");
+}
+
+#[test]
+fn unexpected_tokens_show_their_line_and_column() {
+    let source = "x = 1;\n\ny = [1, \"é\" 2];\n";
+    let err = parse_error(Lexer::new(source));
+    match &err {
+        Error::UnexpectedToken(tk, _) => {
+            assert_eq!((tk.line, tk.col), (3, 13));
+            assert_eq!(tk.value, "2");
+        }
+        other => panic!("expected an unexpected token, got {:?}", other),
+    }
+    assert!(err.to_string().contains(" at 3:13, expected one of "), "{}", err);
+    assert!(err.pretty(source).contains("\n3:13: | y = [1, \"é\" 2];\n"), "{}", err.pretty(source));
+
+    // as for tokens missing at the end of a line
+    let err = parse_error(Lexer::new("a = 1;\nb = 2;\nc = (3\n;"));
+    assert!(err.to_string().contains(" at 4:1, expected RPar"), "{}", err);
+}
+
+#[test]
+fn multibyte_characters_count_as_one_column() {
+    // a runtime error, after multibyte characters on the same line
+    let source = "x = \"日本語\"; y = \"é\" + [1] - 1;\n";
+    let err = eval(source).unwrap_err();
+    assert_eq!(err.position(), Some(19));
+    assert_eq!(err.pretty(source).lines().skip(1).collect::<Vec<_>>(), [
+        "1:20: | x = \"日本語\"; y = \"é\" + [1] - 1;",
+        "      |                    ^",
+    ]);
+
+    // a parse error on a line after them, and the line before is not included
+    let source = "s = \"→→\"; // ünïcödé\nw = 1 +* 2;\nz = \"€\";\n";
+    let err = parse_error(Lexer::new(source));
+    assert_eq!(err.pretty(source).lines().skip(1).collect::<Vec<_>>(), ["2:8: | w = 1 +* 2;", "     |        ^"]);
+
+    // the end of a source with multibyte characters
+    let source = "x = \"日本語 €";
+    let err = eval(source).unwrap_err();
+    assert_eq!(err.pretty(source).lines().skip(1).collect::<Vec<_>>(), ["1:11: | x = \"日本語 €", "      |           ^"]);
+}
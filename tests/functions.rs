@@ -0,0 +1,371 @@
+#![allow(clippy::result_large_err)]
+
+use script::{compiler::Compiler, errors::Error, eval, eval_on, lexer::Lexer, opcodes::Op, parser::Parser, value::Value, vm::VM};
+
+/// The error of running `source`, without its location
+fn error(source: &str) -> Error {
+    match eval(source) {
+        Err(Error::Located(err, _)) => *err,
+        Err(err) => err,
+        Ok(value) => panic!("{}: expected an error, got {:?}", source, value),
+    }
+}
+
+/// Value of `source` run on `vm`, that must be an integer
+fn int(vm: &mut VM, source: &str) -> i64 {
+    match eval_on(vm, source) {
+        Ok(Value::Int(n)) => n,
+        other => panic!("{}: expected an integer, got {:?}", source, other),
+    }
+}
+
+#[test]
+fn functions_without_return_yield_nil() {
+    assert!(matches!(eval("fun f() {} f();"), Ok(Value::Nil)));
+    assert!(matches!(eval("fun f() { x = 1; } f();"), Ok(Value::Nil)));
+    // not even the value of the last statement is returned
+    assert!(matches!(eval("fun f(a) { a + 1; } f(2);"), Ok(Value::Nil)));
+
+    // the stack is left as after any other call, so calls can be used in expressions
+    let mut vm = VM::new();
+    assert_eq!(int(&mut vm, "fun f(a, b) { let c = a * b; total = c; } x = [f(2, 3), nil, f(4, 5)]; (x[0] == x[2]) * 1000 + length(x) * 100 + total;"), 1320);
+    assert_eq!(vm.stack_len(), 0);
+}
+
+#[test]
+fn parameters_and_lets_are_locals() {
+    let mut vm = VM::new();
+    // `a` and `b` are locals of `f`, that leave the globals alone, and `c` is a global
+    assert_eq!(int(&mut vm, "a = 1; b = 2; fun f(a) { let b = a * 10; a = b + 1; c = a + b; } f(5); a * 1000 + b * 100 + c;"), 1301);
+    // each call has its own locals
+    assert_eq!(int(&mut vm, "fun count(n) { if n > 0 { total = total + n; count(n - 1); total = total + n; } } total = 0; count(100); total;"), 10100);
+    // `while let` binds a local too
+    assert_eq!(int(&mut vm, "l = [1, 2, 3]; fun sum() { let s = 0; while let x = dequeue(l) { s = s + x; } total = s; } sum(); total * 10 + length(l);"), 60);
+    assert!(matches!(eval_on(&mut vm, "x;"), Err(Error::Located(err, _)) if matches!(*err, Error::GlobalNotFound(_))));
+}
+
+#[test]
+fn functions_are_values() {
+    let mut vm = VM::new();
+    assert_eq!(int(&mut vm, "fun set(x) { value = x; } g = set; [g][0](7); value;"), 7);
+    assert_eq!(int(&mut vm, "(g == set) * 10 + (set == \"set\") * 2 + !set;"), 10);
+    assert!(matches!(eval_on(&mut vm, "to_string(set);"), Ok(Value::Str(s)) if s == "<function set>"));
+
+    assert!(matches!(error("x = 1; x(2);"), Error::UnexpectedType(expected, Value::Int(1)) if expected == "function"));
+    assert!(matches!(eval("1(2);"), Err(Error::UnknownFunction(_))));
+}
+
+#[test]
+fn functions_are_only_defined_at_the_top_level() {
+    assert!(matches!(eval("fun f() { fun g() {} }"), Err(Error::NestedFunction(_))));
+    // but can be defined anywhere there
+    assert!(matches!(eval("if 1 { fun f() { v = 1; } } f(); v;"), Ok(Value::Int(1))));
+    // loops outside do not go into the body
+    assert!(matches!(eval("while 1 { fun f() { break; } }"), Err(Error::BreakOutsideLoop(_))));
+}
+
+#[test]
+fn errors_inside_functions_point_into_their_body() {
+    let source = "fun f(a) {\n  a / 0;\n}\nf(1);";
+    match eval(source) {
+        Err(Error::Located(err, at)) => {
+            assert!(matches!(*err, Error::DivisionByZero));
+            assert_eq!(source.chars().nth(at.start), Some('/'));
+        }
+        other => panic!("expected a division by zero, got {:?}", other),
+    }
+
+    // the calls in progress are dropped, so the VM can run again
+    let mut vm = VM::new();
+    assert!(eval_on(&mut vm, source).is_err());
+    assert_eq!(int(&mut vm, "fun g() { r = 7; } g(); r;"), 7);
+}
+
+#[test]
+fn runaway_recursion_is_a_stack_overflow() {
+    assert!(matches!(error("fun f() { f(); } f();"), Error::StackOverflow));
+
+    let mut vm = VM::new().with_max_call_depth(10);
+    assert_eq!(int(&mut vm, "fun f(n) { depth = n; if n < 10 { f(n + 1); } } f(1); depth;"), 10);
+    assert!(matches!(eval_on(&mut vm, "f(0);"), Err(Error::Located(err, _)) if matches!(*err, Error::StackOverflow)));
+}
+
+#[test]
+fn locals_survive_collections() {
+    let source = "
+        fun f(n) {
+            let keep = [n, [n]];
+            let i = 0;
+            while i < 3000 { let garbage = [i, [i]]; i = i + 1; }
+            kept = keep[1][0];
+        }
+        f(42); kept;";
+    for counting in [false, true] {
+        let mut vm = VM::new().with_heap_limit(512).with_reference_counting(counting);
+        assert_eq!(int(&mut vm, source), 42, "counting: {}", counting);
+    }
+}
+
+#[test]
+fn calls_to_undeclared_globals_are_warned() {
+    // functions can be called before they are defined, as from other functions
+    let source = "fun f() { g(); h(1); } fun g() {} f(); k = f; k();";
+    let mut parser = Parser::new(Lexer::new(source)).unwrap();
+    let mut compiler = Compiler::new();
+    while let Some(ast) = parser.next().unwrap() {
+        compiler.feed(&ast).unwrap();
+    }
+    let program = compiler.build().unwrap();
+    let warned: Vec<&str> = program.diagnostics.iter().map(|d| &source[d.at.clone()]).collect();
+    assert_eq!(warned, vec!["h"]);
+    assert_eq!(program.diagnostics[0].message, "'h' is not a known function");
+}
+
+#[test]
+fn hosts_call_functions_by_name() {
+    let mut vm = VM::new();
+    eval_on(&mut vm, "events = []; fun on_event(name, data) { append(events, [name, data]); }").unwrap();
+    assert!(matches!(vm.run_function("on_event", &["click".into(), Value::Int(3)]), Ok(Value::Nil)));
+    vm.run_function("on_event", &["key".into(), Value::Float(2.5)]).unwrap();
+    assert_eq!(vm.stack_len(), 0);
+    assert!(matches!(eval_on(&mut vm, "to_string(events);"), Ok(Value::Str(s)) if s == "[[click, 3], [key, 2.5]]"));
+
+    // errors are returned, leaving the stack as it was
+    eval_on(&mut vm, "x = 1; fun fail(a) { a / 0; }").unwrap();
+    assert!(matches!(vm.run_function("missing", &[]), Err(Error::GlobalNotFound(name)) if name == "missing"));
+    assert!(matches!(vm.run_function("x", &[]), Err(Error::UnexpectedType(_, Value::Int(1)))));
+    assert!(matches!(vm.run_function("fail", &[Value::Int(1)]), Err(Error::Located(err, _)) if matches!(*err, Error::DivisionByZero)));
+    assert!(matches!(vm.run_function("fail", &[]), Err(Error::ArityMismatch(name, 0, 1)) if name == "fail"));
+    assert_eq!(vm.stack_len(), 0);
+}
+
+#[test]
+fn natives_call_functions_in_the_middle_of_a_call() {
+    let mut vm = VM::new();
+    vm.register_native("twice", 1, Box::new(|vm, args| {
+        let arg = vm.get_clone(args[0])?;
+        vm.run_function("handler", std::slice::from_ref(&arg))?;
+        vm.run_function("handler", &[arg])
+    }));
+    let source = "fun handler(x) { count = count + x; } fun go(n) { let before = count; twice(n); diff = count - before; } count = 0; go(5); go(1); count * 100 + diff;";
+    assert_eq!(int(&mut vm, source), 1202);
+    assert_eq!(vm.stack_len(), 0);
+}
+
+#[test]
+fn generators_yield_a_sequence() {
+    let mut vm = VM::new();
+    let source = "
+        fun count(from, to) {
+            let i = from;
+            while i <= to { yield i; i = i + 1; }
+        }
+        g = count(1, 5);
+        l = [];
+        while let x = resume(g) { append(l, x); }
+        to_string(l);";
+    assert!(matches!(eval_on(&mut vm, source), Ok(Value::Str(s)) if s == "[1, 2, 3, 4, 5]"));
+    // once it returned, there is nothing more to run
+    assert!(matches!(eval_on(&mut vm, "resume(g);"), Ok(Value::Nil)));
+    assert_eq!(vm.stack_len(), 0);
+
+    // each call has its own state, kept between `resume`s
+    let source = "
+        fun fib() {
+            let a = 0;
+            let b = 1;
+            while 1 { yield a; let t = a + b; a = b; b = t; }
+        }
+        x = fib(); y = fib(); resume(y);
+        l = [];
+        for (let i = 0; i < 10; i = i + 1) { append(l, resume(x) * 1000 + resume(y)); }
+        to_string(l);";
+    assert!(matches!(eval_on(&mut vm, source), Ok(Value::Str(s)) if s == "[1, 1001, 1002, 2003, 3005, 5008, 8013, 13021, 21034, 34055]"));
+}
+
+#[test]
+fn generators_only_run_when_resumed() {
+    let mut vm = VM::new();
+    assert!(eval_on(&mut vm, "fun g() { started = 1; yield 2; ended = 3; } x = g();").is_ok());
+    assert!(matches!(eval_on(&mut vm, "started;"), Err(Error::Located(err, _)) if matches!(*err, Error::GlobalNotFound(_))));
+    assert_eq!(int(&mut vm, "resume(x) * 10 + started;"), 21);
+    assert!(matches!(eval_on(&mut vm, "[resume(x), ended];"), Ok(Value::List(l)) if l.len() == 2));
+    assert!(matches!(eval_on(&mut vm, "to_string(x);"), Ok(Value::Str(s)) if s == "<generator g>"));
+    // generators are only equal to themselves
+    assert_eq!(int(&mut vm, "(x == x) * 10 + (g() == g());"), 10);
+}
+
+#[test]
+fn generator_errors() {
+    assert!(matches!(eval("yield 1;"), Err(Error::YieldOutsideFunction(_))));
+    assert!(matches!(error("resume(1);"), Error::InvalidArgument(_, Value::Int(1))));
+    assert!(matches!(error("fun f() {} resume(f);"), Error::InvalidArgument(_, Value::Func(_))));
+    assert!(matches!(error("fun g() { yield resume(me); } me = g(); resume(me);"), Error::GeneratorRunning));
+
+    // a generator that failed is done
+    let mut vm = VM::new();
+    assert_eq!(int(&mut vm, "fun bad() { yield 1; yield 1 / 0; yield 2; } b = bad(); resume(b);"), 1);
+    assert!(matches!(eval_on(&mut vm, "resume(b);"), Err(Error::Located(err, _)) if matches!(*err, Error::DivisionByZero)));
+    assert!(matches!(eval_on(&mut vm, "resume(b);"), Ok(Value::Nil)));
+}
+
+#[test]
+fn suspended_generators_keep_their_locals() {
+    let source = "
+        fun keeper(n) {
+            let keep = [n, [n]];
+            while 1 { yield keep[1][0]; }
+        }
+        g = keeper(42);
+        resume(g);
+        let i = 0;
+        while i < 3000 { garbage = [i, [i]]; i = i + 1; }
+        resume(g);";
+    for counting in [false, true] {
+        let mut vm = VM::new().with_heap_limit(512).with_reference_counting(counting);
+        assert_eq!(int(&mut vm, source), 42, "counting: {}", counting);
+    }
+}
+
+#[test]
+fn return_leaves_the_function_with_a_value() {
+    let mut vm = VM::new();
+    assert_eq!(int(&mut vm, "fun square(x) { return x * x; } square(7);"), 49);
+    assert_eq!(int(&mut vm, "fun fib(n) { if n < 2 { return n; } return fib(n - 1) + fib(n - 2); } fib(20);"), 6765);
+    // from inside loops and matches, leaving the stack as after any other call
+    let source = "
+        fun find(l, v) {
+            let i = 0;
+            while i < length(l) {
+                match l[i] { 0 => {} _ => { if l[i] == v { return i; } } }
+                i = i + 1;
+            }
+            return -1;
+        }
+        to_string([find([5, 0, 7], 7), find([], 1), square(find([3], 3) + 2)]);";
+    assert!(matches!(eval_on(&mut vm, source), Ok(Value::Str(s)) if s == "[2, -1, 4]"));
+    assert!(matches!(eval_on(&mut vm, "fun f() { return; } f();"), Ok(Value::Nil)));
+    assert_eq!(vm.stack_len(), 0);
+
+    // the host gets the value returned
+    assert!(matches!(vm.run_function("square", &[Value::Int(5)]), Ok(Value::Int(25))));
+
+    assert!(matches!(eval("return 1;"), Err(Error::ReturnOutsideFunction(_))));
+    assert!(matches!(eval("if 1 { return; }"), Err(Error::ReturnOutsideFunction(_))));
+}
+
+#[test]
+fn return_ends_generators() {
+    let mut vm = VM::new();
+    let source = "
+        fun upto(n) {
+            let i = 0;
+            while 1 { if i == n { return \"done\"; } yield i; i = i + 1; }
+        }
+        g = upto(2);
+        to_string([resume(g), resume(g), resume(g), resume(g), resume(upto(0))]);";
+    assert!(matches!(eval_on(&mut vm, source), Ok(Value::Str(s)) if s == "[0, 1, done, nil, done]"));
+}
+
+#[test]
+fn calls_must_give_one_argument_per_parameter() {
+    let mut vm = VM::new();
+    eval_on(&mut vm, "fun add(a, b) { let c = a + b; return c; } fun pairs(a, b) { yield a; yield b; }").unwrap();
+    assert_eq!(int(&mut vm, "add(1, 2);"), 3);
+    for source in &["add(1);", "add(1, 2, 3);", "f = add; f(1);", "[add][0](1, 2, 3);"] {
+        match eval_on(&mut vm, source) {
+            Err(Error::Located(err, _)) => assert!(matches!(*err, Error::ArityMismatch(ref name, _, 2) if name == "add"), "{}: {:?}", source, err),
+            other => panic!("{}: expected an arity mismatch, got {:?}", source, other),
+        }
+    }
+    assert!(matches!(error("fun add(a, b) { a + b; } add(1);"), Error::ArityMismatch(_, 1, 2)));
+    assert!(matches!(error("fun add(a, b) { a + b; } add(1, 2, 3);"), Error::ArityMismatch(_, 3, 2)));
+    // generators are checked when called, not when first resumed
+    assert!(matches!(error("fun pairs(a, b) { yield a; } pairs(1);"), Error::ArityMismatch(_, 1, 2)));
+    assert_eq!(error("fun add(a, b) { a + b; } add(1);").to_string(), "Wrong number of arguments to add, given 1 but expected 2");
+}
+
+/// Number of `Op::Call` in `code`, including the code of the functions it defines
+fn calls(code: &[Op]) -> usize {
+    code.iter().map(|op| match op {
+        Op::Call(_) => 1,
+        Op::Function(function) => calls(&function.program.code),
+        _ => 0,
+    }).sum()
+}
+
+/// Run `source`, compiled with calls inlined or not: the global `result` (or the error)
+/// and the number of calls in the code
+fn run_inlined(source: &str, inline: bool) -> (Result<Value, Error>, usize) {
+    let mut parser = Parser::new(Lexer::new(source)).unwrap();
+    let mut compiler = Compiler::new().inline_calls(inline);
+    while let Some(ast) = parser.next().unwrap() {
+        compiler.feed(&ast).unwrap();
+    }
+    let program = compiler.build().unwrap();
+    let mut vm = VM::new();
+    let result = vm.run(&program).and_then(|_| vm.get_clone(vm.get_global("result").unwrap()));
+    (result, calls(&program.code))
+}
+
+#[test]
+fn inlined_calls_give_the_same_results() {
+    let source = "
+        fun square(x) { return x * x; }
+        fun area(w, h) { return w * h; }
+        fun first(l) { return l[0]; }
+        fun log(a, b) { out = out + to_string(a) + \":\" + to_string(b) + \" \"; }
+        fun twice(x) { return square(x) + square(x); }
+        out = \"\";
+        total = 0;
+        for (let i = 0; i < 5; i = i + 1) { total = total + square(i) + area(i, 2); log(i, square(2)); }
+        result = to_string([total, first([7, 8]), twice(3), log(1, area(2, 3)), out]);";
+    let (plain, plain_calls) = run_inlined(source, false);
+    let (inlined, inlined_calls) = run_inlined(source, true);
+    assert!(matches!(&plain, Ok(Value::Str(s)) if s == "[50, 7, 18, nil, 0:4 1:4 2:4 3:4 4:4 1:6 ]"), "{:?}", plain);
+    assert!(matches!((&plain, &inlined), (Ok(Value::Str(a)), Ok(Value::Str(b))) if a == b));
+    assert_eq!(plain_calls, 10);
+    assert_eq!(inlined_calls, 0);
+
+    // errors in an inlined body are located there, as when called
+    let source = "fun ratio(a, b) { return a / b; } result = ratio(1, 0);";
+    let (plain, _) = run_inlined(source, false);
+    let (inlined, _) = run_inlined(source, true);
+    match (plain, inlined) {
+        (Err(Error::Located(a, at)), Err(Error::Located(b, inlined_at))) => {
+            assert!(matches!((*a, *b), (Error::DivisionByZero, Error::DivisionByZero)));
+            assert_eq!(&source[at.clone()], "/");
+            assert_eq!(at, inlined_at);
+        }
+        other => panic!("expected division by zero, got {:?}", other),
+    }
+}
+
+#[test]
+fn only_small_straight_functions_are_inlined() {
+    let not_inlined = [
+        // recursive, with conditionals, loops or locals
+        "fun fib(n) { if n < 2 { return n; } return fib(n - 1) + fib(n - 2); } result = fib(10);",
+        "fun sum(n) { let s = 0; while n > 0 { s = s + n; n = n - 1; } return s; } result = sum(10);",
+        "fun sq(x) { let y = x * x; return y; } result = sq(3);",
+        // generators
+        "fun g(x) { yield x; } result = resume(g(1));",
+        // too long
+        "fun long(x) { return x + x + x + x + x + x + x + x + x + x; } result = long(1);",
+        // called before defined, or after assigned another value
+        "fun f() { return g(1); } fun g(x) { return x; } result = f();",
+        "fun g(x) { return x; } fun h(x) { return x + 1; } g = h; result = g(1);",
+        // with other than one argument per parameter, so it fails as when called
+        "fun g(x) { return x; } result = g(1, 2);",
+    ];
+    for source in &not_inlined {
+        let (plain, plain_calls) = run_inlined(source, false);
+        let (inlined, inlined_calls) = run_inlined(source, true);
+        assert_eq!(format!("{:?}", plain), format!("{:?}", inlined), "{}", source);
+        assert_eq!(plain_calls, inlined_calls, "{}", source);
+    }
+    assert!(matches!(run_inlined("fun g(x) { return x; } fun h(x) { return x + 1; } g = h; result = g(1);", true).0, Ok(Value::Int(2))));
+    assert!(matches!(run_inlined("fun g(x) { return x; } result = g(1, 2);", true).0, Err(Error::Located(err, _)) if matches!(*err, Error::ArityMismatch(_, 2, 1))));
+    // the shortest functions are, nonetheless
+    assert_eq!(run_inlined("fun short(x) { return x + x + x + x + x; } result = short(1);", true).1, 0);
+}
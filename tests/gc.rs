@@ -0,0 +1,415 @@
+#![allow(clippy::result_large_err)]
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    io::Write,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use script::{
+    errors::Error,
+    eval_on,
+    heap::{Heap, VecHeap},
+    value::Value,
+    vm::VM,
+};
+
+/// Value of `source` run on `vm`, that must be an integer
+fn int(vm: &mut VM, source: &str) -> i64 {
+    match eval_on(vm, source) {
+        Ok(Value::Int(n)) => n,
+        other => panic!("{}: expected an integer, got {:?}", source, other),
+    }
+}
+
+/// Allocates enough short-lived values for several young collections
+const GARBAGE: &str = "for (g = 0; g < 2000; g = g + 1) { t = [g, [g]]; }";
+
+#[test]
+fn old_entries_keep_young_values_alive() {
+    let mut vm = VM::new();
+    eval_on(&mut vm, "l = [0, 0, 0]; m = {\"k\": 0}; q = []; s = [0, 0];").unwrap();
+    // everything allocated so far is now old
+    vm.collect();
+
+    // each write stores a young value into an old entry, then enough is allocated for
+    // the young values to be collected if only reachable through it
+    eval_on(&mut vm, &format!("
+        for (i = 1; i <= 50; i = i + 1) {{
+            l[0] = [i];
+            m[\"k\"] = [i * 2];
+            append(q, [i * 3]);
+            insert(q, 0, [i * 4]);
+            s[0 : 1] = [[i * 5]];
+            {}
+        }}
+    ", GARBAGE)).unwrap();
+    assert_eq!(int(&mut vm, "l[0][0] * 10000 + m[\"k\"][0] * 100 + s[0][0];"), 50 * 10000 + 100 * 100 + 250);
+    assert_eq!(int(&mut vm, "t = 0; for (i = 0; i < length(q); i = i + 1) { t = t + q[i][0]; } t;"), 7 * 50 * 51 / 2);
+}
+
+#[test]
+fn old_entries_changed_by_the_host_keep_young_values_alive() {
+    let mut vm = VM::new();
+    eval_on(&mut vm, "l = [];").unwrap();
+    vm.collect();
+
+    let young = vm.push_value(Value::Str("young".to_string())).unwrap();
+    let list = vm.get_global("l").unwrap();
+    match vm.get_mut(list).unwrap() {
+        Value::List(lst) => lst.push(young),
+        other => panic!("expected a list, got {:?}", other),
+    }
+    vm.pop().unwrap();
+    eval_on(&mut vm, GARBAGE).unwrap();
+    assert!(matches!(eval_on(&mut vm, "l[0] + \"!\";"), Ok(Value::Str(s)) if s == "young!"));
+}
+
+#[test]
+fn young_garbage_is_collected_without_a_full_collection() {
+    let mut vm = VM::new();
+    eval_on(&mut vm, "w = weak([1, 2]); k = [3]; v = weak(k);").unwrap();
+    eval_on(&mut vm, GARBAGE).unwrap();
+    assert_eq!(int(&mut vm, "(deref_weak(w) == nil) + (deref_weak(v) == k) * 10;"), 11);
+}
+
+#[test]
+fn old_garbage_waits_for_a_full_collection() {
+    let mut vm = VM::new();
+    eval_on(&mut vm, "x = [1, 2]; w = weak(x);").unwrap();
+    vm.collect();
+    eval_on(&mut vm, &format!("x = nil; {}", GARBAGE)).unwrap();
+    assert_eq!(int(&mut vm, "deref_weak(w) != nil;"), 1);
+    vm.collect();
+    assert_eq!(int(&mut vm, "deref_weak(w) == nil;"), 1);
+}
+
+#[test]
+fn cycles_are_collected() {
+    let mut vm = VM::new();
+    eval_on(&mut vm, "a = [1]; b = [a]; append(a, b); w = weak(a); a = nil; b = nil;").unwrap();
+    vm.collect();
+    assert_eq!(int(&mut vm, "deref_weak(w) == nil;"), 1);
+}
+
+#[test]
+fn surviving_values_are_intact() {
+    // a large live structure, changed while garbage forces collections of both kinds
+    let mut vm = VM::new();
+    eval_on(&mut vm, "
+        keep = [];
+        for (i = 0; i < 3000; i = i + 1) {
+            append(keep, [i, \"${i}\"]);
+            if i % 3 == 0 { keep[i / 2] = [i / 2, \"${i / 2}\"]; }
+            t = [i, [i, [i]]];
+        }
+    ").unwrap();
+    assert_eq!(int(&mut vm, "
+        bad = 0;
+        for (i = 0; i < length(keep); i = i + 1) {
+            if keep[i][0] != i || keep[i][1] != \"${i}\" { bad = bad + 1; }
+        }
+        bad;
+    "), 0);
+}
+
+#[test]
+fn finalizers_run_once_for_collected_values() {
+    let mut vm = VM::new();
+    let finalized = Rc::new(RefCell::new(vec![]));
+    eval_on(&mut vm, "old = [2];").unwrap();
+    let old = vm.get_global("old").unwrap();
+    vm.collect();
+    eval_on(&mut vm, "young = [1];").unwrap();
+    for name in &["young", "old"] {
+        let log = finalized.clone();
+        let name = name.to_string();
+        vm.register_finalizer(vm.get_global(&name).unwrap(), move |_, _| log.borrow_mut().push(name)).unwrap();
+    }
+
+    eval_on(&mut vm, &format!("young = nil; old = nil; {}", GARBAGE)).unwrap();
+    assert_eq!(*finalized.borrow(), vec!["young".to_string()]);
+    vm.collect();
+    vm.collect();
+    assert_eq!(*finalized.borrow(), vec!["young".to_string(), "old".to_string()]);
+    assert!(matches!(vm.get(old), Err(Error::InvalidMemoryAccess(_))));
+}
+
+#[test]
+fn heap_limit_counts_live_values() {
+    let mut vm = VM::new().with_heap_limit(2000);
+    eval_on(&mut vm, "for (i = 0; i < 100000; i = i + 1) { t = [i]; }").unwrap();
+    let kept = eval_on(&mut vm, "l = []; for (i = 0; i < 100000; i = i + 1) { append(l, [i]); }");
+    assert!(matches!(kept, Err(Error::Located(err, _)) if matches!(*err, Error::OutOfMemory)));
+}
+
+/// Pauses of the script while allocating short-lived values with `live` lists alive
+/// (gaps between calls of a `tick` native much longer than the usual, not to count
+/// noise), and the duration of a full collection of the same heap
+fn pauses(live: usize) -> (Vec<Duration>, Duration) {
+    let mut vm = VM::new();
+    let ticks = Rc::new(RefCell::new(vec![]));
+    let log = ticks.clone();
+    vm.register_native("tick", 0, Box::new(move |_, _| {
+        log.borrow_mut().push(Instant::now());
+        Ok(Value::Nil)
+    }));
+    eval_on(&mut vm, &format!("keep = []; for (i = 0; i < {}; i = i + 1) {{ append(keep, [i]); }}", live)).unwrap();
+    vm.collect();
+    ticks.borrow_mut().clear();
+    eval_on(&mut vm, "for (i = 0; i < 500000; i = i + 1) { t = [i, i]; tick(); }").unwrap();
+
+    let start = Instant::now();
+    vm.collect();
+    let full = start.elapsed();
+
+    let ticks = ticks.borrow();
+    let mut gaps: Vec<Duration> = ticks.windows(2).map(|w| w[1] - w[0]).collect();
+    gaps.sort();
+    let usual = gaps[gaps.len() / 2];
+    let threshold = usual * 20 + Duration::from_micros(100);
+    (gaps.into_iter().filter(|gap| *gap > threshold).collect(), full)
+}
+
+/// Pause times of young collections, compared with full ones
+///
+/// Run with `cargo test --release --test gc -- --ignored --nocapture`
+#[test]
+#[ignore]
+fn benchmark_pause_times() {
+    for live in &[10_000, 100_000, 1_000_000] {
+        let (pauses, full) = pauses(*live);
+        let total: Duration = pauses.iter().sum();
+        let average = total / pauses.len().max(1) as u32;
+        let longest = pauses.iter().max().copied().unwrap_or_default();
+        println!(
+            "{:>9} live: {:>4} pauses, average {:>10?}, longest {:>10?}; full collection {:>10?}",
+            live, pauses.len(), average, longest, full,
+        );
+        assert!(average < full, "young collections should pause less than a full one");
+    }
+}
+
+/// A `VecHeap` that counts the values stored in it (allocations)
+struct CountingHeap(VecHeap, Rc<RefCell<usize>>);
+
+impl Heap for CountingHeap {
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn grow(&mut self) -> usize {
+        self.0.grow()
+    }
+
+    fn get(&self, index: usize) -> Option<&Value> {
+        self.0.get(index)
+    }
+
+    fn get_mut(&mut self, index: usize) -> Option<&mut Value> {
+        self.0.get_mut(index)
+    }
+
+    fn store(&mut self, index: usize, value: Value) {
+        *self.1.borrow_mut() += 1;
+        self.0.store(index, value)
+    }
+
+    fn take(&mut self, index: usize) -> Option<Value> {
+        self.0.take(index)
+    }
+
+    fn clear(&mut self) {
+        self.0.clear()
+    }
+}
+
+/// Number of allocations running `source`, and its result
+fn allocations(tagging: bool, source: &str) -> (usize, i64) {
+    let count = Rc::new(RefCell::new(0));
+    let mut vm = VM::new().with_heap(CountingHeap(VecHeap::new(), count.clone())).with_small_int_tagging(tagging);
+    let result = int(&mut vm, source);
+    let allocated = *count.borrow();
+    (allocated, result)
+}
+
+/// Allocations of an integer-heavy loop, with small integers allocated and held by
+/// their pointers
+///
+/// Run with `cargo test --test gc -- --nocapture` to see the counts
+#[test]
+fn tagging_saves_allocations_of_small_integers() {
+    let source = "
+        total = 0;
+        for (r = 0; r < 10; r = r + 1) {
+            for (i = 0; i < 1000; i = i + 1) {
+                if i % 3 == 0 && i % 5 != 0 { total = total + i % 100; }
+            }
+        }
+        total;
+    ";
+    let (boxed, expected) = allocations(false, source);
+    let (tagged, result) = allocations(true, source);
+    println!("allocations: {} boxed, {} tagged", boxed, tagged);
+    assert_eq!(result, expected);
+    // only the total is allocated, once past the small range
+    assert!(tagged * 10 < boxed, "{} tagged, {} boxed", tagged, boxed);
+}
+
+#[test]
+fn tagged_integers_survive_collections() {
+    let mut vm = VM::new().with_small_int_tagging(true);
+    eval_on(&mut vm, &format!("
+        keep = [];
+        for (i = 0; i < 3000; i = i + 1) {{ append(keep, [i % 2000 - 500, i]); }}
+        w = weak(keep[1][0]);
+        {}
+    ", GARBAGE)).unwrap();
+    vm.collect();
+    assert_eq!(int(&mut vm, "
+        bad = 0;
+        for (i = 0; i < length(keep); i = i + 1) {
+            if keep[i][0] != i % 2000 - 500 || keep[i][1] != i { bad = bad + 1; }
+        }
+        bad;
+    "), 0);
+    // they are never collected
+    assert_eq!(int(&mut vm, "deref_weak(w);"), -499);
+}
+
+#[test]
+fn host_changes_do_not_alias_integers() {
+    let source = "x = 7; y = 7; l = [7, 7];";
+
+    // with tagging, the integer has no entry to change
+    let mut vm = VM::new().with_small_int_tagging(true);
+    eval_on(&mut vm, source).unwrap();
+    let x = vm.get_global("x").unwrap();
+    assert!(matches!(vm.get(x), Ok(Value::Int(7))));
+    assert!(matches!(vm.get_mut(x), Err(Error::ImmutableValue(ptr)) if ptr == x));
+    // integers past the small range are still allocated
+    eval_on(&mut vm, "z = 100000; w = 100000;").unwrap();
+    if let Ok(Value::Int(n)) = vm.get_mut(vm.get_global("z").unwrap()) {
+        *n = 5;
+    }
+    assert_eq!(int(&mut vm, "x * 1000000 + y * 10000 + l[0] * 100 + l[1] + (z == 5) + (w == 100000);"), 7070709);
+
+    // without, each integer is its own entry
+    let mut vm = VM::new();
+    eval_on(&mut vm, source).unwrap();
+    match vm.get_mut(vm.get_global("x").unwrap()) {
+        Ok(Value::Int(n)) => *n = 8,
+        other => panic!("expected an integer, got {:?}", other),
+    }
+    assert_eq!(int(&mut vm, "x * 1000000 + y * 10000 + l[0] * 100 + l[1];"), 8070707);
+}
+
+/// Time taken appending `n` lists to one
+fn append_time(n: usize) -> Duration {
+    let mut vm = VM::new();
+    let source = format!("l = []; for (i = 0; i < {}; i = i + 1) {{ append(l, [i]); }} length(l);", n);
+    let start = Instant::now();
+    assert_eq!(int(&mut vm, &source), n as i64);
+    start.elapsed()
+}
+
+#[test]
+fn appends_are_amortized_constant_time() {
+    // each minor collection scans the heap and the list being appended to, which is old,
+    // so they must get rarer as it grows
+    let small = append_time(20_000);
+    let large = append_time(160_000);
+    println!("appends: {:?} for 20000, {:?} for 160000", small, large);
+    // 8 times as many appends, with a margin for noise; quadratic growth takes over 30 times
+    assert!(large < small * 16, "{:?} for 8 times as many appends as in {:?}", large, small);
+}
+
+/// A heap keeping only its used slots, in a map, as an alternative backend
+#[derive(Default)]
+struct MapHeap {
+    slots: HashMap<usize, Value>,
+    len: usize,
+}
+
+impl Heap for MapHeap {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn grow(&mut self) -> usize {
+        self.len += 1;
+        self.len - 1
+    }
+
+    fn get(&self, index: usize) -> Option<&Value> {
+        self.slots.get(&index)
+    }
+
+    fn get_mut(&mut self, index: usize) -> Option<&mut Value> {
+        self.slots.get_mut(&index)
+    }
+
+    fn store(&mut self, index: usize, value: Value) {
+        assert!(index < self.len);
+        self.slots.insert(index, value);
+    }
+
+    fn take(&mut self, index: usize) -> Option<Value> {
+        self.slots.remove(&index)
+    }
+
+    fn clear(&mut self) {
+        self.slots.clear();
+        self.len = 0;
+    }
+}
+
+/// A `Write` whose output can be read after it is given to a VM
+#[derive(Clone, Default)]
+struct Shared(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Output of running `source` on `vm`, and the heap after a full collection
+fn output_and_heap(vm: VM, source: &str) -> (String, String) {
+    let out = Shared::default();
+    let mut vm = vm.with_output(out.clone());
+    eval_on(&mut vm, source).unwrap();
+    vm.collect();
+    let heap = vm.dump_heap().unwrap();
+    let bytes = out.0.borrow().clone();
+    (String::from_utf8(bytes).unwrap(), heap)
+}
+
+#[test]
+fn heap_backends_give_the_same_results() {
+    let source = format!("
+        keep = {{}};
+        for (i = 0; i < 3000; i = i + 1) {{
+            l = [i, \"s${{i}}\", [i * 2]];
+            if i % 7 == 0 {{ keep[i] = l; }}
+        }}
+        c = [1]; append(c, c); w = weak(c); c = nil;
+        {}
+        print(length(keep), keep[2996]);
+    ", GARBAGE);
+    let (vec_output, vec_heap) = output_and_heap(VM::new(), &source);
+    let (map_output, map_heap) = output_and_heap(VM::new().with_heap(MapHeap::default()), &source);
+    assert_eq!(vec_output, "429[2996, s2996, [5992]]\n");
+    assert_eq!(map_output, vec_output);
+    assert_eq!(map_heap, vec_heap);
+
+    // and with the other collectors
+    let (counted_output, _) = output_and_heap(VM::new().with_heap(MapHeap::default()).with_reference_counting(true), &source);
+    assert_eq!(counted_output, vec_output);
+}
@@ -0,0 +1,198 @@
+use std::io::BufReader;
+use script::{errors::Error, eval, lexer::Lexer, token::{Kind, Token}, value::Value};
+
+/// Kinds and values of the tokens of `source`
+fn tokens(source: &str) -> Vec<(Kind, String)> {
+    Lexer::new(source).collect().unwrap().into_iter().map(|tk| (tk.kind, tk.value)).collect()
+}
+
+fn kinds(source: &str) -> Vec<Kind> {
+    tokens(source).into_iter().map(|(kind, _)| kind).collect()
+}
+
+#[test]
+fn shifts_are_single_tokens() {
+    assert_eq!(tokens("1<<2"), vec![(Kind::Int, "1".to_string()), (Kind::Shl, "<<".to_string()), (Kind::Int, "2".to_string())]);
+    assert_eq!(kinds("a >> b"), vec![Kind::Id, Kind::Shr, Kind::Id]);
+    // the longest operator is taken first
+    assert_eq!(kinds("a<<=b"), vec![Kind::Id, Kind::Shl, Kind::Assign, Kind::Id]);
+    assert_eq!(kinds("a< <b"), vec![Kind::Id, Kind::Lt, Kind::Lt, Kind::Id]);
+    assert_eq!(kinds("a<=b>=c"), vec![Kind::Id, Kind::Lte, Kind::Id, Kind::Gte, Kind::Id]);
+}
+
+/// Value of `source`, that must be an integer
+fn int(source: &str) -> i64 {
+    match eval(source) {
+        Ok(Value::Int(n)) => n,
+        other => panic!("{}: expected an integer, got {:?}", source, other),
+    }
+}
+
+#[test]
+fn adjacent_operators() {
+    assert_eq!(kinds("a==b"), vec![Kind::Id, Kind::Eq, Kind::Id]);
+    assert_eq!(kinds("a!=b"), vec![Kind::Id, Kind::NotEq, Kind::Id]);
+    assert_eq!(kinds("a!b"), vec![Kind::Id, Kind::Not, Kind::Id]);
+    assert_eq!(kinds("a= =b"), vec![Kind::Id, Kind::Assign, Kind::Assign, Kind::Id]);
+    assert_eq!(kinds("a===b"), vec![Kind::Id, Kind::Eq, Kind::Assign, Kind::Id]);
+    assert_eq!(kinds("a== =b"), vec![Kind::Id, Kind::Eq, Kind::Assign, Kind::Id]);
+    assert_eq!(kinds("a!==b"), vec![Kind::Id, Kind::NotEq, Kind::Assign, Kind::Id]);
+    // there are no `<-`, `--` or `=!` operators, so these are two operators each
+    assert_eq!(kinds("a<-1"), vec![Kind::Id, Kind::Lt, Kind::Sub, Kind::Int]);
+    assert_eq!(kinds("a--1"), vec![Kind::Id, Kind::Sub, Kind::Sub, Kind::Int]);
+    assert_eq!(kinds("a---1"), vec![Kind::Id, Kind::Sub, Kind::Sub, Kind::Sub, Kind::Int]);
+    assert_eq!(kinds("a=!b"), vec![Kind::Id, Kind::Assign, Kind::Not, Kind::Id]);
+    assert_eq!(kinds("a<=-1"), vec![Kind::Id, Kind::Lte, Kind::Sub, Kind::Int]);
+    assert_eq!(kinds("a<<-1"), vec![Kind::Id, Kind::Shl, Kind::Sub, Kind::Int]);
+}
+
+#[test]
+fn adjacent_operators_evaluate_as_lexed() {
+    assert_eq!(int("a = 2; a<-1;"), 0);
+    assert_eq!(int("a = -2; a<-1;"), 1);
+    assert_eq!(int("a = 2; a--1;"), 3);
+    assert_eq!(int("a = 2; a---1;"), 1);
+    assert_eq!(int("a = 2; a<--1;"), 0);
+    assert_eq!(int("a = 2; a==-2;"), 0);
+    assert_eq!(int("a = 2; b = a=!a; b + a * 10;"), 0);
+    assert_eq!(int("a = 2; a!=!a;"), 1);
+    // an operator followed by one that cannot start an operand is a syntax error
+    for source in ["a===b;", "a= =b;", "a!==b;", "a<>b;"] {
+        assert!(matches!(eval(source), Err(Error::UnexpectedToken(_, _))), "{}", source);
+    }
+}
+
+#[test]
+fn integers_in_each_base() {
+    for (literal, expected) in [
+        ("0x1F", 31), ("0xff", 255), ("0o17", 15), ("0b1010", 10), ("0x0", 0), ("0b1", 1), ("017", 17),
+        ("0x7FFFFFFFFFFFFFFF", i64::MAX), ("0b111111111111111111111111111111111111111111111111111111111111111", i64::MAX),
+        ("0o777777777777777777777", i64::MAX), ("-0x10", -16),
+    ] {
+        assert_eq!(int(&format!("{};", literal)), expected, "{}", literal);
+    }
+    assert_eq!(kinds("0x1F+0b1"), vec![Kind::Int, Kind::Add, Kind::Int]);
+}
+
+#[test]
+fn integers_too_large_for_their_base_are_errors() {
+    for literal in ["0x8000000000000000", "0o1000000000000000000000", "9223372036854775808", &format!("0b1{}", "0".repeat(63))] {
+        let result = eval(&format!("{};", literal));
+        assert!(matches!(result, Err(Error::ParsingError(ref tk)) if tk.value == literal), "{}: {:?}", literal, result);
+    }
+}
+
+#[test]
+fn invalid_digits_point_at_the_digit() {
+    for (literal, digit, base, at) in [("x = 0b12;", '2', 2, 7), ("0o78", '8', 8, 3), ("0x1G", 'G', 16, 3)] {
+        match Lexer::new(literal).collect() {
+            Err(Error::InvalidDigit(c, b, i)) => assert_eq!((c, b, i), (digit, base, at), "{}", literal),
+            other => panic!("{}: expected an invalid digit, got {:?}", literal, other),
+        }
+    }
+    // and a prefix needs digits
+    assert!(matches!(Lexer::new("0x;").collect(), Err(Error::SyntaxError(2))));
+}
+
+#[test]
+fn underscores_separate_digits() {
+    for (literal, expected) in [("1_000_000", 1_000_000), ("0x7F_FF", 0x7fff), ("0b1010_1010", 0b1010_1010), ("0o1_7", 0o17), ("1_2 * 1_0", 120)] {
+        assert_eq!(int(&format!("{};", literal)), expected, "{}", literal);
+    }
+    // the token keeps them, as written
+    assert_eq!(tokens("1_000"), vec![(Kind::Int, "1_000".to_string())]);
+    assert!(matches!(eval("1_000.5e1_0;"), Ok(Value::Float(f)) if f == 1000.5e10));
+    // a leading one starts an identifier instead
+    assert_eq!(kinds("_1"), vec![Kind::Id]);
+}
+
+#[test]
+fn misplaced_underscores_are_errors() {
+    for (literal, at) in [("1_", 1), ("x = 1__0;", 5), ("1_000_", 5), ("0x_1", 2), ("1_.5", 1), ("1.5_", 3), ("12_e5", 2)] {
+        match Lexer::new(literal).collect() {
+            Err(Error::MisplacedSeparator(i)) => assert_eq!(i, at, "{}", literal),
+            other => panic!("{}: expected a misplaced separator, got {:?}", literal, other),
+        }
+    }
+}
+
+#[test]
+fn block_comments_nest() {
+    assert_eq!(kinds("1 /* outer /* inner */ still commented */ + 2"), vec![Kind::Int, Kind::Add, Kind::Int]);
+    assert_eq!(int("1 /* outer /* inner */ still commented */ + 2;"), 3);
+    assert_eq!(kinds("/**/ a /* b */ /* c */ d /* /* /* */ */ */"), vec![Kind::Id, Kind::Id]);
+    // line comments inside them and them inside line comments are ignored
+    assert_eq!(kinds("/* // */ a // /* b\nc"), vec![Kind::Id, Kind::Id]);
+    assert_eq!(tokens("/*/ a */ b"), vec![(Kind::Id, "b".to_string())]);
+    // lines are still counted
+    let tokens = Lexer::new("/*\n/*\n*/\n*/ x").collect().unwrap();
+    assert_eq!((tokens[0].line, tokens[0].col), (4, 4));
+}
+
+#[test]
+fn unterminated_block_comments_are_errors_at_their_start() {
+    for (source, at) in [("/* a", 0), ("x = 1; /*", 7), ("x /* a /* b */", 2), ("/* a */ /* b /* c */", 8)] {
+        match Lexer::new(source).collect() {
+            Err(Error::UnterminatedComment(i)) => assert_eq!(i, at, "{}", source),
+            other => panic!("{}: expected an unterminated comment, got {:?}", source, other),
+        }
+    }
+}
+
+#[test]
+fn unicode_escapes_decode_to_their_char() {
+    assert_eq!(tokens(r#""\u{1F600}""#), vec![(Kind::Str, "\u{1F600}".to_string())]);
+    assert_eq!(tokens(r#""a\u{41}\u{e9}\u{0}b""#), vec![(Kind::Str, "aA\u{e9}\0b".to_string())]);
+    assert_eq!(tokens(r#""\u{10FFFF}""#), vec![(Kind::Str, "\u{10FFFF}".to_string())]);
+}
+
+#[test]
+fn invalid_unicode_escapes_are_errors_at_the_escape() {
+    for (source, at) in [
+        // surrogates and beyond the last code point
+        (r#""\u{D800}""#, 2), (r#""\u{DFFF}""#, 2), (r#""\u{110000}""#, 2), (r#"x = "ab\u{1234567}""#, 8),
+        // malformed braces
+        (r#""\u1F600""#, 2), (r#""\u{1F600""#, 2), (r#""\u{}""#, 2), (r#""\u{zz}""#, 2), (r#""\u{1F600"#, 2),
+    ] {
+        match Lexer::new(source).collect() {
+            Err(Error::InvalidStringEscape('u', i)) => assert_eq!(i, at, "{}", source),
+            other => panic!("{}: expected an invalid escape, got {:?}", source, other),
+        }
+    }
+}
+
+/// Tokens of `lexer`, in full, as text to compare them
+fn described(mut lexer: Lexer) -> Vec<String> {
+    let mut tokens: Vec<Token> = vec![];
+    while let Some(tk) = lexer.next().unwrap() {
+        tokens.push(tk);
+    }
+    tokens.iter().map(|tk| format!("{:?}", tk)).collect()
+}
+
+#[test]
+fn readers_give_the_same_tokens_as_strings() {
+    let source = "let x = 1.5e3; // comment\n\
+        s = \"multi\nline ${x} \\u{1F44D} \u{e9}\";\n\
+        /* block\n   comment */ if x >= 0x1F && s != \"\" {\n\
+        \tprint(s[0:2], [1, 2], {\"a\": nil});\n\
+        }";
+    let from_string = described(Lexer::new(source));
+    assert!(from_string.len() > 30, "{:?}", from_string);
+    // also when the reader gives a byte at a time, cutting chars and tokens
+    assert_eq!(described(Lexer::from_reader(source.as_bytes())), from_string);
+    assert_eq!(described(Lexer::from_reader(BufReader::with_capacity(1, source.as_bytes()))), from_string);
+
+    // including errors and where they are found
+    let unterminated = "x = 1;\ny = \"abc";
+    let (mut string, mut reader) = (Lexer::new(unterminated), Lexer::from_reader(unterminated.as_bytes()));
+    for _ in 0..8 {
+        assert_eq!(format!("{:?}", reader.next()), format!("{:?}", string.next()));
+    }
+}
+
+#[test]
+fn read_errors_are_input_errors() {
+    let mut lexer = Lexer::from_reader(&b"x = \xff;"[..]);
+    assert!(matches!(lexer.next(), Err(Error::InputError(_))));
+}
@@ -0,0 +1,286 @@
+#![allow(clippy::result_large_err)]
+
+use std::{cell::RefCell, io::Write, rc::Rc};
+
+use script::{
+    errors::Error,
+    eval,
+    eval_on,
+    opcodes::{Native, Op},
+    program::Program,
+    value::Value,
+    vm::VM,
+};
+
+/// The error of running `source`, without its location
+fn error(source: &str) -> Error {
+    match eval(source) {
+        Err(Error::Located(err, _)) => *err,
+        Err(err) => err,
+        Ok(value) => panic!("{}: expected an error, got {:?}", source, value),
+    }
+}
+
+/// Value of the expression `source`, that must be an integer (e.g. a comparison)
+fn int(source: &str) -> i64 {
+    match eval(&format!("{};", source)) {
+        Ok(Value::Int(n)) => n,
+        other => panic!("{}: expected an integer, got {:?}", source, other),
+    }
+}
+
+/// Value of the expression `source`, that must be a string
+fn string(source: &str) -> String {
+    match eval(&format!("{};", source)) {
+        Ok(Value::Str(s)) => s,
+        other => panic!("{}: expected a string, got {:?}", source, other),
+    }
+}
+
+#[test]
+fn strings_index_to_one_char_strings() {
+    assert_eq!(int("\"abc\"[1] == \"b\""), 1);
+    assert_eq!(string("\"abc\"[0] + \"abc\"[2]"), "ac");
+    // by char, not by byte
+    assert_eq!(string("\"h\u{e9}llo\"[1]"), "\u{e9}");
+    assert_eq!(string("\"\u{1F600}x\"[1]"), "x");
+    assert!(matches!(error("\"abc\"[3];"), Error::IndexOutOfRange(_, _)));
+}
+
+#[test]
+fn ord_gives_the_code_of_a_char() {
+    assert_eq!(int("ord(\"b\") == 98"), 1);
+    assert_eq!(int("ord(\"abc\"[1])"), 98);
+    assert_eq!(int("ord(\"\u{e9}\")"), 0xe9);
+    assert_eq!(int("ord(\"\u{1F600}\")"), 0x1F600);
+    assert!(matches!(error("ord(\"\");"), Error::InvalidArgument(Native::Ord, _)));
+    assert!(matches!(error("ord(98);"), Error::InvalidArgument(Native::Ord, _)));
+}
+
+#[test]
+fn range_counts_up_and_down() {
+    assert_eq!(int("range(4) == [0, 1, 2, 3]"), 1);
+    assert_eq!(int("range(2, 5) == [2, 3, 4]"), 1);
+    assert_eq!(int("range(0, 10, 3) == [0, 3, 6, 9]"), 1);
+    assert_eq!(int("range(5, 0, -2) == [5, 3, 1]"), 1);
+    assert_eq!(int("length(range(5, 0)) + length(range(0, 5, -1))"), 0);
+    assert!(matches!(error("range(0, 5, 0);"), Error::InvalidArgument(_, _)));
+}
+
+#[test]
+fn range_at_the_ends_of_integers() {
+    assert_eq!(int("range(9223372036854775806, 9223372036854775807) == [9223372036854775806]"), 1);
+    assert_eq!(int("m = -9223372036854775807 - 1; range(m, 9223372036854775807, 9223372036854775807) == [m, -1, 9223372036854775806]"), 1);
+    assert_eq!(int("m = -9223372036854775807 - 1; range(9223372036854775807, m, m) == [9223372036854775807, -1]"), 1);
+}
+
+#[test]
+fn range_length_is_limited() {
+    assert!(matches!(error("range(-9223372036854775807 - 1, 9223372036854775807);"), Error::AllocationTooLarge(_)));
+    assert!(matches!(error("range(0, 1099511627776, 2);"), Error::AllocationTooLarge(549755813888)));
+
+    let mut vm = VM::new().with_max_repeat_length(10);
+    assert!(matches!(eval_on(&mut vm, "length(range(10));"), Ok(Value::Int(10))));
+    assert!(matches!(eval_on(&mut vm, "range(11);"), Err(Error::Located(err, _)) if matches!(*err, Error::AllocationTooLarge(11))));
+    assert!(matches!(eval_on(&mut vm, "length(range(-1000, 1000, 200));"), Ok(Value::Int(10))));
+}
+
+#[test]
+fn at_gives_nil_out_of_range() {
+    assert_eq!(int("at([1, 2, 3], 1) * 10 + at([1, 2, 3], -1)"), 23);
+    assert_eq!(int("(at([1, 2, 3], 3) == nil) + (at([], 0) == nil) + (at(\"ab\", -3) == nil)"), 3);
+    assert_eq!(string("at(\"h\u{e9}llo\", 1) + at(\"abc\", -1)"), "\u{e9}c");
+    assert_eq!(int("m = {\"a\": 1}; at(m, \"a\") * 10 + (at(m, \"b\") == nil)"), 11);
+    assert!(matches!(error("at([1], \"0\");"), Error::InvalidArgument(_, _)));
+}
+
+#[test]
+fn queues_and_stacks() {
+    assert_eq!(int("q = []; enqueue(q, 1, 2); enqueue(q, 3); a = dequeue(q); b = dequeue(q); a * 100 + b * 10 + length(q)"), 121);
+    assert_eq!(int("q = [1]; dequeue(q); (dequeue(q) == nil) + (peek(q) == nil) * 10 + length(q) * 100"), 11);
+    assert_eq!(int("s = []; append(s, 1, 2, 3); peek(s) * 10 + length(s)"), 33);
+    // elements are returned themselves, not copies
+    assert_eq!(int("q = [[1]]; e = dequeue(q); append(e, 2); s = [e]; append(peek(s), 3); length(e)"), 3);
+    assert!(matches!(error("dequeue(1);"), Error::InvalidArgument(_, _)));
+    assert!(matches!(error("enqueue(\"s\", 1);"), Error::InvalidAppend(_)));
+}
+
+#[test]
+fn insert_shifts_elements_right() {
+    assert_eq!(int("l = [1, 3]; n = insert(l, 1, 2); (l == [1, 2, 3]) * 10 + n"), 13);
+    assert_eq!(int("l = [1]; insert(l, 0, 0); insert(l, length(l), 2); insert(l, -1, 9); l == [0, 1, 9, 2]"), 1);
+    assert!(matches!(error("insert([1], 2, 0);"), Error::IndexOutOfRange(_, 2)));
+    assert!(matches!(error("insert([1], -2, 0);"), Error::IndexOutOfRange(_, 2)));
+    assert!(matches!(error("insert(\"s\", 0, 0);"), Error::InvalidArgument(_, _)));
+    assert!(matches!(error("insert([], \"0\", 0);"), Error::InvalidArgument(_, _)));
+}
+
+#[test]
+fn min_and_max_give_an_argument() {
+    assert_eq!(int("min(3, 1, 2) * 10 + max(3, 1, 2)"), 13);
+    assert_eq!(int("min([4, 2, 8]) * 10 + max([4, 2, 8])"), 28);
+    assert_eq!(int("(min([]) == nil) + (max(1) == 1) * 10"), 11);
+    assert_eq!(string("min(\"b\", \"a\", \"c\") + max(\"b\", \"a\", \"c\")"), "ac");
+    // the argument itself, not a copy, and the first of equal arguments
+    assert_eq!(int("x = [1]; y = [2]; append(min(x, y), 5); length(x) * 10 + length(y)"), 21);
+    assert_eq!(int("x = [1]; y = [1]; append(max(x, y), 5); length(x) * 10 + length(y)"), 21);
+    assert!(matches!(error("min(1, \"a\");"), Error::IncompatibleOperands(_, _, _)));
+}
+
+#[test]
+fn approx_eq_compares_with_a_tolerance() {
+    assert_eq!(int("(0.1 + 0.2 == 0.3) * 10 + approx_eq(0.1 + 0.2, 0.3)"), 1);
+    assert_eq!(int("approx_eq(1, 1.05, 0.1) * 10 + approx_eq(1, 1.05)"), 10);
+    assert_eq!(int("approx_eq(2, 2) * 10 + approx_eq(0.0 / 0.0, 0.0 / 0.0)"), 10);
+    assert!(matches!(error("approx_eq(1, \"1\");"), Error::InvalidArgument(_, _)));
+}
+
+#[test]
+fn keys_and_values_in_key_order() {
+    assert_eq!(int("m = {\"b\": 2, 10: 3, \"a\": 1, -1: 4}; keys(m) == [-1, 10, \"a\", \"b\"]"), 1);
+    assert_eq!(int("m = {\"b\": 2, 10: 3, \"a\": 1, -1: 4}; values(m) == [4, 3, 1, 2]"), 1);
+    assert_eq!(int("length(keys({})) + length(values({}))"), 0);
+    // values are the elements themselves
+    assert_eq!(int("m = {\"l\": [1]}; append(values(m)[0], 2); length(m[\"l\"])"), 2);
+    assert!(matches!(error("keys([1]);"), Error::InvalidArgument(_, _)));
+    assert!(matches!(error("values(1);"), Error::InvalidArgument(_, _)));
+}
+
+#[test]
+fn native_argument_counts_are_checked_when_running() {
+    // code may not come from the compiler
+    let run = |code| VM::new().run(&Program::new(code));
+    let length_of_two = vec![Op::PushI(1), Op::PushI(2), Op::Native(2, Native::Length)];
+    assert!(matches!(run(length_of_two).unwrap_err().cause(), Error::InvalidNativeCall(Native::Length, 2)));
+    let insert_of_one = vec![Op::PushI(1), Op::Native(1, Native::Insert)];
+    assert!(matches!(run(insert_of_one).unwrap_err().cause(), Error::InvalidNativeCall(Native::Insert, 1)));
+    let missing_arguments = vec![Op::PushI(1), Op::Native(3, Native::Insert)];
+    assert!(matches!(run(missing_arguments).unwrap_err().cause(), Error::StackUnderflow));
+    assert!(run(vec![Op::PushI(1), Op::Native(1, Native::Length)]).is_ok());
+}
+
+#[test]
+fn native_argument_counts_are_checked_when_compiling() {
+    assert!(matches!(error("length();"), Error::NotEnoughArguments(_, name, 0, 1) if name == "length"));
+    assert!(matches!(error("insert([], 0);"), Error::NotEnoughArguments(_, name, 2, 3) if name == "insert"));
+    assert!(matches!(error("x = 1 + range(1, 2, 3, 4);"), Error::TooManyArguments(_, name, 4, 3) if name == "range"));
+    assert_eq!(error("ord(\"a\", \"b\");").to_string(), "Too many arguments to ord, given 2 but expected at most 1");
+
+    let mut vm = VM::new();
+    vm.register_native("twice", 1, Box::new(|vm, args| Ok(vm.get(args[0])?.clone())));
+    assert!(matches!(eval_on(&mut vm, "twice(1, 2);"), Err(Error::TooManyArguments(_, name, 2, 1)) if name == "twice"));
+}
+
+/// A `Write` whose output can be read after it is given to a VM
+#[derive(Clone, Default)]
+struct Shared(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn dump_heap_lists_values_and_their_reachability() {
+    let out = Shared::default();
+    let mut vm = VM::new().with_output(out.clone());
+    eval_on(&mut vm, "[5, 6]; x = [1, \"a\"]; n = dump_heap();").unwrap();
+    let dump = String::from_utf8(out.0.borrow().clone()).unwrap();
+    let lines: Vec<&str> = dump.lines().collect();
+    assert!(lines.iter().any(|line| line.ends_with(": [5, 6] (unreachable)")), "{}", dump);
+    assert!(lines.iter().any(|line| line.ends_with(": [1, a]")), "{}", dump);
+    assert!(lines.iter().all(|line| line.split(": ").next().unwrap().parse::<usize>().is_ok()), "{}", dump);
+    assert!(matches!(eval_on(&mut vm, "n;"), Ok(Value::Int(n)) if n as usize == lines.len()));
+
+    // the same listing as from the host, which has nothing unreachable after a collection
+    vm.collect();
+    assert!(!vm.dump_heap().unwrap().contains("(unreachable)"));
+}
+
+#[test]
+fn weak_references_do_not_keep_values_alive() {
+    let mut vm = VM::new();
+    eval_on(&mut vm, "x = [1]; w = weak(x); l = [w, weak(x)];").unwrap();
+    assert!(matches!(eval_on(&mut vm, "(deref_weak(w) == x) + (deref_weak(l[1]) == x) * 10;"), Ok(Value::Int(11))));
+    assert!(matches!(eval_on(&mut vm, "to_string(w);"), Ok(Value::Str(s)) if s == "<weak>"));
+
+    eval_on(&mut vm, "x = nil;").unwrap();
+    vm.collect();
+    assert!(matches!(eval_on(&mut vm, "(deref_weak(w) == nil) + (deref_weak(l[1]) == nil) * 10;"), Ok(Value::Int(11))));
+    assert!(matches!(eval_on(&mut vm, "to_string(w);"), Ok(Value::Str(s)) if s == "<weak collected>"));
+    assert!(matches!(error("deref_weak([1]);"), Error::InvalidArgument(Native::DerefWeak, _)));
+}
+
+/// What `source` prints on `vm`
+fn output_of(vm: VM, source: &str) -> String {
+    let out = Shared::default();
+    let mut vm = vm.with_output(out.clone());
+    eval_on(&mut vm, source).unwrap();
+    let bytes = out.0.borrow().clone();
+    String::from_utf8(bytes).unwrap()
+}
+
+#[test]
+fn strings_count_chars() {
+    // a thumbs up with a skin tone modifier is two chars
+    assert_eq!(output_of(VM::new(), "s = \"a\u{1F44D}\u{1F3FD}b\"; print(length(s), \" \", ord(s[2]));"), "4 127997\n");
+}
+
+#[cfg(feature = "graphemes")]
+#[test]
+fn graphemes_count_composed_characters_once() {
+    let source = "s = \"a\u{1F44D}\u{1F3FD}b\"; print(gllength(s), \" \", length(glat(s, 1)), \" \", glat(s, -1), glat(s, 0), \" \", glat(s, 3));";
+    assert_eq!(output_of(VM::new(), source), "3 2 ba nil\n");
+    assert_eq!(output_of(VM::new(), "print(gllength(\"\"), gllength([1, 2]));"), "02\n");
+}
+
+#[test]
+fn print_shows_nesting_up_to_the_print_depth() {
+    let five = "print([1, [2, [3, [4, [5]]]]]);";
+    assert_eq!(output_of(VM::new().with_print_depth(2), five), "[1, [2, [3, [...]]]]\n");
+    assert_eq!(output_of(VM::new().with_print_depth(5), five), "[1, [2, [3, [4, [5]]]]]\n");
+    // which the default already allows
+    assert_eq!(output_of(VM::new(), five), "[1, [2, [3, [4, [5]]]]]\n");
+
+    // maps count as a level too
+    let deeper = "l = [0]; for (i = 1; i < 12; i = i + 1) { l = [i, {\"n\": l}]; } print(l[1][\"n\"][1][\"n\"][1][\"n\"][1][\"n\"][1][\"n\"]);";
+    assert_eq!(output_of(VM::new(), deeper), "[6, {n: [5, {n: [4, {n: [3, {n: [2, {...}]}]}]}]}]\n");
+    assert_eq!(output_of(VM::new().with_print_depth(20), deeper), "[6, {n: [5, {n: [4, {n: [3, {n: [2, {n: [1, {n: [0]}]}]}]}]}]}]\n");
+}
+
+#[test]
+fn pretty_print_indents_nested_values() {
+    let x = "x = [1, [2, [\"three\"]], {\"a\": [], \"b\": {\"c\": nil}}, {}];";
+    assert_eq!(output_of(VM::new(), &format!("{} print(x);", x)), "[1, [2, [three]], {a: [], b: {c: nil}}, {}]\n");
+    assert_eq!(output_of(VM::new(), &format!("{} pretty_print(x);", x)), "\
+[
+  1,
+  [
+    2,
+    [
+      three
+    ]
+  ],
+  {
+    a: [],
+    b: {
+      c: nil
+    }
+  },
+  {}
+]
+");
+    // scalars print as with print
+    assert_eq!(output_of(VM::new(), "pretty_print(1.5); pretty_print(\"s\"); pretty_print([]);"), "1.5\ns\n[]\n");
+
+    // nesting is limited as with print, which also stops cycles
+    let nested = "l = [1, [2, [3, [4]]]]; pretty_print(l); print(l);";
+    assert_eq!(output_of(VM::new().with_print_depth(1), nested), "[\n  1,\n  [\n    2,\n    [...]\n  ]\n]\n[1, [2, [...]]]\n");
+    let cycle = output_of(VM::new().with_print_depth(2), "l = [1]; append(l, l); pretty_print(l);");
+    assert_eq!(cycle, "[\n  1,\n  [\n    1,\n    [\n      1,\n      [...]\n    ]\n  ]\n]\n");
+}
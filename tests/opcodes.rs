@@ -0,0 +1,127 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use script::{opcodes::{disassemble, Native, Op}, program::{Function, Program}};
+
+/// Expected mnemonic of `op`, which tools rely on, so they must not change
+fn stable_mnemonic(op: &Op) -> &'static str {
+    match op {
+        Op::Target(_) => "target",
+        Op::Nop => "nop",
+        Op::Native(_, _) => "native",
+        Op::Foreign(_, _) => "foreign",
+        Op::PushI(_) => "pushi",
+        Op::PushI0 => "pushi0",
+        Op::PushI1 => "pushi1",
+        Op::PushIByte(_) => "pushib",
+        Op::PushF(_) => "pushf",
+        Op::PushNil => "pushnil",
+        Op::PushS(_) => "pushs",
+        Op::MakeList(_) => "mklist",
+        Op::MakeMap(_) => "mkmap",
+        Op::Interpolate(_) => "interp",
+        Op::Index => "index",
+        Op::IndexStore => "idxstore",
+        Op::Slice => "slice",
+        Op::SliceStore => "slicestore",
+        Op::Function(_) => "function",
+        Op::Call(_) => "call",
+        Op::Return => "return",
+        Op::LoadL(_) => "loadl",
+        Op::StoreL(_) => "storel",
+        Op::MoveL(_) => "movel",
+        Op::Dup(_) => "dup",
+        Op::Swap => "swap",
+        Op::NotNil => "notnil",
+        Op::Line(_) => "line",
+        Op::Pop => "pop",
+        Op::LoadG(_) => "loadg",
+        Op::StoreG(_) => "storeg",
+        Op::MoveG(_) => "moveg",
+        Op::Lt => "lt",
+        Op::Lte => "lte",
+        Op::Gt => "gt",
+        Op::Gte => "gte",
+        Op::Eq => "eq",
+        Op::Neq => "neq",
+        Op::JmpF(_) => "jmpf",
+        Op::JmpT(_) => "jmpt",
+        Op::Jmp(_) => "jmp",
+        Op::Add => "add",
+        Op::Sub => "sub",
+        Op::Mul => "mul",
+        Op::Div => "div",
+        Op::Mod => "mod",
+        Op::Shl => "shl",
+        Op::Shr => "shr",
+        Op::Neg => "neg",
+        Op::Not => "not",
+        Op::Concat => "concat",
+        Op::AddI => "addi",
+        Op::SubI => "subi",
+        Op::MulI => "muli",
+        Op::Yield => "yield",
+        Op::JmpTable(_, _) => "jmptable",
+        Op::IndexInto => "idxinto",
+    }
+}
+
+/// One opcode of each variant
+fn every_op() -> Vec<Op> {
+    vec![
+        Op::Target(0), Op::Nop, Op::Native(1, Native::Print), Op::Foreign(2, "f".to_string()),
+        Op::PushI(-7), Op::PushI0, Op::PushI1, Op::PushIByte(-1), Op::PushF(0.5), Op::PushNil,
+        Op::PushS("s".to_string()), Op::MakeList(2), Op::MakeMap(1), Op::Interpolate(3),
+        Op::Index, Op::IndexInto, Op::IndexStore, Op::Slice, Op::SliceStore, Op::Dup(1), Op::Swap, Op::NotNil,
+        Op::Line(4), Op::Pop, Op::LoadG("a".to_string()), Op::StoreG("b".to_string()),
+        Op::MoveG("c".to_string()), Op::Lt, Op::Lte, Op::Gt, Op::Gte, Op::Eq, Op::Neq,
+        Op::JmpF(1), Op::JmpT(2), Op::Jmp(0), Op::Add, Op::Sub, Op::Mul, Op::Div, Op::Mod,
+        Op::Shl, Op::Shr, Op::Neg, Op::Not, Op::Concat, Op::AddI, Op::SubI, Op::MulI,
+        Op::Function(Arc::new(Function { name: "f".to_string(), arity: 1, locals: 0, generator: false, program: Program::new(vec![Op::Return]) })),
+        Op::Call(1), Op::Return, Op::LoadL(1), Op::StoreL(2), Op::MoveL(0), Op::Yield,
+        Op::JmpTable(-1, vec![0, 2]),
+    ]
+}
+
+#[test]
+fn every_opcode_has_a_stable_mnemonic() {
+    let ops = every_op();
+    for op in &ops {
+        assert_eq!(op.mnemonic(), stable_mnemonic(op), "{:?}", op);
+    }
+    // mnemonics are unique, so this covers every variant
+    let unique: HashSet<_> = ops.iter().map(|op| op.mnemonic()).collect();
+    assert_eq!(unique.len(), ops.len());
+    assert_eq!(unique.len(), 57);
+}
+
+#[test]
+fn disassembly_lists_mnemonics_and_operands() {
+    let code = vec![
+        Op::PushIByte(3), Op::MoveG("x".to_string()), Op::LoadG("x".to_string()), Op::JmpF(7),
+        Op::PushS("a\"b".to_string()), Op::Native(1, Native::Print), Op::Jmp(2), Op::PushF(1.0), Op::Pop,
+    ];
+    assert_eq!(disassemble(&code), [
+        "   0   pushib   3",
+        "   1   moveg    x",
+        "   2 > loadg    x",
+        "   3   jmpf     -> 7",
+        "   4   pushs    \"a\\\"b\"",
+        "   5   native   print/1",
+        "   6   jmp      -> 2",
+        "   7 > pushf    1.0",
+        "   8   pop",
+        "",
+    ].join("\n"));
+    // as are the targets of a jump table
+    assert_eq!(disassemble(&[Op::JmpTable(-1, vec![2, 1]), Op::Pop, Op::Pop]), "   0   jmptable -1 -> 2, 1\n   1 > pop\n   2 > pop\n");
+
+    // every instruction is on its own line, starting with its index and mnemonic
+    let ops = every_op();
+    let text = disassemble(&ops);
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines.len(), ops.len());
+    for (i, (line, op)) in lines.iter().zip(&ops).enumerate() {
+        let fields: Vec<&str> = line.split_whitespace().filter(|field| *field != ">").collect();
+        assert_eq!(fields[.. 2], [i.to_string().as_str(), op.mnemonic()], "{}", line);
+    }
+}
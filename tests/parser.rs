@@ -0,0 +1,141 @@
+use script::{
+    ast::Ast,
+    compiler::Compiler,
+    errors::Error,
+    eval,
+    lexer::Lexer,
+    parser::Parser,
+    token::{Kind, Token},
+    value::Value,
+};
+
+/// Errors of parsing (and compiling, if it parsed) `source`
+fn errors(source: &str) -> Vec<Error> {
+    let (asts, mut errors) = Parser::new(Lexer::new(source)).unwrap().parse_all();
+    let mut compiler = Compiler::new();
+    for ast in &asts {
+        if let Err(err) = compiler.feed(ast) {
+            errors.push(err);
+        }
+    }
+    errors
+}
+
+fn assert_too_deep(source: &str) {
+    let errors = errors(source);
+    assert!(errors.iter().any(|err| matches!(err, Error::NestingTooDeep(_))), "{:?}", errors);
+}
+
+/// Value of `source`, that must be an integer
+fn int(source: &str) -> i64 {
+    match eval(source) {
+        Ok(Value::Int(n)) => n,
+        other => panic!("expected an integer, got {:?}", other),
+    }
+}
+
+#[test]
+fn thousands_of_nested_parentheses() {
+    assert_too_deep(&format!("x = {}1{};", "(".repeat(5000), ")".repeat(5000)));
+    assert_too_deep(&format!("x = {}1;", "(".repeat(5000)));
+}
+
+#[test]
+fn long_operator_chains() {
+    assert_too_deep(&format!("x = 1{};", " + 1".repeat(30_000)));
+    assert_too_deep(&format!("x = 1{};", " && 1".repeat(30_000)));
+    assert_too_deep(&format!("x = 1{};", " * 1 - 1".repeat(30_000)));
+    assert_too_deep(&format!("x = {}1;", "-".repeat(30_000)));
+    assert_too_deep(&format!("{}1;", "x = ".repeat(30_000)));
+}
+
+#[test]
+fn long_index_chains() {
+    assert_too_deep(&format!("a = [0]; x = a{};", "[0]".repeat(30_000)));
+    assert_too_deep(&format!("a = [0]; x = a{};", "[0:1]".repeat(30_000)));
+}
+
+#[test]
+fn deeply_nested_statements_and_literals() {
+    assert_too_deep(&format!("{}{}", "{".repeat(5000), "}".repeat(5000)));
+    assert_too_deep(&format!("{}{}", "while 0 {".repeat(5000), "}".repeat(5000)));
+    assert_too_deep(&format!("if 0 {{}}{}", " else if 0 {}".repeat(5000)));
+    assert_too_deep(&format!("x = {}{};", "[".repeat(5000), "]".repeat(5000)));
+    assert_too_deep(&format!("x = {}1{};", "{\"a\": ".repeat(5000), "}".repeat(5000)));
+    assert_too_deep(&format!("x = {}1{};", "\"${".repeat(5000), "}\"".repeat(5000)));
+}
+
+#[test]
+fn usual_nesting_is_accepted() {
+    assert_eq!(int(&format!("{}1{};", "(".repeat(50), ")".repeat(50))), 1);
+    assert_eq!(int(&format!("1{};", " + 1".repeat(50))), 51);
+    assert_eq!(int(&format!("a = 7; {}a{};", "[".repeat(25), "][0]".repeat(25))), 7);
+    assert_eq!(int(&format!("x = 0; {}x = 3;{} x;", "{".repeat(50), "}".repeat(50))), 3);
+}
+
+#[test]
+fn limit_can_be_raised() {
+    let source = format!("x = 1{};", " + 1".repeat(150));
+    let mut parser = Parser::new(Lexer::new(&source)).unwrap();
+    assert!(matches!(parser.next(), Err(Error::NestingTooDeep(_))));
+
+    let mut parser = Parser::new(Lexer::new(&source)).unwrap().with_max_depth(200);
+    let ast = parser.next().unwrap().unwrap();
+    assert!(matches!(Compiler::new().feed(&ast), Err(Error::NestingTooDeep(_))));
+    assert!(Compiler::new().max_depth(200).feed(&ast).is_ok());
+}
+
+#[test]
+fn compiler_rejects_deep_asts_built_by_hand() {
+    let token = |kind, value: &str| Token { kind, value: value.to_string(), at: 0..1, line: 1, col: 1 };
+    let mut ast = Ast::Int(1, token(Kind::Int, "1"));
+    for _ in 0..1000 {
+        ast = Ast::BinOp(token(Kind::Add, "+"), Box::new(ast), Box::new(Ast::Int(1, token(Kind::Int, "1"))));
+    }
+    assert!(matches!(Compiler::new().feed(&ast), Err(Error::NestingTooDeep(_))));
+}
+
+#[test]
+fn negating_the_smallest_integer_is_not_folded() {
+    // the literal cannot be written in source, but an `Ast` can hold it
+    let token = |kind, value: &str| Token { kind, value: value.to_string(), at: 0..1, line: 1, col: 1 };
+    let ast = Ast::Unary(token(Kind::Sub, "-"), Box::new(Ast::Int(i64::MIN, token(Kind::Int, "0"))));
+    let mut compiler = Compiler::new();
+    compiler.feed(&ast).unwrap();
+    let program = compiler.build().unwrap();
+    assert!(matches!(script::vm::VM::new().run(&program).unwrap_err().cause(), Error::IntegerOverflow));
+}
+
+#[test]
+fn while_let_loops_until_nil() {
+    // falsy values that are not nil do not stop the loop
+    assert_eq!(int("q = [1, 0, [], 2]; n = 0; while let x = dequeue(q) { n = n + 1; } n * 10 + length(q);"), 40);
+    assert_eq!(int("q = [3, 4]; s = 0; while let x = dequeue(q) { s = s * 10 + x; } s + (x == nil) * 100;"), 134);
+    assert_eq!(int("n = 0; while let x = nil { n = 1; } n;"), 0);
+    // with continue and break
+    assert_eq!(int("q = [1, 2, 3, 4, 5]; s = 0; while let x = dequeue(q) { if x == 2 { continue; } if x == 4 { break; } s = s + x; } s * 10 + length(q);"), 41);
+    assert!(!errors("while let 1 = x {}").is_empty());
+    assert!(!errors("while let x = {}").is_empty());
+}
+
+#[test]
+fn sources_ending_where_a_token_is_checked_are_errors() {
+    // each ends where the parser checks if the next token is one of some kinds
+    for source in ["x", "x = 1", "x =", "-", "!", "a[0]", "f(1)", "let", "if 1 {} else", "for (", "while 1 {} x"] {
+        let (_, errors) = Parser::new(Lexer::new(source)).unwrap().parse_all();
+        assert!(matches!(errors.as_slice(), [Error::UnexpectedEOF, ..]), "{}: {:?}", source, errors);
+    }
+    assert!(Parser::new(Lexer::new("")).unwrap().next().unwrap().is_none());
+}
+
+#[test]
+fn every_prefix_of_a_program_parses_without_panicking() {
+    let source = "let m = {\"a\": [1, -2]}; for (i = 0; i < 3; i = i + 1) { if !m[\"a\"][0:1] { x = f(i)[0]; } else { continue; } }";
+    for end in 0 .. source.len() {
+        // a prefix cutting a string fails already when lexing
+        if let Ok(mut parser) = Parser::new(Lexer::new(&source[.. end])) {
+            parser.parse_all();
+        }
+    }
+    assert!(Parser::new(Lexer::new(source)).unwrap().parse_all().1.is_empty());
+}
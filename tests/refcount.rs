@@ -0,0 +1,157 @@
+use std::{cell::RefCell, io::Write, rc::Rc};
+
+use script::{eval_on, value::Value, vm::VM};
+
+fn counting() -> VM {
+    VM::new().with_reference_counting(true)
+}
+
+/// Number of values on the heap, and how many of these are unreachable
+fn heap_entries(vm: &VM) -> (usize, usize) {
+    let dump = vm.dump_heap().unwrap();
+    let lines: Vec<&str> = dump.lines().filter(|line| !line.is_empty()).collect();
+    (lines.len(), lines.iter().filter(|line| line.ends_with("(unreachable)")).count())
+}
+
+#[test]
+fn unreferenced_values_are_freed_promptly() {
+    let mut vm = counting();
+    eval_on(&mut vm, "x = [[1, 2], {\"a\": [3]}]; w = weak(x); y = x[1];").unwrap();
+    assert!(matches!(eval_on(&mut vm, "deref_weak(w) != nil;"), Ok(Value::Int(1))));
+
+    // no collection runs for this small heap, so only counting frees `x`
+    eval_on(&mut vm, "x = nil;").unwrap();
+    assert!(matches!(eval_on(&mut vm, "deref_weak(w) == nil;"), Ok(Value::Int(1))));
+    assert!(matches!(eval_on(&mut vm, "y[\"a\"][0];"), Ok(Value::Int(3))));
+    assert_eq!(heap_entries(&vm).1, 0);
+}
+
+#[test]
+fn temporaries_do_not_accumulate() {
+    let mut vm = counting();
+    eval_on(&mut vm, "
+        l = [];
+        for (i = 0; i < 200; i = i + 1) {
+            s = \"${i}\" + to_string([i, i * 2]);
+            append(l, s);
+            if length(l) > 10 { dequeue(l); }
+            m = {\"k\": [i]};
+            m[\"k\"] = nil;
+            l[0 : 1] = [l[0]];
+        }
+    ").unwrap();
+    eval_on(&mut vm, "nil;").unwrap();
+    let (entries, unreachable) = heap_entries(&vm);
+    assert_eq!(unreachable, 0);
+    assert!(entries < 100, "{} entries left", entries);
+}
+
+#[test]
+fn cycles_are_left_to_a_full_collection() {
+    let mut vm = counting();
+    eval_on(&mut vm, "a = []; append(a, a); w = weak(a); a = nil;").unwrap();
+    assert!(matches!(eval_on(&mut vm, "deref_weak(w) != nil;"), Ok(Value::Int(1))));
+    vm.collect();
+    assert!(matches!(eval_on(&mut vm, "deref_weak(w) == nil;"), Ok(Value::Int(1))));
+}
+
+#[test]
+fn cycles_do_not_grow_the_heap_forever() {
+    let mut vm = counting();
+    eval_on(&mut vm, "
+        for (i = 0; i < 20000; i = i + 1) {
+            a = [i]; b = [a]; append(a, b);
+            m = {\"x\": 1}; m[\"self\"] = [m];
+        }
+    ").unwrap();
+    let (entries, _) = heap_entries(&vm);
+    assert!(entries < 10_000, "{} entries left", entries);
+    assert!(matches!(eval_on(&mut vm, "a[1][0][0];"), Ok(Value::Int(19999))));
+    assert!(matches!(eval_on(&mut vm, "m[\"self\"][0][\"x\"];"), Ok(Value::Int(1))));
+}
+
+#[test]
+fn finalizers_run_when_the_last_reference_is_dropped() {
+    let mut vm = counting();
+    let finalized = Rc::new(RefCell::new(vec![]));
+    eval_on(&mut vm, "x = [\"resource\"]; y = x;").unwrap();
+    let log = finalized.clone();
+    vm.register_finalizer(vm.get_global("x").unwrap(), move |_, value| log.borrow_mut().push(format!("{:?}", value))).unwrap();
+
+    eval_on(&mut vm, "x = nil;").unwrap();
+    assert!(finalized.borrow().is_empty());
+    eval_on(&mut vm, "y = 1;").unwrap();
+    assert_eq!(finalized.borrow().len(), 1);
+}
+
+#[test]
+fn changes_by_the_host_are_counted() {
+    let mut vm = counting();
+    eval_on(&mut vm, "l = []; w = weak(l);").unwrap();
+    let item = vm.push_value(Value::Int(42)).unwrap();
+    let list = vm.get_global("l").unwrap();
+    match vm.get_mut(list).unwrap() {
+        Value::List(lst) => lst.push(item),
+        other => panic!("expected a list, got {:?}", other),
+    }
+    vm.pop().unwrap();
+    // the element is now only referred to by the list
+    assert!(matches!(eval_on(&mut vm, "l[0] + 1;"), Ok(Value::Int(43))));
+
+    match vm.get_mut(list).unwrap() {
+        Value::List(lst) => lst.clear(),
+        other => panic!("expected a list, got {:?}", other),
+    }
+    eval_on(&mut vm, "l = nil;").unwrap();
+    assert!(matches!(eval_on(&mut vm, "deref_weak(w) == nil;"), Ok(Value::Int(1))));
+    assert_eq!(heap_entries(&vm).1, 0);
+}
+
+#[test]
+fn caches_keep_their_values() {
+    let mut vm = counting().with_string_interning(true).with_small_int_tagging(true);
+    eval_on(&mut vm, "s = \"abc\"; n = 7; s = nil; n = nil;").unwrap();
+    assert!(matches!(eval_on(&mut vm, "\"abc\" + \"d\";"), Ok(Value::Str(s)) if s == "abcd"));
+    assert!(matches!(eval_on(&mut vm, "7 * 6;"), Ok(Value::Int(42))));
+}
+
+/// A `Write` whose output can be read after it is given to a VM
+#[derive(Clone, Default)]
+struct Shared(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn same_output_as_tracing() {
+    let source = "
+        q = [];
+        for (i = 0; i < 50; i = i + 1) { enqueue(q, [i, \"${i}\"]); }
+        total = 0;
+        while length(q) > 0 {
+            e = dequeue(q);
+            total = total + e[0];
+            if e[0] % 7 == 0 { insert(q, 0, [e[0] + 1, \"again\"]); }
+        }
+        m = {\"a\": [1], \"b\": [2]};
+        m = m + {\"b\": [3]};
+        print(\"${total} ${m} ${keys(m)} ${range(3)}\");
+    ";
+    let output = |vm: VM| {
+        let out = Shared::default();
+        let mut vm = vm.with_output(out.clone());
+        eval_on(&mut vm, source).unwrap();
+        let bytes = out.0.borrow().clone();
+        String::from_utf8(bytes).unwrap()
+    };
+    let traced = output(VM::new());
+    assert!(traced.starts_with("1"), "{}", traced);
+    assert_eq!(output(counting()), traced);
+}
@@ -0,0 +1,211 @@
+use script::{errors::Error, eval, value::Value};
+
+/// Value of the expression `source`, that must be an integer (e.g. a comparison)
+fn int(source: &str) -> i64 {
+    match eval(&format!("{};", source)) {
+        Ok(Value::Int(n)) => n,
+        other => panic!("{}: expected an integer, got {:?}", source, other),
+    }
+}
+
+#[test]
+fn int_float_equality_is_exact() {
+    assert_eq!(int("1 == 1.0"), 1);
+    assert_eq!(int("1.0 == 1"), 1);
+    assert_eq!(int("1 == 1.5"), 0);
+    assert_eq!(int("-3 == -3.0"), 1);
+    assert_eq!(int("0 == 0.0"), 1);
+    assert_eq!(int("0 == -0.0"), 1);
+    assert_eq!(int("-0.0 == 0"), 1);
+    assert_eq!(int("1 != 2.0"), 1);
+    assert_eq!(int("1 == 2.0"), 0);
+
+    // 2^53 + 1 is not representable as f64, and rounds to 2^53
+    assert_eq!(int("9007199254740993 == 9007199254740992.0"), 0);
+    assert_eq!(int("9007199254740992 == 9007199254740992.0"), 1);
+    assert_eq!(int("9007199254740993 != 9007199254740992.0"), 1);
+}
+
+#[test]
+fn int_float_equality_out_of_range() {
+    // 2^63 is past the largest i64, even though i64::MAX as f64 rounds to it
+    assert_eq!(int("9223372036854775807 == 9223372036854775808.0"), 0);
+    assert_eq!(int("-9223372036854775807 - 1 == -9223372036854775808.0"), 1);
+    assert_eq!(int("0 == 0.0 / 0.0"), 0);
+    assert_eq!(int("1 == 1e300"), 0);
+}
+
+#[test]
+fn int_float_equality_inside_containers() {
+    assert_eq!(int("[1, 2] == [1.0, 2.0]"), 1);
+    assert_eq!(int("[9007199254740993] == [9007199254740992.0]"), 0);
+    assert_eq!(int("({\"a\": 9007199254740993} == {\"a\": 9007199254740992.0})"), 0);
+}
+
+#[test]
+fn maps_merge_with_add() {
+    assert_eq!(int("({\"a\": 1} + {\"b\": 2}) == {\"a\": 1, \"b\": 2}"), 1);
+    // keys on the right override those on the left
+    assert_eq!(int("({\"a\": 1, \"b\": 2} + {\"b\": 3, 4: 5}) == {\"a\": 1, \"b\": 3, 4: 5}"), 1);
+    assert_eq!(int("({\"b\": 3} + {\"a\": 1, \"b\": 2})[\"b\"]"), 2);
+    assert_eq!(int("length({} + {})"), 0);
+
+    // operands are left untouched
+    assert_eq!(int("a = {\"k\": 1}; b = a + {\"j\": 2}; b[\"k\"] = 3; length(a) * 10 + a[\"k\"]"), 11);
+    assert!(matches!(eval("({\"a\": 1} + [1]);"), Err(Error::Located(err, _)) if matches!(*err, Error::IncompatibleOperands(_, _, _))));
+}
+
+#[test]
+fn lists_compare_lexicographically() {
+    assert_eq!(int("[1, [2, 3]] < [1, [2, 4]]"), 1);
+    assert_eq!(int("[1, 2] < [1]"), 0);
+    assert_eq!(int("[1] < [1, 2]"), 1);
+    assert_eq!(int("[[1], 2] >= [[1], 2]"), 1);
+    assert!(matches!(eval("[1] < [[1]];"), Err(Error::Located(err, _)) if matches!(*err, Error::IncompatibleOperands(_, _, _))));
+}
+
+#[test]
+fn cyclic_lists_compare() {
+    // lists already being compared are the same, so these end
+    assert_eq!(int("a = []; append(a, a); b = []; append(b, b); (a < b) + (a > b) * 10 + (a == b) * 100"), 100);
+    assert_eq!(int("a = [1]; append(a, a); b = [1]; append(b, b); append(b, 1); a < b"), 1);
+    assert_eq!(int("a = [1]; append(a, a); b = [2]; append(b, b); (a < b) + (a == b) * 10"), 1);
+    assert_eq!(int("m = {\"x\": 1}; m[\"m\"] = m; n = {\"x\": 1}; n[\"m\"] = n; m == n"), 1);
+}
+
+#[test]
+fn deeply_nested_lists_compare() {
+    // nesting built at run time is not limited by the parser, and used to overflow the stack
+    let nest = |tail: &str| format!("a = []; b = []; for (i = 0; i < 50000; i = i + 1) {{ a = [a]; b = [b]; }} {}", tail);
+    assert_eq!(int(&nest("(a == b) + (a < b) * 10 + (a <= b) * 100")), 101);
+    assert_eq!(int(&nest("c = [a, 2]; d = [b, 1]; (c > d) + (c == d) * 10")), 1);
+    assert_eq!(int(&nest("a[0][0][0] = [[]]; (a == b) + (a < b) * 10")), 10);
+}
+
+#[test]
+fn different_types_are_not_equal() {
+    assert_eq!(int("(\"a\" == 1) + (\"a\" != 1) * 10"), 10);
+    assert_eq!(int("([1] == 1) + ([1] != 1) * 10"), 10);
+    assert_eq!(int("({\"a\": 1} == [1]) + ({\"a\": 1} != [1]) * 10"), 10);
+    assert_eq!(int("(\"1\" == [\"1\"]) + (nil == 0) * 10 + (weak([]) == []) * 100"), 0);
+    // ordering them is still an error
+    assert!(matches!(eval("\"a\" < 1;"), Err(Error::Located(err, _)) if matches!(*err, Error::IncompatibleOperands(_, _, _))));
+}
+
+#[test]
+fn weak_references_compare_by_entry() {
+    assert_eq!(int("a = [1]; b = [1]; (weak(a) == weak(a)) + (weak(a) == weak(b)) * 10 + (weak(a) != weak(b)) * 100"), 101);
+    // equal lists, but different entries
+    assert_eq!(int("a = [1]; b = [1]; wa = weak(a); wb = weak(b); (wa == wb) + (a == b) * 10"), 10);
+    // once both are collected, they are cleared alike
+    assert_eq!(int("wa = weak([1]); wb = weak([2]); for (i = 0; i < 2000; i = i + 1) { t = [i]; } wa == wb"), 1);
+}
+
+#[test]
+fn nan_is_ordered_last() {
+    let nan = |tail: &str| int(&format!("nan = 0.0 / 0.0; {}", tail));
+    assert_eq!(nan("(nan < 1) + (1 < nan) * 10 + (nan > 1e308) * 100"), 110);
+    assert_eq!(nan("(nan <= nan) + (nan < nan) * 10"), 1);
+    // ordering puts it after every number, as when taking the smallest or largest
+    assert_eq!(nan("min(3, nan, 1.5, 2) == 1.5"), 1);
+    assert_eq!(nan("n = max([3, nan, 2]); n != n"), 1);
+    assert_eq!(nan("([1, nan] < [1, 2]) + ([nan] > [1]) * 10"), 10);
+}
+
+#[test]
+fn nan_is_not_equal_to_itself() {
+    let nan = |tail: &str| int(&format!("nan = 0.0 / 0.0; {}", tail));
+    assert_eq!(nan("(nan == nan) + (nan != nan) * 10"), 10);
+    assert_eq!(nan("x = nan; (x == x) + ([x] == [x]) * 10 + ({\"k\": x} == {\"k\": x}) * 100"), 0);
+    assert_eq!(nan("(nan == 0) + (nan == 0.0) * 10"), 0);
+}
+
+/// `to_string` of the map built by `source`
+fn printed(source: &str) -> String {
+    match eval(&format!("m = {}; to_string(m);", source)) {
+        Ok(Value::Str(s)) => s,
+        other => panic!("{}: expected a string, got {:?}", source, other),
+    }
+}
+
+#[test]
+fn maps_print_sorted_by_key() {
+    // integers first, then strings
+    let expected = "{-5: 1, 3: 2, a: 3, b: 4, ba: 5}";
+    for source in &[
+        "{-5: 1, 3: 2, \"a\": 3, \"b\": 4, \"ba\": 5}",
+        "{\"ba\": 5, \"b\": 4, 3: 2, \"a\": 3, -5: 1}",
+        "{3: 2, \"b\": 4, -5: 1, \"ba\": 5, \"a\": 3}",
+        // keys added one by one, in reverse
+        "{}; m[\"ba\"] = 5; m[\"b\"] = 4; m[\"a\"] = 3; m[3] = 2; m[-5] = 1",
+        // and merged from two maps
+        "{\"ba\": 5} + {\"a\": 3, -5: 1, \"b\": 4, 3: 2}",
+    ] {
+        assert_eq!(printed(source), expected, "{}", source);
+    }
+    // so are keys and values
+    assert!(matches!(eval("m = {\"b\": 2, 1: 3, \"a\": 1}; to_string([keys(m), values(m)]);"), Ok(Value::Str(s)) if s == "[[1, a, b], [3, 1, 2]]"));
+}
+
+#[test]
+fn integers_shift() {
+    assert_eq!(int("1 << 2"), 4);
+    assert_eq!(int("-8 >> 1"), -4);
+    assert_eq!(int("1 << 63 == -9223372036854775807 - 1"), 1);
+    assert_eq!(int("3 << 62 >> 62"), -1);
+    // looser than `+`, tighter than comparisons
+    assert_eq!(int("1 << 1 + 1"), 4);
+    assert_eq!(int("1 << 2 == 4"), 1);
+    assert!(matches!(eval("1 << 64;"), Err(Error::Located(err, _)) if matches!(*err, Error::InvalidShift(64))));
+    assert!(matches!(eval("1 >> -1;"), Err(Error::Located(err, _)) if matches!(*err, Error::InvalidShift(-1))));
+    assert!(matches!(eval("1.0 << 1;"), Err(Error::Located(err, _)) if matches!(*err, Error::IncompatibleOperands(_, _, _))));
+}
+
+/// Whether `source` fails with `IntegerOverflow` at run time
+fn overflows(source: &str) -> bool {
+    matches!(eval(&format!("{};", source)), Err(Error::Located(err, _)) if matches!(*err, Error::IntegerOverflow))
+}
+
+#[test]
+fn integer_arithmetic_overflow_is_an_error() {
+    let (max, min) = ("9223372036854775807", "(-9223372036854775807 - 1)");
+    // with literals the compiler knows the operands are integers, and specializes the
+    // operators; through variables the generic ones are used
+    for (a, b) in &[(max, "1"), (min, "-1")] {
+        assert!(overflows(&format!("{} + {}", a, b)));
+        assert!(overflows(&format!("x = {}; x + {}", a, b)));
+        assert!(overflows(&format!("{} - -{}", a, b)));
+        assert!(overflows(&format!("x = {}; y = {}; x - -y", a, b)));
+        assert!(overflows(&format!("{} * 2", a)));
+        assert!(overflows(&format!("x = {}; x * 2", a)));
+    }
+    assert!(overflows(&format!("x = {}; -x", min)));
+    assert!(overflows(&format!("{} / -1", min)));
+
+    // results that fit are exact
+    assert_eq!(int(&format!("{} - 1 + 1 == {}", max, max)), 1);
+    assert_eq!(int(&format!("{} % -1", min)), 0);
+    assert_eq!(int(&format!("{} / 2 * 2 + 1 == {}", max, max)), 1);
+    assert_eq!(int(&format!("-({} + 1) == {}", min, max)), 1);
+    // floats do not overflow
+    assert_eq!(int(&format!("{} * 2.0 > 1e18", max)), 1);
+}
+
+const FALSY: &[&str] = &["nil", "0", "0.0", "-0.0", "\"\"", "[]", "({})"];
+const TRUTHY: &[&str] = &["1", "-1", "0.5", "(0.0 / 0.0)", "\" \"", "\"0\"", "[0]", "[[]]", "({0: 0})", "weak([])"];
+
+#[test]
+fn falsy_values() {
+    for (values, truthy) in [(FALSY, 0), (TRUTHY, 1)] {
+        for value in values {
+            assert_eq!(int(&format!("x = 0; if {} {{ x = 1; }} x", value)), truthy, "if {}", value);
+            assert_eq!(int(&format!("x = 0; if {} {{ x = 1; }} else {{ x = 0; }} x", value)), truthy, "if-else {}", value);
+            assert_eq!(int(&format!("x = 0; while {} && x < 1 {{ x = x + 1; }} x", value)), truthy, "while {}", value);
+            assert_eq!(int(&format!("!{}", value)), 1 - truthy, "!{}", value);
+            // `&&` and `||` give the operand that decided the result
+            assert_eq!(int(&format!("({} && 2) == 2", value)), truthy, "&& {}", value);
+            assert_eq!(int(&format!("({} || 2) == 2", value)), 1 - truthy, "|| {}", value);
+            assert_eq!(eval(&format!("{};", value)).unwrap().is_truthy(), truthy == 1, "{}", value);
+        }
+    }
+}
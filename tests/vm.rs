@@ -0,0 +1,584 @@
+#![allow(clippy::result_large_err)]
+
+use std::{cell::RefCell, io::Write, rc::Rc};
+use script::{compiler::Compiler, errors::Error, eval_on, lexer::Lexer, opcodes::{disassemble, Op}, parser::Parser, program::Program, value::Value, vm::VM};
+
+/// Integers reachable from the global `name` after running `source`, with their depth
+fn walked_ints(source: &str, name: &str) -> (Vec<(i64, usize)>, usize) {
+    let mut vm = VM::new();
+    eval_on(&mut vm, source).unwrap();
+    let mut ints = vec![];
+    let mut visited = 0;
+    vm.walk(vm.get_global(name).unwrap(), &mut |value, depth| {
+        visited += 1;
+        if let Value::Int(n) = value {
+            ints.push((*n, depth));
+        }
+    }).unwrap();
+    (ints, visited)
+}
+
+#[test]
+fn walk_descends_into_maps() {
+    let (ints, visited) = walked_ints("m = {\"c\": {\"d\": 3}, \"a\": [1, {\"b\": 2}], 0: 4};", "m");
+    // integer keys come before strings, as maps are ordered
+    assert_eq!(ints, vec![(4, 1), (1, 2), (2, 3), (3, 2)]);
+    assert_eq!(visited, 8);
+}
+
+#[test]
+fn walk_visits_cycles_once() {
+    let (ints, visited) = walked_ints("m = {\"n\": 1}; m[\"self\"] = m; l = [m, m];", "l");
+    assert_eq!(ints, vec![(1, 2)]);
+    assert_eq!(visited, 3);
+}
+
+/// Value of `source` run on `vm`, that must be an integer
+fn int(vm: &mut VM, source: &str) -> i64 {
+    match eval_on(vm, source) {
+        Ok(Value::Int(n)) => n,
+        other => panic!("{}: expected an integer, got {:?}", source, other),
+    }
+}
+
+#[test]
+fn maps_get_and_set() {
+    let mut vm = VM::new();
+    eval_on(&mut vm, "m = {\"a\": 1, 2: \"two\"};").unwrap();
+    assert_eq!(int(&mut vm, "m[\"a\"];"), 1);
+    assert!(matches!(eval_on(&mut vm, "m[2];"), Ok(Value::Str(s)) if s == "two"));
+
+    // setting a new key adds it, an existing one is updated
+    assert_eq!(int(&mut vm, "m[\"b\"] = 3; m[\"a\"] = m[\"a\"] + 10; m[\"a\"] * 100 + m[\"b\"] * 10 + length(m);"), 1133);
+    assert!(matches!(eval_on(&mut vm, "m[[1]];"), Err(Error::Located(err, _)) if matches!(*err, Error::InvalidKey(_))));
+}
+
+/// Error of running `source` on `vm`, without its location
+fn located_error(vm: &mut VM, source: &str) -> Error {
+    match eval_on(vm, source) {
+        Err(Error::Located(err, _)) => *err,
+        other => panic!("{}: expected a located error, got {:?}", source, other),
+    }
+}
+
+#[test]
+fn dotted_paths_get_and_set_nested_maps() {
+    let mut vm = VM::new();
+    eval_on(&mut vm, "config = {\"server\": {\"port\": 80, \"hosts\": [{\"name\": \"a\"}]}, \"debug\": 0};").unwrap();
+    assert_eq!(int(&mut vm, "config.server.port;"), 80);
+    assert!(matches!(eval_on(&mut vm, "config.server.hosts[0].name;"), Ok(Value::Str(s)) if s == "a"));
+
+    // `a.b` is `a["b"]`, so both set the same entry, and assignments are still expressions
+    assert_eq!(int(&mut vm, "x = (config.server.port = 8080); config[\"server\"].port + x;"), 16160);
+    assert_eq!(int(&mut vm, "config.server.hosts[0].port = 1; config.server.new = 2; config.server.hosts[0][\"port\"] * 10 + config[\"server\"][\"new\"];"), 12);
+    assert_eq!(int(&mut vm, "fun set(c, v) { c.server.port = v; } set(config, 443); config.server.port;"), 443);
+
+    // a missing intermediate, or one that is not a list or map, has nothing to store into
+    let err = located_error(&mut vm, "config.client.port = 1;");
+    assert!(matches!(err, Error::CannotIndexInto(Value::Str(ref k), Value::Nil) if k == "client"), "{:?}", err);
+    assert!(matches!(located_error(&mut vm, "config.debug.level = 1;"), Error::CannotIndexInto(_, Value::Int(0))));
+    assert!(matches!(located_error(&mut vm, "config.server.hosts[3].name = 1;"), Error::CannotIndexInto(Value::Int(3), Value::Nil)));
+    assert_eq!(located_error(&mut vm, "config.server.port.x.y = 1;").to_string(), "Cannot index into integer at key Str(\"port\")");
+    // reading is as indexing
+    assert!(matches!(located_error(&mut vm, "config.client.port;"), Error::KeyNotFound(_)));
+    assert!(matches!(Parser::new(Lexer::new("config.1;")).and_then(|mut p| p.next()), Err(Error::UnexpectedToken(_, _))));
+    assert!(matches!(eval_on(&mut vm, "to_string(config.server.hosts);"), Ok(Value::Str(s)) if s == "[{name: a, port: 1}]"));
+}
+
+#[test]
+fn missing_keys_are_errors_by_default() {
+    let mut vm = VM::new();
+    let missing = eval_on(&mut vm, "m = {\"a\": 1}; m[\"b\"];");
+    assert!(matches!(missing, Err(Error::Located(err, _)) if matches!(*err, Error::KeyNotFound(Value::Str(ref k)) if k == "b")));
+}
+
+#[test]
+fn missing_keys_can_be_nil() {
+    let mut vm = VM::new().with_missing_key_as_nil(true);
+    assert_eq!(int(&mut vm, "m = {\"a\": 1}; (m[\"b\"] == nil) + (m[\"a\"] == 1) * 10;"), 11);
+    // reading does not add the key
+    assert_eq!(int(&mut vm, "length(m);"), 1);
+    // the tolerance is only for maps
+    assert!(matches!(eval_on(&mut vm, "[1][5];"), Err(Error::Located(err, _)) if matches!(*err, Error::IndexOutOfRange(_, 5))));
+}
+
+/// Program compiled from `source`
+fn compile(source: &str) -> Program {
+    let mut parser = Parser::new(Lexer::new(source)).unwrap();
+    let mut compiler = Compiler::new();
+    while let Some(ast) = parser.next().unwrap() {
+        compiler.feed(&ast).unwrap();
+    }
+    compiler.build().unwrap()
+}
+
+/// VM after running `source` for at most `max_steps` opcodes, that must not be exceeded
+fn run_limited(source: &str, max_steps: u64) -> VM {
+    let mut vm = VM::new();
+    vm.run_with_limit(&compile(source), max_steps).unwrap();
+    vm
+}
+
+#[test]
+fn infinite_loops_stop_at_the_step_limit() {
+    let mut vm = VM::new();
+    let result = vm.run_with_limit(&compile("n = 0; while 1 { n = n + 1; }"), 1000);
+    assert!(matches!(result, Err(Error::Located(err, _)) if matches!(*err, Error::StepLimitExceeded)));
+    // having run part of the loop
+    let n = int(&mut vm, "n;");
+    assert!(n > 10 && n < 1000, "{} iterations", n);
+
+    // the limit counts opcodes, and is not hit by a program of exactly that length
+    let program = compile("x = 1; y = x + 2;");
+    let size = program.code.len() as u64;
+    assert!(VM::new().run_with_limit(&program, size).is_ok());
+    assert!(VM::new().run_with_limit(&program, size - 1).is_err());
+}
+
+#[test]
+fn continue_in_for_runs_the_update() {
+    let mut vm = run_limited("
+        odd = 0; n = 0;
+        for (i = 0; i < 10; i = i + 1) {
+            if i % 2 == 0 { continue; }
+            odd = odd + i;
+        }
+        for (j = 0; j < 10; j = j + 1) {
+            k = 0;
+            while k < 3 { k = k + 1; if k == 2 { continue; } n = n + 1; }
+            if j > 4 { continue; }
+            n = n + 100;
+        }
+    ", 10_000);
+    assert_eq!(int(&mut vm, "odd;"), 25);
+    assert_eq!(int(&mut vm, "n;"), 5 * 100 + 10 * 2);
+    assert_eq!(int(&mut vm, "i * 100 + j;"), 1010);
+}
+
+#[test]
+fn labeled_continue_runs_the_outer_update() {
+    let mut vm = run_limited("
+        inner = 0;
+        outer: for (i = 0; i < 5; i = i + 1) {
+            for (j = 0; j < 5; j = j + 1) { inner = inner + 1; continue outer; }
+        }
+    ", 10_000);
+    assert_eq!(int(&mut vm, "i * 100 + inner;"), 505);
+}
+
+#[test]
+fn labeled_break_leaves_both_loops() {
+    let mut vm = run_limited("
+        steps = 0;
+        outer: while 1 {
+            i = 0;
+            while 1 { i = i + 1; steps = steps + 1; if i == 3 { break outer; } }
+            steps = steps + 100;
+        }
+        steps = steps + 1000;
+    ", 10_000);
+    assert_eq!(int(&mut vm, "steps;"), 1003);
+}
+
+#[test]
+fn continue_in_while_tests_the_condition() {
+    let mut vm = run_limited("skipped = 0; i = 0; while i < 10 { i = i + 1; if i > 3 { skipped = skipped + 1; continue; } }", 10_000);
+    assert_eq!(int(&mut vm, "i * 100 + skipped;"), 1007);
+}
+
+#[test]
+fn match_runs_the_first_arm_with_an_equal_pattern() {
+    let mut vm = VM::new();
+    eval_on(&mut vm, r#"
+        fun name(x) {
+            match x {
+                1 => { r = "one"; }
+                2, 3 => { r = "two or three"; }
+                "s", [1] => { r = "string or list"; }
+                3 => { r = "never"; }
+                _ => { r = "other"; }
+            }
+        }
+    "#).unwrap();
+    for (subject, expected) in [("1", "one"), ("3", "two or three"), ("2.0", "two or three"), ("\"s\"", "string or list"), ("[1]", "string or list"), ("7", "other"), ("nil", "other")] {
+        assert!(matches!(eval_on(&mut vm, &format!("name({}); r;", subject)), Ok(Value::Str(s)) if s == expected), "{}", subject);
+    }
+    // without a default arm, nothing is run
+    assert_eq!(int(&mut vm, "r = 0; match 5 { 1 => { r = 1; } } match 1 {} r;"), 0);
+    assert_eq!(vm.stack_len(), 0);
+    // the default arm must be the last one
+    assert!(matches!(Parser::new(Lexer::new("match x { _ => {} 1 => {} }")).and_then(|mut p| p.next()), Err(Error::UnexpectedToken(_, _))));
+}
+
+/// Number of opcodes executed running `source`
+fn steps(source: &str) -> u64 {
+    let program = compile(source);
+    (1 ..).find(|limit| VM::new().run_with_limit(&program, *limit).is_ok()).unwrap()
+}
+
+#[test]
+fn dense_integer_matches_dispatch_in_constant_time() {
+    // 0 to 15, with 13 missing and -1 for 14
+    let arms: String = (0 .. 16).filter(|i| *i != 13).map(|i| format!("{} => {{ r = {}; }} ", if i == 14 { -1 } else { i }, i * 10)).collect();
+    let dense = |x: &str| format!("x = {}; match x {{ {}_ => {{ r = 999; }} }}", x, arms);
+    assert!(disassemble(&compile(&dense("0")).code).contains("jmptable -1 -> "));
+    for (subject, expected) in [("0", 0), ("15", 150), ("-1", 140), ("13", 999), ("16", 999), ("-2", 999), ("7.0", 70), ("7.5", 999), ("\"a\"", 999)] {
+        let mut vm = VM::new();
+        assert_eq!(int(&mut vm, &format!("{} r;", dense(subject))), expected, "{}", subject);
+    }
+    let first = steps(&dense("0"));
+    assert_eq!(steps(&dense("15")), first);
+    assert_eq!(steps(&dense("16")), steps(&dense("-2")));
+
+    // sparse patterns are compared in turn
+    let arms: String = (0 .. 16).map(|i| format!("{} => {{ r = {}; }} ", i * 100, i)).collect();
+    let sparse = |x: &str| format!("x = {}; match x {{ {}_ => {{ r = -1; }} }}", x, arms);
+    assert!(!disassemble(&compile(&sparse("0")).code).contains("jmptable"));
+    assert_eq!(int(&mut VM::new(), &format!("{} r;", sparse("1500"))), 15);
+    assert_eq!(steps(&sparse("1500")), steps(&sparse("0")) + 15 * 4);
+}
+
+/// `to_string` of the value of the last expression of `source`, after its statements
+fn printed(source: &str) -> String {
+    let (statements, last) = match source.rsplit_once("; ") {
+        Some((statements, last)) => (format!("{};", statements), last),
+        None => (String::new(), source),
+    };
+    match eval_on(&mut VM::new(), &format!("{} to_string({});", statements, last)) {
+        Ok(Value::Str(s)) => s,
+        other => panic!("{}: expected a string, got {:?}", source, other),
+    }
+}
+
+#[test]
+fn slices_clamp_their_bounds() {
+    assert_eq!(printed("[1, 2, 3][0:100]"), "[1, 2, 3]");
+    assert_eq!(printed("[1, 2, 3][-100:2]"), "[1, 2]");
+    assert_eq!(printed("[1, 2, 3][-2:100]"), "[2, 3]");
+    assert_eq!(printed("[1, 2, 3][5:9]"), "[]");
+    // an end before the start is empty
+    assert_eq!(printed("[1, 2, 3][2:1]"), "[]");
+    assert_eq!(printed("[1, 2, 3][-1:-2]"), "[]");
+    assert_eq!(printed("[][0:1]"), "[]");
+    assert_eq!(printed("[1, 2][-9223372036854775807 - 1:9223372036854775807]"), "[1, 2]");
+    assert_eq!(printed("\"abc\"[1:100] + \"abc\"[-100:1] + \"abc\"[3:4]"), "bca");
+}
+
+#[test]
+fn slice_assignments_clamp_their_bounds() {
+    assert_eq!(printed("l = [1, 2, 3]; l[1:100] = [9]; l"), "[1, 9]");
+    assert_eq!(printed("l = [1, 2, 3]; l[-100:1] = [8, 9]; l"), "[8, 9, 2, 3]");
+    // past the end or with an end before the start, it inserts at the start
+    assert_eq!(printed("l = [1, 2, 3]; l[10:20] = [4]; l"), "[1, 2, 3, 4]");
+    assert_eq!(printed("l = [1, 2, 3]; l[2:0] = [9]; l"), "[1, 2, 9, 3]");
+}
+
+#[test]
+fn slice_assignments_splice_lists() {
+    // equal size, growing and shrinking
+    assert_eq!(printed("l = [1, 2, 3, 4]; l[1:3] = [8, 9]; l"), "[1, 8, 9, 4]");
+    assert_eq!(printed("l = [1, 2, 3]; l[0:1] = [7, 8, 9]; l"), "[7, 8, 9, 2, 3]");
+    assert_eq!(printed("l = [1, 2, 3, 4]; l[1:4] = [0]; l"), "[1, 0]");
+    assert_eq!(printed("l = [1, 2, 3]; l[0:3] = []; l"), "[]");
+    // an empty range inserts
+    assert_eq!(printed("l = [1, 2]; l[1:1] = [5, 6]; l"), "[1, 5, 6, 2]");
+    // negative indices count from the end
+    assert_eq!(printed("l = [1, 2, 3, 4]; l[-2:-1] = [0, 0]; l"), "[1, 2, 0, 0, 4]");
+    // the list can be spliced into itself, and the assignment gives the replacement
+    assert_eq!(printed("l = [1, 2]; l[1:2] = l; l"), "[1, 1, 2]");
+    assert_eq!(printed("l = [1, 2, 3]; r = (l[0:2] = [4]); [r, l]"), "[[4], [4, 3]]");
+    // elements are shared, not copied
+    assert_eq!(printed("l = [0]; e = [1]; l[0:1] = [e, e]; e[0] = 2; l"), "[[2], [2]]");
+
+    let mut vm = VM::new();
+    assert!(matches!(error(&mut vm, "l = [1, 2]; l[0:1] = 5;"), Error::IncompatibleOperands(_, _, _)));
+    assert!(matches!(error(&mut vm, "s = \"abc\"; s[0:1] = \"x\";"), Error::IncompatibleOperands(_, _, _)));
+    assert!(matches!(error(&mut vm, "l = [1, 2]; l[0:\"a\"] = [];"), Error::IncompatibleOperands(_, _, _)));
+}
+
+/// A `Write` whose output can be read after it is given to a VM
+#[derive(Clone, Default)]
+struct Shared(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A VM with its output buffered into the returned `Shared`, with a `written()` native
+/// giving how many bytes reached it so far
+fn buffered_vm() -> (VM, Shared) {
+    let out = Shared::default();
+    let mut vm = VM::new().with_buffered_output(out.clone());
+    let seen = out.clone();
+    vm.register_native("written", 0, Box::new(move |_, _| Ok(Value::Int(seen.0.borrow().len() as i64))));
+    (vm, out)
+}
+
+#[test]
+fn buffered_output_is_flushed_after_run() {
+    let (mut vm, out) = buffered_vm();
+    let source = "i = 0; while i < 100 { print(\"line\"); i = i + 1; } n = written();";
+    eval_on(&mut vm, source).unwrap();
+    // nothing was written while running, and everything after it
+    assert!(matches!(vm.get_global("n"), Some(ptr) if matches!(vm.get(ptr), Ok(Value::Int(0)))));
+    assert_eq!(String::from_utf8(out.0.borrow().clone()).unwrap(), "line\n".repeat(100));
+}
+
+#[test]
+fn flush_writes_buffered_output() {
+    let (mut vm, out) = buffered_vm();
+    assert_eq!(int(&mut vm, "print(\"ab\"); a = written(); flush(); a * 10 + written();"), 3);
+    assert_eq!(out.0.borrow().as_slice(), b"ab\n");
+}
+
+#[test]
+fn buffered_output_is_flushed_after_errors() {
+    let (mut vm, out) = buffered_vm();
+    assert!(eval_on(&mut vm, "print(\"before\"); x = [1] - 1;").is_err());
+    assert_eq!(out.0.borrow().as_slice(), b"before\n");
+}
+
+/// The error of running `source` on `vm`, without its location
+fn error(vm: &mut VM, source: &str) -> Error {
+    match eval_on(vm, source) {
+        Err(Error::Located(err, _)) => *err,
+        Err(err) => err,
+        Ok(value) => panic!("{}: expected an error, got {:?}", source, value),
+    }
+}
+
+#[test]
+fn repetitions_are_limited() {
+    let mut vm = VM::new();
+    assert!(matches!(error(&mut vm, "\"a\" * 1000000000;"), Error::AllocationTooLarge(1000000000)));
+    assert!(matches!(error(&mut vm, "[1, 2] * 1000000000;"), Error::AllocationTooLarge(2000000000)));
+    assert!(matches!(error(&mut vm, "range(1000000000);"), Error::AllocationTooLarge(_)));
+    // the length overflowing is too large as well
+    assert!(matches!(error(&mut vm, "\"ab\" * 9223372036854775807;"), Error::AllocationTooLarge(_)));
+    // the VM is still usable after
+    assert_eq!(int(&mut vm, "length(\"ab\" * 1000) + length([1] * 0);"), 2000);
+
+    let mut vm = VM::new().with_max_repeat_length(10);
+    assert_eq!(int(&mut vm, "length(\"ab\" * 5) + length([1, 2] * 5) + length(range(10));"), 30);
+    assert!(matches!(error(&mut vm, "\"ab\" * 6;"), Error::AllocationTooLarge(12)));
+    assert!(matches!(error(&mut vm, "[nil] * 11;"), Error::AllocationTooLarge(11)));
+    assert!(matches!(error(&mut vm, "range(11);"), Error::AllocationTooLarge(11)));
+}
+
+#[test]
+fn values_convert_to_and_from_rust() {
+    let mut vm = VM::new();
+    vm.set_global("n", Value::from(20)).unwrap();
+    vm.set_global("x", Value::from(0.5)).unwrap();
+    vm.set_global("name", Value::from("world")).unwrap();
+    vm.set_global("suffix", Value::from(String::from("!"))).unwrap();
+    eval_on(&mut vm, "greeting = \"hello \" + name + suffix; l = [n + 1, n * x, greeting, [nil]];").unwrap();
+
+    let greeting = vm.get(vm.get_global("greeting").unwrap()).unwrap();
+    assert_eq!(greeting.as_str(), Some("hello world!"));
+    assert_eq!(greeting.as_i64(), None);
+
+    let list = vm.to_rust_list(vm.get_global("l").unwrap()).unwrap();
+    assert_eq!(list.len(), 4);
+    assert_eq!(list[0].as_i64(), Some(21));
+    assert_eq!(list[1].as_f64(), Some(10.0));
+    assert_eq!(list[1].as_i64(), None);
+    assert_eq!(list[2].as_str(), Some("hello world!"));
+    // nested containers still point into the VM
+    match &list[3] {
+        Value::List(inner) => assert!(matches!(vm.get(inner[0]), Ok(Value::Nil))),
+        other => panic!("expected a list, got {:?}", other),
+    }
+    assert!(matches!(vm.to_rust_list(vm.get_global("n").unwrap()), Err(Error::UnexpectedType(_, Value::Int(20)))));
+}
+
+#[test]
+fn undefined_globals_can_be_nil() {
+    let source = "seen = []; i = 0; while i < 2 { append(seen, later); later = i; i = i + 1; } to_string(seen);";
+    assert!(matches!(error(&mut VM::new(), source), Error::GlobalNotFound(name) if name == "later"));
+
+    // a reference before the definition sees nil, and the value after it
+    let mut vm = VM::new().with_undefined_as_nil(true);
+    assert!(matches!(eval_on(&mut vm, source), Ok(Value::Str(s)) if s == "[nil, 0]"));
+    assert_eq!(int(&mut vm, "(never == nil) * 10 + (later == 1);"), 11);
+    // reading does not define the global
+    assert!(vm.get_global("never").is_none());
+}
+
+/// Line reported by the VM after running `source`, compiled with line markers if `markers`,
+/// and whether it failed
+fn failing_line(source: &str, markers: bool) -> (usize, bool) {
+    let mut parser = Parser::new(Lexer::new(source)).unwrap();
+    let mut compiler = Compiler::new().line_markers(markers);
+    while let Some(ast) = parser.next().unwrap() {
+        compiler.feed(&ast).unwrap();
+    }
+    let mut vm = VM::new();
+    let failed = vm.run(&compiler.build().unwrap()).is_err();
+    (vm.current_line(), failed)
+}
+
+#[test]
+fn line_markers_report_the_failing_line() {
+    let source = "x = 1;\nif x {\n  y = 2;\n  z = [1] - 1;\n}\nw = 3;\n";
+    assert_eq!(failing_line(source, true), (4, true));
+    assert_eq!(failing_line(source, false), (0, true));
+
+    // loop conditions are marked too
+    let source = "i = 0;\nwhile\n  i < 3 - [] {\n  i = i + 1;\n}\n";
+    assert_eq!(failing_line(source, true), (3, true));
+    let source = "for (i = 0; i < 3; i = i + 1) {\n  x = i;\n}\ny = nope;\n";
+    assert_eq!(failing_line(source, true), (4, true));
+    // after running without errors, it is the last line executed
+    assert_eq!(failing_line("x = 1;\nwhile x < 3 {\n  x = x + 1;\n}\n", true), (2, false));
+}
+
+#[test]
+fn interned_strings_share_one_entry() {
+    let source = "a = \"key\"; b = \"key\"; l = []; for (i = 0; i < 3; i = i + 1) { append(l, \"key\"); } c = \"other\";";
+    let mut vm = VM::new().with_string_interning(true);
+    eval_on(&mut vm, source).unwrap();
+    let a = vm.get_global("a").unwrap();
+    assert_eq!(vm.get_global("b"), Some(a));
+    assert_ne!(vm.get_global("c"), Some(a));
+    match vm.get(vm.get_global("l").unwrap()) {
+        Ok(Value::List(l)) => assert!(l.iter().all(|ptr| *ptr == a)),
+        other => panic!("expected a list, got {:?}", other),
+    }
+    // strings built at runtime are not interned
+    eval_on(&mut vm, "d = \"ke\" + \"y\";").unwrap();
+    assert_ne!(vm.get_global("d"), Some(a));
+    assert_eq!(int(&mut vm, "(a == d) + (a == b) * 10;"), 11);
+
+    // they are kept while unreferenced, and still shared after
+    eval_on(&mut vm, "a = nil; b = nil; l = nil;").unwrap();
+    vm.collect();
+    assert!(matches!(vm.get(a), Ok(Value::Str(s)) if s == "key"));
+    eval_on(&mut vm, "e = \"key\";").unwrap();
+    assert_eq!(vm.get_global("e"), Some(a));
+
+    // without interning, each literal is its own entry
+    let mut vm = VM::new();
+    eval_on(&mut vm, source).unwrap();
+    assert_ne!(vm.get_global("a"), vm.get_global("b"));
+}
+
+/// Time comparing two copies of a long literal string, with `interning`
+fn string_comparisons(interning: bool) -> std::time::Duration {
+    let mut vm = VM::new().with_string_interning(interning);
+    let long = "x".repeat(2_000_000);
+    eval_on(&mut vm, &format!("a = \"{}\"; b = \"{}\";", long, long)).unwrap();
+    let start = std::time::Instant::now();
+    assert_eq!(int(&mut vm, "n = 0; for (i = 0; i < 200; i = i + 1) { n = n + (a == b); } n;"), 200);
+    start.elapsed()
+}
+
+#[test]
+fn interned_strings_compare_by_entry() {
+    let interned = string_comparisons(true);
+    let copies = string_comparisons(false);
+    println!("string comparisons: {:?} interned, {:?} copies", interned, copies);
+    assert!(interned * 3 < copies, "{:?} interned, {:?} copies", interned, copies);
+}
+
+/// Time comparing a long list with `other` (`l` itself or a copy)
+fn list_comparisons(other: &str) -> std::time::Duration {
+    let mut vm = VM::new();
+    eval_on(&mut vm, &format!("l = range(10000); m = range(10000); o = {};", other)).unwrap();
+    let start = std::time::Instant::now();
+    assert_eq!(int(&mut vm, "n = 0; for (i = 0; i < 20; i = i + 1) { n = n + (l == o) - (l != o); } n;"), 20);
+    start.elapsed()
+}
+
+#[test]
+fn values_are_equal_to_themselves() {
+    let mut vm = VM::new();
+    assert_eq!(int(&mut vm, "x = 1; l = [1, [2]]; (x == x) + (l == l) * 10 + (l[1] == l[1]) * 100 + (l != l) * 1000;"), 111);
+    assert_eq!(int(&mut vm, "c = [1]; append(c, c); (c == c) + (c != c) * 10;"), 1);
+    assert_eq!(int(&mut vm, "m = {\"a\": []}; s = \"s\"; (m == m) + (s == s) * 10 + (nil == nil) * 100;"), 111);
+    // except NaN, even in the same entry
+    assert_eq!(int(&mut vm, "f = 0.0 / 0.0; (f == f) + (f != f) * 10 + ([f] == [f]) * 100;"), 10);
+
+    // without comparing the elements
+    let same = list_comparisons("l");
+    let copy = list_comparisons("m");
+    println!("list comparisons: {:?} with itself, {:?} with a copy", same, copy);
+    assert!(same * 3 < copy, "{:?} with itself, {:?} with a copy", same, copy);
+}
+
+#[test]
+fn reset_clears_state_but_keeps_configuration() {
+    let out = Shared::default();
+    let mut vm = VM::new().with_output(out.clone()).with_output_limit(8).with_max_repeat_length(10);
+    vm.register_native("twice", 1, Box::new(|vm, args| match vm.get(args[0])? {
+        Value::Int(n) => Ok(Value::Int(n * 2)),
+        other => Ok(other.clone()),
+    }));
+    eval_on(&mut vm, "x = twice(21); l = [x, [x]]; print(\"1234\");").unwrap();
+    let finalized = Rc::new(RefCell::new(false));
+    let flag = finalized.clone();
+    vm.register_finalizer(vm.get_global("l").unwrap(), move |_, _| *flag.borrow_mut() = true).unwrap();
+    assert!(matches!(error(&mut vm, "print(\"12345\");"), Error::OutputLimitExceeded));
+
+    vm.reset();
+    assert!(vm.get_global("x").is_none());
+    assert_eq!(vm.stack_len(), 0);
+    assert!(matches!(error(&mut vm, "x;"), Error::GlobalNotFound(name) if name == "x"));
+    // finalizers of the cleared values are dropped, not called
+    vm.collect();
+    assert!(!*finalized.borrow());
+
+    // natives, limits and output are kept, with the output budget restored
+    assert_eq!(int(&mut vm, "twice(4);"), 8);
+    assert!(matches!(error(&mut vm, "\"ab\" * 6;"), Error::AllocationTooLarge(12)));
+    eval_on(&mut vm, "print(\"abcdefg\");").unwrap();
+    assert_eq!(out.0.borrow().as_slice(), b"1234\nabcdefg\n");
+    assert!(matches!(error(&mut vm, "print(\"h\");"), Error::OutputLimitExceeded));
+}
+
+#[test]
+fn logical_operators_short_circuit() {
+    let out = Shared::default();
+    let mut vm = VM::new().with_output(out.clone());
+    eval_on(&mut vm, "
+        a = 0 && print(\"and skipped\");
+        b = 1 || print(\"or skipped\");
+        c = 1 && print(\"and ran\");
+        d = 0 || print(\"or ran\");
+        n = 0;
+        e = 0 && (n = n + 1) || nil && (n = n + 10) || (n = n + 100);
+    ").unwrap();
+    assert_eq!(String::from_utf8(out.0.borrow().clone()).unwrap(), "and ran\nor ran\n");
+    assert_eq!(int(&mut vm, "n;"), 100);
+    // the result is the operand that decided it
+    assert_eq!(int(&mut vm, "(a == 0) + (b == 1) * 10 + (c == nil) * 100 + (d == nil) * 1000 + (e == 100) * 10000;"), 11111);
+    assert!(matches!(eval_on(&mut vm, "[] || \"x\";"), Ok(Value::Str(s)) if s == "x"));
+    assert!(matches!(eval_on(&mut vm, "[1] && \"\";"), Ok(Value::Str(s)) if s.is_empty()));
+}
+
+#[test]
+fn jumps_past_the_end_are_invalid() {
+    let run = |code: Vec<Op>| {
+        let mut vm = VM::new();
+        match vm.run(&Program::new(code)) {
+            Ok(()) => Ok(vm.stack_len()),
+            Err(Error::Located(err, _)) => Err(*err),
+            Err(err) => Err(err),
+        }
+    };
+    // to the end is a normal exit
+    assert!(matches!(run(vec![Op::PushI1, Op::Jmp(3), Op::PushI0]), Ok(1)));
+    assert!(matches!(run(vec![Op::PushI0, Op::JmpF(2)]), Ok(0)));
+    // past it is not, for any jump taken
+    assert!(matches!(run(vec![Op::PushI1, Op::Jmp(4), Op::PushI0]), Err(Error::InvalidOpCode(1))));
+    assert!(matches!(run(vec![Op::PushI0, Op::JmpF(usize::MAX)]), Err(Error::InvalidOpCode(1))));
+    assert!(matches!(run(vec![Op::PushI1, Op::PushI1, Op::JmpT(9)]), Err(Error::InvalidOpCode(2))));
+    // a jump that is not taken is not checked
+    assert!(matches!(run(vec![Op::PushI1, Op::JmpF(9)]), Ok(0)));
+}